@@ -0,0 +1,247 @@
+use std::{cmp::Reverse, collections::HashMap, net::IpAddr};
+
+use crate::{
+    expected_timeline, find_clusters, hosts::Target, recovery, replicas::Replica, resolve_cached,
+    timeline, Host, EXIT_UNHEALTHY,
+};
+
+/// One standby weighed as a promotion target
+struct Candidate<'a> {
+    host: &'a Host,
+    /// Everything it has of the WAL, replayed or not
+    received: i64,
+    /// How its primary counts it in synchronous replication, `None` when the primary wasn't
+    /// checked or doesn't list it
+    sync_state: Option<&'a str>,
+}
+
+impl Candidate<'_> {
+    /// Further ahead first, then synchronous before asynchronous, then the one with less left
+    /// to replay and the fresher one
+    fn rank(&self) -> (Reverse<i64>, Reverse<u8>, Reverse<i64>, u64) {
+        let sync = match self.sync_state {
+            Some("sync") => 3,
+            Some("quorum") => 2,
+            Some("potential") => 1,
+            _ => 0,
+        };
+        let lag = self
+            .host
+            .lag_seconds
+            .map_or(u64::MAX, |s| (s * 1000.0) as u64);
+        (
+            Reverse(self.received),
+            Reverse(sync),
+            Reverse(self.host.wal_lsn.unwrap_or(0)),
+            lag,
+        )
+    }
+}
+
+/// Ranks each cluster's standbys by how safe promoting them would be and prints them with the
+/// WAL each would lose, against the primary's position when it was checked and the furthest
+/// standby's otherwise. Standbys on another timeline than the cluster, or whose position wasn't
+/// checked, are listed as ineligible. Returns the exit code, unhealthy when a cluster has no
+/// standby to promote
+pub async fn run(hosts: &[Target], res: &[Host]) -> u8 {
+    let mut resolved = HashMap::new();
+    let mut code = 0;
+    for cluster in find_clusters(res) {
+        let members: Vec<&Host> = res
+            .iter()
+            .filter(|r| cluster.hosts.contains(&r.name))
+            .collect();
+        let primary = members.iter().find(|r| r.is_primary);
+        let Some(expected) = primary
+            .and_then(|p| p.timeline_id)
+            .or_else(|| expected_timeline(&members))
+        else {
+            continue;
+        };
+
+        let mut candidates = Vec::new();
+        let mut ineligible = Vec::new();
+        for r in members.iter().filter(|r| !r.is_primary) {
+            let (Some(timeline), Some(replayed)) = (r.timeline_id, r.wal_lsn) else {
+                ineligible.push(format!(
+                    "{}: its timeline or WAL position wasn't checked",
+                    r.name
+                ));
+                continue;
+            };
+            if timeline != expected {
+                ineligible.push(format!(
+                    "{}: on timeline {}, the cluster is on {}",
+                    r.name, timeline, expected
+                ));
+                continue;
+            }
+            let sync_state = match primary {
+                Some(p) => sync_state(&mut resolved, hosts, p, r).await,
+                None => None,
+            };
+            candidates.push(Candidate {
+                host: r,
+                received: replayed + r.replay_gap_bytes.unwrap_or(0),
+                sync_state,
+            });
+        }
+        candidates.sort_by_key(|c| c.rank());
+
+        let reference = match primary.and_then(|p| p.wal_lsn) {
+            Some(at) => {
+                println!(
+                    "Cluster {}, primary {} on timeline {} at {}:",
+                    cluster.system_identifier,
+                    primary.map_or("?", |p| p.name.as_str()),
+                    expected,
+                    timeline::format_lsn(at)
+                );
+                Some(at)
+            }
+            None => {
+                println!(
+                    "Cluster {}, on timeline {} without a primary checked, losses against the \
+                     standby furthest ahead:",
+                    cluster.system_identifier, expected
+                );
+                candidates.first().map(|c| c.received)
+            }
+        };
+        for (i, c) in candidates.iter().enumerate() {
+            let loss = reference.map_or(0, |at| (at - c.received).max(0));
+            let loses = match loss {
+                0 => "loses nothing".to_string(),
+                n => format!("loses {} bytes", n),
+            };
+            let mut notes = vec![
+                format!("received {}", timeline::format_lsn(c.received)),
+                format!(
+                    "replayed {}",
+                    timeline::format_lsn(c.host.wal_lsn.unwrap_or(0))
+                ),
+            ];
+            notes.extend(c.sync_state.map(str::to_string));
+            notes.extend(c.host.lag_seconds.map(|s| format!("{:.1}s behind", s)));
+            if c.host.replay_paused == Some(true) {
+                notes.push("replay paused".to_string());
+            }
+            println!(
+                "  {}. {}: {}, {}{}",
+                i + 1,
+                c.host.name,
+                notes.join(", "),
+                loses,
+                if i == 0 { " (safest)" } else { "" }
+            );
+        }
+        for reason in &ineligible {
+            println!("  -  {}", reason);
+        }
+        if candidates.is_empty() {
+            println!("  No standby to promote");
+            code = EXIT_UNHEALTHY;
+        }
+    }
+    code
+}
+
+/// The standby's sync_state in its primary's pg_stat_replication, found by the application_name
+/// in its primary_conninfo, by its address, or by the name WAL receivers get by default when
+/// it streams from the primary and only one goes by it
+async fn sync_state<'a>(
+    resolved: &mut HashMap<String, Vec<IpAddr>>,
+    hosts: &[Target],
+    primary: &'a Host,
+    standby: &Host,
+) -> Option<&'a str> {
+    let named = standby
+        .recovery
+        .as_ref()
+        .and_then(|c| c.primary_conninfo.as_deref())
+        .and_then(recovery::application_name);
+    let called = |name: &str| -> Vec<&'a Replica> {
+        primary
+            .replicas
+            .iter()
+            .filter(|r| r.application_name.as_deref() == Some(name))
+            .collect()
+    };
+    if let Some(replica) = named.as_deref().and_then(|n| called(n).first().copied()) {
+        return replica.sync_state.as_deref();
+    }
+
+    let mut addrs = Vec::new();
+    let target = hosts.iter().find(|t| t.name == standby.name);
+    for name in target.iter().flat_map(|t| t.addresses()) {
+        addrs.extend(resolve_cached(resolved, name).await);
+    }
+    let by_addr = |r: &&Replica| {
+        r.client_addr
+            .as_deref()
+            .and_then(|a| a.parse::<IpAddr>().ok())
+            .is_some_and(|a| addrs.contains(&a))
+    };
+    if let Some(replica) = primary.replicas.iter().find(by_addr) {
+        return replica.sync_state.as_deref();
+    }
+
+    // Cascading standbys go by the default name too, only one streaming from the primary may
+    // take it
+    let upstream = standby.upstream.as_deref().filter(|_| named.is_none())?;
+    let upstream_addrs = resolve_cached(resolved, upstream).await;
+    let mut from_primary = upstream.eq_ignore_ascii_case(&primary.name);
+    let target = hosts.iter().find(|t| t.name == primary.name);
+    for name in target.iter().flat_map(|t| t.addresses()) {
+        from_primary |= resolve_cached(resolved, name)
+            .await
+            .iter()
+            .any(|a| upstream_addrs.contains(a));
+    }
+    if !from_primary {
+        return None;
+    }
+    match called("walreceiver")[..] {
+        [replica] => replica.sync_state.as_deref(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_furthest_standby_ranks_first_and_synchronous_ones_break_ties() {
+        let standby = |wal_lsn| {
+            let mut host = Host::default();
+            host.wal_lsn = Some(wal_lsn);
+            host.replay_gap_bytes = Some(0);
+            host
+        };
+        let (behind, ahead, tied) = (standby(100), standby(200), standby(200));
+        let candidate = |host, sync_state| Candidate {
+            host,
+            received: host.wal_lsn.unwrap(),
+            sync_state,
+        };
+        let mut candidates = [
+            candidate(&behind, Some("sync")),
+            candidate(&ahead, Some("async")),
+            candidate(&tied, Some("sync")),
+        ];
+        candidates.sort_by_key(|c| c.rank());
+        let order: Vec<(i64, Option<&str>)> = candidates
+            .iter()
+            .map(|c| (c.received, c.sync_state))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (200, Some("sync")),
+                (200, Some("async")),
+                (100, Some("sync"))
+            ]
+        );
+    }
+}
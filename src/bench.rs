@@ -0,0 +1,91 @@
+use std::{future, io, process::ExitCode, time::Duration};
+
+use clap::Args;
+
+use crate::{
+    describe_error, error::ErrorFormat, hosts::Target, percentile, probe::Live, sweep,
+    CheckOptions, Pools, EXIT_UNREACHABLE,
+};
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Times to run the checks against each host
+    #[arg(short = 'n', long, default_value_t = 20)]
+    runs: usize,
+}
+
+/// Runs the checks against every host `runs` times and prints each host's latency percentiles.
+/// Connections are opened by a first, untimed round and reused, so a standby that's attached
+/// but slow to answer stands out from the connect and TLS cost every host pays once.
+pub async fn run(
+    conn: &CheckOptions,
+    hosts: &[Target],
+    args: &BenchArgs,
+    errors: ErrorFormat,
+) -> ExitCode {
+    let probe = Live::new(conn.clone(), Pools::default());
+    let mut timings: Vec<Vec<Duration>> = vec![Vec::new(); hosts.len()];
+    let mut failed = vec![false; hosts.len()];
+    // The first round connects and is left out, hosts it can't reach aren't tried again
+    let mut reachable = hosts.to_vec();
+    for round in 0..=args.runs.max(1) {
+        sweep(
+            &probe,
+            &reachable,
+            conn.concurrency,
+            future::pending::<io::Error>(),
+            |name, res, elapsed| {
+                let Some(i) = hosts.iter().position(|t| t.name == name) else {
+                    return;
+                };
+                match res {
+                    Ok(_) if round == 0 => {}
+                    Ok(_) => timings[i].push(elapsed),
+                    Err(e) => {
+                        if !failed[i] {
+                            eprintln!("{}", describe_error(errors, e));
+                        }
+                        failed[i] = true;
+                    }
+                }
+            },
+        )
+        .await;
+        if round == 0 {
+            reachable.retain(|t| {
+                hosts
+                    .iter()
+                    .zip(&failed)
+                    .any(|(h, f)| h.name == t.name && !f)
+            });
+        }
+    }
+
+    let width = hosts.iter().map(|t| t.name.len()).max().unwrap_or(0).max(4);
+    println!(
+        "{:<width$}  {:>9}  {:>9}  {:>9}  {:>4}",
+        "host", "p50", "p95", "p99", "runs"
+    );
+    for (i, target) in hosts.iter().enumerate() {
+        let times = &mut timings[i];
+        times.sort_unstable();
+        let p = |q: f64| match percentile(times, q) {
+            Some(d) => format!("{:.1}ms", d.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<width$}  {:>9}  {:>9}  {:>9}  {:>4}",
+            target.name,
+            p(0.50),
+            p(0.95),
+            p(0.99),
+            times.len()
+        );
+    }
+
+    if failed.contains(&true) {
+        ExitCode::from(EXIT_UNREACHABLE)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,137 @@
+use std::{collections::HashMap, time::Duration};
+
+use sqlx::{Connection, Row};
+use tokio::{task::JoinSet, time};
+
+use crate::{
+    error::Error, hosts::Target, recovery, replication, route, tls, CheckOptions, Host,
+    EXIT_UNHEALTHY, EXIT_UNREACHABLE,
+};
+
+/// How long to wait for an upstream to accept a replication connection
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What came of probing one standby's upstreams
+struct Outcome {
+    standby: String,
+    /// Each upstream with the probe's error, if it failed
+    probes: Vec<(String, u16, Option<String>)>,
+    /// Why the upstream couldn't be determined
+    unknown: Option<String>,
+}
+
+/// Opens a replication connection from here to the upstream each standby is configured to
+/// stream from and closes it again, so broken pg_hba entries or network paths show up before a
+/// rebuild needs them. Prints one line per upstream and returns the exit code.
+pub async fn run(conn: &CheckOptions, hosts: &[Target], res: &[Host]) -> u8 {
+    let mut tasks = JoinSet::new();
+    // A task that panicked only leaves its id behind, to tell which standby it was probing for
+    let mut ids = HashMap::new();
+    for (i, host) in res.iter().filter(|h| !h.is_primary).enumerate() {
+        let target = hosts
+            .iter()
+            .find(|t| t.name == host.name)
+            .cloned()
+            .unwrap_or_else(|| Target::new(&host.name));
+        let (mut conn, login) = (conn.clone(), conn.login.clone());
+        let (standby, streaming_from) = (host.name.clone(), host.upstream.clone());
+        let task = tasks.spawn(async move {
+            let upstreams = match configured_upstreams(&mut conn, &target).await {
+                Ok(u) if !u.is_empty() => Ok(u),
+                // Without the rights to read primary_conninfo, fall back to what it streams from
+                res => match streaming_from {
+                    Some(upstream) => Ok(vec![(upstream, login.port)]),
+                    None => Err(match res {
+                        Ok(_) => "no upstream configured".to_string(),
+                        Err(e) => format!("couldn't determine upstream: {}", e),
+                    }),
+                },
+            };
+            let mut outcome = Outcome {
+                standby,
+                probes: Vec::new(),
+                unknown: None,
+            };
+            match upstreams {
+                Ok(upstreams) => {
+                    for (upstream, port) in upstreams {
+                        let error = match time::timeout(
+                            PROBE_TIMEOUT,
+                            replication::probe(&login, &upstream, port),
+                        )
+                        .await
+                        {
+                            Ok(Ok(())) => None,
+                            Ok(Err(e)) => Some(e.to_string()),
+                            Err(_) => Some("timed out".to_string()),
+                        };
+                        outcome.probes.push((upstream, port, error));
+                    }
+                }
+                Err(reason) => outcome.unknown = Some(reason),
+            }
+            (i, outcome)
+        });
+        ids.insert(task.id(), (i, host.name.clone()));
+    }
+
+    let mut outcomes = Vec::new();
+    while let Some(joined) = tasks.join_next_with_id().await {
+        outcomes.push(match joined {
+            Ok((_, outcome)) => outcome,
+            Err(e) => {
+                let (i, standby) = ids[&e.id()].clone();
+                let outcome = Outcome {
+                    standby,
+                    probes: Vec::new(),
+                    unknown: Some(Error::from(e).to_string()),
+                };
+                (i, outcome)
+            }
+        });
+    }
+    outcomes.sort_unstable_by_key(|(i, _)| *i);
+
+    let mut code = 0;
+    for (_, outcome) in &outcomes {
+        if let Some(reason) = &outcome.unknown {
+            println!("{}: {}", outcome.standby, reason);
+            code = code.max(EXIT_UNREACHABLE);
+        }
+        for (upstream, port, error) in &outcome.probes {
+            match error {
+                None => println!(
+                    "{}: {}:{} accepts replication connections",
+                    outcome.standby, upstream, port
+                ),
+                Some(e) => {
+                    println!("{}: {}:{} failed: {}", outcome.standby, upstream, port, e);
+                    code = EXIT_UNHEALTHY;
+                }
+            }
+        }
+    }
+    if outcomes.is_empty() {
+        println!("No standbys to check");
+    }
+    code
+}
+
+/// Hosts and ports from the standby's `primary_conninfo`, which needs superuser or
+/// `pg_read_all_settings` to read
+async fn configured_upstreams(
+    conn: &mut CheckOptions,
+    target: &Target,
+) -> Result<Vec<(String, u16)>, Error> {
+    let (addr, port) = route(conn, target, target.addresses()[0]).await?;
+    if let Some(name) = &conn.verify_name {
+        tls::verify_name(&addr, port, name, conn.client_cert.as_ref()).await?;
+    }
+    let mut db = crate::connect(&conn.pg, &addr, conn.set_role.as_deref()).await?;
+    let row = sqlx::query("SELECT current_setting('primary_conninfo')")
+        .fetch_one(&mut db)
+        .await;
+    let _ = db.close().await;
+    let conninfo: String = row?.try_get(0)?;
+    Ok(recovery::upstreams(&conninfo, conn.login.port))
+}
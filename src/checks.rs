@@ -0,0 +1,283 @@
+use std::{collections::HashSet, fmt, fs, io, path::Path};
+
+use regex::Regex;
+use serde::{
+    de::{MapAccess, Visitor},
+    Deserialize, Deserializer, Serializer,
+};
+use serde_yaml::Value;
+use sqlx::{Executor, PgPool, Row};
+
+use crate::{error::Error, interpolate};
+
+/// The built-in checks that can be turned off, for roles without access to what they query.
+/// Whether a host is in recovery is always checked.
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Builtin {
+    /// Timeline and timeline age, from pg_control_checkpoint()
+    Timeline,
+    /// The timelines the current one descends from, from its history file
+    History,
+    /// System identifier, from pg_control_system(); hosts without one are left out of cluster
+    /// comparisons
+    SystemIdentifier,
+    /// Whether replicas are attached, from pg_stat_replication
+    Replicas,
+    /// The data_checksums setting
+    Checksums,
+    /// WAL position, and with it byte lag
+    WalLsn,
+    /// Streaming status and upstream, from pg_stat_wal_receiver
+    WalReceiver,
+    /// Seconds since the last replayed transaction
+    Lag,
+    /// Whether the standbys synchronous_standby_names waits for are connected, on primaries
+    Sync,
+    /// Pages that failed their checksum, from pg_stat_database
+    ChecksumFailures,
+    /// Physical replication slots, and the one a standby streams through
+    Slots,
+    /// How far the server's clock is from this machine's, from clock_timestamp()
+    Clock,
+}
+
+impl Builtin {
+    /// Every built-in check, all of which run unless turned off
+    pub const ALL: [Builtin; 12] = [
+        Builtin::Timeline,
+        Builtin::History,
+        Builtin::SystemIdentifier,
+        Builtin::Replicas,
+        Builtin::Checksums,
+        Builtin::WalLsn,
+        Builtin::WalReceiver,
+        Builtin::Lag,
+        Builtin::Sync,
+        Builtin::ChecksumFailures,
+        Builtin::Slots,
+        Builtin::Clock,
+    ];
+}
+
+/// A site-specific query from `--checks`, run on every host next to the built-in checks
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomCheck {
+    /// Names the check's column in the output
+    pub name: String,
+    /// Its first row's first column is the result
+    pub query: String,
+    /// What the result has to be for the host to count as healthy
+    #[serde(default)]
+    pub expect: Option<Expectation>,
+}
+
+/// A comparison a check's result is held to, one of `equals`, `less_than`, `greater_than` or
+/// `matches` (a regex) in the file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "ExpectationSpec")]
+pub enum Expectation {
+    Equals(String),
+    LessThan(f64),
+    GreaterThan(f64),
+    Matches(Regex),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExpectationSpec {
+    equals: Option<Value>,
+    less_than: Option<f64>,
+    greater_than: Option<f64>,
+    matches: Option<String>,
+}
+
+impl TryFrom<ExpectationSpec> for Expectation {
+    type Error = String;
+
+    fn try_from(spec: ExpectationSpec) -> Result<Self, String> {
+        let expectation = match spec {
+            ExpectationSpec {
+                equals: Some(v),
+                less_than: None,
+                greater_than: None,
+                matches: None,
+            } => Expectation::Equals(match v {
+                // `equals: 100` and `equals: on` read as a number and a bool, compare them as text
+                Value::String(s) => s,
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                _ => return Err("equals takes a string, number or bool".to_string()),
+            }),
+            ExpectationSpec {
+                equals: None,
+                less_than: Some(n),
+                greater_than: None,
+                matches: None,
+            } => Expectation::LessThan(n),
+            ExpectationSpec {
+                equals: None,
+                less_than: None,
+                greater_than: Some(n),
+                matches: None,
+            } => Expectation::GreaterThan(n),
+            ExpectationSpec {
+                equals: None,
+                less_than: None,
+                greater_than: None,
+                matches: Some(re),
+            } => Expectation::Matches(Regex::new(&re).map_err(|e| e.to_string())?),
+            _ => {
+                return Err(
+                    "expect takes exactly one of equals, less_than, greater_than or matches"
+                        .to_string(),
+                )
+            }
+        };
+        Ok(expectation)
+    }
+}
+
+impl Expectation {
+    /// Whether the result meets the expectation, NULL never does
+    pub fn holds(&self, value: Option<&str>) -> bool {
+        let Some(value) = value else {
+            return false;
+        };
+        let number = || value.trim().parse::<f64>().ok();
+        match self {
+            Expectation::Equals(expected) => value == expected,
+            Expectation::LessThan(max) => number().is_some_and(|n| n < *max),
+            Expectation::GreaterThan(min) => number().is_some_and(|n| n > *min),
+            Expectation::Matches(re) => re.is_match(value),
+        }
+    }
+}
+
+impl std::fmt::Display for Expectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Expectation::Equals(v) => write!(f, "{}", v),
+            Expectation::LessThan(n) => write!(f, "less than {}", n),
+            Expectation::GreaterThan(n) => write!(f, "greater than {}", n),
+            Expectation::Matches(re) => write!(f, "to match /{}/", re),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChecksFile {
+    checks: Vec<CustomCheck>,
+}
+
+/// Reads a YAML file with a `checks` list of `name` and `query` pairs, each optionally with an
+/// `expect` comparison
+pub fn load(path: &Path) -> io::Result<Vec<CustomCheck>> {
+    let text = fs::read_to_string(path)?;
+    let text =
+        interpolate::env_vars(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let file: ChecksFile =
+        serde_yaml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut names = HashSet::new();
+    for c in &file.checks {
+        if !names.insert(c.name.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("check {} is defined twice", c.name),
+            ));
+        }
+    }
+    Ok(file.checks)
+}
+
+/// Runs every check in order. Results are text whatever the column's type, NULL and empty
+/// results are `None`.
+pub async fn run(
+    pool: &PgPool,
+    checks: &[CustomCheck],
+) -> Result<Vec<(String, Option<String>)>, Error> {
+    let mut results = Vec::with_capacity(checks.len());
+    for c in checks {
+        // The simple query protocol sends every value as text, so any type can be read back
+        let row = pool
+            .fetch_optional(c.query.as_str())
+            .await
+            .map_err(|source| Error::Check {
+                name: c.name.clone(),
+                source,
+            })?;
+        let value = match row {
+            Some(r) => r
+                .try_get_unchecked::<Option<String>, _>(0)
+                .map_err(|source| Error::Check {
+                    name: c.name.clone(),
+                    source,
+                })?,
+            None => None,
+        };
+        results.push((c.name.clone(), value));
+    }
+    Ok(results)
+}
+
+/// Describes every result that doesn't meet its check's expectation
+pub fn failures(
+    checks: &[CustomCheck],
+    host: &str,
+    results: &[(String, Option<String>)],
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (name, value) in results {
+        let Some(expect) = checks
+            .iter()
+            .find(|c| &c.name == name)
+            .and_then(|c| c.expect.as_ref())
+        else {
+            continue;
+        };
+        if !expect.holds(value.as_deref()) {
+            problems.push(format!(
+                "{}: check {} is {}, expected {}",
+                host,
+                name,
+                value.as_deref().unwrap_or("NULL"),
+                expect
+            ));
+        }
+    }
+    problems
+}
+
+/// Serializes check results as an object, keeping the file's order
+pub fn as_map<S: Serializer>(
+    results: &[(String, Option<String>)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(results.iter().map(|(k, v)| (k, v)))
+}
+
+/// Reads check results back from the object `as_map` writes, in its order
+pub fn from_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, Option<String>)>, D::Error> {
+    struct InOrder;
+
+    impl<'de> Visitor<'de> for InOrder {
+        type Value = Vec<(String, Option<String>)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("check results by name")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut results = Vec::new();
+            while let Some(entry) = map.next_entry()? {
+                results.push(entry);
+            }
+            Ok(results)
+        }
+    }
+
+    deserializer.deserialize_map(InOrder)
+}
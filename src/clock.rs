@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sqlx::PgPool;
+
+use crate::Host;
+
+/// Readings taken, the one with the shortest round trip is kept
+const SAMPLES: usize = 3;
+
+/// How far the server's clock is from this machine's
+#[derive(Clone, Copy, Debug)]
+pub struct Skew {
+    /// Ahead of this machine, negative when behind
+    pub seconds: f64,
+    /// Half the round trip of the reading, the server read its clock somewhere within it
+    pub error: f64,
+}
+
+/// Compares the server's clock_timestamp() with this machine's clock halfway through the
+/// query's round trip, the way NTP does
+pub async fn measure(pool: &PgPool) -> Result<Skew, sqlx::Error> {
+    let mut best: Option<(Duration, Skew)> = None;
+    for _ in 0..SAMPLES {
+        let sent = SystemTime::now();
+        let started = Instant::now();
+        let server: f64 =
+            sqlx::query_scalar("SELECT extract(epoch FROM clock_timestamp())::float8;")
+                .fetch_one(pool)
+                .await?;
+        let round_trip = started.elapsed();
+        if best.is_some_and(|(shortest, _)| shortest <= round_trip) {
+            continue;
+        }
+        let sent = sent.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let midway = (sent + round_trip / 2).as_secs_f64();
+        let skew = Skew {
+            seconds: server - midway,
+            error: round_trip.as_secs_f64() / 2.0,
+        };
+        best = Some((round_trip, skew));
+    }
+    Ok(best.expect("at least one sample").1)
+}
+
+/// Hosts whose clock is off from this machine's, and standbys whose clock is off from their
+/// primary's, by more than `warning` seconds beyond what the readings can tell apart. A standby
+/// computes its lag in seconds and recovery_min_apply_delay from its own clock against commit
+/// timestamps from its primary's
+pub fn find_warnings(res: &[Host], warning: f64) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let off = |skew: f64, error: f64| skew.abs() - error > warning;
+    for r in res {
+        let (Some(skew), Some(error)) = (r.clock_skew_seconds, r.clock_skew_error_seconds) else {
+            continue;
+        };
+        if off(skew, error) {
+            warnings.push(format!(
+                "{}'s clock is {}, timestamps compared across machines are off by as much",
+                r.name,
+                describe(skew, "this machine's")
+            ));
+        }
+        if r.is_primary {
+            continue;
+        }
+        let primary = res.iter().find(|p| {
+            p.is_primary
+                && p.system_identifier.is_some()
+                && p.system_identifier == r.system_identifier
+        });
+        let Some((p, p_skew, p_error)) =
+            primary.and_then(|p| Some((p, p.clock_skew_seconds?, p.clock_skew_error_seconds?)))
+        else {
+            continue;
+        };
+        if off(skew - p_skew, error + p_error) {
+            warnings.push(format!(
+                "{}'s clock is {}, its lag in seconds and recovery_min_apply_delay are off by as \
+                 much",
+                r.name,
+                describe(skew - p_skew, &format!("its primary {}'s", p.name))
+            ));
+        }
+    }
+    warnings
+}
+
+/// `2.5s ahead of this machine's`
+fn describe(skew: f64, of: &str) -> String {
+    let direction = if skew > 0.0 { "ahead of" } else { "behind" };
+    format!("{:.1}s {} {}", skew.abs(), direction, of)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skew_is_reported_against_this_machine_and_the_primary() {
+        let host = |name: &str, is_primary, skew| Host {
+            name: name.to_string(),
+            is_primary,
+            system_identifier: Some(7),
+            clock_skew_seconds: Some(skew),
+            clock_skew_error_seconds: Some(0.05),
+            ..Host::default()
+        };
+        let res = [
+            host("db1", true, 3.0),
+            host("db2", false, 3.2),
+            host("db3", false, 0.0),
+        ];
+        assert_eq!(
+            find_warnings(&res, 1.0),
+            vec![
+                "db1's clock is 3.0s ahead of this machine's, timestamps compared across machines are off by as much",
+                "db2's clock is 3.2s ahead of this machine's, timestamps compared across machines are off by as much",
+                "db3's clock is 3.0s behind its primary db1's, its lag in seconds and recovery_min_apply_delay are off by as much",
+            ]
+        );
+    }
+}
@@ -0,0 +1,634 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    signal::unix::{self, SignalKind},
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    checks, deadline,
+    error::{ErrorFormat, Reason},
+    failures, find_clusters, find_problems,
+    hosts::{HostSource, Target},
+    jittered,
+    journal::{Log, LogTarget, Priority},
+    metrics, now,
+    output::SCHEMA_VERSION,
+    probe::Live,
+    rotate::{self, Rotation},
+    statsd, sweep,
+    systemd::{self, Notifier},
+    telemetry::Telemetry,
+    transitions::Snapshot,
+    write_sinks, CheckOptions, Cli, Failure, HealthArgs, Host, Pools, Summary, Verdict,
+};
+
+#[derive(Args, Debug)]
+pub struct DaemonArgs {
+    /// Seconds between sweeps
+    #[arg(long, default_value_t = 60)]
+    interval: u64,
+
+    /// Address to serve Prometheus metrics on, e.g. 0.0.0.0:9187
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+
+    /// File to append each sweep's results to, one JSON object per host and line
+    #[arg(long)]
+    history: Option<PathBuf>,
+
+    /// Move the --history file aside to FILE.1 once it has grown to this size, e.g. 100M
+    #[arg(long, value_name = "SIZE", value_parser = rotate::parse_size, requires = "history")]
+    history_max_size: Option<u64>,
+
+    /// Move the --history file aside to FILE.1 once its first sweep is this many hours old
+    #[arg(
+        long,
+        value_name = "HOURS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "history"
+    )]
+    history_max_age: Option<u64>,
+
+    /// Rotated --history files to keep as FILE.1 (the newest) to FILE.N, older ones are deleted
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    history_keep: usize,
+
+    /// Seconds a host's connections may sit unused before they're closed and reopened
+    #[arg(long, default_value_t = 600)]
+    pool_idle_ttl: u64,
+
+    /// Most hosts to keep connections open to between sweeps
+    #[arg(long)]
+    max_pools: Option<usize>,
+
+    /// Where messages about hosts and sweeps go. journald records carry HOST, CLUSTER and
+    /// ERROR_KIND fields
+    #[arg(long, value_enum, default_value_t)]
+    log_target: LogTarget,
+
+    /// When to push to --statsd and log unhealthy findings
+    #[arg(long, value_enum, default_value_t)]
+    notify: Notify,
+
+    #[command(flatten)]
+    health: HealthArgs,
+}
+
+/// When a sweep's alerts are passed on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Notify {
+    /// After every sweep
+    #[default]
+    Always,
+    /// After the first sweep and those in which a host turned healthy or unhealthy, so an alert
+    /// fires once per change instead of on every sweep
+    OnChange,
+}
+
+/// Everything the daemon knows about its most recent sweep
+pub struct LastSweep {
+    pub hosts: Vec<Target>,
+    pub res: Vec<Host>,
+    pub failed: Vec<Failure>,
+    pub problems: Vec<String>,
+    pub duration: Duration,
+    pub finished_at: SystemTime,
+}
+
+type Shared = Arc<RwLock<Option<LastSweep>>>;
+
+#[derive(Serialize)]
+struct HistoryRecord<'a> {
+    schema_version: u32,
+    /// Unix timestamp of the end of the sweep
+    swept_at: f64,
+    #[serde(flatten)]
+    host: &'a Host,
+}
+
+/// Sweeps every `interval` until interrupted, optionally appending results to `history`
+/// and serving the latest sweep as Prometheus metrics on `listen`
+pub async fn run(
+    cli: &Cli,
+    telemetry: &Telemetry,
+    mut conn: CheckOptions,
+    source: HostSource,
+    mut hosts: Vec<Target>,
+    args: &DaemonArgs,
+) -> ExitCode {
+    let log = match Log::new(args.log_target) {
+        Ok(log) => Arc::new(log),
+        Err(e) => {
+            eprintln!("Error connecting to the journal: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let last: Shared = Arc::new(RwLock::new(None));
+    let interval = Duration::from_secs(args.interval.max(1));
+    let pools = Pools::new(
+        Some(Duration::from_secs(args.pool_idle_ttl)),
+        args.max_pools,
+    );
+    let mut probe = Live::new(conn.clone(), pools.clone());
+    let rotation = Rotation {
+        max_bytes: args.history_max_size,
+        max_age: args
+            .history_max_age
+            .map(|hours| Duration::from_secs(hours * 3600)),
+        keep: args.history_keep,
+    };
+
+    if let Some(addr) = args.listen {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                log.write(
+                    Priority::Error,
+                    &format!("Error listening on {}: {}", addr, e),
+                    &[],
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+        tokio::spawn(serve(listener, last.clone(), interval, log.clone()));
+    }
+    let notifier = Notifier::from_env();
+    if let Some(every) = systemd::watchdog_interval() {
+        tokio::spawn(watchdog(notifier.clone(), last.clone(), interval, every));
+    }
+
+    // Each host's health after the last sweep, for --notify on-change
+    let mut healthy_before: Option<HashMap<String, bool>> = None;
+
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Kept across iterations so an interrupt during a sweep isn't lost. SIGTERM is what systemd
+    // stops services with
+    let signals = [
+        SignalKind::hangup(),
+        SignalKind::interrupt(),
+        SignalKind::terminate(),
+    ]
+    .map(unix::signal);
+    let [mut hangup, mut interrupt, mut terminate] = match signals {
+        [Ok(hangup), Ok(interrupt), Ok(terminate)] => [hangup, interrupt, terminate],
+        [Err(e), _, _] | [_, Err(e), _] | [_, _, Err(e)] => {
+            log.write(
+                Priority::Error,
+                &format!("Error listening for signals: {}", e),
+                &[],
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = hangup.recv() => {
+                reload_hosts(&log, &source, &mut hosts).await;
+                if let Some(path) = &cli.checks {
+                    reload_checks(&log, path, &mut conn);
+                    probe = Live::new(conn.clone(), pools.clone());
+                }
+                continue;
+            }
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        }
+        if cli.jitter > 0 {
+            ticker.reset_after(jittered(interval, cli.jitter));
+        }
+
+        // Unreachable hosts are logged with the cluster they were in last time
+        let clusters = clusters_of(last.read().unwrap().iter().flat_map(|l| &l.res));
+        let started = Instant::now();
+        let mut reasons = HashMap::new();
+        let res = sweep(
+            &probe,
+            &hosts,
+            conn.concurrency,
+            deadline(cli.deadline.map(Duration::from_secs_f64)),
+            |name, res, elapsed| {
+                telemetry.record(name, res, elapsed);
+                if let Err(e) = res {
+                    reasons.insert(name.to_string(), e.reason());
+                    // The journal gets these as fields either way, --errors only changes stderr
+                    if cli.errors == ErrorFormat::Json && args.log_target == LogTarget::Stderr {
+                        eprintln!("{}", e.to_json_line());
+                        return;
+                    }
+                    let mut fields = host_fields(name, &clusters);
+                    fields.push(("ERROR_KIND", e.kind()));
+                    log.write(
+                        Priority::Error,
+                        &format!("Error checking host: {} ({}): {}", name, e.kind(), e),
+                        &fields,
+                    );
+                }
+            },
+        )
+        .await;
+        telemetry.record_sweep(&res);
+        // Failed runs count on from the last sweep's, a restart starts them over
+        let previous: HashMap<String, (Reason, u32)> = last
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|l| &l.failed)
+            .map(|f| (f.name.clone(), (f.reason, f.failed_runs)))
+            .collect();
+        let failed = failures(&hosts, &res, &reasons, &previous);
+
+        let healthy = healthy_hosts(&args.health, &hosts, &res);
+        let notify =
+            args.notify == Notify::Always || health_changed(healthy_before.as_ref(), &healthy);
+        healthy_before = Some(healthy);
+
+        // Neither may hold up the sweeps after this one
+        if let (Some(addr), true) = (&cli.statsd, notify) {
+            let pushed = match time::timeout(interval, statsd::push(addr, &hosts, &res)).await {
+                Ok(pushed) => pushed,
+                Err(_) => Err(timed_out(interval)),
+            };
+            if let Err(e) = pushed {
+                log.write(
+                    Priority::Error,
+                    &format!("Error pushing to StatsD at {}: {}", addr, e),
+                    &[],
+                );
+            }
+        }
+        if !cli.sink.is_empty() {
+            let mut summary = Summary::new(&res, hosts.len(), started.elapsed());
+            summary.failed = failed.clone();
+            summary.judge(&args.health, &res);
+            let written = time::timeout(interval, write_sinks(&cli.sink, &hosts, &res, &summary));
+            let errors = match written.await {
+                Ok(errors) => errors,
+                Err(_) => vec![format!("Error writing results: {}", timed_out(interval))],
+            };
+            for e in errors {
+                log.write(Priority::Error, &e, &[]);
+            }
+        }
+
+        let problems = find_problems(&args.health, &conn.checks, &res, hosts.len());
+        if notify {
+            for p in &problems {
+                log.write(Priority::Warning, &format!("Unhealthy: {}", p), &[]);
+            }
+        }
+
+        let sweep = LastSweep {
+            hosts: hosts.clone(),
+            res,
+            failed,
+            problems,
+            duration: started.elapsed(),
+            finished_at: SystemTime::now(),
+        };
+
+        if let Some(prev) = &*last.read().unwrap() {
+            let clusters = clusters_of(prev.res.iter().chain(&sweep.res));
+            let prev = Snapshot::of(&prev.hosts, &prev.res);
+            for t in Snapshot::of(&sweep.hosts, &sweep.res).since(&prev, now()) {
+                log.write(
+                    Priority::Notice,
+                    &t.to_string(),
+                    &host_fields(&t.host, &clusters),
+                );
+            }
+        }
+
+        if let Some(path) = &args.history {
+            if let Err(e) = rotation.rotate_if_due(path) {
+                log.write(
+                    Priority::Error,
+                    &format!("Error rotating {}: {}", path.display(), e),
+                    &[],
+                );
+            }
+            if let Err(e) = append_history(path, &sweep) {
+                log.write(
+                    Priority::Error,
+                    &format!("Error writing history to {}: {}", path.display(), e),
+                    &[],
+                );
+            }
+        }
+
+        notifier.notify(&format!(
+            "READY=1\nSTATUS=Last sweep checked {} of {} hosts, {} problems",
+            sweep.res.len(),
+            sweep.hosts.len(),
+            sweep.problems.len()
+        ));
+        *last.write().unwrap() = Some(sweep);
+    }
+
+    notifier.notify("STOPPING=1");
+    telemetry.shutdown();
+    ExitCode::SUCCESS
+}
+
+/// Pings systemd's watchdog twice per `every` for as long as `/healthz` would answer ok, so
+/// systemd restarts a daemon whose sweeps stopped completing
+async fn watchdog(notifier: Notifier, last: Shared, interval: Duration, every: Duration) {
+    let started = Instant::now();
+    let stale_after = interval * STALE_INTERVALS;
+    let mut ticker = time::interval(every / 2);
+    loop {
+        ticker.tick().await;
+        let since_sweep = last
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|l| l.finished_at.elapsed().unwrap_or_default());
+        if liveness(since_sweep, started.elapsed(), stale_after).0 == OK {
+            notifier.notify("WATCHDOG=1");
+        }
+    }
+}
+
+/// Swaps in the current host list, keeping the old list if it can't be read
+async fn reload_hosts(log: &Log, source: &HostSource, hosts: &mut Vec<Target>) {
+    match source.load().await {
+        Ok(h) => {
+            let message = format!("Reloaded {} hosts from {}", h.len(), source);
+            log.write(Priority::Info, &message, &[]);
+            *hosts = h;
+        }
+        Err(e) => log.write(
+            Priority::Error,
+            &format!("Error reloading {}: {}", source, e),
+            &[],
+        ),
+    }
+}
+
+/// Swaps in the custom checks `path` holds now, keeping the old ones if it can't be read
+fn reload_checks(log: &Log, path: &Path, conn: &mut CheckOptions) {
+    match checks::load(path) {
+        Ok(c) => {
+            let message = format!("Reloaded {} checks from {}", c.len(), path.display());
+            log.write(Priority::Info, &message, &[]);
+            conn.checks = c.into();
+        }
+        Err(e) => log.write(
+            Priority::Error,
+            &format!("Error reloading {}: {}", path.display(), e),
+            &[],
+        ),
+    }
+}
+
+fn timed_out(after: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("timed out after {}s", after.as_secs()),
+    )
+}
+
+/// Whether each host is healthy: reachable, in a cluster judged HEALTHY and, for a standby,
+/// within --max-lag-bytes and --max-lag-seconds
+fn healthy_hosts(health: &HealthArgs, hosts: &[Target], res: &[Host]) -> HashMap<String, bool> {
+    let clusters = find_clusters(res);
+    hosts
+        .iter()
+        .map(|t| {
+            let healthy = res.iter().find(|r| r.name == t.name).is_some_and(|r| {
+                let cluster_ok = clusters
+                    .iter()
+                    .find(|c| c.hosts.contains(&r.name))
+                    .is_none_or(|c| c.verdict == Verdict::Healthy);
+                let bytes = health.max_lag_bytes.zip(r.lag_bytes);
+                let seconds = health.max_lag_seconds.zip(r.lag_seconds);
+                cluster_ok
+                    && bytes.is_none_or(|(max, lag)| lag <= max)
+                    && seconds.is_none_or(|(max, lag)| lag <= max)
+            });
+            (t.name.clone(), healthy)
+        })
+        .collect()
+}
+
+/// Whether a host turned healthy or unhealthy since `before`. The first sweep and hosts added
+/// by a reload count as changes, hosts a reload removed don't.
+fn health_changed(before: Option<&HashMap<String, bool>>, after: &HashMap<String, bool>) -> bool {
+    let Some(before) = before else {
+        return true;
+    };
+    after
+        .iter()
+        .any(|(name, healthy)| before.get(name) != Some(healthy))
+}
+
+/// Each host's system identifier, later results winning
+fn clusters_of<'a>(res: impl Iterator<Item = &'a Host>) -> HashMap<String, String> {
+    res.filter_map(|r| Some((r.name.clone(), r.system_identifier?.to_string())))
+        .collect()
+}
+
+/// HOST, and CLUSTER if the host's is known
+fn host_fields<'a>(
+    host: &'a str,
+    clusters: &'a HashMap<String, String>,
+) -> Vec<(&'static str, &'a str)> {
+    let mut fields = vec![("HOST", host)];
+    if let Some(cluster) = clusters.get(host) {
+        fields.push(("CLUSTER", cluster.as_str()));
+    }
+    fields
+}
+
+/// Appends one NDJSON line per checked host
+fn append_history(path: &Path, sweep: &LastSweep) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let swept_at = unix_seconds(sweep.finished_at);
+
+    let mut buf = Vec::new();
+    for host in &sweep.res {
+        let record = HistoryRecord {
+            schema_version: SCHEMA_VERSION,
+            swept_at,
+            host,
+        };
+        serde_json::to_writer(&mut buf, &record)?;
+        buf.push(b'\n');
+    }
+    file.write_all(&buf)
+}
+
+pub fn unix_seconds(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// A sweep that hasn't finished within this many intervals is considered stuck
+const STALE_INTERVALS: u32 = 3;
+
+async fn serve(listener: TcpListener, last: Shared, interval: Duration, log: Arc<Log>) {
+    let started = Instant::now();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(respond(stream, last.clone(), interval, started));
+            }
+            Err(e) => log.write(
+                Priority::Error,
+                &format!("Error accepting metrics connection: {}", e),
+                &[],
+            ),
+        }
+    }
+}
+
+/// Answers a single HTTP request, only the request line is looked at
+async fn respond(mut stream: TcpStream, last: Shared, interval: Duration, started: Instant) {
+    let mut buf = [0; 1024];
+    let n = match stream.read(&mut buf).await {
+        Ok(n) => n,
+        Err(_) => return,
+    };
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, body) = {
+        let last = last.read().unwrap();
+        let stale_after = interval * STALE_INTERVALS;
+        let since_sweep = last
+            .as_ref()
+            .map(|l| l.finished_at.elapsed().unwrap_or_default());
+
+        match path {
+            "/metrics" => (OK, metrics::render(last.as_ref())),
+            "/healthz" => probe(liveness(since_sweep, started.elapsed(), stale_after)),
+            "/readyz" => probe(readiness(since_sweep, stale_after)),
+            _ => probe(("404 Not Found", "not found")),
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+const OK: &str = "200 OK";
+const UNAVAILABLE: &str = "503 Service Unavailable";
+
+fn probe((status, body): (&'static str, &str)) -> (&'static str, String) {
+    (status, format!("{}\n", body))
+}
+
+/// Live until sweeps stop completing, giving the first one the same grace
+fn liveness(
+    since_sweep: Option<Duration>,
+    uptime: Duration,
+    stale_after: Duration,
+) -> (&'static str, &'static str) {
+    match since_sweep {
+        Some(d) if d > stale_after => (UNAVAILABLE, "last sweep is stale"),
+        None if uptime > stale_after => (UNAVAILABLE, "no sweep completed yet"),
+        _ => (OK, "ok"),
+    }
+}
+
+/// Ready once there is a recent sweep to serve
+fn readiness(since_sweep: Option<Duration>, stale_after: Duration) -> (&'static str, &'static str) {
+    match since_sweep {
+        Some(d) if d <= stale_after => (OK, "ok"),
+        Some(_) => (UNAVAILABLE, "last sweep is stale"),
+        None => (UNAVAILABLE, "no sweep completed yet"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(name: &str) -> Target {
+        Target {
+            name: name.to_string(),
+            aliases: Vec::new(),
+            addr: None,
+            fallbacks: Vec::new(),
+            options: Default::default(),
+        }
+    }
+
+    fn host(name: &str, is_primary: bool, timeline_id: u32, lag_bytes: Option<i64>) -> Host {
+        let mut host = Host::default();
+        host.name = name.to_string();
+        host.is_primary = is_primary;
+        host.timeline_id = Some(timeline_id);
+        host.system_identifier = Some(7);
+        host.lag_bytes = lag_bytes;
+        host
+    }
+
+    #[test]
+    fn only_a_host_turning_healthy_or_unhealthy_is_a_change() {
+        let hosts = [target("db1"), target("db2"), target("db3")];
+        let health = HealthArgs {
+            expect_primary: Vec::new(),
+            expect_replicas: Vec::new(),
+            max_lag_bytes: Some(1024),
+            max_lag_seconds: None,
+            max_missing_replicas: None,
+        };
+        let sweep = |db3_lag| {
+            let res = [
+                host("db1", true, 2, None),
+                host("db2", false, 2, Some(0)),
+                host("db3", false, 2, Some(db3_lag)),
+            ];
+            healthy_hosts(&health, &hosts, &res)
+        };
+
+        let first = sweep(0);
+        assert!(first.values().all(|&h| h));
+        assert!(health_changed(None, &first));
+        assert!(!health_changed(Some(&first), &first));
+
+        // Lagging further behind isn't a change, catching up is
+        let lagging = sweep(4096);
+        assert!(!lagging["db3"]);
+        assert!(lagging["db1"] && lagging["db2"]);
+        assert!(health_changed(Some(&first), &lagging));
+        assert!(!health_changed(Some(&lagging), &sweep(8192)));
+        assert!(health_changed(Some(&lagging), &sweep(0)));
+
+        // An unreachable host is unhealthy, a diverged one leaves its whole cluster unhealthy
+        let res = [host("db1", true, 2, None), host("db3", false, 1, Some(0))];
+        let diverged = healthy_hosts(&health, &hosts, &res);
+        assert!(diverged.values().all(|&h| !h));
+
+        // A reload adding a host is a change, one removing a host isn't
+        let mut more = first.clone();
+        more.insert("db4".to_string(), true);
+        assert!(health_changed(Some(&first), &more));
+        let mut fewer = first.clone();
+        fewer.remove("db3");
+        assert!(!health_changed(Some(&first), &fewer));
+    }
+}
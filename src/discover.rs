@@ -0,0 +1,208 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Args;
+use sqlx::{postgres::PgConnectOptions, Connection, Row};
+use tokio::{net::lookup_host, task::JoinSet, time};
+
+use crate::{error::Error, server_version, version::Version};
+
+#[derive(Args, Debug)]
+pub struct DiscoverArgs {
+    /// Host to start walking the replication topology from. Can be repeated
+    #[arg(long, required = true)]
+    pub seed: Vec<String>,
+
+    /// File to write the hosts to instead of stdout
+    #[arg(long)]
+    out: Option<PathBuf>,
+
+    /// Add a comment above each cluster naming its primary and what each standby streams from
+    #[arg(long)]
+    annotate: bool,
+}
+
+/// How long to wait for a host to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server found while walking the topology
+struct Node {
+    /// The name or address it was first reached by, as written to the hosts file
+    name: String,
+    addrs: HashSet<IpAddr>,
+    system_identifier: i64,
+    is_primary: bool,
+    /// Host its WAL receiver streams from
+    upstream: Option<String>,
+}
+
+/// Walks the replication topology from the seeds, upstream through each standby's WAL receiver
+/// and downstream through each server's attached replicas, and writes every server reached as a
+/// hosts file, grouped by cluster with primaries first
+pub async fn run(pg: &PgConnectOptions, role: Option<&str>, args: &DiscoverArgs) -> io::Result<()> {
+    let mut nodes: Vec<Node> = Vec::new();
+    // Addresses connected to or being connected to, so each server is visited once
+    let mut seen: HashSet<IpAddr> = HashSet::new();
+    let mut frontier: Vec<String> = args.seed.clone();
+
+    while !frontier.is_empty() {
+        let mut tasks = JoinSet::new();
+        // A task that panicked only leaves its id behind, to tell which host it was visiting
+        let mut ids = HashMap::new();
+        for name in frontier.drain(..) {
+            let addrs = resolve(&name).await;
+            // Names that don't resolve are still tried, connecting reports why they fail
+            if !addrs.is_empty() && addrs.is_subset(&seen) {
+                continue;
+            }
+            seen.extend(addrs.iter().copied());
+            let (pg, role) = (pg.clone(), role.map(str::to_string));
+            let task = tasks.spawn({
+                let name = name.clone();
+                async move {
+                    let visiting = visit(&pg, role.as_deref(), &name);
+                    let visited = time::timeout(CONNECT_TIMEOUT, visiting).await;
+                    (name, addrs, visited)
+                }
+            });
+            ids.insert(task.id(), name);
+        }
+
+        while let Some(joined) = tasks.join_next_with_id().await {
+            let (name, addrs, visited) = match joined {
+                Ok((_, visit)) => visit,
+                Err(e) => {
+                    let name = &ids[&e.id()];
+                    eprintln!("Error connecting to host: {}: {}", name, Error::from(e));
+                    continue;
+                }
+            };
+            let (node, neighbours) = match visited {
+                Ok(Ok(v)) => v,
+                Ok(Err(e)) => {
+                    eprintln!("Error connecting to host: {}: {}", name, e);
+                    continue;
+                }
+                Err(_) => {
+                    eprintln!("Error connecting to host: {}: timed out", name);
+                    continue;
+                }
+            };
+            frontier.extend(neighbours);
+            nodes.push(Node {
+                name,
+                addrs,
+                ..node
+            });
+        }
+    }
+
+    let text = render(&nodes, args.annotate);
+    match &args.out {
+        Some(path) => fs::write(path, text),
+        None => io::stdout().write_all(text.as_bytes()),
+    }
+}
+
+/// Connects to a server and reads its identity, upstream and attached replicas
+async fn visit(
+    pg: &PgConnectOptions,
+    role: Option<&str>,
+    name: &str,
+) -> Result<(Node, Vec<String>), sqlx::Error> {
+    let mut conn = crate::connect(pg, name, role).await?;
+    let version = server_version(&mut conn).await?;
+    // Before sender_host the upstream is only in the (password-less) conninfo
+    let query = format!(
+        "SELECT system_identifier, NOT pg_is_in_recovery() AS is_primary,
+            (SELECT {} FROM pg_stat_wal_receiver) AS upstream
+        FROM pg_control_system();",
+        if version >= Version::PG11 {
+            "sender_host"
+        } else {
+            "substring(conninfo FROM 'host=([^ ]+)')"
+        }
+    );
+    let row = sqlx::query(&query).fetch_one(&mut conn).await?;
+    // client_hostname is only filled in with log_hostname on
+    let replicas: Vec<String> = sqlx::query(
+        "SELECT coalesce(client_hostname, host(client_addr)) AS replica FROM pg_stat_replication
+        WHERE client_addr IS NOT NULL;",
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .iter()
+    .map(|r| r.get("replica"))
+    .collect();
+    let _ = conn.close().await;
+
+    // A receiver connected over a Unix socket streams from the same box, not worth a visit
+    let upstream: Option<String> = row
+        .get::<Option<String>, _>("upstream")
+        .filter(|h| !h.starts_with('/'));
+    let mut neighbours = replicas;
+    neighbours.extend(upstream.clone());
+
+    let node = Node {
+        name: name.to_string(),
+        addrs: HashSet::new(),
+        system_identifier: row.get("system_identifier"),
+        is_primary: row.get("is_primary"),
+        upstream,
+    };
+    Ok((node, neighbours))
+}
+
+async fn resolve(name: &str) -> HashSet<IpAddr> {
+    match lookup_host((name, 0)).await {
+        Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+        Err(_) => HashSet::new(),
+    }
+}
+
+fn render(nodes: &[Node], annotate: bool) -> String {
+    let mut clusters: Vec<i64> = Vec::new();
+    for n in nodes {
+        if !clusters.contains(&n.system_identifier) {
+            clusters.push(n.system_identifier);
+        }
+    }
+
+    let mut out = String::new();
+    for id in clusters {
+        let mut members: Vec<&Node> = nodes.iter().filter(|n| n.system_identifier == id).collect();
+        members.sort_by_key(|n| !n.is_primary);
+
+        if annotate {
+            let roles: Vec<String> = members
+                .iter()
+                .map(|n| match &n.upstream {
+                    _ if n.is_primary => format!("{} (primary)", n.name),
+                    Some(upstream) => format!("{} (from {})", n.name, name_of(nodes, upstream)),
+                    None => format!("{} (not streaming)", n.name),
+                })
+                .collect();
+            out.push_str(&format!("# cluster {}: {}\n", id, roles.join(", ")));
+        }
+        for n in members {
+            out.push_str(&n.name);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The name a server was written as, for an upstream that may have been given as an address
+fn name_of<'a>(nodes: &'a [Node], host: &'a str) -> &'a str {
+    let addr: Option<IpAddr> = host.parse().ok();
+    nodes
+        .iter()
+        .find(|n| n.name == host || addr.is_some_and(|a| n.addrs.contains(&a)))
+        .map_or(host, |n| n.name.as_str())
+}
@@ -0,0 +1,385 @@
+use std::{fmt, io};
+
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use thiserror::Error;
+use tokio::task::JoinError;
+
+/// Why a host couldn't be checked. It takes the host's place in the results and the run goes on
+/// with the others.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Connecting, logging in or one of the built-in queries failed
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    /// Resolving or tunnelling to the host failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The run's deadline or Ctrl-C gave up on the check before it finished, for the reason
+    /// given
+    #[error(transparent)]
+    Cutoff(io::Error),
+    /// A `--checks` query failed, e.g. because the user lacks a permission it needs
+    #[error("check {name}: {source}")]
+    Check {
+        name: String,
+        #[source]
+        source: sqlx::Error,
+    },
+    /// The host is a PgBouncer and the server behind it can't be checked
+    #[error("{0}")]
+    Pooler(String),
+    /// A bug in timeline_check, only this host's result is lost
+    #[error("checking the host panicked: {0}")]
+    Panicked(String),
+}
+
+impl Error {
+    /// Whether the server never answered: the connection was refused, reset or timed out, or
+    /// its address couldn't be reached. Anything else, like a certificate file that can't be
+    /// read or a failed login, would go the same way at another of the host's addresses
+    pub fn unanswered(&self) -> bool {
+        match self {
+            Error::Io(e) | Error::Database(sqlx::Error::Io(e)) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::AddrNotAvailable
+                    | io::ErrorKind::HostUnreachable
+                    | io::ErrorKind::NetworkUnreachable
+                    | io::ErrorKind::TimedOut
+            ),
+            Error::Database(sqlx::Error::PoolTimedOut) => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<JoinError> for Error {
+    fn from(e: JoinError) -> Self {
+        let message = match e.try_into_panic() {
+            Ok(panic) => match panic.downcast::<String>() {
+                Ok(message) => *message,
+                Err(panic) => panic
+                    .downcast::<&str>()
+                    .map_or("no message".to_string(), |m| m.to_string()),
+            },
+            Err(e) => e.to_string(),
+        };
+        Error::Panicked(message)
+    }
+}
+
+/// How `check`, `report`, `bench` and the daemon print hosts that couldn't be checked on stderr
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorFormat {
+    /// A line naming the host and what failed, then the error
+    #[default]
+    Text,
+    /// One JSON object per failure: host, phase, class and message
+    Json,
+}
+
+/// Why a host couldn't be checked, finer than `CheckError`'s classes and named the same from
+/// release to release, for grouping failures across the fleet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    /// The host's name didn't resolve
+    Dns,
+    /// Nothing accepted the TCP connection: refused, reset or no route
+    TcpRefused,
+    /// The TCP connection or the login didn't complete in time
+    TcpTimeout,
+    /// The TLS handshake or the server's certificate failed
+    Tls,
+    /// The server turned the login away
+    Auth,
+    /// The role lacks a privilege one of the queries needs
+    Permission,
+    /// A built-in or `--checks` query failed or was cancelled
+    Query,
+    /// The server answered but wouldn't hold a session to check: too many clients, starting up,
+    /// no such database, a pooler with nothing behind it, or replies that made no sense
+    Protocol,
+    /// The run's deadline or Ctrl-C gave up on the check
+    Deadline,
+}
+
+impl Reason {
+    pub const ALL: [Reason; 9] = [
+        Reason::Dns,
+        Reason::TcpRefused,
+        Reason::TcpTimeout,
+        Reason::Tls,
+        Reason::Auth,
+        Reason::Permission,
+        Reason::Query,
+        Reason::Protocol,
+        Reason::Deadline,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::Dns => "dns",
+            Reason::TcpRefused => "tcp-refused",
+            Reason::TcpTimeout => "tcp-timeout",
+            Reason::Tls => "tls",
+            Reason::Auth => "auth",
+            Reason::Permission => "permission",
+            Reason::Query => "query",
+            Reason::Protocol => "protocol",
+            Reason::Deadline => "deadline",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Reason::ALL.into_iter().find(|r| r.name() == s)
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A host that couldn't be checked, sorted by what went wrong, so callers can tell a wrong
+/// password from a firewall without reading the message. Displays as the underlying error
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CheckError {
+    /// The host couldn't be resolved or reached, refused the connection, or is a pooler without
+    /// a server to check behind it
+    ConnectFailed { host: String, error: Error },
+    /// The server turned the login away, for the password or by pg_hba.conf
+    AuthFailed { host: String, error: Error },
+    /// Logged in, but a built-in or `--checks` query failed, or checking the host hit a bug
+    QueryFailed { host: String, error: Error },
+    /// Connecting or a query took too long, or the run's deadline or Ctrl-C cut the check short
+    Timeout { host: String, error: Error },
+    /// The role lacks a privilege one of the queries needs
+    PermissionDenied { host: String, error: Error },
+}
+
+impl CheckError {
+    pub fn new(host: &str, error: Error) -> Self {
+        let host = host.to_string();
+        let sqlstate = match &error {
+            Error::Database(e) | Error::Check { source: e, .. } => e
+                .as_database_error()
+                .and_then(|e| e.code())
+                .map(String::from),
+            _ => None,
+        };
+        let sqlstate = sqlstate.as_deref().unwrap_or_default();
+        let io_kind = match &error {
+            Error::Io(e) | Error::Database(sqlx::Error::Io(e)) => Some(e.kind()),
+            _ => None,
+        };
+
+        // 57014 is a query cancelled by statement_timeout, classes 28 and 08 are failed logins
+        // and connections
+        let timed_out = matches!(
+            io_kind,
+            Some(io::ErrorKind::TimedOut | io::ErrorKind::Interrupted)
+        ) || matches!(
+            error,
+            Error::Cutoff(_) | Error::Database(sqlx::Error::PoolTimedOut)
+        ) || sqlstate == "57014";
+        let connect = matches!(
+            error,
+            Error::Io(_)
+                | Error::Pooler(_)
+                | Error::Database(
+                    sqlx::Error::Io(_)
+                        | sqlx::Error::Tls(_)
+                        | sqlx::Error::Configuration(_)
+                        | sqlx::Error::PoolClosed
+                        | sqlx::Error::WorkerCrashed
+                )
+        ) || sqlstate.starts_with("08")
+            || ["53300", "57P03", "3D000"].contains(&sqlstate);
+
+        if timed_out {
+            CheckError::Timeout { host, error }
+        } else if sqlstate.starts_with("28") {
+            CheckError::AuthFailed { host, error }
+        } else if sqlstate == "42501" {
+            CheckError::PermissionDenied { host, error }
+        } else if connect {
+            CheckError::ConnectFailed { host, error }
+        } else {
+            CheckError::QueryFailed { host, error }
+        }
+    }
+
+    /// What failed in a couple of words, e.g. `login failed`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CheckError::ConnectFailed { .. } => "connect failed",
+            CheckError::AuthFailed { .. } => "login failed",
+            CheckError::QueryFailed { .. } => "query failed",
+            CheckError::Timeout { .. } => "timed out",
+            CheckError::PermissionDenied { .. } => "permission denied",
+        }
+    }
+
+    /// How far checking the host got: `connect`, `login`, `query`, or `cutoff` for a check the
+    /// run's deadline or Ctrl-C gave up on
+    pub fn phase(&self) -> &'static str {
+        match self {
+            CheckError::ConnectFailed { .. } => "connect",
+            CheckError::AuthFailed { .. } => "login",
+            CheckError::QueryFailed { .. } | CheckError::PermissionDenied { .. } => "query",
+            CheckError::Timeout { error, .. } => match error {
+                Error::Cutoff(_) => "cutoff",
+                Error::Io(_) | Error::Database(sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut) => {
+                    "connect"
+                }
+                _ => "query",
+            },
+        }
+    }
+
+    /// The reason code `--errors json`, unreachable rows and the state file record
+    pub fn reason(&self) -> Reason {
+        let error = match self {
+            CheckError::AuthFailed { .. } => return Reason::Auth,
+            CheckError::PermissionDenied { .. } => return Reason::Permission,
+            CheckError::Timeout { .. } => {
+                return match self.phase() {
+                    "cutoff" => Reason::Deadline,
+                    "connect" => Reason::TcpTimeout,
+                    _ => Reason::Query,
+                }
+            }
+            CheckError::ConnectFailed { error, .. } | CheckError::QueryFailed { error, .. } => {
+                error
+            }
+        };
+        match error {
+            Error::Check { .. } | Error::Panicked(_) => Reason::Query,
+            Error::Cutoff(_) => Reason::Deadline,
+            Error::Pooler(_) => Reason::Protocol,
+            Error::Database(sqlx::Error::Tls(_)) => Reason::Tls,
+            Error::Io(e) | Error::Database(sqlx::Error::Io(e)) => {
+                // getaddrinfo failures surface as other errors, told apart by their message,
+                // and certificates failing verification as rustls errors inside
+                if e.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) {
+                    Reason::Tls
+                } else if e.to_string().contains("failed to lookup address") {
+                    Reason::Dns
+                } else {
+                    Reason::TcpRefused
+                }
+            }
+            Error::Database(sqlx::Error::Database(_)) => match self {
+                CheckError::ConnectFailed { .. } => Reason::Protocol,
+                _ => Reason::Query,
+            },
+            Error::Database(_) => Reason::Protocol,
+        }
+    }
+
+    /// One line of JSON for `--errors json`, with the `--checks` query's name when one failed
+    pub fn to_json_line(&self) -> String {
+        #[derive(Serialize)]
+        struct Record<'a> {
+            host: &'a str,
+            phase: &'a str,
+            class: &'a str,
+            reason: Reason,
+            message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            check: Option<&'a str>,
+        }
+        let check = match self.error() {
+            Error::Check { name, .. } => Some(name.as_str()),
+            _ => None,
+        };
+        let record = Record {
+            host: self.host(),
+            phase: self.phase(),
+            class: self.kind(),
+            reason: self.reason(),
+            message: self.to_string(),
+            check,
+        };
+        serde_json::to_string(&record).expect("the record is plain strings")
+    }
+
+    pub fn host(&self) -> &str {
+        match self {
+            CheckError::ConnectFailed { host, .. }
+            | CheckError::AuthFailed { host, .. }
+            | CheckError::QueryFailed { host, .. }
+            | CheckError::Timeout { host, .. }
+            | CheckError::PermissionDenied { host, .. } => host,
+        }
+    }
+
+    pub fn error(&self) -> &Error {
+        match self {
+            CheckError::ConnectFailed { error, .. }
+            | CheckError::AuthFailed { error, .. }
+            | CheckError::QueryFailed { error, .. }
+            | CheckError::Timeout { error, .. }
+            | CheckError::PermissionDenied { error, .. } => error,
+        }
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error().fmt(f)
+    }
+}
+
+impl std::error::Error for CheckError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error().source()
+    }
+}
+
+/// As `{"kind": "login failed", "message": "..."}`, the host is left to the surrounding record
+impl Serialize for CheckError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("CheckError", 2)?;
+        s.serialize_field("kind", self.kind())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_checks_the_run_gave_up_on_are_cut_off() {
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let cases = [
+            (Error::Cutoff(timed_out()), "cutoff", Reason::Deadline),
+            (Error::Io(timed_out()), "connect", Reason::TcpTimeout),
+            (
+                Error::Database(sqlx::Error::Io(timed_out())),
+                "connect",
+                Reason::TcpTimeout,
+            ),
+            (
+                Error::Cutoff(io::Error::new(io::ErrorKind::Interrupted, "interrupted")),
+                "cutoff",
+                Reason::Deadline,
+            ),
+        ];
+        for (error, phase, reason) in cases {
+            let e = CheckError::new("db1", error);
+            assert!(matches!(e, CheckError::Timeout { .. }), "{:?}", e);
+            assert_eq!((e.phase(), e.reason()), (phase, reason), "{:?}", e);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future, io,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use timeline_check::{
+    error::Error, hosts::Target, now, probe::HostProbe, version::Version, Host, Role, WalSource,
+};
+
+/// What the fake says about one server
+#[derive(Clone, Copy, Debug)]
+pub struct Server {
+    pub is_primary: bool,
+    pub timeline_id: u32,
+    pub system_identifier: i64,
+    pub wal_lsn: i64,
+    /// Whether a standby streams from it
+    pub replica_attached: bool,
+    /// Whether a standby streams from an upstream
+    pub streaming: bool,
+}
+
+impl Server {
+    pub fn primary(timeline_id: u32) -> Self {
+        Server {
+            is_primary: true,
+            timeline_id,
+            system_identifier: 1,
+            wal_lsn: 1 << 24,
+            replica_attached: true,
+            streaming: false,
+        }
+    }
+
+    /// Caught up with a primary at the default position
+    pub fn standby(timeline_id: u32) -> Self {
+        Server {
+            is_primary: false,
+            replica_attached: false,
+            streaming: true,
+            ..Server::primary(timeline_id)
+        }
+    }
+
+    pub fn behind(self, bytes: i64) -> Self {
+        Server {
+            wal_lsn: self.wal_lsn - bytes,
+            ..self
+        }
+    }
+
+    pub fn in_cluster(self, system_identifier: i64) -> Self {
+        Server {
+            system_identifier,
+            ..self
+        }
+    }
+}
+
+/// An in-memory fleet, hosts without a server refuse connections. Clones share the fleet,
+/// so a test can fail over between sweeps
+#[derive(Clone, Default)]
+pub struct Fake {
+    servers: Arc<Mutex<HashMap<String, Server>>>,
+    /// Hosts that never answer
+    hung: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Fake {
+    pub fn with(self, name: &str, server: Server) -> Self {
+        self.set(name, server);
+        self
+    }
+
+    pub fn set(&self, name: &str, server: Server) {
+        self.servers
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), server);
+    }
+
+    pub fn stop(&self, name: &str) {
+        self.servers.lock().unwrap().remove(name);
+    }
+
+    pub fn hang(&self, name: &str) {
+        self.hung.lock().unwrap().insert(name.to_string());
+    }
+}
+
+#[async_trait]
+impl HostProbe for Fake {
+    async fn probe(&self, target: Target) -> Result<Host, Error> {
+        if self.hung.lock().unwrap().contains(&target.name) {
+            future::pending::<()>().await;
+        }
+        let Some(s) = self.servers.lock().unwrap().get(&target.name).copied() else {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        };
+        let mut host = Host::default();
+        host.name = target.name;
+        host.is_primary = s.is_primary;
+        host.role = Role::of(s.is_primary, Some(s.replica_attached));
+        host.timeline_id = Some(s.timeline_id);
+        host.replica_attached = Some(s.replica_attached);
+        host.system_identifier = Some(s.system_identifier);
+        host.wal_lsn = Some(s.wal_lsn);
+        host.streaming = Some(s.streaming);
+        host.lag_seconds = (!s.is_primary).then_some(0.0);
+        host.wal_source = WalSource::of(s.is_primary, Some(s.streaming), Some(false));
+        host.aliases = target.aliases;
+        host.tags = target.options.tags;
+        host.checked_at = Some(now());
+        host.server_version_num = Some(Version(160000));
+        Ok(host)
+    }
+}
@@ -0,0 +1,1047 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{self, BufRead},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use regex::Regex;
+use sqlx::postgres::PgSslMode;
+
+use crate::{hosts_query::HostsQuery, interpolate, inventory, ssh_config};
+use tokio::{net::lookup_host, task::JoinSet};
+
+/// A host to check, as listed in the hosts file
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    pub name: String,
+    /// Later hosts file entries that resolve to the same address
+    pub aliases: Vec<String>,
+    /// Address to connect to instead of the name, from `--resolve`, the inventory or a
+    /// `name=addr1,addr2` line
+    pub addr: Option<String>,
+    /// Addresses tried in order when `addr` doesn't accept a connection
+    pub fallbacks: Vec<String>,
+    /// Settings its hosts file line overrides
+    pub options: HostOptions,
+}
+
+impl Target {
+    /// A host connected to by its name, with the default settings
+    pub fn new(name: &str) -> Self {
+        Target {
+            name: name.to_string(),
+            ..Target::default()
+        }
+    }
+
+    /// Where to connect, in the order to try: the address or else the name, then the fallbacks
+    pub fn addresses(&self) -> Vec<&str> {
+        let first = self.addr.as_deref().unwrap_or(&self.name);
+        std::iter::once(first)
+            .chain(self.fallbacks.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// Connection settings a hosts file line overrides for its host, as `key=value` pairs after the
+/// name, e.g. `db7 port=5433 sslmode=verify-full connect_timeout=2`, and tags as `tag.NAME=value`
+#[derive(Debug, Clone, Default)]
+pub struct HostOptions {
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub dbname: Option<String>,
+    pub sslmode: Option<PgSslMode>,
+    /// Root certificate to verify the server against, as libpq's sslrootcert
+    pub sslrootcert: Option<PathBuf>,
+    /// Client certificate and key to log in with, as libpq's sslcert and sslkey, which are
+    /// given together
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    pub connect_timeout: Option<Duration>,
+    /// Labels such as `tag.dc=fra` carried to the output, to slice results by
+    pub tags: BTreeMap<String, String>,
+    /// The connection settings as written, for printing them back
+    given: Vec<(String, String)>,
+}
+
+/// The settings a hosts file line can override
+const KEYS: [&str; 8] = [
+    "port",
+    "user",
+    "dbname",
+    "sslmode",
+    "sslrootcert",
+    "sslcert",
+    "sslkey",
+    "connect_timeout",
+];
+
+impl HostOptions {
+    pub fn parse<'a>(pairs: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut options = HostOptions::default();
+        let (mut sslcert, mut sslkey) = (None, None);
+        for pair in pairs {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {}", pair))?;
+            let invalid = |e: &dyn fmt::Display| format!("{}: {}", pair, e);
+            match key {
+                "port" => options.port = Some(value.parse().map_err(|e| invalid(&e))?),
+                "user" => options.user = Some(value.to_string()),
+                "dbname" => options.dbname = Some(value.to_string()),
+                "sslmode" => options.sslmode = Some(value.parse().map_err(|e| invalid(&e))?),
+                "sslrootcert" => options.sslrootcert = Some(PathBuf::from(value)),
+                "sslcert" => sslcert = Some(PathBuf::from(value)),
+                "sslkey" => sslkey = Some(PathBuf::from(value)),
+                "connect_timeout" => {
+                    let secs: f64 = value.parse().map_err(|e| invalid(&e))?;
+                    let timeout = Duration::try_from_secs_f64(secs)
+                        .ok()
+                        .filter(|t| !t.is_zero())
+                        .ok_or_else(|| invalid(&"expected a positive number of seconds"))?;
+                    options.connect_timeout = Some(timeout);
+                }
+                _ => {
+                    let Some(tag) = key.strip_prefix("tag.") else {
+                        return Err(match suggest(key) {
+                            Some(known) => {
+                                format!("unknown option {}, did you mean {}?", key, known)
+                            }
+                            None => format!(
+                                "unknown option {}, expected {} or tag.NAME for a tag",
+                                key,
+                                KEYS.join(", ")
+                            ),
+                        });
+                    };
+                    // Tags become Prometheus labels and InfluxDB tags next to host and cluster
+                    let label = tag.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                        && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    if tag == "host" || tag == "cluster" {
+                        return Err(format!(
+                            "{} is taken by the built-in label, pick another tag",
+                            tag
+                        ));
+                    }
+                    if !label {
+                        return Err(format!(
+                            "invalid tag name {}, expected letters, digits and underscores",
+                            tag
+                        ));
+                    }
+                    options.tags.insert(tag.to_string(), value.to_string());
+                    continue;
+                }
+            }
+            // A line's own settings override its defaults
+            options.given.retain(|(k, _)| k != key);
+            options.given.push((key.to_string(), value.to_string()));
+        }
+        options.client_cert = match (sslcert, sslkey) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err("sslcert and sslkey have to be given together".to_string()),
+        };
+        Ok(options)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.given.is_empty()
+    }
+
+    /// Whether both connect the same way and carry the same tags, however they were written
+    pub fn same_settings(&self, other: &HostOptions) -> bool {
+        self.port == other.port
+            && self.user == other.user
+            && self.dbname == other.dbname
+            && format!("{:?}", self.sslmode) == format!("{:?}", other.sslmode)
+            && self.sslrootcert == other.sslrootcert
+            && self.client_cert == other.client_cert
+            && self.connect_timeout == other.connect_timeout
+            && self.tags == other.tags
+    }
+
+    /// The settings and tags as written, in key order
+    pub fn pairs(&self) -> BTreeMap<String, &str> {
+        self.given
+            .iter()
+            .map(|(k, v)| (k.clone(), v.as_str()))
+            .chain(
+                self.tags
+                    .iter()
+                    .map(|(k, v)| (format!("tag.{}", k), v.as_str())),
+            )
+            .collect()
+    }
+}
+
+/// The setting an unknown key is most likely a typo of, at most two edits away
+pub fn suggest(key: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(d, _)| *d <= 2)
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// Levenshtein distance, characters inserted, removed or replaced to get from `a` to `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+impl fmt::Display for HostOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pairs: Vec<String> = self
+            .given
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        write!(f, "{}", pairs.join(" "))
+    }
+}
+
+/// Where the list of hosts is read from
+#[derive(Debug, Clone)]
+pub enum Listing {
+    /// Plain hosts files, one host per line, as paths or globs. Globs are expanded on every
+    /// read, so a reload picks up files added since
+    Files(Vec<String>),
+    /// The hosts of one group of an Ansible inventory
+    Inventory { path: PathBuf, group: String },
+    /// The hosts named in an OpenSSH client config
+    SshConfig(PathBuf),
+    /// The rows a query on an inventory database returns
+    Query(Box<HostsQuery>),
+}
+
+/// Where the hosts come from and which of them to keep, re-read on every reload
+#[derive(Debug, Clone)]
+pub struct HostSource {
+    pub listing: Listing,
+    /// Only hosts matching at least one of these are checked, all of them when empty
+    pub patterns: Vec<HostPattern>,
+    /// Addresses pinned with `--resolve`, bypassing DNS
+    pub overrides: HashMap<String, IpAddr>,
+}
+
+/// The file the hosts come from, or the database for a query
+impl fmt::Display for HostSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.listing {
+            Listing::Files(patterns) => write!(f, "{}", patterns.join(", ")),
+            Listing::Inventory { path, .. } | Listing::SshConfig(path) => {
+                write!(f, "{}", path.display())
+            }
+            Listing::Query(query) => write!(f, "{}", query),
+        }
+    }
+}
+
+impl HostSource {
+    /// Reads the listing, merging entries that resolve to the same address into one target
+    pub async fn load(&self) -> io::Result<Vec<Target>> {
+        let without_options = |entries: Vec<(String, Option<String>)>| {
+            entries
+                .into_iter()
+                .map(|(name, addr)| (name, addr.into_iter().collect(), HostOptions::default()))
+                .collect()
+        };
+        let mut entries: Vec<Entry> = match &self.listing {
+            Listing::Files(patterns) => read_hosts_files(&expand_files(patterns)?)?,
+            Listing::Inventory { path, group } => without_options(inventory::read(path, group)?),
+            Listing::SshConfig(path) => without_options(ssh_config::read(path)?),
+            Listing::Query(query) => without_options(query.read().await?),
+        };
+        if !self.patterns.is_empty() {
+            entries.retain(|(n, _, _)| self.patterns.iter().any(|p| p.matches(n)));
+        }
+        for (name, addrs, _) in &mut entries {
+            if let Some(pinned) = self.overrides.get(name) {
+                *addrs = vec![pinned.to_string()];
+            }
+        }
+        Ok(dedupe(entries).await)
+    }
+}
+
+/// A host's name, the addresses to connect to instead in the order to try them, and its
+/// overridden settings
+pub type Entry = (String, Vec<String>, HostOptions);
+
+/// The tag naming the file a host came from when several are read
+pub const HOSTS_FILE_TAG: &str = "hosts_file";
+
+/// The files `--hosts` names, each a path or a glob, in the order given and a glob's matches
+/// in alphabetical order. A file named twice is read once
+pub fn expand_files(patterns: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            files.push(PathBuf::from(pattern));
+            continue;
+        }
+        let matches = glob::glob(pattern).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", pattern, e))
+        })?;
+        let matches = matches
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io::Error::from)?;
+        if matches.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} matches no files", pattern),
+            ));
+        }
+        files.extend(matches);
+    }
+    let mut seen = HashSet::new();
+    files.retain(|f| seen.insert(f.clone()));
+    Ok(files)
+}
+
+/// Reads every file in turn, one after the other as if they were one. With more than one, each
+/// host is tagged with the name of its file, minus the extension, unless its line sets the
+/// tag itself, and errors name the file they're in. A host may be in several files only with
+/// the same addresses and settings in all of them, and then belongs to the first
+pub fn read_hosts_files(paths: &[PathBuf]) -> io::Result<Vec<Entry>> {
+    let [path] = paths else {
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut listed: HashMap<String, String> = HashMap::new();
+        // Tagged once all are read, so a host's listings compare as written
+        let mut stems = Vec::new();
+        for path in paths {
+            let in_file =
+                |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+            let (read, lines) = read_listed(path).map_err(in_file)?;
+            let stem = path
+                .file_stem()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy();
+            for (name, addrs, options) in read {
+                let at = format!("{} {}", path.display(), lines[&name]);
+                if let Some(first) = listed.get(&name) {
+                    if !entries
+                        .iter()
+                        .any(|(n, a, o)| *n == name && *a == addrs && o.same_settings(&options))
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{}: {}", at, conflict(&name, first)),
+                        ));
+                    }
+                    continue;
+                }
+                listed.insert(name.clone(), at);
+                entries.push((name, addrs, options));
+                stems.push(stem.to_string());
+            }
+        }
+        for ((_, _, options), stem) in entries.iter_mut().zip(stems) {
+            options
+                .tags
+                .entry(HOSTS_FILE_TAG.to_string())
+                .or_insert(stem);
+        }
+        return Ok(entries);
+    };
+    read_hosts_file(path)
+}
+
+/// Names from a hosts file, each optionally followed by `key=value` overrides. A name written
+/// as `cluster-a=db1,db2,db3` is checked at the first of those addresses that accepts a
+/// connection. `#` starts a comment, blank lines are skipped and `include FILE` reads another
+/// hosts file in place, relative to this one. `defaults key=value...` sets overrides for the
+/// lines after it and the files they include, until the next `defaults` line. Certificate
+/// paths are relative to the file they're written in. A name listed twice is one host, and
+/// has to have the same addresses and settings both times.
+pub fn read_hosts_file(path: &Path) -> io::Result<Vec<Entry>> {
+    read_listed(path).map(|(entries, _)| entries)
+}
+
+/// The hosts file's entries, and the line each name is on
+fn read_listed(path: &Path) -> io::Result<(Vec<Entry>, HashMap<String, String>)> {
+    let (mut entries, mut listed) = (Vec::new(), HashMap::new());
+    read_hosts_file_into(path, &mut Vec::new(), &[], &mut entries, &mut listed)?;
+    Ok((entries, listed))
+}
+
+/// `including` holds the files whose includes led here, to catch include cycles, `inherited`
+/// the defaults in effect where this file was included and `listed` the line each name read so
+/// far is on
+fn read_hosts_file_into(
+    path: &Path,
+    including: &mut Vec<PathBuf>,
+    inherited: &[String],
+    entries: &mut Vec<Entry>,
+    listed: &mut HashMap<String, String>,
+) -> io::Result<()> {
+    let included = !including.is_empty();
+    let at = |i: usize| {
+        if included {
+            format!("{} line {}", path.display(), i + 1)
+        } else {
+            format!("line {}", i + 1)
+        }
+    };
+    let invalid = |i: usize, e: String| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", at(i), e))
+    };
+    let canonical = path.canonicalize()?;
+    including.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut defaults = inherited.to_vec();
+    for (i, line) in read_lines(path)?.enumerate() {
+        let line = line?;
+        // Comments, e.g. the cluster annotations `discover --annotate` writes
+        let line = line
+            .split_once('#')
+            .map_or(line.as_str(), |(l, _)| l)
+            .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line = interpolate::env_vars(line).map_err(|e| invalid(i, e))?;
+        let mut words = line.split_whitespace();
+        let first = words.next().unwrap_or_default();
+        if first == "include" {
+            let Some(file) = words.next().filter(|_| words.next().is_none()) else {
+                return Err(invalid(i, "expected include FILE".to_string()));
+            };
+            let file = dir.join(file);
+            if file.canonicalize().is_ok_and(|f| including.contains(&f)) {
+                return Err(invalid(
+                    i,
+                    format!("{} is already being included", file.display()),
+                ));
+            }
+            read_hosts_file_into(&file, including, &defaults, entries, listed).map_err(
+                |e| match e.kind() {
+                    io::ErrorKind::InvalidData => e,
+                    _ => invalid(i, format!("{}: {}", file.display(), e)),
+                },
+            )?;
+            continue;
+        }
+        let words: Vec<String> = words.map(|w| relative_to(dir, w)).collect();
+        if first == "defaults" {
+            HostOptions::parse(words.iter().map(String::as_str)).map_err(|e| invalid(i, e))?;
+            defaults = inherited.iter().cloned().chain(words).collect();
+            continue;
+        }
+        let options = HostOptions::parse(defaults.iter().chain(&words).map(String::as_str))
+            .map_err(|e| invalid(i, e))?;
+        if let Some((name, addrs)) = first.split_once('=') {
+            let addrs: Vec<String> = addrs.split(',').map(str::to_string).collect();
+            if name.is_empty() || addrs.iter().any(String::is_empty) {
+                return Err(invalid(
+                    i,
+                    format!("expected NAME=ADDR,ADDR,..., got {}", first),
+                ));
+            }
+            list(entries, listed, (name.to_string(), addrs, options), at(i))
+                .map_err(|e| invalid(i, e))?;
+            continue;
+        }
+        for name in expand(first).map_err(|e| invalid(i, e))? {
+            list(entries, listed, (name, Vec::new(), options.clone()), at(i))
+                .map_err(|e| invalid(i, e))?;
+        }
+    }
+    including.pop();
+    Ok(())
+}
+
+/// Adds the entry unless its name is listed already, which it may only be with the same
+/// addresses and settings
+fn list(
+    entries: &mut Vec<Entry>,
+    listed: &mut HashMap<String, String>,
+    entry: Entry,
+    at: String,
+) -> Result<(), String> {
+    let Some(first) = listed.get(&entry.0) else {
+        listed.insert(entry.0.clone(), at);
+        entries.push(entry);
+        return Ok(());
+    };
+    let (name, addrs, options) = &entry;
+    if entries
+        .iter()
+        .any(|(n, a, o)| n == name && a == addrs && o.same_settings(options))
+    {
+        Ok(())
+    } else {
+        Err(conflict(name, first))
+    }
+}
+
+fn conflict(name: &str, first: &str) -> String {
+    format!(
+        "{} is already listed on {} with other addresses or settings",
+        name, first
+    )
+}
+
+/// A certificate setting with its relative path made relative to `dir`, any other as it is
+fn relative_to(dir: &Path, pair: &str) -> String {
+    match pair.split_once('=') {
+        Some((key @ ("sslrootcert" | "sslcert" | "sslkey"), value))
+            if Path::new(value).is_relative() =>
+        {
+            format!("{}={}", key, dir.join(value).display())
+        }
+        _ => pair.to_string(),
+    }
+}
+
+/// Parses a `--resolve host=ip` option
+pub fn parse_resolve(s: &str) -> Result<(String, IpAddr), String> {
+    let (host, addr) = s
+        .split_once('=')
+        .ok_or_else(|| "expected host=ip".to_string())?;
+    let addr = addr.parse().map_err(|e| format!("{}: {}", addr, e))?;
+    Ok((host.to_string(), addr))
+}
+
+/// A `--match` pattern, a regex when wrapped in slashes (`/^db0[1-3]\./`) and a glob otherwise
+#[derive(Debug, Clone)]
+pub enum HostPattern {
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.strip_prefix('/').and_then(|r| r.strip_suffix('/')) {
+            Some(re) => Regex::new(re)
+                .map(HostPattern::Regex)
+                .map_err(|e| e.to_string()),
+            None => glob::Pattern::new(s)
+                .map(HostPattern::Glob)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            HostPattern::Glob(g) => g.matches(name),
+            HostPattern::Regex(r) => r.is_match(name),
+        }
+    }
+}
+
+/// The most names one hosts file line may expand to, so a stray digit can't exhaust memory
+const MAX_EXPANDED: u64 = 10_000;
+
+/// Expands bracketed ranges and lists, so `db[01-03,07].example.com` becomes db01, db02, db03
+/// and db07. Zero padding follows the range's start, several brackets multiply out, up to
+/// `MAX_EXPANDED` names.
+pub fn expand(line: &str) -> Result<Vec<String>, String> {
+    let Some(open) = line.find('[') else {
+        return Ok(vec![line.to_string()]);
+    };
+    let close = line[open..]
+        .find(']')
+        .map(|i| open + i)
+        .ok_or_else(|| format!("unclosed '[' in {}", line))?;
+    let (prefix, spec, rest) = (&line[..open], &line[open + 1..close], &line[close + 1..]);
+
+    let mut items = Vec::new();
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let invalid = || format!("invalid range [{}] in {}", part, line);
+                let from: u64 = start.parse().map_err(|_| invalid())?;
+                let to: u64 = end.parse().map_err(|_| invalid())?;
+                if from > to {
+                    return Err(invalid());
+                }
+                if to - from >= MAX_EXPANDED.saturating_sub(items.len() as u64) {
+                    return Err(too_many(line));
+                }
+                let width = if start.starts_with('0') {
+                    start.len()
+                } else {
+                    0
+                };
+                items.extend((from..=to).map(|n| format!("{:0width$}", n, width = width)));
+            }
+            None if !part.is_empty() => items.push(part.to_string()),
+            None => return Err(format!("empty item in [{}] in {}", spec, line)),
+        }
+    }
+
+    let suffixes = expand(rest)?;
+    if (items.len() * suffixes.len()) as u64 > MAX_EXPANDED {
+        return Err(too_many(line));
+    }
+    Ok(items
+        .iter()
+        .flat_map(|item| {
+            suffixes
+                .iter()
+                .map(move |s| format!("{}{}{}", prefix, item, s))
+        })
+        .collect())
+}
+
+fn too_many(line: &str) -> String {
+    format!("{} expands to more than {} names", line, MAX_EXPANDED)
+}
+
+/// Keeps the first of several names for the same box, e.g. a CNAME next to its canonical name,
+/// so it's only checked once. Names that don't resolve are kept and fail when connecting, and
+/// entries with different overrides, such as two ports, are different servers. So are entries
+/// with fallback addresses, which stand for whichever of them answers. A name listed again
+/// with the same addresses and settings is the same host.
+async fn dedupe(entries: Vec<Entry>) -> Vec<Target> {
+    let mut kept: Vec<Entry> = Vec::new();
+    for (name, addrs, options) in entries {
+        if !kept
+            .iter()
+            .any(|(n, a, o)| *n == name && *a == addrs && o.same_settings(&options))
+        {
+            kept.push((name, addrs, options));
+        }
+    }
+    let entries = kept;
+
+    let mut lookups = JoinSet::new();
+    for (i, (name, addrs, _)) in entries.iter().cloned().enumerate() {
+        if addrs.len() > 1 {
+            continue;
+        }
+        lookups.spawn(async move {
+            let host = addrs.into_iter().next().unwrap_or(name);
+            let addrs: BTreeSet<IpAddr> = match lookup_host((host.as_str(), 0)).await {
+                Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+                Err(_) => BTreeSet::new(),
+            };
+            (i, addrs)
+        });
+    }
+    let mut resolved = vec![BTreeSet::new(); entries.len()];
+    // A lookup that panicked leaves its name unresolved, like one that failed
+    while let Some(joined) = lookups.join_next().await {
+        if let Ok((i, addrs)) = joined {
+            resolved[i] = addrs;
+        }
+    }
+
+    let mut targets: Vec<(Target, BTreeSet<IpAddr>)> = Vec::new();
+    for ((name, given, options), addrs) in entries.into_iter().zip(resolved) {
+        let existing = targets.iter_mut().find(|(t, a)| {
+            !a.is_disjoint(&addrs) && !addrs.is_empty() && t.options.same_settings(&options)
+        });
+        match existing {
+            Some((target, _)) => target.aliases.push(name),
+            None => {
+                let mut given = given.into_iter();
+                targets.push((
+                    Target {
+                        name,
+                        aliases: Vec::new(),
+                        addr: given.next(),
+                        fallbacks: given.collect(),
+                        options,
+                    },
+                    addrs,
+                ))
+            }
+        }
+    }
+    targets.into_iter().map(|(t, _)| t).collect()
+}
+
+fn read_lines<P: AsRef<Path>>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>> {
+    let file = File::open(filename)?;
+    Ok(io::BufReader::new(file).lines())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<HostOptions, String> {
+        HostOptions::parse(line.split_whitespace())
+    }
+
+    /// A directory of its own per test holding these files, removed again by the caller
+    fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "timeline_check_hosts_{}_{}",
+            test,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|(n, _, _)| n.as_str()).collect()
+    }
+
+    #[test]
+    fn ranges_and_lists_expand() {
+        for (line, expanded) in [
+            ("db1", vec!["db1"]),
+            ("db[1-3]", vec!["db1", "db2", "db3"]),
+            (
+                "db[08-10].example.com",
+                vec!["db08.example.com", "db09.example.com", "db10.example.com"],
+            ),
+            ("db[1,3-4,a]", vec!["db1", "db3", "db4", "dba"]),
+            ("[a,b]-[1-2]", vec!["a-1", "a-2", "b-1", "b-2"]),
+        ] {
+            assert_eq!(expand(line).unwrap(), expanded, "{}", line);
+        }
+    }
+
+    #[test]
+    fn broken_and_huge_ranges_are_rejected() {
+        for (line, error) in [
+            ("db[1-3", "unclosed '[' in db[1-3"),
+            ("db[3-1]", "invalid range [3-1] in db[3-1]"),
+            ("db[a-c]", "invalid range [a-c] in db[a-c]"),
+            ("db[1,]", "empty item in [1,] in db[1,]"),
+            (
+                "db[1-999999999]",
+                "db[1-999999999] expands to more than 10000 names",
+            ),
+            (
+                "db[1-5000,1-5001]",
+                "db[1-5000,1-5001] expands to more than 10000 names",
+            ),
+            (
+                "db[1-200]-[1-200]",
+                "db[1-200]-[1-200] expands to more than 10000 names",
+            ),
+        ] {
+            assert_eq!(expand(line).unwrap_err(), error, "{}", line);
+        }
+        assert_eq!(expand("db[1-10000]").unwrap().len(), 10_000);
+    }
+
+    #[test]
+    fn settings_are_parsed_into_their_types() {
+        let options = parse(
+            "port=5433 user=monitor dbname=app sslmode=verify-full connect_timeout=2.5 \
+             sslcert=client.crt sslkey=client.key",
+        )
+        .unwrap();
+        assert_eq!(options.port, Some(5433));
+        assert_eq!(options.user.as_deref(), Some("monitor"));
+        assert_eq!(options.dbname.as_deref(), Some("app"));
+        assert!(matches!(options.sslmode, Some(PgSslMode::VerifyFull)));
+        assert_eq!(options.connect_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(
+            options.client_cert,
+            Some((PathBuf::from("client.crt"), PathBuf::from("client.key")))
+        );
+        // The last of a key given twice wins, as when a line overrides its defaults
+        assert_eq!(
+            parse("port=5433 port=6432").unwrap().to_string(),
+            "port=6432"
+        );
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for (line, error) in [
+            (
+                "port=70000",
+                "port=70000: number too large to fit in target type",
+            ),
+            ("port=-1", "port=-1: invalid digit found in string"),
+            ("port=", "port=: cannot parse integer from empty string"),
+            (
+                "sslmode=on",
+                "sslmode=on: error with configuration: unknown value \"on\" for `ssl_mode`",
+            ),
+            (
+                "connect_timeout=soon",
+                "connect_timeout=soon: invalid float literal",
+            ),
+            (
+                "connect_timeout=0",
+                "connect_timeout=0: expected a positive number of seconds",
+            ),
+            (
+                "connect_timeout=-2",
+                "connect_timeout=-2: expected a positive number of seconds",
+            ),
+            (
+                "sslcert=client.crt",
+                "sslcert and sslkey have to be given together",
+            ),
+            (
+                "sslkey=client.key",
+                "sslcert and sslkey have to be given together",
+            ),
+            ("port", "expected key=value, got port"),
+            (
+                "hostaddr=10.0.0.1",
+                "unknown option hostaddr, expected port, user, dbname, sslmode, sslrootcert, \
+                 sslcert, sslkey, connect_timeout or tag.NAME for a tag",
+            ),
+        ] {
+            assert_eq!(parse(line).unwrap_err(), error, "{}", line);
+        }
+    }
+
+    #[test]
+    fn defaults_apply_to_later_lines_and_includes_until_the_next() {
+        let dir = files(
+            "defaults",
+            &[
+                (
+                    "hosts",
+                    "db1\ndefaults port=5433 user=monitor sslrootcert=ca.crt\ndb2 user=admin\n\
+                     include more\ndefaults\ndb5\n",
+                ),
+                ("more", "db3\ndefaults port=6432\ndb4\n"),
+            ],
+        );
+        let entries = read_hosts_file(&dir.join("hosts")).unwrap();
+        let settings: Vec<(&str, Option<u16>, Option<&str>)> = entries
+            .iter()
+            .map(|(n, _, o)| (n.as_str(), o.port, o.user.as_deref()))
+            .collect();
+        assert_eq!(
+            settings,
+            vec![
+                ("db1", None, None),
+                ("db2", Some(5433), Some("admin")),
+                ("db3", Some(5433), Some("monitor")),
+                ("db4", Some(6432), Some("monitor")),
+                ("db5", None, None),
+            ]
+        );
+        // Certificate paths are anchored to the file they're written in
+        assert_eq!(entries[1].2.sslrootcert, Some(dir.join("ca.crt")));
+        assert_eq!(
+            entries[1].2.to_string(),
+            format!(
+                "port=5433 sslrootcert={} user=admin",
+                dir.join("ca.crt").display()
+            )
+        );
+
+        std::fs::write(dir.join("hosts"), "defaults port=none\n").unwrap();
+        let e = read_hosts_file(&dir.join("hosts")).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "line 1: port=none: invalid digit found in string"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hosts_from_several_files_are_tagged_with_their_file() {
+        let dir = files(
+            "several",
+            &[
+                ("fra.hosts", "db1\ndb2 tag.hosts_file=other\n"),
+                ("ams.hosts", "db3\n"),
+            ],
+        );
+        let entries = read_hosts_files(&[dir.join("fra.hosts"), dir.join("ams.hosts")]).unwrap();
+        let tags: Vec<Option<&str>> = entries
+            .iter()
+            .map(|(_, _, o)| o.tags.get(HOSTS_FILE_TAG).map(String::as_str))
+            .collect();
+        assert_eq!(tags, vec![Some("fra"), Some("other"), Some("ams")]);
+        let alone = read_hosts_files(&[dir.join("ams.hosts")]).unwrap();
+        assert!(alone[0].2.tags.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn names_listed_again_must_agree_with_the_first_listing() {
+        let dir = files(
+            "again",
+            &[
+                ("hosts", "db1 port=5433\ndb[1-2] port=05433\n"),
+                ("fra.hosts", "db1 port=5433\n"),
+                ("ams.hosts", "db3\ndb1 port=5434\n"),
+            ],
+        );
+        let entries = read_hosts_file(&dir.join("hosts")).unwrap();
+        assert_eq!(names(&entries), vec!["db1", "db2"]);
+
+        let entries = read_hosts_files(&[dir.join("hosts"), dir.join("fra.hosts")]).unwrap();
+        assert_eq!(names(&entries), vec!["db1", "db2"]);
+        assert_eq!(entries[0].2.tags[HOSTS_FILE_TAG], "hosts");
+
+        let e = read_hosts_files(&[dir.join("fra.hosts"), dir.join("ams.hosts")]).unwrap_err();
+        let error = format!(
+            "{} line 2: db1 is already listed on {} line 1 with other addresses or settings",
+            dir.join("ams.hosts").display(),
+            dir.join("fra.hosts").display()
+        );
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), error);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tags_are_written_with_their_prefix() {
+        let options = parse("port=5433 tag.dc=fra tag.tier=prod").unwrap();
+        assert_eq!(options.port, Some(5433));
+        assert_eq!(
+            options.tags,
+            BTreeMap::from([
+                ("dc".to_string(), "fra".to_string()),
+                ("tier".to_string(), "prod".to_string())
+            ])
+        );
+        assert_eq!(options.to_string(), "port=5433");
+        assert_eq!(
+            options.pairs().into_iter().collect::<Vec<_>>(),
+            vec![
+                ("port".to_string(), "5433"),
+                ("tag.dc".to_string(), "fra"),
+                ("tag.tier".to_string(), "prod")
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected_with_the_option_they_look_like() {
+        for (line, error) in [
+            (
+                "sslmod=require",
+                "unknown option sslmod, did you mean sslmode?",
+            ),
+            (
+                "conect_timeout=2",
+                "unknown option conect_timeout, did you mean connect_timeout?",
+            ),
+            ("usr=monitor", "unknown option usr, did you mean user?"),
+            (
+                "dc=fra",
+                "unknown option dc, expected port, user, dbname, sslmode, sslrootcert, \
+                 sslcert, sslkey, connect_timeout or tag.NAME for a tag",
+            ),
+            (
+                "tag.host=db1",
+                "host is taken by the built-in label, pick another tag",
+            ),
+            (
+                "tag.dc-1=fra",
+                "invalid tag name dc-1, expected letters, digits and underscores",
+            ),
+        ] {
+            assert_eq!(parse(line).unwrap_err(), error, "{}", line);
+        }
+    }
+
+    #[test]
+    fn comments_blank_lines_and_fallback_addresses_are_read() {
+        let dir = files(
+            "lines",
+            &[(
+                "hosts",
+                "# the orders cluster\n\ndb1 port=5433 # the primary\n  db[2-3]  \n\
+                 cluster-a=10.0.0.1,10.0.0.2 user=monitor\n",
+            )],
+        );
+        let entries = read_hosts_file(&dir.join("hosts")).unwrap();
+        assert_eq!(names(&entries), vec!["db1", "db2", "db3", "cluster-a"]);
+        assert_eq!(entries[0].2.port, Some(5433));
+        assert!(entries[1].1.is_empty() && entries[1].2.is_empty());
+        assert_eq!(entries[3].1, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(entries[3].2.user.as_deref(), Some("monitor"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_lines_name_their_line() {
+        for (content, error) in [
+            (
+                "db1\n=10.0.0.1\n",
+                "line 2: expected NAME=ADDR,ADDR,..., got =10.0.0.1",
+            ),
+            (
+                "cluster-a=10.0.0.1,\n",
+                "line 1: expected NAME=ADDR,ADDR,..., got cluster-a=10.0.0.1,",
+            ),
+            ("db1\ndb2 port\n", "line 2: expected key=value, got port"),
+            (
+                "db1 sslmod=require\n",
+                "line 1: unknown option sslmod, did you mean sslmode?",
+            ),
+            ("include\n", "line 1: expected include FILE"),
+            ("include a b\n", "line 1: expected include FILE"),
+            ("db[1-\n", "line 1: unclosed '[' in db[1-"),
+            (
+                "db1 port=5432\ndb[1-2] port=5433\n",
+                "line 2: db1 is already listed on line 1 with other addresses or settings",
+            ),
+        ] {
+            let dir = files("malformed", &[("hosts", content)]);
+            let e = read_hosts_file(&dir.join("hosts")).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(e.to_string(), error, "{:?}", content);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn includes_are_read_in_place_and_cycles_are_caught() {
+        let dir = files(
+            "includes",
+            &[
+                ("hosts", "db1\ninclude staging\ndb4\n"),
+                ("staging", "db2\ndb3 port=5433\n"),
+            ],
+        );
+        let entries = read_hosts_file(&dir.join("hosts")).unwrap();
+        assert_eq!(names(&entries), vec!["db1", "db2", "db3", "db4"]);
+        assert_eq!(entries[2].2.port, Some(5433));
+
+        std::fs::write(dir.join("staging"), "db2\ninclude hosts\n").unwrap();
+        let e = read_hosts_file(&dir.join("hosts")).unwrap_err();
+        let error = format!(
+            "{} line 2: {} is already being included",
+            dir.join("staging").display(),
+            dir.join("hosts").display()
+        );
+        assert_eq!(e.to_string(), error);
+
+        std::fs::write(dir.join("staging"), "db2 port=x\n").unwrap();
+        let e = read_hosts_file(&dir.join("hosts")).unwrap_err();
+        let error = format!(
+            "{} line 1: port=x: invalid digit found in string",
+            dir.join("staging").display()
+        );
+        assert_eq!(e.to_string(), error);
+
+        let e = read_hosts_file(&dir.join("missing")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        std::fs::write(dir.join("hosts"), "include missing\n").unwrap();
+        let e = read_hosts_file(&dir.join("hosts")).unwrap_err();
+        assert!(e.to_string().starts_with("line 1: "), "{}", e);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use std::{fmt, io, str::FromStr};
+
+use sqlx::{postgres::PgConnectOptions, ConnectOptions, Connection, Executor, Row};
+
+/// A `--hosts-query`: an inventory database's URL, then `#` and the query that lists the hosts
+#[derive(Debug, Clone)]
+pub struct HostsQuery {
+    database: PgConnectOptions,
+    query: String,
+    /// The URL set application_name itself, which --application-name leaves alone
+    names_session: bool,
+}
+
+impl HostsQuery {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let Some((url, query)) = s.split_once('#') else {
+            return Err("expected URL # QUERY".to_string());
+        };
+        let query = query.trim();
+        if query.is_empty() {
+            return Err("no query after the #".to_string());
+        }
+        let database = PgConnectOptions::from_str(url.trim()).map_err(|e| e.to_string())?;
+        Ok(HostsQuery {
+            database,
+            query: query.to_string(),
+            names_session: url.contains("application_name="),
+        })
+    }
+
+    /// Logs in with `name` unless the URL has an application_name of its own
+    pub fn application_name(&mut self, name: &str) {
+        if !self.names_session {
+            self.database = self.database.clone().application_name(name);
+        }
+    }
+
+    /// Runs the query. Its first column names a host and the second, if there is one, is the
+    /// address to connect to, like `ansible_host`
+    pub async fn read(&self) -> io::Result<Vec<(String, Option<String>)>> {
+        let mut conn = self.database.connect().await.map_err(io::Error::other)?;
+        // The simple query protocol sends every value as text, so any type can be read back
+        let rows = conn
+            .fetch_all(self.query.as_str())
+            .await
+            .map_err(io::Error::other)?;
+        let _ = conn.close().await;
+
+        let mut hosts = Vec::with_capacity(rows.len());
+        for (i, row) in rows.iter().enumerate() {
+            let invalid = |e: String| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("row {}: {}", i + 1, e))
+            };
+            let name = row
+                .try_get_unchecked::<Option<String>, _>(0)
+                .map_err(|e| invalid(e.to_string()))?
+                .filter(|n| !n.trim().is_empty())
+                .ok_or_else(|| invalid("the host name is NULL or empty".to_string()))?;
+            let addr = match row.len() {
+                1 => None,
+                _ => row
+                    .try_get_unchecked::<Option<String>, _>(1)
+                    .map_err(|e| invalid(e.to_string()))?
+                    .filter(|a| !a.trim().is_empty()),
+            };
+            hosts.push((name.trim().to_string(), addr));
+        }
+        Ok(hosts)
+    }
+}
+
+/// Where the query runs, without the credentials
+impl fmt::Display for HostsQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the hosts query on {}", self.database.get_host())?;
+        if let Some(db) = self.database.get_database() {
+            write!(f, "/{}", db)?;
+        }
+        Ok(())
+    }
+}
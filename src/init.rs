@@ -0,0 +1,167 @@
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use clap::Args;
+
+#[derive(Args, Debug)]
+pub struct InitArgs {
+    /// Directory to write the files to, created when missing
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+
+    /// Replace files that are already there
+    #[arg(long)]
+    force: bool,
+}
+
+const HOSTS: &str = r#"# Hosts for timeline_check --hosts, one per line: the name, then key=value settings that
+# override the command line's for this host alone.
+#
+#   timeline_check -u monitor --password-file monitor.pass --hosts hosts check
+#
+# Servers that want client certificates need all three files:
+#
+#   timeline_check -u monitor --password-file monitor.pass --hosts hosts \
+#       --root-cert root.crt --client-cert client.crt --client-key client.key check
+#
+# Settings are port, user, dbname, sslmode (disable, allow, prefer, require, verify-ca or
+# verify-full), sslrootcert, sslcert and sslkey (certificate files for hosts with PKI of
+# their own, relative to this file) and connect_timeout in seconds. `defaults` sets them for
+# the lines after it, until the next `defaults`. tag.NAME=value sets a tag carried to the
+# output, like tag.dc=fra below. ${VAR} is replaced with the environment variable,
+# ${VAR:-default} falls back to the default when it's unset. Comments start with #.
+#
+# Clusters are told apart by the system identifier their hosts share, so the [cluster]
+# headings below are comments for people, as are the ones `timeline_check discover --seed
+# HOST --annotate` writes from a running cluster.
+
+# [orders] the primary and two standbys, verifying the server certificates against the
+# system's CAs. One standby is slower to answer than the others
+orders-db1.example.com sslmode=verify-full tag.dc=fra
+orders-db2.example.com sslmode=verify-full tag.dc=fra
+orders-db3.example.com sslmode=verify-full tag.dc=ams connect_timeout=5
+
+# [billing] on another port and role: [01-03] stands for billing-db01 to billing-db03
+billing-db[01-03].example.com port=5433 user=${BILLING_USER:-monitor} tag.dc=fra
+
+# [payments] verified against its own CA and logging in with a client certificate. Uncomment
+# once the files are in certs/ next to this file
+# defaults sslmode=verify-ca sslrootcert=certs/payments-ca.crt
+# payments-db1.example.com sslcert=certs/monitor.crt sslkey=certs/monitor.key tag.dc=fra
+# payments-db2.example.com sslcert=certs/monitor.crt sslkey=certs/monitor.key tag.dc=ams
+# defaults
+
+# [staging] another hosts file can be read in place, relative to this one
+# include staging-hosts
+"#;
+
+const CHECKS: &str = r#"# Site-specific queries for timeline_check --checks checks.yaml. Each runs on every host
+# next to the built-in checks and the first column of its first row becomes a column of its
+# own, named after the check. Environment variables are filled in like in the hosts file.
+#
+# With `expect`, `check` counts the host as unhealthy unless the result holds up to one of
+# equals, less_than, greater_than or matches (a regex).
+checks:
+  - name: max_connections
+    query: SHOW max_connections
+  - name: wal_senders
+    query: SELECT count(*) FROM pg_stat_replication
+  - name: fsync
+    query: SHOW fsync
+    expect:
+      equals: "on"
+"#;
+
+/// Writes an example hosts file and checks file to start from, each explaining its format
+pub fn run(args: &InitArgs) -> ExitCode {
+    if let Err(e) = fs::create_dir_all(&args.dir) {
+        eprintln!("Error creating {}: {}", args.dir.display(), e);
+        return ExitCode::FAILURE;
+    }
+    let hosts = args.dir.join("hosts");
+    let checks = args.dir.join("checks.yaml");
+    // Either both files are written or neither
+    if let Some(existing) = [&hosts, &checks].into_iter().find(|p| p.exists()) {
+        if !args.force {
+            eprintln!(
+                "{} already exists, pass --force to replace it",
+                existing.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+    for (path, text) in [(&hosts, HOSTS), (&checks, CHECKS)] {
+        match fs::write(path, text) {
+            Ok(()) => println!("Wrote {}", path.display()),
+            Err(e) => {
+                eprintln!("Error writing {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    println!(
+        "Edit them, then try: timeline_check -u USER -p PASSWORD --hosts {} --checks {} --dry-run report",
+        hosts.display(),
+        checks.display()
+    );
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checks, hosts};
+
+    #[test]
+    fn the_examples_are_read_as_they_describe() {
+        let dir = std::env::temp_dir().join(format!("timeline_check_init_{}", std::process::id()));
+        let args = InitArgs {
+            dir: dir.clone(),
+            force: false,
+        };
+        assert_eq!(run(&args), ExitCode::SUCCESS);
+        assert_eq!(run(&args), ExitCode::FAILURE);
+
+        let entries = hosts::read_hosts_file(&dir.join("hosts")).unwrap();
+        let names: Vec<&str> = entries.iter().map(|(n, _, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "orders-db1.example.com",
+                "orders-db2.example.com",
+                "orders-db3.example.com",
+                "billing-db01.example.com",
+                "billing-db02.example.com",
+                "billing-db03.example.com",
+            ]
+        );
+        let orders = &entries[2].2;
+        assert_eq!(orders.to_string(), "sslmode=verify-full connect_timeout=5");
+        assert_eq!(orders.tags["dc"], "ams");
+        assert_eq!(entries[3].2.to_string(), "port=5433 user=monitor");
+
+        // The payments cluster as it reads once uncommented
+        let uncommented = HOSTS
+            .lines()
+            .filter_map(|l| l.strip_prefix("# "))
+            .filter(|l| l.starts_with("defaults") || l.starts_with("payments-db"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(dir.join("payments"), uncommented).unwrap();
+        let entries = hosts::read_hosts_file(&dir.join("payments")).unwrap();
+        assert_eq!(entries.len(), 2);
+        let payments = &entries[1].2;
+        assert_eq!(
+            payments.sslrootcert,
+            Some(dir.join("certs/payments-ca.crt"))
+        );
+        assert_eq!(
+            payments.client_cert,
+            Some((dir.join("certs/monitor.crt"), dir.join("certs/monitor.key")))
+        );
+        assert!(payments.to_string().starts_with("sslmode=verify-ca"));
+
+        let checks = checks::load(&dir.join("checks.yaml")).unwrap();
+        assert_eq!(checks.len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
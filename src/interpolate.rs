@@ -0,0 +1,82 @@
+use std::env;
+
+/// Replaces `${VAR}` with the environment variable's value, or `${VAR:-default}` with `default`
+/// when it's unset or empty, so one file serves every environment. `$${` is a literal `${`.
+pub fn env_vars(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(body) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = body
+            .find('}')
+            .ok_or_else(|| format!("unclosed ${{ in {}", rest.lines().next().unwrap_or(rest)))?;
+        let (name, default) = match body[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&body[..end], None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable name in ${{{}}}", &body[..end]));
+        }
+        match (env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => out.push_str(default),
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => return Err(format!("{} isn't set", name)),
+        }
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_are_replaced_with_their_value_or_default() {
+        env::set_var("TIMELINE_CHECK_TEST_SET", "db 1, \"a\"=b");
+        env::set_var("TIMELINE_CHECK_TEST_EMPTY", "");
+        env::remove_var("TIMELINE_CHECK_TEST_UNSET");
+        for (text, expected) in [
+            ("host=${TIMELINE_CHECK_TEST_SET}", "host=db 1, \"a\"=b"),
+            ("${TIMELINE_CHECK_TEST_SET:-other}", "db 1, \"a\"=b"),
+            ("${TIMELINE_CHECK_TEST_EMPTY}", ""),
+            ("${TIMELINE_CHECK_TEST_EMPTY:-5432}", "5432"),
+            ("${TIMELINE_CHECK_TEST_UNSET:-a b=c}", "a b=c"),
+            ("${TIMELINE_CHECK_TEST_UNSET:-}", ""),
+            ("$${TIMELINE_CHECK_TEST_SET}", "${TIMELINE_CHECK_TEST_SET}"),
+            ("cost $5 and $", "cost $5 and $"),
+        ] {
+            assert_eq!(env_vars(text).as_deref(), Ok(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn unset_and_malformed_variables_are_errors() {
+        env::remove_var("TIMELINE_CHECK_TEST_MISSING");
+        for (text, error) in [
+            (
+                "${TIMELINE_CHECK_TEST_MISSING}",
+                "TIMELINE_CHECK_TEST_MISSING isn't set",
+            ),
+            ("a ${HOME\nb", "unclosed ${ in ${HOME"),
+            ("${}", "invalid variable name in ${}"),
+            ("${A-B}", "invalid variable name in ${A-B}"),
+            ("${:-x}", "invalid variable name in ${:-x}"),
+        ] {
+            assert_eq!(env_vars(text), Err(error.to_string()), "{}", text);
+        }
+    }
+}
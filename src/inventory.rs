@@ -0,0 +1,303 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+};
+
+use serde_yaml::Value;
+
+use crate::{hosts::expand, interpolate};
+
+/// Hosts and child groups of one inventory group, in file order
+#[derive(Default)]
+struct Group {
+    hosts: Vec<(String, Option<String>)>,
+    children: Vec<String>,
+}
+
+/// Reads the hosts of `group` (including its child groups, `all` for every host) from an
+/// Ansible inventory, with `ansible_host` as the address to connect to. `.yml` and `.yaml`
+/// files are read as YAML inventories, anything else as INI.
+pub fn read(path: &Path, group: &str) -> io::Result<Vec<(String, Option<String>)>> {
+    let text = fs::read_to_string(path)?;
+    let text =
+        interpolate::env_vars(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let yaml = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yml" | "yaml")
+    );
+    let groups = if yaml {
+        parse_yaml(&text)
+    } else {
+        parse_ini(&text)
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    if !groups.contains_key(group) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no group {} in the inventory", group),
+        ));
+    }
+    let mut hosts = Vec::new();
+    collect(&groups, group, &mut HashSet::new(), &mut hosts);
+    Ok(hosts)
+}
+
+/// Ansible writes ranges as `db[01:03]`, hosts files as `db[01-03]`
+fn ansible_ranges(name: &str) -> String {
+    let mut in_brackets = false;
+    name.chars()
+        .map(|c| match c {
+            '[' | ']' => {
+                in_brackets = c == '[';
+                c
+            }
+            ':' if in_brackets => '-',
+            _ => c,
+        })
+        .collect()
+}
+
+fn collect(
+    groups: &HashMap<String, Group>,
+    name: &str,
+    visited: &mut HashSet<String>,
+    out: &mut Vec<(String, Option<String>)>,
+) {
+    if !visited.insert(name.to_string()) {
+        return;
+    }
+    let Some(group) = groups.get(name) else {
+        return;
+    };
+    out.extend(group.hosts.iter().cloned());
+    for child in &group.children {
+        collect(groups, child, visited, out);
+    }
+}
+
+/// `[group]` sections list hosts with optional `key=value` vars, `[group:children]` list
+/// groups, `[group:vars]` are ignored
+fn parse_ini(text: &str) -> Result<HashMap<String, Group>, String> {
+    let mut groups: HashMap<String, Group> = HashMap::new();
+    // Hosts before the first section are ungrouped
+    let mut section = ("ungrouped".to_string(), "hosts");
+    let mut all = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = match header.split_once(':') {
+                Some((name, "children")) => (name.to_string(), "children"),
+                Some((name, "vars")) => (name.to_string(), "vars"),
+                Some(_) => return Err(format!("line {}: unknown section [{}]", i + 1, header)),
+                None => (header.to_string(), "hosts"),
+            };
+            groups.entry(section.0.clone()).or_default();
+            continue;
+        }
+
+        let group = groups.entry(section.0.clone()).or_default();
+        let mut words = line.split_whitespace();
+        let first = words.next().unwrap_or_default();
+        match section.1 {
+            "hosts" => {
+                let addr = words
+                    .filter_map(|w| w.split_once('='))
+                    .find(|(k, _)| *k == "ansible_host")
+                    .map(|(_, v)| v.trim_matches(|c| c == '"' || c == '\'').to_string());
+                let names =
+                    expand(&ansible_ranges(first)).map_err(|e| format!("line {}: {}", i + 1, e))?;
+                let hosts: Vec<_> = names.into_iter().map(|n| (n, addr.clone())).collect();
+                group.hosts.extend(hosts.iter().cloned());
+                // Every host belongs to all, in file order
+                all.extend(hosts);
+            }
+            "children" => group.children.push(first.to_string()),
+            _ => {}
+        }
+    }
+    groups
+        .entry("all".to_string())
+        .or_default()
+        .hosts
+        .extend(all);
+    Ok(groups)
+}
+
+/// Groups nest under `children`, each with a `hosts` mapping of host names to their vars
+fn parse_yaml(text: &str) -> Result<HashMap<String, Group>, String> {
+    let root: Value = serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+    let mut groups: HashMap<String, Group> = HashMap::new();
+    let mut top_level = Vec::new();
+    if let Value::Mapping(top) = root {
+        for (name, body) in top {
+            if let Some(name) = name.as_str() {
+                yaml_group(name, &body, &mut groups)?;
+                top_level.push(name.to_string());
+            }
+        }
+    }
+    // Groups can sit at the top level instead of under all, they still belong to it
+    top_level.retain(|g| g != "all");
+    groups
+        .entry("all".to_string())
+        .or_default()
+        .children
+        .extend(top_level);
+    Ok(groups)
+}
+
+fn yaml_group(name: &str, body: &Value, groups: &mut HashMap<String, Group>) -> Result<(), String> {
+    let mut group = Group::default();
+    if let Some(Value::Mapping(hosts)) = body.get("hosts") {
+        for (host, vars) in hosts {
+            let Some(host) = host.as_str() else {
+                return Err(format!("host name in group {} isn't a string", name));
+            };
+            let addr = vars
+                .get("ansible_host")
+                .and_then(Value::as_str)
+                .map(str::to_string);
+            let names = expand(&ansible_ranges(host))?;
+            group
+                .hosts
+                .extend(names.into_iter().map(|n| (n, addr.clone())));
+        }
+    }
+    if let Some(Value::Mapping(children)) = body.get("children") {
+        for (child, child_body) in children {
+            if let Some(child) = child.as_str() {
+                group.children.push(child.to_string());
+                yaml_group(child, child_body, groups)?;
+            }
+        }
+    }
+
+    // A group can be listed under several parents, the later listings add to it
+    let existing = groups.entry(name.to_string()).or_default();
+    existing.hosts.extend(group.hosts);
+    existing.children.extend(group.children);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(groups: &HashMap<String, Group>, name: &str) -> Vec<(String, Option<String>)> {
+        let mut hosts = Vec::new();
+        collect(groups, name, &mut HashSet::new(), &mut hosts);
+        hosts
+    }
+
+    fn host(name: &str, addr: Option<&str>) -> (String, Option<String>) {
+        (name.to_string(), addr.map(str::to_string))
+    }
+
+    #[test]
+    fn ini_groups_ranges_and_children_are_read() {
+        let groups = parse_ini(
+            "lone ansible_host=10.0.0.9\n\
+             # comment\n\
+             ; another\n\
+             [primaries]\n\
+             db[01:02] ansible_user=postgres\n\
+             [standbys]\n\
+             db3 ansible_host=\"10.0.0.3\" ansible_port=5433\n\
+             db4 ansible_host='db4.internal'\n\
+             [db:children]\n\
+             primaries\n\
+             standbys\n\
+             [db:vars]\n\
+             ansible_user=postgres\n",
+        )
+        .unwrap();
+        let db = vec![
+            host("db01", None),
+            host("db02", None),
+            host("db3", Some("10.0.0.3")),
+            host("db4", Some("db4.internal")),
+        ];
+        assert_eq!(hosts(&groups, "db"), db);
+        assert_eq!(
+            hosts(&groups, "ungrouped"),
+            vec![host("lone", Some("10.0.0.9"))]
+        );
+        assert_eq!(hosts(&groups, "all")[0], host("lone", Some("10.0.0.9")));
+        assert_eq!(hosts(&groups, "all")[1..], db);
+    }
+
+    #[test]
+    fn ini_errors_name_their_line() {
+        for (text, error) in [
+            (
+                "[db]\n[db:hostvars]\n",
+                "line 2: unknown section [db:hostvars]",
+            ),
+            ("[db]\ndb[3:1]\n", "line 2: "),
+        ] {
+            let e = parse_ini(text).err().unwrap();
+            assert!(e.starts_with(error), "{}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn yaml_groups_nest_and_belong_to_all() {
+        let groups = parse_yaml(
+            "all:\n\
+             \x20 hosts:\n\
+             \x20   lone:\n\
+             \x20 children:\n\
+             \x20   db:\n\
+             \x20     children:\n\
+             \x20       primaries:\n\
+             \x20         hosts:\n\
+             \x20           db[01:02]:\n\
+             \x20             ansible_host: 10.0.0.1\n\
+             \x20       standbys:\n\
+             \x20         hosts:\n\
+             \x20           db3:\n\
+             cache:\n\
+             \x20 hosts:\n\
+             \x20   redis1:\n",
+        )
+        .unwrap();
+        assert_eq!(
+            hosts(&groups, "db"),
+            vec![
+                host("db01", Some("10.0.0.1")),
+                host("db02", Some("10.0.0.1")),
+                host("db3", None),
+            ]
+        );
+        let all: Vec<_> = hosts(&groups, "all").into_iter().map(|(n, _)| n).collect();
+        assert_eq!(all, ["lone", "db01", "db02", "db3", "redis1"]);
+    }
+
+    #[test]
+    fn files_are_read_by_extension_and_missing_groups_are_errors() {
+        let dir =
+            std::env::temp_dir().join(format!("timeline_check_inventory_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hosts.yml"), "db:\n  hosts:\n    db1:\n").unwrap();
+        fs::write(dir.join("hosts"), "[db]\ndb1\n").unwrap();
+        for name in ["hosts.yml", "hosts"] {
+            let path = dir.join(name);
+            assert_eq!(
+                read(&path, "db").unwrap(),
+                vec![host("db1", None)],
+                "{}",
+                name
+            );
+            let e = read(&path, "web").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::NotFound);
+            assert_eq!(e.to_string(), "no group web in the inventory");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
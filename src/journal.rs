@@ -0,0 +1,91 @@
+use std::{io, os::unix::net::UnixDatagram};
+
+use clap::ValueEnum;
+
+/// Where the daemon's messages about hosts and sweeps go
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogTarget {
+    /// Plain lines on stderr
+    #[default]
+    Stderr,
+    /// Structured records in the systemd journal, with HOST, CLUSTER and ERROR_KIND fields
+    Journald,
+}
+
+/// syslog(3) levels, as journald's PRIORITY field takes them
+#[derive(Clone, Copy, Debug)]
+pub enum Priority {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+pub struct Log {
+    journal: Option<UnixDatagram>,
+}
+
+impl Log {
+    pub fn new(target: LogTarget) -> io::Result<Self> {
+        let journal = match target {
+            LogTarget::Stderr => None,
+            LogTarget::Journald => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(JOURNAL_SOCKET)?;
+                Some(socket)
+            }
+        };
+        Ok(Log { journal })
+    }
+
+    /// Logs `message` with `fields`, upper case names like `HOST`, next to it. A record the
+    /// journal doesn't take is printed to stderr instead
+    pub fn write(&self, priority: Priority, message: &str, fields: &[(&str, &str)]) {
+        let Some(journal) = &self.journal else {
+            eprintln!("{}", message);
+            return;
+        };
+        let priority = (priority as u8).to_string();
+        let common = [
+            ("MESSAGE", message),
+            ("PRIORITY", priority.as_str()),
+            ("SYSLOG_IDENTIFIER", "timeline_check"),
+        ];
+        let mut record = Vec::new();
+        for (name, value) in common.iter().chain(fields) {
+            append_field(&mut record, name, value);
+        }
+        if journal.send(&record).is_err() {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// `NAME=value` lines, and for values with a newline in them the name, the value's length as a
+/// little-endian u64 and then the value, as the journal's native protocol has it
+fn append_field(record: &mut Vec<u8>, name: &str, value: &str) {
+    record.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        record.push(b'\n');
+        record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        record.push(b'=');
+    }
+    record.extend_from_slice(value.as_bytes());
+    record.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::append_field;
+
+    #[test]
+    fn values_with_newlines_are_sent_with_their_length() {
+        let mut record = Vec::new();
+        append_field(&mut record, "HOST", "db1");
+        append_field(&mut record, "MESSAGE", "a\nb");
+        assert_eq!(record, b"HOST=db1\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use russh::{
+    client::{self, Msg},
+    ChannelStream,
+};
+use russh_keys::{agent::client::AgentClient, key};
+
+/// A bastion, given as `user@host[:port]`
+#[derive(Clone, Debug)]
+pub struct JumpHost {
+    user: String,
+    host: String,
+    port: u16,
+}
+
+impl fmt::Display for JumpHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.host, self.port)
+    }
+}
+
+pub fn parse(s: &str) -> Result<JumpHost, String> {
+    let (user, rest) = s
+        .split_once('@')
+        .ok_or_else(|| format!("expected user@host[:port], got {}", s))?;
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("invalid port {} in {}", port, s))?,
+        ),
+        None => (rest, 22),
+    };
+    if user.is_empty() || host.is_empty() {
+        return Err(format!("expected user@host[:port], got {}", s));
+    }
+    Ok(JumpHost {
+        user: user.to_string(),
+        host: host.to_string(),
+        port,
+    })
+}
+
+/// An SSH session to the bastion, each connection through it a channel of its own
+pub struct Jump {
+    bastion: JumpHost,
+    session: client::Handle<Client>,
+}
+
+struct Client {
+    host: String,
+    port: u16,
+}
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    /// Only bastions already in ~/.ssh/known_hosts are trusted
+    async fn check_server_key(&mut self, key: &key::PublicKey) -> Result<bool, Self::Error> {
+        Ok(russh_keys::check_known_hosts(&self.host, self.port, key)?)
+    }
+}
+
+impl Jump {
+    /// Logs in to the bastion with `key`, or else the keys in ssh-agent and then the default
+    /// unencrypted ones in ~/.ssh
+    pub async fn connect(bastion: &JumpHost, key: Option<&Path>) -> io::Result<Jump> {
+        let client = Client {
+            host: bastion.host.clone(),
+            port: bastion.port,
+        };
+        let config = Arc::new(client::Config::default());
+        let mut session = client::connect(config, (bastion.host.as_str(), bastion.port), client)
+            .await
+            .map_err(|e| match e {
+                russh::Error::UnknownKey => io::Error::other(format!(
+                    "{}'s host key isn't in ~/.ssh/known_hosts",
+                    bastion.host
+                )),
+                e => io::Error::other(e),
+            })?;
+
+        if !authenticate(&mut session, &bastion.user, key).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{}@{} accepted none of the keys",
+                    bastion.user, bastion.host
+                ),
+            ));
+        }
+        Ok(Jump {
+            bastion: bastion.clone(),
+            session,
+        })
+    }
+
+    pub fn bastion(&self) -> &JumpHost {
+        &self.bastion
+    }
+
+    /// Opens a connection from the bastion to `host:port`, on behalf of the local `peer`
+    pub async fn open(
+        &self,
+        host: &str,
+        port: u16,
+        peer: SocketAddr,
+    ) -> io::Result<ChannelStream<Msg>> {
+        let channel = self
+            .session
+            .channel_open_direct_tcpip(host, port.into(), peer.ip().to_string(), peer.port().into())
+            .await
+            .map_err(io::Error::other)?;
+        Ok(channel.into_stream())
+    }
+}
+
+async fn authenticate(
+    session: &mut client::Handle<Client>,
+    user: &str,
+    key: Option<&Path>,
+) -> io::Result<bool> {
+    let ssh_error = |e: russh::Error| io::Error::other(e);
+    if let Some(path) = key {
+        let pair = russh_keys::load_secret_key(path, None)
+            .map_err(|e| io::Error::other(format!("{}: {}", path.display(), e)))?;
+        return session
+            .authenticate_publickey(user, Arc::new(pair))
+            .await
+            .map_err(ssh_error);
+    }
+
+    if let Ok(mut agent) = AgentClient::connect_env().await {
+        let identities = agent.request_identities().await.unwrap_or_default();
+        for identity in identities {
+            let (a, authenticated) = session.authenticate_future(user, identity, agent).await;
+            agent = a;
+            if authenticated.unwrap_or(false) {
+                return Ok(true);
+            }
+        }
+    }
+
+    let Some(home) = std::env::var_os("HOME").map(PathBuf::from) else {
+        return Ok(false);
+    };
+    for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+        let Ok(pair) = russh_keys::load_secret_key(home.join(".ssh").join(name), None) else {
+            continue;
+        };
+        if session
+            .authenticate_publickey(user, Arc::new(pair))
+            .await
+            .map_err(ssh_error)?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,57 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Host;
+
+/// How far behind a group of standbys is, for capacity reviews
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LagStats {
+    /// Standbys the lag was measured on
+    pub standbys: usize,
+    pub bytes: Option<Spread<i64>>,
+    pub seconds: Option<Spread<f64>>,
+}
+
+/// Nearest-rank percentiles of one measure
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Spread<T> {
+    pub min: T,
+    pub median: T,
+    pub p95: T,
+    pub max: T,
+}
+
+impl LagStats {
+    /// `None` when none of `hosts` is a standby with its lag known
+    pub fn of<'a>(hosts: impl IntoIterator<Item = &'a Host>) -> Option<Self> {
+        let standbys: Vec<&Host> = hosts
+            .into_iter()
+            .filter(|h| !h.is_primary && (h.lag_bytes.is_some() || h.lag_seconds.is_some()))
+            .collect();
+        if standbys.is_empty() {
+            return None;
+        }
+        Some(LagStats {
+            standbys: standbys.len(),
+            bytes: spread(standbys.iter().filter_map(|h| h.lag_bytes).collect()),
+            seconds: spread(standbys.iter().filter_map(|h| h.lag_seconds).collect()),
+        })
+    }
+}
+
+fn spread<T: Copy + PartialOrd>(mut values: Vec<T>) -> Option<Spread<T>> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    Some(Spread {
+        min: *values.first()?,
+        median: percentile(&values, 0.5)?,
+        p95: percentile(&values, 0.95)?,
+        max: *values.last()?,
+    })
+}
+
+/// Nearest-rank percentile `q` of sorted `values`, `None` when there are none
+pub fn percentile<T: Copy>(values: &[T], q: f64) -> Option<T> {
+    let rank = (q * values.len() as f64).ceil() as usize;
+    values.get(rank.max(1) - 1).copied()
+}
@@ -0,0 +1,1999 @@
+mod interpolate;
+mod inventory;
+mod lag;
+mod pace;
+mod pooler;
+mod ssh_config;
+
+/// Declares modules the command line is built from: public with the `cli` feature, for the
+/// binary alone, and private to the library without it
+macro_rules! internal_mods {
+    ($($name:ident),* $(,)?) => {$(
+        #[cfg(feature = "cli")]
+        #[doc(hidden)]
+        pub mod $name;
+        #[cfg(not(feature = "cli"))]
+        #[allow(dead_code)]
+        mod $name;
+    )*};
+}
+
+/// Declares an item the command line needs, public with the `cli` feature and private to the
+/// crate without it
+macro_rules! internal {
+    ($(#[$attr:meta])* pub $($item:tt)*) => {
+        #[cfg(feature = "cli")]
+        #[doc(hidden)]
+        $(#[$attr])* pub $($item)*
+        #[cfg(not(feature = "cli"))]
+        #[allow(dead_code, unused_imports)]
+        $(#[$attr])* pub(crate) $($item)*
+    };
+}
+
+internal_mods!(
+    checks,
+    clock,
+    error,
+    hosts,
+    hosts_query,
+    jump,
+    logical,
+    output,
+    phases,
+    probe,
+    recovery,
+    replicas,
+    replication,
+    slots,
+    sync,
+    timeline,
+    tls,
+    tunnel,
+    version,
+);
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{stream, Stream};
+use rustls::RootCertStore;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPool, PgPoolOptions, PgRow, PgSslMode},
+    Connection, Executor, PgConnection, Postgres, Row,
+};
+use tokio::{join, sync::Semaphore, task::JoinSet, time};
+
+pub use checks::{Builtin, CustomCheck};
+pub use error::{CheckError, Error, Reason};
+pub use hosts::{HostOptions, Target};
+pub use lag::LagStats;
+internal! {
+    pub use lag::percentile;
+}
+pub use logical::Logical;
+pub use output::Status;
+pub use phases::Phases;
+pub use probe::{HostProbe, Live};
+pub use recovery::RecoveryConfig;
+pub use replicas::Replica;
+pub use slots::Slot;
+pub use sync::SyncReplication;
+pub use timeline::Switch;
+pub use tls::{Encryption, ServerTls};
+pub use version::Version;
+
+internal! {
+    /// Thresholds and role assertions that make a run unhealthy
+    #[cfg_attr(feature = "cli", derive(clap::Args))]
+    #[derive(Debug, Default)]
+    pub struct HealthArgs {
+        /// Treat the run as unhealthy unless this host is a primary and no other host is. Can be
+        /// repeated, one per cluster
+        #[cfg_attr(feature = "cli", arg(long, value_name = "HOST"))]
+        pub expect_primary: Vec<String>,
+
+        /// Treat the run as unhealthy unless exactly this many standbys are streaming in each
+        /// cluster, or only in the cluster of HOST (or with that system identifier) when given as
+        /// HOST=N. Can be repeated
+        #[cfg_attr(
+            feature = "cli",
+            arg(long, value_name = "[HOST=]N", value_parser = parse_expect_replicas)
+        )]
+        pub expect_replicas: Vec<(Option<String>, usize)>,
+
+        /// Treat the run as unhealthy if a standby's replay is this many bytes behind its primary
+        #[cfg_attr(feature = "cli", arg(long))]
+        pub max_lag_bytes: Option<i64>,
+
+        /// Treat the run as unhealthy if a standby's replay is this many seconds behind
+        #[cfg_attr(feature = "cli", arg(long))]
+        pub max_lag_seconds: Option<f64>,
+
+        /// Treat the run as unhealthy if more non-primary hosts than this aren't streaming
+        #[cfg_attr(feature = "cli", arg(long))]
+        pub max_missing_replicas: Option<usize>,
+    }
+}
+
+internal! {
+    pub fn parse_expect_replicas(s: &str) -> Result<(Option<String>, usize), String> {
+        let (cluster, count) = match s.rsplit_once('=') {
+            Some((cluster, count)) => (Some(cluster.to_string()), count),
+            None => (None, s),
+        };
+        let count = count.parse().map_err(|e| format!("{}: {}", count, e))?;
+        Ok((cluster, count))
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Host {
+    pub name: String,
+    pub is_primary: bool,
+    /// Where the host sits in the replication chain, from whether it's a primary and whether
+    /// standbys stream from it
+    pub role: Role,
+    /// The built-in check fields are `None` when the check is turned off or, over a replication
+    /// connection, can't be run
+    pub timeline_id: Option<u32>,
+    pub replica_attached: Option<bool>,
+    pub data_checksums: Option<bool>,
+    /// Pages that failed their checksum since the statistics were reset, across all databases
+    pub checksum_failures: Option<i64>,
+    /// When the latest of them was found, in UTC
+    #[serde(default, with = "::time::serde::rfc3339::option")]
+    pub checksum_last_failure: Option<::time::OffsetDateTime>,
+    pub system_identifier: Option<i64>,
+    /// Seconds since the current timeline began, on primaries. `None` on standbys and when it's
+    /// unknown: the login can't stat the history file and the cluster checkpointed since the
+    /// promotion, or it's still on timeline 1
+    #[serde(rename = "timeline_age_seconds")]
+    pub timeline_age: Option<f64>,
+    /// The timelines the current one branched off from, oldest first, with where each ended.
+    /// Empty on timeline 1, `None` when the history file couldn't be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeline_history: Option<Vec<timeline::Switch>>,
+    /// Current WAL position on primaries, replay position on standbys
+    pub wal_lsn: Option<i64>,
+    /// Whether a WAL receiver is streaming from an upstream
+    pub streaming: Option<bool>,
+    /// Bytes of WAL the standby has yet to replay, compared to its primary
+    pub lag_bytes: Option<i64>,
+    /// How much of that the standby received already and has yet to replay, the rest is still
+    /// on its way
+    pub replay_gap_bytes: Option<i64>,
+    /// Whether the standby stopped applying WAL, with pg_wal_replay_pause() or at a recovery
+    /// target with `recovery_target_action = pause`. It keeps receiving WAL meanwhile
+    pub replay_paused: Option<bool>,
+    /// Seconds since the last replayed transaction, zero when caught up
+    pub lag_seconds: Option<f64>,
+    /// Where a standby gets its WAL from
+    pub wal_source: Option<WalSource>,
+    /// How far the server's clock is ahead of the checking machine's, negative when behind
+    pub clock_skew_seconds: Option<f64>,
+    /// How far off that can be, half the round trip of the reading
+    pub clock_skew_error_seconds: Option<f64>,
+    /// Host the WAL receiver is streaming from
+    pub upstream: Option<String>,
+    /// Timeline of the WAL the receiver last got, behind the primary's until it follows a
+    /// promotion
+    pub received_tli: Option<u32>,
+    /// The WAL receiver's state, `streaming`, `waiting`, `stopped` and so on. A receiver that
+    /// stopped streaming while its process lives on shows up here before anywhere else
+    pub walreceiver_status: Option<String>,
+    /// When the WAL receiver last heard from its upstream, in UTC
+    #[serde(default, with = "::time::serde::rfc3339::option")]
+    pub last_msg_receipt_time: Option<::time::OffsetDateTime>,
+    /// Other hosts file entries that resolve to the same address and weren't checked again
+    pub aliases: Vec<String>,
+    /// From its hosts file line, e.g. `tag.dc=fra tag.tier=prod`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Set when the host is a PgBouncer, the rest of the row then describes the server behind it
+    pub pooler: Option<String>,
+    /// Standby recovery settings, only collected with `--extended`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery: Option<recovery::RecoveryConfig>,
+    /// The standbys streaming from the host and their lag as it sees them, only collected with
+    /// `--extended`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<replicas::Replica>,
+    /// The server's TLS certificate, from a handshake of its own, collected by `check` and with
+    /// `--extended`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<tls::ServerTls>,
+    /// How the checks' connection is protected, only collected with `--tls`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<tls::Encryption>,
+    /// Physical replication slots on the host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slots: Vec<slots::Slot>,
+    /// The slot a standby streams through, its primary_slot_name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot_name: Option<String>,
+    /// Synchronous replication settings and connected standbys, on primaries that have any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync: Option<sync::SyncReplication>,
+    /// Subscriptions, publications and logical slots, only collected with `--logical`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logical: Option<logical::Logical>,
+    /// Where connecting and checking took its time, only collected with `--timings`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phases: Option<phases::Phases>,
+    /// Judged from the whole run for table output, see `mark_status`
+    #[serde(skip)]
+    pub status: Option<output::Status>,
+    /// Why the host couldn't be checked, on the rows table output adds for unreachable hosts
+    #[serde(skip)]
+    pub reason: Option<Reason>,
+    /// When the checks finished, in UTC
+    #[serde(default, with = "::time::serde::rfc3339::option")]
+    pub checked_at: Option<::time::OffsetDateTime>,
+    /// Decides which queries the built-in checks use
+    pub server_version_num: Option<Version>,
+    /// Checks skipped because the server is too old for them, with why, e.g. `timeline`:
+    /// `not supported on 9.5`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unsupported: BTreeMap<String, String>,
+    /// Results of the `--checks` queries, in the file's order
+    #[serde(
+        rename = "checks",
+        default,
+        serialize_with = "checks::as_map",
+        deserialize_with = "checks::from_map",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub custom: Vec<(String, Option<String>)>,
+}
+
+/// How `report` names a host's place in its cluster
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Primary,
+    #[default]
+    Standby,
+    /// A standby other standbys stream from
+    CascadingStandby,
+}
+
+impl Role {
+    pub fn of(is_primary: bool, replica_attached: Option<bool>) -> Self {
+        match (is_primary, replica_attached) {
+            (true, _) => Role::Primary,
+            (false, Some(true)) => Role::CascadingStandby,
+            (false, _) => Role::Standby,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Primary => "primary",
+            Role::Standby => "standby",
+            Role::CascadingStandby => "cascading-standby",
+        }
+    }
+}
+
+/// A standby's pg_stat_wal_receiver row. Roles without pg_read_all_stats only see its pid,
+/// the rest is `None` for them
+struct WalReceiver {
+    sender_host: Option<String>,
+    received_tli: Option<u32>,
+    status: Option<String>,
+    last_msg_receipt_time: Option<::time::OffsetDateTime>,
+}
+
+/// Where a standby replays WAL from. An archive-only standby has no pg_stat_replication row on
+/// the primary, which makes it look detached without this.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WalSource {
+    /// A WAL receiver is streaming from an upstream
+    Streaming,
+    /// Not streaming, but restore_command fetches WAL from the archive
+    Archive,
+    /// Neither, replay stands still
+    None,
+}
+
+impl WalSource {
+    /// `None` on primaries, and on standbys whose receiver status or restore_command the role
+    /// can't read
+    pub fn of(is_primary: bool, streaming: Option<bool>, archive: Option<bool>) -> Option<Self> {
+        match (is_primary, streaming, archive) {
+            (true, _, _) => None,
+            (false, Some(true), _) => Some(WalSource::Streaming),
+            (false, Some(false), Some(true)) => Some(WalSource::Archive),
+            (false, Some(false), Some(false)) => Some(WalSource::None),
+            (false, None, _) | (false, Some(false), None) => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WalSource::Streaming => "streaming",
+            WalSource::Archive => "archive",
+            WalSource::None => "none",
+        }
+    }
+}
+
+/// Fleet-wide counts printed after the per-host rows
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Summary {
+    pub total_hosts: usize,
+    pub primaries: usize,
+    pub replicas: usize,
+    /// Distinct timeline ids observed, in ascending order
+    pub timelines: Vec<u32>,
+    pub unreachable: usize,
+    pub runtime_seconds: f64,
+    /// When the run started, in UTC
+    #[serde(with = "::time::serde::rfc3339")]
+    pub checked_at: ::time::OffsetDateTime,
+    /// Whether Ctrl-C cut the run short, hosts still being checked then count as unreachable
+    pub interrupted: bool,
+    /// Hosts sharing a system identifier, in the order they first appear
+    pub clusters: Vec<Cluster>,
+    /// Lag across every standby, `None` without any
+    pub lag: Option<lag::LagStats>,
+    /// The worst of the clusters', and at least DEGRADED while some hosts couldn't be checked
+    #[serde(default)]
+    pub health: Health,
+    /// Hosts that couldn't be checked, in hosts file order
+    #[serde(default, rename = "unreachable_hosts")]
+    pub failed: Vec<Failure>,
+}
+
+/// A host that couldn't be checked
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Failure {
+    pub name: String,
+    pub reason: Reason,
+    /// Runs in a row it has failed for this reason, this one included
+    pub failed_runs: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Cluster {
+    pub system_identifier: i64,
+    /// The hosts files its hosts came from, when several were read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub verdict: Verdict,
+    #[serde(default)]
+    pub health: Health,
+    pub hosts: Vec<String>,
+    /// Lag across the cluster's standbys
+    pub lag: Option<lag::LagStats>,
+}
+
+impl Cluster {
+    /// Its system identifier, after the name of its hosts files when it has one
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, self.system_identifier),
+            None => self.system_identifier.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Verdict {
+    #[serde(rename = "HEALTHY")]
+    Healthy,
+    /// Hosts disagree on the timeline
+    #[serde(rename = "DIVERGED")]
+    Diverged,
+    #[serde(rename = "NO PRIMARY")]
+    NoPrimary,
+    /// More than one primary, which trumps the other verdicts
+    #[serde(rename = "SPLIT-BRAIN")]
+    SplitBrain,
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Verdict::Healthy => "HEALTHY",
+            Verdict::Diverged => "DIVERGED",
+            Verdict::NoPrimary => "NO PRIMARY",
+            Verdict::SplitBrain => "SPLIT-BRAIN",
+        })
+    }
+}
+
+/// A cluster's state in one word, for automation to branch on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Health {
+    #[default]
+    Ok,
+    /// Taking writes, but a standby is missing, behind a threshold or unreachable
+    Degraded,
+    /// Diverged, split brain, without a primary or with commits waiting on absent sync standbys
+    Critical,
+}
+
+impl std::fmt::Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Health::Ok => "OK",
+            Health::Degraded => "DEGRADED",
+            Health::Critical => "CRITICAL",
+        })
+    }
+}
+
+impl Summary {
+    pub fn new(res: &[Host], host_count: usize, runtime: Duration) -> Self {
+        let mut timelines: Vec<u32> = res.iter().filter_map(|r| r.timeline_id).collect();
+        timelines.sort_unstable();
+        timelines.dedup();
+
+        let mut summary = Summary {
+            total_hosts: host_count,
+            primaries: res.iter().filter(|r| r.is_primary).count(),
+            replicas: res.iter().filter(|r| !r.is_primary).count(),
+            timelines,
+            unreachable: host_count - res.len(),
+            runtime_seconds: runtime.as_secs_f64(),
+            checked_at: to_millis(::time::OffsetDateTime::now_utc() - runtime),
+            interrupted: false,
+            clusters: find_clusters(res),
+            lag: lag::LagStats::of(res),
+            health: Health::Ok,
+            failed: Vec::new(),
+        };
+        summary.judge(&HealthArgs::default(), res);
+        summary
+    }
+
+    internal! {
+        /// Sets each cluster's health and the fleet's, counting `health`'s lag thresholds and
+        /// replica expectations
+        pub fn judge(&mut self, health: &HealthArgs, res: &[Host]) {
+            let (expected_replicas, unchecked) = expected_replicas(health, res);
+            for c in &mut self.clusters {
+                let hosts: Vec<&Host> = res
+                    .iter()
+                    .filter(|r| r.system_identifier == Some(c.system_identifier))
+                    .collect();
+                c.health = cluster_health(
+                    c.verdict,
+                    &hosts,
+                    health,
+                    expected_replicas.get(&c.system_identifier),
+                );
+            }
+
+            let mut fleet = self
+                .clusters
+                .iter()
+                .map(|c| c.health)
+                .max()
+                .unwrap_or_default();
+            if self.unreachable > 0
+                || !unchecked.is_empty()
+                || !check_thresholds(health, res, self.total_hosts).is_empty()
+            {
+                fleet = fleet.max(Health::Degraded);
+            }
+            if res.is_empty() && self.total_hosts > 0 {
+                fleet = Health::Critical;
+            }
+            self.health = fleet;
+        }
+    }
+}
+
+internal! {
+    pub const PORT: u16 = 5432;
+}
+
+internal! {
+    /// The current time in UTC, for `checked_at`
+    pub fn now() -> ::time::OffsetDateTime {
+        to_millis(::time::OffsetDateTime::now_utc())
+    }
+}
+
+/// A timestamp the server sent as seconds since the epoch
+fn from_epoch(seconds: f64) -> Option<::time::OffsetDateTime> {
+    let t = ::time::OffsetDateTime::from_unix_timestamp_nanos((seconds * 1e9) as i128).ok()?;
+    Some(to_millis(t))
+}
+
+/// Drops the sub-millisecond part so timestamps stay readable
+fn to_millis(t: ::time::OffsetDateTime) -> ::time::OffsetDateTime {
+    t.replace_nanosecond(t.millisecond() as u32 * 1_000_000)
+        .unwrap_or(t)
+}
+
+/// How to reach hosts, shared by every check
+#[derive(Clone, Debug)]
+pub struct CheckOptions {
+    pub pg: PgConnectOptions,
+    /// Role every SQL connection assumes after logging in
+    pub set_role: Option<String>,
+    /// Check the server behind a host that turns out to be PgBouncer instead of failing it
+    pub follow_poolers: bool,
+    /// Set with `--via replication`, checks then use a replication connection instead of SQL
+    pub replication: Option<replication::Login>,
+    /// Also collect the recovery settings of standbys, and the lag of the standbys streaming from
+    /// each host as it sees them
+    pub extended: bool,
+    /// Also collect subscriptions, publications and logical slots
+    pub logical: bool,
+    /// Queries from `--checks`
+    pub checks: Arc<[checks::CustomCheck]>,
+    /// Built-in checks left after `--checks-enable` and `--checks-disable`
+    pub builtins: Arc<[checks::Builtin]>,
+    /// Also look at each server's TLS certificate
+    probe_tls: bool,
+    /// Also collect how each connection is encrypted
+    pub encryption: bool,
+    /// Set with `report --timings`, the login for a connection of its own that times each phase
+    pub timings: Option<replication::Login>,
+    /// Bastion or proxy that connections are tunneled through
+    pub tunnel: Option<Arc<tunnel::Tunnel>>,
+    /// Client certificate and key files
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    /// The sslmode SQL connections use, before the hosts file's overrides
+    ssl_mode: PgSslMode,
+    /// Set by `route` under verify-full when the address connected to isn't the host name, which
+    /// the certificate is then checked against apart from the connection, see `tls::verify_name`
+    pub verify_name: Option<String>,
+    /// How long connecting may take, set per host in the hosts file
+    pub connect_timeout: Option<Duration>,
+    /// Set with `--max-connects-per-second`, shared by every host's checks
+    pacer: Option<Arc<pace::Pacer>>,
+    /// The login for replication connections, before the hosts file's overrides
+    pub login: replication::Login,
+    /// Most hosts checked at the same time
+    pub concurrency: usize,
+}
+
+impl CheckOptions {
+    /// Waits until the next new connection may be opened
+    pub async fn pace(&self) {
+        if let Some(pacer) = &self.pacer {
+            pacer.wait().await;
+        }
+    }
+}
+
+/// Builds `CheckOptions` from a login. What isn't set is what a bare command line does: every
+/// built-in check and no custom ones over SQL, TLS when the server offers it, 16 hosts at a
+/// time and no connect timeout
+#[derive(Clone, Debug)]
+pub struct CheckOptionsBuilder {
+    pub options: CheckOptions,
+    pub via_replication: bool,
+    pub timings: bool,
+    pub max_connects_per_second: Option<f64>,
+}
+
+impl CheckOptionsBuilder {
+    pub fn new(user: &str, password: &str) -> Self {
+        let pg = PgConnectOptions::new()
+            .port(PORT)
+            .database("postgres")
+            .username(user)
+            .password(password)
+            .ssl_mode(PgSslMode::Prefer)
+            .application_name("timeline_check");
+        let login = replication::Login {
+            user: user.to_string(),
+            password: password.to_string(),
+            database: "postgres".to_string(),
+            port: PORT,
+            require_tls: false,
+            client_cert: None,
+            verify: None,
+            server_name: None,
+            connect_timeout: None,
+            application_name: "timeline_check".to_string(),
+        };
+        CheckOptionsBuilder {
+            options: CheckOptions {
+                pg,
+                set_role: None,
+                follow_poolers: false,
+                replication: None,
+                extended: false,
+                logical: false,
+                checks: Arc::new([]),
+                builtins: checks::Builtin::ALL.into(),
+                probe_tls: false,
+                encryption: false,
+                timings: None,
+                tunnel: None,
+                client_cert: None,
+                ssl_mode: PgSslMode::Prefer,
+                verify_name: None,
+                connect_timeout: None,
+                pacer: None,
+                login,
+                concurrency: 16,
+            },
+            via_replication: false,
+            timings: false,
+            max_connects_per_second: None,
+        }
+    }
+
+    /// Requires TLS, verifying servers against `root_cert` and logging in with the client
+    /// certificate
+    pub fn tls(mut self, root_cert: &Path, client_cert: &Path, client_key: &Path) -> Self {
+        let o = &mut self.options;
+        o.pg =
+            o.pg.clone()
+                .ssl_mode(PgSslMode::Require)
+                .ssl_root_cert(root_cert)
+                .ssl_client_cert(client_cert)
+                .ssl_client_key(client_key);
+        o.client_cert = Some((client_cert.to_path_buf(), client_key.to_path_buf()));
+        o.ssl_mode = PgSslMode::Require;
+        o.login.require_tls = true;
+        o.login.client_cert = o.client_cert.clone();
+        self
+    }
+
+    /// Requires TLS, verifying servers and their host names against `roots`, with `pem` the
+    /// same certificates for sqlx
+    pub fn system_roots(mut self, roots: RootCertStore, pem: Vec<u8>) -> Self {
+        let o = &mut self.options;
+        o.pg =
+            o.pg.clone()
+                .ssl_mode(PgSslMode::VerifyFull)
+                .ssl_root_cert_from_pem(pem);
+        o.ssl_mode = PgSslMode::VerifyFull;
+        o.login.require_tls = true;
+        o.login.verify = Some(tls::Verify {
+            roots: Arc::new(roots),
+            hostname: true,
+        });
+        self
+    }
+
+    /// What sessions show as their application_name on the servers
+    pub fn application_name(mut self, name: &str) -> Self {
+        let o = &mut self.options;
+        o.pg = o.pg.clone().application_name(name);
+        o.login.application_name = name.to_string();
+        self
+    }
+
+    /// Assumes `role` on every SQL connection
+    pub fn set_role(mut self, role: &str) -> Self {
+        self.options.set_role = Some(role.to_string());
+        self
+    }
+
+    /// Most hosts checked at the same time, at least one
+    pub fn concurrency(mut self, n: usize) -> Self {
+        self.options.concurrency = n.max(1);
+        self
+    }
+
+    /// For hosts whose hosts file line doesn't set connect_timeout
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self.options.login.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Opens at most `n` new connections a second across all hosts, a positive number
+    pub fn max_connects_per_second(mut self, n: f64) -> Self {
+        self.max_connects_per_second = Some(n);
+        self
+    }
+
+    /// Checks over a replication connection instead of SQL, see `Via::Replication`
+    pub fn via_replication(mut self, yes: bool) -> Self {
+        self.via_replication = yes;
+        self
+    }
+
+    /// Checks the server behind a host that turns out to be PgBouncer
+    pub fn follow_poolers(mut self, yes: bool) -> Self {
+        self.options.follow_poolers = yes;
+        self
+    }
+
+    /// Also check logical replication
+    pub fn logical(mut self, yes: bool) -> Self {
+        self.options.logical = yes;
+        self
+    }
+
+    /// The built-in checks to run instead of all of them
+    pub fn builtins(mut self, builtins: Vec<checks::Builtin>) -> Self {
+        self.options.builtins = builtins.into();
+        self
+    }
+
+    /// Site-specific queries to run next to the built-in checks
+    pub fn checks(mut self, checks: Vec<checks::CustomCheck>) -> Self {
+        self.options.checks = checks.into();
+        self
+    }
+
+    /// Also collects the recovery settings of standbys and the lag of their upstreams' standbys
+    pub fn extended(mut self, yes: bool) -> Self {
+        self.options.extended = yes;
+        self
+    }
+
+    /// Also looks at each server's TLS certificate, in a handshake of its own
+    pub fn certificates(mut self, yes: bool) -> Self {
+        self.options.probe_tls = yes;
+        self
+    }
+
+    /// Also collects how each connection is encrypted
+    pub fn encryption(mut self, yes: bool) -> Self {
+        self.options.encryption = yes;
+        self
+    }
+
+    /// Also times each connection phase, over a connection of its own
+    pub fn timings(mut self, yes: bool) -> Self {
+        self.timings = yes;
+        self
+    }
+
+    /// Fails on settings out of range, such as a rate of connects that isn't a positive number
+    pub fn build(&self) -> Result<CheckOptions, String> {
+        if let Some(n) = self.max_connects_per_second {
+            if !(n > 0.0 && n.is_finite()) {
+                return Err(format!(
+                    "max_connects_per_second must be a positive number, not {}",
+                    n
+                ));
+            }
+        }
+        let mut options = self.options.clone();
+        let login = &options.login;
+        options.replication = self.via_replication.then(|| login.clone());
+        options.timings = self.timings.then(|| login.clone());
+        options.pacer = self
+            .max_connects_per_second
+            .map(|n| Arc::new(pace::Pacer::new(n)));
+        Ok(options)
+    }
+}
+
+/// Per-host pools kept between sweeps, so repeated sweeps skip the connect and TLS handshake.
+/// An evicted pool's connections close once a check still using it lets go
+#[derive(Clone, Default)]
+pub struct Pools {
+    cached: Arc<Mutex<HashMap<String, CachedPool>>>,
+    /// Pools unused for this long are closed instead of reused
+    idle_ttl: Option<Duration>,
+    /// Most pools kept open at once, the least recently used one is closed to make room
+    max_size: Option<usize>,
+}
+
+struct CachedPool {
+    pool: PgPool,
+    /// Set when the pool connects to the server behind a PgBouncer host, see `Host::pooler`
+    pooler: Option<String>,
+    last_used: Instant,
+}
+
+impl Pools {
+    pub fn new(idle_ttl: Option<Duration>, max_size: Option<usize>) -> Self {
+        Pools {
+            cached: Default::default(),
+            idle_ttl,
+            max_size,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<(PgPool, Option<String>)> {
+        let mut cached = self.cached.lock().unwrap();
+        let entry = cached.get_mut(name)?;
+        entry.last_used = Instant::now();
+        Some((entry.pool.clone(), entry.pooler.clone()))
+    }
+
+    pub fn insert(&self, name: &str, pool: PgPool, pooler: Option<String>) {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some(max) = self.max_size {
+            while cached.len() >= max.max(1) {
+                let Some(oldest) = cached
+                    .iter()
+                    .min_by_key(|(_, c)| c.last_used)
+                    .map(|(n, _)| n.clone())
+                else {
+                    break;
+                };
+                cached.remove(&oldest);
+            }
+        }
+        let last_used = Instant::now();
+        let entry = CachedPool {
+            pool,
+            pooler,
+            last_used,
+        };
+        cached.insert(name.to_string(), entry);
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.cached.lock().unwrap().remove(name);
+    }
+
+    /// Closes pools for hosts that are no longer listed, e.g. after a reload, and idle ones
+    pub fn retain(&self, hosts: &[Target]) {
+        let mut cached = self.cached.lock().unwrap();
+        let evict: Vec<String> = cached
+            .iter()
+            .filter(|(name, c)| {
+                let idle = self.idle_ttl.is_some_and(|ttl| c.last_used.elapsed() > ttl);
+                idle || !hosts.iter().any(|t| &t.name == *name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in evict {
+            cached.remove(&name);
+        }
+    }
+}
+
+/// One host's check as `check_hosts_stream` hands it out. Serializes as the host's name,
+/// `elapsed_seconds` and either `host` or `error`
+#[derive(Debug, Serialize)]
+pub struct HostReport {
+    /// The host's name in the hosts file
+    pub name: String,
+    #[serde(flatten, serialize_with = "either_host_or_error")]
+    pub result: Result<Host, CheckError>,
+    /// How long checking it took, waiting for a `--concurrency` slot left out
+    #[serde(rename = "elapsed_seconds", serialize_with = "seconds")]
+    pub elapsed: Duration,
+}
+
+fn either_host_or_error<S: serde::Serializer>(
+    result: &Result<Host, CheckError>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match result {
+        Ok(host) => serializer.collect_map([("host", host)]),
+        Err(e) => serializer.collect_map([("error", e)]),
+    }
+}
+
+fn seconds<S: serde::Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(d.as_secs_f64())
+}
+
+/// Hosts being checked by `check_hosts_stream`
+struct Checking<'a, C> {
+    hosts: &'a [Target],
+    tasks: JoinSet<(Result<Host, Error>, Duration)>,
+    /// A task that panicked only leaves its id behind, to tell which host it was checking
+    ids: HashMap<tokio::task::Id, usize>,
+    pending: Vec<bool>,
+    started: Instant,
+    cutoff: Pin<Box<C>>,
+    /// Why the rest were given up on, once `cutoff` resolved
+    stopped: Option<io::Error>,
+}
+
+impl<C: Future<Output = io::Error>> Checking<'_, C> {
+    async fn next_report(&mut self) -> Option<HostReport> {
+        if self.stopped.is_none() {
+            let joined = tokio::select! {
+                joined = self.tasks.join_next_with_id() => joined,
+                reason = &mut self.cutoff => {
+                    self.tasks.abort_all();
+                    self.stopped = Some(reason);
+                    None
+                }
+            };
+            if let Some(joined) = joined {
+                let (i, res, elapsed) = match joined {
+                    Ok((id, (res, elapsed))) => (self.ids[&id], res, elapsed),
+                    Err(e) => (self.ids[&e.id()], Err(e.into()), self.started.elapsed()),
+                };
+                return Some(self.report(i, res, elapsed));
+            }
+        }
+
+        // Whatever is still running at the cutoff is abandoned and reported with its reason
+        let reason = self.stopped.as_ref()?;
+        let i = self.pending.iter().position(|p| *p)?;
+        let e = Error::Cutoff(io::Error::new(reason.kind(), reason.to_string()));
+        Some(self.report(i, Err(e), self.started.elapsed()))
+    }
+
+    pub fn report(&mut self, i: usize, res: Result<Host, Error>, elapsed: Duration) -> HostReport {
+        self.pending[i] = false;
+        let name = self.hosts[i].name.clone();
+        HostReport {
+            result: res.map_err(|e| CheckError::new(&name, e)),
+            name,
+            elapsed,
+        }
+    }
+}
+
+/// Checks every host concurrently with `probe`, yielding each as it completes, until `cutoff`
+/// resolves with the reason to give up on the rest, which are then yielded with it. Byte lag
+/// needs the primary's position and is left out
+pub fn check_hosts_stream<'a>(
+    probe: &impl HostProbe,
+    hosts: &'a [Target],
+    concurrency: usize,
+    cutoff: impl Future<Output = io::Error> + 'a,
+) -> impl Stream<Item = HostReport> + 'a {
+    probe.sweep_started(hosts);
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let mut ids = HashMap::new();
+    for (i, host) in hosts.iter().cloned().enumerate() {
+        let probe = probe.clone();
+        let semaphore = semaphore.clone();
+        let task = tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            let started = Instant::now();
+            let res = probe.probe(host).await;
+            (res, started.elapsed())
+        });
+        ids.insert(task.id(), i);
+    }
+
+    let checking = Checking {
+        hosts,
+        tasks,
+        ids,
+        pending: vec![true; hosts.len()],
+        started: Instant::now(),
+        cutoff: Box::pin(cutoff),
+        stopped: None,
+    };
+    stream::unfold(checking, |mut checking| async move {
+        let report = checking.next_report().await?;
+        Some((report, checking))
+    })
+}
+
+internal! {
+    /// Groups hosts by system identifier, leaving out those it wasn't checked on
+    pub fn find_clusters(res: &[Host]) -> Vec<Cluster> {
+        let mut clusters: Vec<(i64, Vec<&Host>)> = Vec::new();
+        for r in res {
+            let Some(id) = r.system_identifier else {
+                continue;
+            };
+            match clusters.iter_mut().find(|(c, _)| *c == id) {
+                Some((_, hosts)) => hosts.push(r),
+                None => clusters.push((id, vec![r])),
+            }
+        }
+
+        clusters
+            .into_iter()
+            .map(|(system_identifier, hosts)| {
+                let primaries = hosts.iter().filter(|r| r.is_primary).count();
+                let verdict = if primaries > 1 {
+                    Verdict::SplitBrain
+                } else if expected_timeline(&hosts)
+                    .is_some_and(|t| hosts.iter().any(|r| r.timeline_id.is_some_and(|h| h != t)))
+                {
+                    Verdict::Diverged
+                } else if primaries == 0 {
+                    Verdict::NoPrimary
+                } else {
+                    Verdict::Healthy
+                };
+                let mut files: Vec<&str> = Vec::new();
+                for r in &hosts {
+                    match r.tags.get(hosts::HOSTS_FILE_TAG) {
+                        Some(file) if !files.contains(&file.as_str()) => files.push(file),
+                        _ => {}
+                    }
+                }
+                Cluster {
+                    system_identifier,
+                    name: (!files.is_empty()).then(|| files.join("+")),
+                    verdict,
+                    health: Health::Ok,
+                    hosts: hosts.iter().map(|r| r.name.clone()).collect(),
+                    lag: lag::LagStats::of(hosts.iter().copied()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// CRITICAL when the cluster can't be trusted with writes, DEGRADED when it's running with less
+/// redundancy or freshness than it should: a standby that isn't streaming, fewer streaming than
+/// `expected_replicas` or one behind `health`'s lag thresholds
+fn cluster_health(
+    verdict: Verdict,
+    hosts: &[&Host],
+    health: &HealthArgs,
+    expected_replicas: Option<&usize>,
+) -> Health {
+    let waiting = hosts
+        .iter()
+        .any(|r| r.sync.as_ref().is_some_and(|s| s.missing().is_some()));
+    if verdict != Verdict::Healthy || waiting {
+        return Health::Critical;
+    }
+    let standbys: Vec<&&Host> = hosts.iter().filter(|r| !r.is_primary).collect();
+    let detached = standbys
+        .iter()
+        .any(|r| r.streaming == Some(false) && r.wal_source != Some(WalSource::Archive));
+    let streaming = standbys
+        .iter()
+        .filter(|r| r.streaming == Some(true))
+        .count();
+    let behind = standbys.iter().any(|r| {
+        health
+            .max_lag_bytes
+            .zip(r.lag_bytes)
+            .is_some_and(|(max, lag)| lag > max)
+            || health
+                .max_lag_seconds
+                .zip(r.lag_seconds)
+                .is_some_and(|(max, lag)| lag > max)
+    });
+    if detached || behind || expected_replicas.is_some_and(|n| streaming != *n) {
+        Health::Degraded
+    } else {
+        Health::Ok
+    }
+}
+
+internal! {
+    /// The timeline most of a cluster's hosts are on. On a tie the primary's wins, then the
+    /// newest. `None` when no host's timeline was checked
+    pub fn expected_timeline(hosts: &[&Host]) -> Option<u32> {
+        let mut counts: BTreeMap<u32, (usize, bool)> = BTreeMap::new();
+        for r in hosts {
+            let Some(timeline) = r.timeline_id else {
+                continue;
+            };
+            let e = counts.entry(timeline).or_default();
+            e.0 += 1;
+            e.1 |= r.is_primary;
+        }
+        counts
+            .into_iter()
+            .max_by_key(|&(timeline, count)| (count, timeline))
+            .map(|(timeline, _)| timeline)
+    }
+}
+
+internal! {
+    /// Describes every threshold from the command line that the results exceed
+    pub fn check_thresholds(health: &HealthArgs, res: &[Host], host_count: usize) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for r in res {
+            if let (Some(max), Some(lag)) = (health.max_lag_bytes, r.lag_bytes) {
+                if lag > max {
+                    problems.push(format!("{} is {} bytes behind (max {})", r.name, lag, max));
+                }
+            }
+            if let (Some(max), Some(lag)) = (health.max_lag_seconds, r.lag_seconds) {
+                if lag > max {
+                    problems.push(format!("{} is {:.1}s behind (max {})", r.name, lag, max));
+                }
+            }
+        }
+
+        // Every host that isn't a primary should be a streaming standby, unreachable ones included
+        if let Some(max) = health.max_missing_replicas {
+            let primaries = res.iter().filter(|r| r.is_primary).count();
+            let streaming = res
+                .iter()
+                .filter(|r| !r.is_primary && r.streaming == Some(true))
+                .count();
+            let missing = host_count.saturating_sub(primaries + streaming);
+            if missing > max {
+                problems.push(format!("{} replicas missing (max {})", missing, max));
+            }
+        }
+
+        problems
+    }
+}
+
+internal! {
+    /// How many streaming standbys `--expect-replicas` wants in each cluster, by system identifier,
+    /// and the HOST=N ones whose cluster couldn't be checked
+    pub fn expected_replicas(
+        health: &HealthArgs,
+        res: &[Host],
+    ) -> (BTreeMap<i64, usize>, Vec<String>) {
+        let is = |r: &Host, name: &str| r.name == name || r.aliases.iter().any(|a| a == name);
+        let mut unchecked = Vec::new();
+
+        // A plain count applies to every cluster, HOST=N then overrides it for one
+        let mut expected_replicas: BTreeMap<i64, usize> = BTreeMap::new();
+        for (_, count) in health.expect_replicas.iter().filter(|(c, _)| c.is_none()) {
+            for id in res.iter().filter_map(|r| r.system_identifier) {
+                expected_replicas.insert(id, *count);
+            }
+        }
+        for (cluster, count) in &health.expect_replicas {
+            let Some(key) = cluster else { continue };
+            let id = match res.iter().find(|r| is(r, key)) {
+                Some(r) => r.system_identifier,
+                None => key
+                    .parse()
+                    .ok()
+                    .filter(|id| res.iter().any(|r| r.system_identifier == Some(*id))),
+            };
+            match id {
+                Some(id) => {
+                    expected_replicas.insert(id, *count);
+                }
+                None => unchecked.push(format!(
+                    "{} standbys expected in the cluster of {}, but it couldn't be checked",
+                    count, key
+                )),
+            }
+        }
+        (expected_replicas, unchecked)
+    }
+}
+
+/// Connects to a single host, or reuses its pool from an earlier sweep, and runs every check
+async fn connect_and_check(
+    conn: CheckOptions,
+    pools: &Pools,
+    target: Target,
+) -> Result<Host, Error> {
+    at_first_answering(&target, |addr| check_at(conn.clone(), pools, &target, addr)).await
+}
+
+internal! {
+    /// Tries `attempt` at each of the target's addresses in turn, moving on while they refuse or
+    /// time out the connection, as libpq does with several hosts. Once one has been reached, its
+    /// outcome is the target's
+    pub async fn at_first_answering<'a, T, F, Fut>(
+        target: &'a Target,
+        mut attempt: F,
+    ) -> Result<T, Error>
+    where
+        F: FnMut(&'a str) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let addrs = target.addresses();
+        let (last, others) = addrs.split_last().expect("a target has an address");
+        for addr in others {
+            match attempt(addr).await {
+                Err(e) if e.unanswered() => continue,
+                done => return done,
+            }
+        }
+        attempt(last).await
+    }
+}
+
+/// Checks `target` at `addr`, one of its addresses
+async fn check_at(
+    mut conn: CheckOptions,
+    pools: &Pools,
+    target: &Target,
+    addr: &str,
+) -> Result<Host, Error> {
+    let (addr, port) = route(&mut conn, target, addr).await?;
+
+    if let Some(login) = &conn.replication {
+        let timed = conn.timings.is_some();
+        conn.pace().await;
+        let mut host = replication::check_host(login, &addr, target.name.clone(), timed).await?;
+        host.aliases = target.aliases.clone();
+        host.tags = target.options.tags.clone();
+        host.encryption = host.encryption.filter(|_| conn.encryption);
+        host.tls = probe_tls(&conn, &addr, port, &target.name).await;
+        return Ok(host);
+    }
+
+    // A cached pool whose connections broke since the last sweep is replaced with a fresh one
+    let cached = match pools.get(&target.name) {
+        Some((pool, pooler)) if pool.acquire().await.is_ok() => Some((pool, pooler)),
+        Some(_) => {
+            pools.remove(&target.name);
+            None
+        }
+        None => None,
+    };
+
+    let (mut host, pooler) = match cached {
+        // Connections can drop mid-check, e.g. when the server restarts, don't reuse them next time
+        Some((pool, pooler)) => match check_host(&pool, target.name.clone(), &conn).await {
+            Ok(host) => (host, pooler),
+            Err(e) => {
+                pools.remove(&target.name);
+                return Err(e);
+            }
+        },
+        None => {
+            if let Some(name) = &conn.verify_name {
+                conn.pace().await;
+                tls::verify_name(&addr, port, name, conn.client_cert.as_ref()).await?;
+            }
+            conn.pace().await;
+            let (pool, host, pooler) = connect_fresh(&conn, &addr, &target.name).await?;
+            pools.insert(&target.name, pool, pooler.clone());
+            (host, pooler)
+        }
+    };
+    host.aliases = target.aliases.clone();
+    host.tags = target.options.tags.clone();
+    host.pooler = pooler;
+    host.tls = probe_tls(&conn, &addr, port, &target.name).await;
+    if let (Some(phases), Some(login)) = (&mut host.phases, &conn.timings) {
+        // A failed login leaves the connection phases at zero, the checks got through already
+        conn.pace().await;
+        if let Ok(timed) = replication::time_login(login, &addr, port, false).await {
+            *phases = phases::Phases {
+                query: phases.query,
+                ..timed
+            };
+        }
+    }
+    Ok(host)
+}
+
+internal! {
+    /// The address and port to reach `target` at `addr`, one of its addresses, through the tunnel
+    /// if there is one, with the settings its hosts file line overrides applied to `conn`
+    pub async fn route(
+        conn: &mut CheckOptions,
+        target: &Target,
+        addr: &str,
+    ) -> io::Result<(String, u16)> {
+        let mut addr = addr.to_string();
+        let options = &target.options;
+        let mut port = options.port.unwrap_or(PORT);
+        if let Some(tunnel) = &conn.tunnel {
+            let local = tunnel.forward(&addr, port).await?;
+            (addr, port) = (local.ip().to_string(), local.port());
+        }
+        conn.pg = conn.pg.clone().port(port);
+        if let Some(user) = &options.user {
+            conn.pg = conn.pg.clone().username(user);
+        }
+        if let Some(dbname) = &options.dbname {
+            conn.pg = conn.pg.clone().database(dbname);
+        }
+        // As in libpq, a root certificate verifies the chain unless the line says otherwise
+        let sslmode = options
+            .sslmode
+            .or(match (&options.sslrootcert, &options.client_cert) {
+                (Some(_), _) => Some(PgSslMode::VerifyCa),
+                (None, Some(_)) => Some(PgSslMode::Require),
+                (None, None) => None,
+            });
+        if let Some(sslmode) = sslmode {
+            conn.pg = conn.pg.clone().ssl_mode(sslmode);
+        }
+        // sqlx checks the certificate is for the address it connects to, like libpq it has to be
+        // for the host name instead
+        if matches!(sslmode.unwrap_or(conn.ssl_mode), PgSslMode::VerifyFull)
+            && addr != target.name
+        {
+            conn.pg = conn.pg.clone().ssl_mode(PgSslMode::VerifyCa);
+            conn.verify_name = Some(target.name.clone());
+        }
+        // sqlx reads them when connecting and doesn't say which one it couldn't
+        let files = options.sslrootcert.iter().chain(
+            options
+                .client_cert
+                .iter()
+                .flat_map(|(cert, key)| [cert, key]),
+        );
+        for file in files {
+            fs::metadata(file)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+        }
+        if let Some(root_cert) = &options.sslrootcert {
+            conn.pg = conn.pg.clone().ssl_root_cert(root_cert);
+        }
+        if let Some((cert, key)) = &options.client_cert {
+            conn.pg = conn.pg.clone().ssl_client_cert(cert).ssl_client_key(key);
+            conn.client_cert = options.client_cert.clone();
+        }
+        conn.connect_timeout = options.connect_timeout.or(conn.connect_timeout);
+
+        let verifies = matches!(sslmode, Some(PgSslMode::VerifyCa | PgSslMode::VerifyFull));
+        let roots = match &options.sslrootcert {
+            Some(path) if verifies && (conn.replication.is_some() || conn.timings.is_some()) => {
+                Some(Arc::new(tls::read_roots(path)?))
+            }
+            _ => None,
+        };
+
+        for login in [&mut conn.replication, &mut conn.timings]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(user) = &options.user {
+                login.user = user.clone();
+            }
+            if let Some(dbname) = &options.dbname {
+                login.database = dbname.clone();
+            }
+            login.port = port;
+            login.server_name = Some(target.name.clone());
+            if let Some(mode) = sslmode {
+                login.require_tls = !matches!(
+                    mode,
+                    PgSslMode::Disable | PgSslMode::Allow | PgSslMode::Prefer
+                );
+                // Like sqlx, only the verify modes look at the certificate
+                let hostname = matches!(mode, PgSslMode::VerifyFull);
+                login.verify = match &roots {
+                    Some(roots) => Some(tls::Verify {
+                        roots: roots.clone(),
+                        hostname,
+                    }),
+                    None => login
+                        .verify
+                        .take()
+                        .filter(|_| verifies)
+                        .map(|v| tls::Verify { hostname, ..v }),
+                };
+            }
+            if options.client_cert.is_some() {
+                login.client_cert = options.client_cert.clone();
+            }
+            login.connect_timeout = options.connect_timeout.or(login.connect_timeout);
+        }
+        Ok((addr, port))
+    }
+}
+
+/// The host's TLS certificate when `conn` asks for it. A failed handshake leaves it out, the
+/// checks that just ran have already shown whether the host can be reached
+async fn probe_tls(
+    conn: &CheckOptions,
+    addr: &str,
+    port: u16,
+    name: &str,
+) -> Option<tls::ServerTls> {
+    if !conn.probe_tls {
+        return None;
+    }
+    conn.pace().await;
+    tls::probe(
+        addr,
+        port,
+        name,
+        conn.client_cert.as_ref(),
+        conn.connect_timeout,
+    )
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Connects to `addr` and checks it, or the server behind it when it's a PgBouncer and
+/// `--follow-poolers` is given
+async fn connect_fresh(
+    conn: &CheckOptions,
+    addr: &str,
+    name: &str,
+) -> Result<(PgPool, Host, Option<String>), Error> {
+    let e = match open_pool(conn, &conn.pg, addr).await {
+        Ok(pool) => match check_host(&pool, name.to_string(), conn).await {
+            Ok(host) => return Ok((pool, host, None)),
+            Err(e) => e,
+        },
+        Err(e) => e.into(),
+    };
+
+    // PgBouncer answers like a server but fails the checks confusingly, e.g. by rejecting
+    // startup parameters or prepared statements, so only a host that answered could be one
+    if !matches!(
+        e,
+        Error::Database(sqlx::Error::Database(_) | sqlx::Error::Protocol(_))
+    ) {
+        return Err(e);
+    }
+    let Some(version) = pooler::detect(&conn.pg, addr).await else {
+        return Err(e);
+    };
+    if !conn.follow_poolers {
+        return Err(Error::Pooler(format!(
+            "{} is a pooler ({}), pass --follow-poolers to check the server behind it",
+            addr, version
+        )));
+    }
+    let database = conn.pg.get_database().unwrap_or("postgres");
+    let Some((server, port)) = pooler::backing_server(&conn.pg, addr, database).await else {
+        return Err(Error::Pooler(format!(
+            "{} is a pooler ({}) without a server for {}",
+            addr, version, database
+        )));
+    };
+
+    let pool = open_pool(conn, &conn.pg.clone().port(port), &server).await?;
+    let host = check_host(&pool, name.to_string(), conn).await?;
+    let pooler = format!("{} in front of {}:{}", version, server, port);
+    Ok((pool, host, Some(pooler)))
+}
+
+async fn open_pool(
+    conn: &CheckOptions,
+    pg: &PgConnectOptions,
+    addr: &str,
+) -> Result<PgPool, sqlx::Error> {
+    match connect_pool(conn, pg, addr).await {
+        Err(sqlx::Error::PoolTimedOut) => Err(why_timed_out(pg, addr).await),
+        res => res,
+    }
+}
+
+/// The pool takes a refused connection for a server starting up and retries it until it times
+/// out. One more attempt of its own tells a port nothing listens on from a host that doesn't
+/// answer, for the failure's reason
+async fn why_timed_out(pg: &PgConnectOptions, addr: &str) -> sqlx::Error {
+    let pg = pg.clone().host(addr);
+    match time::timeout(Duration::from_secs(1), PgConnection::connect_with(&pg)).await {
+        Ok(Err(e @ sqlx::Error::Io(_))) => e,
+        Ok(Ok(db)) => {
+            let _ = db.close().await;
+            sqlx::Error::PoolTimedOut
+        }
+        _ => sqlx::Error::PoolTimedOut,
+    }
+}
+
+async fn connect_pool(
+    conn: &CheckOptions,
+    pg: &PgConnectOptions,
+    addr: &str,
+) -> Result<PgPool, sqlx::Error> {
+    let mut options = PgPoolOptions::new().max_connections(4);
+    if let Some(timeout) = conn.connect_timeout {
+        options = options.acquire_timeout(timeout);
+    }
+    let Some(role) = conn.set_role.clone() else {
+        return options.connect_with(pg.clone().host(addr)).await;
+    };
+    // The pool retries connections whose after_connect fails until it times out, which would
+    // hide why. The first one, opened before connect_with returns, hands its error back instead
+    let refused = Arc::new(Mutex::new(None));
+    let first = Arc::new(AtomicBool::new(true));
+    let keep = refused.clone();
+    options = options.after_connect(move |db, _| {
+        let (role, keep) = (role.clone(), keep.clone());
+        let first = first.swap(false, Ordering::Relaxed);
+        Box::pin(async move {
+            match set_role(db, &role).await {
+                Err(e) if first => {
+                    *keep.lock().unwrap() = Some(e);
+                    Ok(())
+                }
+                res => res,
+            }
+        })
+    });
+    let pool = options.connect_with(pg.clone().host(addr)).await?;
+    let refused = refused.lock().unwrap().take();
+    match refused {
+        Some(e) => {
+            pool.close().await;
+            Err(e)
+        }
+        None => Ok(pool),
+    }
+}
+
+internal! {
+    /// `SET ROLE`, with the role quoted so its case and any odd characters are kept
+    pub async fn set_role(db: &mut PgConnection, role: &str) -> Result<(), sqlx::Error> {
+        let statement = format!("SET ROLE \"{}\"", role.replace('"', "\"\""));
+        db.execute(statement.as_str()).await?;
+        Ok(())
+    }
+}
+
+async fn check_host(pool: &PgPool, name: String, conn: &CheckOptions) -> Result<Host, Error> {
+    let started = Instant::now();
+    let is_primary = sqlx::query("SELECT pg_is_in_recovery();")
+        .map(|r: PgRow| {
+            let b: bool = r.get("pg_is_in_recovery");
+            !b
+        })
+        .fetch_one(pool);
+
+    let version = server_version(pool).await?;
+    // Checks the server is too old for are skipped and noted, not failed
+    let mut unsupported = BTreeMap::new();
+    let mut since = |on: bool, check: &str, needs: Version| {
+        if on && version < needs {
+            unsupported.insert(check.to_string(), format!("not supported on {}", version));
+        }
+        on && version >= needs
+    };
+    let timeline_on = since(
+        conn.builtins.contains(&Builtin::Timeline),
+        "timeline",
+        Version::PG9_6,
+    );
+    let history_on = since(
+        conn.builtins.contains(&Builtin::History),
+        "history",
+        Version::PG9_6,
+    );
+    let system_identifier_on = since(
+        conn.builtins.contains(&Builtin::SystemIdentifier),
+        "system-identifier",
+        Version::PG9_6,
+    );
+    let wal_receiver_on = since(
+        conn.builtins.contains(&Builtin::WalReceiver),
+        "wal-receiver",
+        Version::PG9_6,
+    );
+    let checksum_failures_on = since(
+        conn.builtins.contains(&Builtin::ChecksumFailures),
+        "checksum-failures",
+        Version::PG12,
+    );
+    let slots_on = since(
+        conn.builtins.contains(&Builtin::Slots),
+        "slots",
+        Version::PG10,
+    );
+    let encryption_on = since(conn.encryption, "tls", Version::PG9_5);
+    let extended_on = since(conn.extended, "extended", Version::PG12);
+    let replica_lag_on = since(conn.extended, "replica-lag", Version::PG10);
+    let logical_on = since(conn.logical, "logical", Version::PG10);
+    let on = |b| conn.builtins.contains(&b);
+
+    let timeline_id = when(timeline_on, async {
+        let checkpoint = sqlx::query("SELECT timeline_id from pg_control_checkpoint();")
+            .map(|r: PgRow| Some(timeline::from_int4(r.get("timeline_id"))))
+            .fetch_one(pool)
+            .await;
+        match checkpoint {
+            Err(e) if permission_denied(&e) => {
+                timeline_without_control_functions(pool, version).await
+            }
+            res => res,
+        }
+    });
+
+    let replica_attached = when(
+        on(Builtin::Replicas),
+        sqlx::query("SELECT EXISTS (select 1 from pg_stat_replication);")
+            .map(|r: PgRow| {
+                let b: bool = r.get("exists");
+                b
+            })
+            .fetch_one(pool),
+    );
+
+    let data_checksums = when(
+        on(Builtin::Checksums),
+        sqlx::query("SHOW data_checksums;")
+            .map(|r: PgRow| {
+                let s: String = r.get("data_checksums");
+                s == "on"
+            })
+            .fetch_one(pool),
+    );
+
+    // Both are NULL with checksums turned off
+    let checksum_failures = when(
+        checksum_failures_on,
+        sqlx::query(
+            "SELECT sum(checksum_failures)::int8 AS failures,
+                extract(epoch FROM max(checksum_last_failure))::float8 AS last_failure
+            FROM pg_stat_database;",
+        )
+        .map(|r: PgRow| {
+            let failures: Option<i64> = r.get("failures");
+            let last: Option<f64> = r.get("last_failure");
+            (failures, last.and_then(from_epoch))
+        })
+        .fetch_one(pool),
+    );
+
+    let system_identifier = when(
+        system_identifier_on,
+        sqlx::query("SELECT system_identifier FROM pg_control_system();")
+            .map(|r: PgRow| {
+                let i: i64 = r.get("system_identifier");
+                i
+            })
+            .fetch_one(pool),
+    );
+
+    // The history file for the current timeline is written at promotion, but a standby writes
+    // its own copy whenever it gets to the timeline, so only a primary's dates it. Without a
+    // grant on pg_stat_file() (superuser before 11) the end-of-recovery checkpoint stands in
+    // until the next checkpoint, and the age is unknown after that or on timeline 1
+    let stat_file = if version >= Version::PG11 {
+        "has_function_privilege('pg_stat_file(text, boolean)', 'EXECUTE')"
+    } else {
+        "current_setting('is_superuser')::bool"
+    };
+    let promoted_query = format!(
+        "SELECT pg_is_in_recovery() AS in_recovery, {} AS stat_file;",
+        stat_file
+    );
+    let timeline_age = when(timeline_on, async {
+        let (in_recovery, stat_file) = sqlx::query(&promoted_query)
+            .map(|r: PgRow| {
+                let in_recovery: bool = r.get("in_recovery");
+                let stat_file: bool = r.get("stat_file");
+                (in_recovery, stat_file)
+            })
+            .fetch_one(pool)
+            .await?;
+        if in_recovery {
+            return Ok(None);
+        }
+        let history = format!(
+            "(pg_stat_file('{}/' || lpad(upper(to_hex(timeline_id)), 8, '0') || '.history', \
+                true)).modification,",
+            version.wal("pg_wal"),
+        );
+        let age = sqlx::query(&format!(
+            "SELECT extract(epoch FROM now() - coalesce(
+                {}
+                CASE WHEN prev_timeline_id <> timeline_id THEN checkpoint_time END
+            ))::float8 AS timeline_age FROM pg_control_checkpoint();",
+            if stat_file { history.as_str() } else { "" },
+        ))
+        .map(|r: PgRow| {
+            let a: Option<f64> = r.get("timeline_age");
+            a
+        })
+        .fetch_one(pool)
+        .await;
+        match age {
+            Err(e) if permission_denied(&e) => Ok(None),
+            res => res,
+        }
+    });
+
+    // Reading files takes superuser, pg_read_server_files or a grant on pg_read_file(); the
+    // history is left unknown without them
+    let history_query = format!(
+        "SELECT timeline_id, pg_read_file('{}/' || lpad(upper(to_hex(timeline_id)), 8, '0')
+            || '.history', 0, 1048576, true) AS history FROM pg_control_checkpoint();",
+        version.wal("pg_wal"),
+    );
+    let timeline_history = when(history_on, async {
+        let history = sqlx::query(&history_query)
+            .map(|r: PgRow| {
+                let timeline = timeline::from_int4(r.get("timeline_id"));
+                match r.get::<Option<String>, _>("history") {
+                    Some(text) => timeline::parse_history(&text),
+                    None if timeline == 1 => Some(Vec::new()),
+                    None => None,
+                }
+            })
+            .fetch_one(pool)
+            .await;
+        match history {
+            Err(e) if permission_denied(&e) => Ok(None),
+            res => res,
+        }
+    });
+
+    // What a standby received but hasn't replayed yet is behind in replay, not in the network
+    let wal_lsn_query = format!(
+        "SELECT {diff}(CASE WHEN pg_is_in_recovery() THEN {replay}()
+            ELSE {current}() END, '0/0')::int8 AS wal_lsn,
+            CASE WHEN pg_is_in_recovery() THEN {diff}({receive}(), {replay}())::int8
+            END AS replay_gap,
+            CASE WHEN pg_is_in_recovery() THEN {paused}() END AS replay_paused;",
+        diff = version.wal("pg_wal_lsn_diff"),
+        replay = version.wal("pg_last_wal_replay_lsn"),
+        current = version.wal("pg_current_wal_lsn"),
+        receive = version.wal("pg_last_wal_receive_lsn"),
+        paused = version.wal("pg_is_wal_replay_paused"),
+    );
+    let wal_lsn = when(
+        on(Builtin::WalLsn),
+        sqlx::query(&wal_lsn_query)
+            .map(|r: PgRow| {
+                let l: Option<i64> = r.get("wal_lsn");
+                let gap: Option<i64> = r.get("replay_gap");
+                let paused: Option<bool> = r.get("replay_paused");
+                (l, gap.map(|g| g.max(0)), paused)
+            })
+            .fetch_one(pool),
+    );
+
+    // Roles that may not read restore_command don't see its pg_settings row. Without
+    // pg_read_all_stats the receiver's row is there but its status NULL, which leaves streaming
+    // unknown rather than false
+    let streaming = when(
+        wal_receiver_on,
+        sqlx::query(
+            "SELECT (SELECT CASE WHEN count(*) = 0 THEN false ELSE bool_or(status = 'streaming') END
+                FROM pg_stat_wal_receiver) AS streaming,
+            (SELECT setting <> '' FROM pg_settings WHERE name = 'restore_command') AS archive;",
+        )
+        .map(|r: PgRow| {
+            let b: Option<bool> = r.get("streaming");
+            let archive: Option<bool> = r.get("archive");
+            (b, archive)
+        })
+        .fetch_one(pool),
+    );
+
+    // An idle primary leaves the last replay timestamp stale, so a standby that has replayed
+    // everything it received counts as caught up, but only while its WAL receiver streams and
+    // has heard from the upstream within wal_receiver_timeout. One cut off from its upstream
+    // has replayed all it got too, and is as far behind as its last replayed transaction
+    let caught_up = if version >= Version::PG9_6 {
+        format!(
+            "WHEN {}() = {}() AND EXISTS (SELECT 1 FROM pg_stat_wal_receiver
+                WHERE status = 'streaming' AND last_msg_receipt_time
+                    > now() - current_setting('wal_receiver_timeout')::interval) THEN 0",
+            version.wal("pg_last_wal_receive_lsn"),
+            version.wal("pg_last_wal_replay_lsn"),
+        )
+    } else {
+        String::new()
+    };
+    let lag_seconds_query = format!(
+        "SELECT CASE WHEN NOT pg_is_in_recovery() THEN NULL
+            {}
+            ELSE extract(epoch FROM now() - pg_last_xact_replay_timestamp())::float8
+        END AS lag_seconds;",
+        caught_up,
+    );
+    let lag_seconds = when(
+        on(Builtin::Lag),
+        sqlx::query(&lag_seconds_query)
+            .map(|r: PgRow| {
+                let s: Option<f64> = r.get("lag_seconds");
+                s
+            })
+            .fetch_one(pool),
+    );
+
+    // Before sender_host the upstream is only in the (password-less) conninfo
+    let upstream_query = format!(
+        "SELECT {}, received_tli, status,
+            extract(epoch FROM last_msg_receipt_time)::float8 AS last_msg_receipt_time
+        FROM pg_stat_wal_receiver;",
+        if version >= Version::PG11 {
+            "sender_host"
+        } else {
+            "substring(conninfo FROM 'host=([^ ]+)') AS sender_host"
+        }
+    );
+    let upstream = when(
+        wal_receiver_on,
+        sqlx::query(&upstream_query)
+            .map(|r: PgRow| {
+                let tli: Option<i32> = r.get("received_tli");
+                let receipt: Option<f64> = r.get("last_msg_receipt_time");
+                WalReceiver {
+                    sender_host: r.get("sender_host"),
+                    received_tli: tli.map(timeline::from_int4),
+                    status: r.get("status"),
+                    last_msg_receipt_time: receipt.and_then(from_epoch),
+                }
+            })
+            .fetch_optional(pool),
+    );
+
+    let recovery = async {
+        if extended_on {
+            recovery::fetch(pool).await
+        } else {
+            Ok(None)
+        }
+    };
+
+    let replicas = async {
+        if replica_lag_on {
+            replicas::fetch(pool).await
+        } else {
+            Ok(Vec::new())
+        }
+    };
+
+    let encryption = when(
+        encryption_on,
+        sqlx::query("SELECT ssl, version, cipher FROM pg_stat_ssl WHERE pid = pg_backend_pid();")
+            .map(|r: PgRow| tls::Encryption {
+                encrypted: r.get("ssl"),
+                version: r.get("version"),
+                cipher: r.get("cipher"),
+            })
+            .fetch_one(pool),
+    );
+
+    let sync = when(on(Builtin::Sync), sync::fetch(pool));
+
+    let slots = when(slots_on, slots::fetch(pool));
+
+    let clock = when(on(Builtin::Clock), clock::measure(pool));
+
+    let logical = when(logical_on, logical::fetch(pool, version));
+
+    let custom = checks::run(pool, &conn.checks);
+
+    let (
+        is_primary,
+        timeline_id,
+        replica_attached,
+        data_checksums,
+        checksum_failures,
+        system_identifier,
+        timeline_age,
+        timeline_history,
+        wal_lsn,
+        streaming,
+        lag_seconds,
+        upstream,
+        recovery,
+        replicas,
+        encryption,
+        sync,
+        slots,
+        clock,
+        logical,
+        custom,
+    ) = join!(
+        is_primary,
+        timeline_id,
+        replica_attached,
+        data_checksums,
+        checksum_failures,
+        system_identifier,
+        timeline_age,
+        timeline_history,
+        wal_lsn,
+        streaming,
+        lag_seconds,
+        upstream,
+        recovery,
+        replicas,
+        encryption,
+        sync,
+        slots,
+        clock,
+        logical,
+        custom
+    );
+
+    let (is_primary, replica_attached) = (is_primary?, replica_attached?);
+    let receiver = upstream?.flatten();
+    let wal_lsn = wal_lsn?;
+    let streaming = streaming?;
+    let checksum_failures = checksum_failures?;
+    let (slots, slot_name) = slots?.unwrap_or_default();
+    let clock = clock?;
+    Ok(Host {
+        name,
+        is_primary,
+        role: Role::of(is_primary, replica_attached),
+        timeline_id: timeline_id?.flatten(),
+        replica_attached,
+        data_checksums: data_checksums?,
+        checksum_failures: checksum_failures.and_then(|(n, _)| n),
+        checksum_last_failure: checksum_failures.and_then(|(_, t)| t),
+        system_identifier: system_identifier?,
+        timeline_age: timeline_age?.flatten(),
+        timeline_history: timeline_history?.flatten(),
+        wal_lsn: wal_lsn.and_then(|(l, _, _)| l),
+        replay_gap_bytes: wal_lsn.and_then(|(_, gap, _)| gap),
+        replay_paused: wal_lsn.and_then(|(_, _, paused)| paused),
+        streaming: streaming.and_then(|(s, _)| s),
+        wal_source: streaming.and_then(|(s, archive)| WalSource::of(is_primary, s, archive)),
+        clock_skew_seconds: clock.map(|c| c.seconds),
+        clock_skew_error_seconds: clock.map(|c| c.error),
+        lag_bytes: None,
+        lag_seconds: lag_seconds?.flatten(),
+        upstream: receiver.as_ref().and_then(|r| r.sender_host.clone()),
+        received_tli: receiver.as_ref().and_then(|r| r.received_tli),
+        walreceiver_status: receiver.as_ref().and_then(|r| r.status.clone()),
+        last_msg_receipt_time: receiver.and_then(|r| r.last_msg_receipt_time),
+        aliases: Vec::new(),
+        tags: BTreeMap::new(),
+        pooler: None,
+        recovery: recovery?,
+        replicas: replicas?,
+        tls: None,
+        encryption: encryption?,
+        sync: sync?.flatten(),
+        logical: logical?,
+        slots,
+        slot_name,
+        phases: conn.timings.is_some().then(|| phases::Phases {
+            query: started.elapsed(),
+            ..phases::Phases::default()
+        }),
+        status: None,
+        reason: None,
+        checked_at: Some(now()),
+        server_version_num: Some(version),
+        unsupported,
+        custom: custom?,
+    })
+}
+
+/// The timeline for roles that may not call pg_control_checkpoint(): from the name of the WAL
+/// file being written on primaries, from the WAL receiver on standbys. `None` on a standby
+/// that isn't receiving, or when the role can't see the receiver's details either.
+async fn timeline_without_control_functions(
+    pool: &PgPool,
+    version: Version,
+) -> Result<Option<u32>, sqlx::Error> {
+    let query = format!(
+        "SELECT CASE WHEN NOT pg_is_in_recovery() THEN substr({}({}()), 1, 8) END AS walfile,
+            (SELECT received_tli FROM pg_stat_wal_receiver) AS received_tli;",
+        version.wal("pg_walfile_name"),
+        version.wal("pg_current_wal_lsn"),
+    );
+    let row = sqlx::query(&query).fetch_one(pool).await?;
+    let walfile: Option<String> = row.get("walfile");
+    let received: Option<i32> = row.get("received_tli");
+    Ok(match walfile {
+        Some(name) => timeline::from_hex(&name),
+        None => received.map(timeline::from_int4),
+    })
+}
+
+/// Whether the role lacks a privilege the query needs
+fn permission_denied(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|code| code == "42501")
+}
+
+internal! {
+    /// The server's `server_version_num`
+    pub async fn server_version<'c>(
+        db: impl Executor<'c, Database = Postgres>,
+    ) -> Result<Version, sqlx::Error> {
+        let num: String = sqlx::query_scalar("SHOW server_version_num;")
+            .fetch_one(db)
+            .await?;
+        num.trim()
+            .parse()
+            .map(Version)
+            .map_err(|e| sqlx::Error::Protocol(format!("server_version_num {}: {}", num, e)))
+    }
+}
+
+/// Runs one of the built-in check queries, or skips it when the check is turned off
+async fn when<T>(
+    on: bool,
+    query: impl Future<Output = Result<T, sqlx::Error>>,
+) -> Result<Option<T>, sqlx::Error> {
+    if on {
+        query.await.map(Some)
+    } else {
+        Ok(None)
+    }
+}
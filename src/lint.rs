@@ -0,0 +1,338 @@
+use std::{
+    collections::BTreeSet,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use tokio::{net::lookup_host, task::JoinSet};
+
+use crate::hosts::{self, Entry, HostOptions};
+
+/// An entry with the file it's listed in
+type Listed<'a> = (&'a Path, Entry);
+
+/// Reads each hosts file the way a run would and prints, one line each, what would fail or be
+/// ignored without a word: syntax errors, names and addresses that aren't host names, hosts
+/// listed twice, names and addresses that don't resolve, certificate files that can't be read
+/// and overrides lost to another entry for the same server. Exits with 1 when it found any, for CI.
+pub async fn run(paths: &[PathBuf]) -> ExitCode {
+    let mut clean = true;
+    let mut listed: Vec<Listed> = Vec::new();
+    for path in paths {
+        match lint(path).await {
+            Some(entries) => listed.extend(entries.into_iter().map(|e| (path.as_path(), e))),
+            None => clean = false,
+        }
+    }
+
+    // A run reads all files as one list, so a host in two of them is merged like one listed
+    // twice in the same file
+    let source: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    let source = source.join(", ");
+    let mut problems = duplicates(&listed);
+    problems.extend(resolution(&listed).await);
+    for p in &problems {
+        println!("{}: {}", source, p);
+    }
+    if clean && problems.is_empty() {
+        println!("{}: {} hosts, no problems", source, listed.len());
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Reads and lints one file on its own, its entries when it has no problems of its own
+async fn lint(path: &Path) -> Option<Vec<Entry>> {
+    let entries = match hosts::read_hosts_file(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            println!("{}: {}", path.display(), e);
+            return None;
+        }
+        Err(e) => {
+            eprintln!("Error reading {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    let mut problems: Vec<String> = entries
+        .iter()
+        .flat_map(|(name, addrs, _)| std::iter::once(name).chain(addrs))
+        .filter_map(|name| malformed(name))
+        .collect();
+    problems.extend(addresses(&entries).await);
+    problems.extend(certificates(&entries));
+
+    for p in &problems {
+        println!("{}: {}", path.display(), p);
+    }
+    problems.is_empty().then_some(entries)
+}
+
+/// Describes a name that can't be connected to, like a connection URI or `host:port`
+fn malformed(name: &str) -> Option<String> {
+    if name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    if name.contains("://") {
+        return Some(format!(
+            "{} looks like a connection URI, list the host name with key=value settings \
+             after it instead, e.g. db1 port=5433 dbname=app",
+            name
+        ));
+    }
+    if let Some((_, port)) = name.rsplit_once(':') {
+        if port.parse::<u16>().is_ok() {
+            return Some(format!(
+                "{} isn't a host name, give the port as port={}",
+                name, port
+            ));
+        }
+    }
+    let label_ok = |l: &str| {
+        (1..=63).contains(&l.len())
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+            && l.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+    let name_ok = name.len() <= 253 && name.trim_end_matches('.').split('.').all(label_ok);
+    (!name_ok).then(|| format!("{} isn't a valid host name", name))
+}
+
+/// A host's settings as they'd be written after its name
+fn written(options: &HostOptions) -> String {
+    let pairs = options.pairs();
+    if pairs.is_empty() {
+        return "none".to_string();
+    }
+    let pairs: Vec<String> = pairs.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    pairs.join(" ")
+}
+
+/// ` in a.hosts and b.hosts` when the entries are spread over several files, nothing otherwise
+fn in_files(files: &[&Path]) -> String {
+    let mut distinct: Vec<String> = Vec::new();
+    for f in files {
+        let f = f.display().to_string();
+        if !distinct.contains(&f) {
+            distinct.push(f);
+        }
+    }
+    match &distinct[..] {
+        [] | [_] => String::new(),
+        [first @ .., last] => format!(" in {} and {}", first.join(", "), last),
+    }
+}
+
+/// Names listed more than once, in one file or across them, of which only the first line counts
+fn duplicates(listed: &[Listed]) -> Vec<String> {
+    let mut names: Vec<(&str, Vec<(&Path, &HostOptions)>)> = Vec::new();
+    for (path, (name, _, options)) in listed {
+        match names.iter_mut().find(|(n, _)| n == name) {
+            Some((_, all)) => all.push((path, options)),
+            None => names.push((name, vec![(path, options)])),
+        }
+    }
+    names
+        .into_iter()
+        .filter(|(_, all)| all.len() > 1)
+        .map(|(name, all)| {
+            let files: Vec<&Path> = all.iter().map(|(f, _)| *f).collect();
+            if all.iter().all(|(_, o)| o.pairs() == all[0].1.pairs()) {
+                format!(
+                    "{} is listed {} times{}, it's checked once",
+                    name,
+                    all.len(),
+                    in_files(&files)
+                )
+            } else {
+                format!(
+                    "{} is listed {} times{} with different settings, only the first line's \
+                     ({}) are used",
+                    name,
+                    all.len(),
+                    in_files(&files),
+                    written(all[0].1)
+                )
+            }
+        })
+        .collect()
+}
+
+/// Names that don't resolve, and names resolving to the same server as an earlier one with
+/// other settings, which a run merges into that one and drops
+async fn resolution(listed: &[Listed<'_>]) -> Vec<String> {
+    let mut unique: Vec<&Entry> = Vec::new();
+    let mut files: Vec<&Path> = Vec::new();
+    for (path, e) in listed {
+        // Names with addresses of their own aren't looked up, see `addresses`
+        if e.1.is_empty() && malformed(&e.0).is_none() && !unique.iter().any(|u| u.0 == e.0) {
+            unique.push(e);
+            files.push(path);
+        }
+    }
+
+    let mut lookups = JoinSet::new();
+    for (i, (name, _, _)) in unique.iter().enumerate() {
+        let name = name.clone();
+        lookups.spawn(async move {
+            let addrs = lookup_host((name.as_str(), 0))
+                .await
+                .map(|addrs| addrs.map(|a| a.ip()).collect::<BTreeSet<IpAddr>>());
+            (i, addrs)
+        });
+    }
+    let mut resolved = vec![Ok(BTreeSet::new()); unique.len()];
+    while let Some(joined) = lookups.join_next().await {
+        let (i, addrs) = joined.expect("lookup task panicked");
+        resolved[i] = addrs.map_err(|e| e.to_string());
+    }
+
+    let mut problems = Vec::new();
+    for (i, (name, _, options)) in unique.iter().enumerate() {
+        let addrs = match &resolved[i] {
+            Ok(addrs) if !addrs.is_empty() => addrs,
+            Ok(_) => {
+                problems.push(format!("{} doesn't resolve to any address", name));
+                continue;
+            }
+            Err(e) => {
+                problems.push(format!("{} doesn't resolve: {}", name, e));
+                continue;
+            }
+        };
+        // The same pairing a run uses to fold names into aliases
+        let earlier = (0..i).find(|&j| {
+            resolved[j].as_ref().is_ok_and(|a| !a.is_disjoint(addrs))
+                && unique[j].2.port == options.port
+        });
+        if let Some(j) = earlier {
+            let (first, _, first_options) = unique[j];
+            if first_options.pairs() != options.pairs() {
+                problems.push(format!(
+                    "{} resolves to the same server as {}{}, so it's checked as an alias of it \
+                     and its settings ({}) are ignored",
+                    name,
+                    first,
+                    in_files(&[files[j], files[i]]),
+                    written(options)
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Addresses of `name=addr1,addr2` entries that don't resolve, and would always be skipped
+async fn addresses(entries: &[Entry]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (name, addrs, _) in entries {
+        for addr in addrs.iter().filter(|a| malformed(a).is_none()) {
+            match lookup_host((addr.as_str(), 0)).await.map(|mut a| a.next()) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    problems.push(format!("{}: {} doesn't resolve to any address", name, addr))
+                }
+                Err(e) => problems.push(format!("{}: {} doesn't resolve: {}", name, addr, e)),
+            }
+        }
+    }
+    problems
+}
+
+/// sslrootcert, sslcert and sslkey files that can't be read, each once
+fn certificates(entries: &[Entry]) -> Vec<String> {
+    let mut seen = BTreeSet::new();
+    let mut problems = Vec::new();
+    for (name, _, options) in entries {
+        let files = options.sslrootcert.iter().chain(
+            options
+                .client_cert
+                .iter()
+                .flat_map(|(cert, key)| [cert, key]),
+        );
+        for file in files.filter(|f| seen.insert(f.to_path_buf())) {
+            if let Err(e) = std::fs::File::open(file) {
+                problems.push(format!("{}: can't read {}: {}", name, file.display(), e));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line: &str) -> Entry {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap().to_string();
+        (name, Vec::new(), HostOptions::parse(words).unwrap())
+    }
+
+    #[test]
+    fn names_that_cant_be_connected_to_are_malformed() {
+        for (name, problem) in [
+            (
+                "postgres://db1:5432/app",
+                Some(
+                    "postgres://db1:5432/app looks like a connection URI, list the host name \
+                     with key=value settings after it instead, e.g. db1 port=5433 dbname=app",
+                ),
+            ),
+            (
+                "db1:5433",
+                Some("db1:5433 isn't a host name, give the port as port=5433"),
+            ),
+            ("-db1", Some("-db1 isn't a valid host name")),
+            (
+                "db1-.example.com",
+                Some("db1-.example.com isn't a valid host name"),
+            ),
+            (
+                "db1..example.com",
+                Some("db1..example.com isn't a valid host name"),
+            ),
+            ("db 1", Some("db 1 isn't a valid host name")),
+            ("db1.example.com", None),
+            ("db1.example.com.", None),
+            ("db_1", None),
+            ("10.0.0.1", None),
+            ("::1", None),
+        ] {
+            assert_eq!(malformed(name).as_deref(), problem, "{}", name);
+        }
+        let label = "a".repeat(64);
+        assert_eq!(
+            malformed(&label),
+            Some(format!("{} isn't a valid host name", label))
+        );
+    }
+
+    #[test]
+    fn names_listed_twice_are_reported_with_the_settings_that_count() {
+        let (fra, ams) = (Path::new("fra.hosts"), Path::new("ams.hosts"));
+        let listed = vec![
+            (fra, entry("db1 port=5433")),
+            (fra, entry("db1 port=5433")),
+            (fra, entry("db2 port=5433 tag.dc=fra")),
+            (fra, entry("db2 port=6432")),
+            (fra, entry("db3")),
+            (ams, entry("db3")),
+            (fra, entry("db4")),
+        ];
+        assert_eq!(
+            duplicates(&listed),
+            vec![
+                "db1 is listed 2 times, it's checked once",
+                "db2 is listed 2 times with different settings, only the first line's \
+                 (port=5433 tag.dc=fra) are used",
+                "db3 is listed 2 times in fra.hosts and ams.hosts, it's checked once",
+            ]
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+use crate::{version::Version, Host};
+
+/// The host's side of logical replication: what it subscribes to, what it publishes and the
+/// slots its subscribers stream through
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Logical {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscriptions: Vec<Subscription>,
+    /// The publications of the database the checks log in to, publications are per database
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub publications: Vec<Publication>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slots: Vec<LogicalSlot>,
+}
+
+/// A subscription, from pg_subscription and its workers in pg_stat_subscription
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Subscription {
+    pub name: String,
+    /// The database it applies changes to
+    pub database: Option<String>,
+    pub enabled: bool,
+    pub publications: Vec<String>,
+    /// Whether its apply worker runs, it exits and restarts while it can't connect or apply
+    pub running: bool,
+    /// Tables still being copied or caught up by their own sync workers
+    pub syncing_tables: i64,
+    /// Seconds since the apply worker last told the publisher where it got to, which it keeps
+    /// doing while the publisher is idle
+    pub lag_seconds: Option<f64>,
+    /// Apply and table sync errors since the statistics were reset, from 15 on
+    pub errors: Option<i64>,
+}
+
+/// A publication and how much it covers
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Publication {
+    pub name: String,
+    /// Published with `FOR ALL TABLES`
+    pub all_tables: bool,
+    /// Tables it publishes
+    pub tables: i64,
+}
+
+/// A logical replication slot on the host, one per subscription streaming from it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogicalSlot {
+    pub slot_name: String,
+    pub database: Option<String>,
+    /// Whether its subscriber is connected right now
+    pub active: bool,
+    /// Bytes of WAL written since the subscriber last confirmed what it applied
+    pub lag_bytes: Option<i64>,
+    /// `lost` once WAL the subscriber still needed was removed, from 13 on
+    pub wal_status: Option<String>,
+}
+
+/// Subscriptions on primaries, standbys only have a copy of the catalog with no workers behind
+/// it, along with the publications and logical slots
+pub async fn fetch(pool: &PgPool, version: Version) -> Result<Logical, sqlx::Error> {
+    let subscriptions_query = format!(
+        "SELECT s.subname, d.datname, s.subenabled, s.subpublications,
+            count(st.pid) FILTER (WHERE st.relid IS NULL) > 0 AS running,
+            count(st.pid) FILTER (WHERE st.relid IS NOT NULL)::int8 AS syncing_tables,
+            extract(epoch FROM now() - max(st.latest_end_time))::float8 AS lag_seconds,
+            {} AS errors
+        FROM pg_subscription s
+        LEFT JOIN pg_database d ON d.oid = s.subdbid
+        LEFT JOIN pg_stat_subscription st ON st.subid = s.oid
+        WHERE NOT pg_is_in_recovery()
+        GROUP BY s.oid, s.subname, d.datname, s.subenabled, s.subpublications
+        ORDER BY s.subname;",
+        if version >= Version::PG15 {
+            "(SELECT apply_error_count + sync_error_count FROM pg_stat_subscription_stats ss
+                WHERE ss.subid = s.oid)::int8"
+        } else {
+            "NULL::int8"
+        }
+    );
+    let subscriptions = sqlx::query(&subscriptions_query)
+        .map(|r: PgRow| Subscription {
+            name: r.get("subname"),
+            database: r.get("datname"),
+            enabled: r.get("subenabled"),
+            publications: r.get("subpublications"),
+            running: r.get("running"),
+            syncing_tables: r.get("syncing_tables"),
+            lag_seconds: r.get("lag_seconds"),
+            errors: r.get("errors"),
+        })
+        .fetch_all(pool);
+
+    let publications = sqlx::query(
+        "SELECT p.pubname, p.puballtables,
+            (SELECT count(*) FROM pg_publication_tables pt WHERE pt.pubname = p.pubname)::int8
+            AS tables
+        FROM pg_publication p
+        ORDER BY p.pubname;",
+    )
+    .map(|r: PgRow| Publication {
+        name: r.get("pubname"),
+        all_tables: r.get("puballtables"),
+        tables: r.get("tables"),
+    })
+    .fetch_all(pool);
+
+    let slots_query = format!(
+        "SELECT slot_name, database, active, {} AS wal_status,
+            pg_wal_lsn_diff(CASE WHEN pg_is_in_recovery() THEN pg_last_wal_replay_lsn()
+                ELSE pg_current_wal_lsn() END, confirmed_flush_lsn)::int8 AS lag_bytes
+        FROM pg_replication_slots
+        WHERE slot_type = 'logical'
+        ORDER BY slot_name;",
+        if version >= Version::PG13 {
+            "wal_status"
+        } else {
+            "NULL::text"
+        }
+    );
+    let slots = sqlx::query(&slots_query)
+        .map(|r: PgRow| LogicalSlot {
+            slot_name: r.get("slot_name"),
+            database: r.get("database"),
+            active: r.get("active"),
+            lag_bytes: r.get("lag_bytes"),
+            wal_status: r.get("wal_status"),
+        })
+        .fetch_all(pool);
+
+    let (subscriptions, publications, slots) = tokio::join!(subscriptions, publications, slots);
+    Ok(Logical {
+        subscriptions: subscriptions?,
+        publications: publications?,
+        slots: slots?,
+    })
+}
+
+/// Subscriptions that are disabled, whose apply worker isn't running or that haven't reported
+/// their position for `lag_warning` seconds, slots with no subscriber or that lost WAL, and
+/// publications without a table
+pub fn find_warnings(res: &[Host], lag_warning: f64) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for r in res {
+        let Some(logical) = &r.logical else {
+            continue;
+        };
+        for s in &logical.subscriptions {
+            let from = s.publications.join(", ");
+            if !s.enabled {
+                warnings.push(format!(
+                    "{}'s subscription {} is disabled, nothing is applied from {}",
+                    r.name, s.name, from
+                ));
+            } else if !s.running {
+                let errors = match s.errors {
+                    Some(n) if n > 0 => format!(" after {} errors", n),
+                    _ => String::new(),
+                };
+                warnings.push(format!(
+                    "{}'s subscription {} has no apply worker running{}, it can't connect to \
+                     the publisher or apply what it sent",
+                    r.name, s.name, errors
+                ));
+            } else if let Some(lag) = s.lag_seconds.filter(|l| *l > lag_warning) {
+                warnings.push(format!(
+                    "{}'s subscription {} last reported its position {:.0}s ago, it's stuck or \
+                     falling behind {}",
+                    r.name, s.name, lag, from
+                ));
+            }
+        }
+        for slot in &logical.slots {
+            if slot.wal_status.as_deref() == Some("lost") {
+                warnings.push(format!(
+                    "{}'s logical slot {} lost WAL its subscriber hadn't applied yet, the \
+                     subscription has to be recreated",
+                    r.name, slot.slot_name
+                ));
+            } else if !slot.active {
+                let retained = match slot.lag_bytes {
+                    Some(bytes) => format!(", retaining {} bytes of WAL", bytes),
+                    None => String::new(),
+                };
+                warnings.push(format!(
+                    "{}'s logical slot {} has no subscriber connected{}",
+                    r.name, slot.slot_name, retained
+                ));
+            }
+        }
+        for p in logical
+            .publications
+            .iter()
+            .filter(|p| !p.all_tables && p.tables == 0)
+        {
+            warnings.push(format!(
+                "{}'s publication {} has no tables, its subscribers get nothing",
+                r.name, p.name
+            ));
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_and_lagging_subscriptions_are_reported() {
+        let subscription = |name: &str, enabled, running, lag_seconds| Subscription {
+            name: name.to_string(),
+            database: Some("orders".to_string()),
+            enabled,
+            publications: vec!["orders_pub".to_string()],
+            running,
+            syncing_tables: 0,
+            lag_seconds: Some(lag_seconds),
+            errors: Some(3),
+        };
+        let host = Host {
+            name: "db1".to_string(),
+            logical: Some(Logical {
+                subscriptions: vec![
+                    subscription("healthy", true, true, 5.0),
+                    subscription("off", false, false, 5.0),
+                    subscription("failing", true, false, 5.0),
+                    subscription("behind", true, true, 900.0),
+                ],
+                publications: vec![Publication {
+                    name: "empty".to_string(),
+                    all_tables: false,
+                    tables: 0,
+                }],
+                slots: vec![LogicalSlot {
+                    slot_name: "to_reporting".to_string(),
+                    database: Some("orders".to_string()),
+                    active: false,
+                    lag_bytes: Some(4096),
+                    wal_status: Some("extended".to_string()),
+                }],
+            }),
+            ..Host::default()
+        };
+        assert_eq!(
+            find_warnings(&[host], 300.0),
+            vec![
+                "db1's subscription off is disabled, nothing is applied from orders_pub",
+                "db1's subscription failing has no apply worker running after 3 errors, it can't connect to the publisher or apply what it sent",
+                "db1's subscription behind last reported its position 900s ago, it's stuck or falling behind orders_pub",
+                "db1's logical slot to_reporting has no subscriber connected, retaining 4096 bytes of WAL",
+                "db1's publication empty has no tables, its subscribers get nothing",
+            ]
+        );
+    }
+}
@@ -1,30 +1,262 @@
+mod advise;
+mod bench;
+mod can_replicate;
+mod daemon;
+mod discover;
+#[cfg(test)]
+mod fake;
+mod init;
+mod journal;
+mod lint;
+mod metrics;
+mod parquet;
+mod pgbackrest;
+mod ping;
+mod profile;
+mod resume;
+mod rotate;
+mod s3;
+mod shell;
+mod sink;
+mod state;
+mod statsd;
+mod systemd;
+mod telemetry;
+mod transitions;
+mod tui;
+
 use std::{
-    fs::File,
-    io::{self, BufRead},
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fs,
+    future::{self, Future},
+    io::{self, IsTerminal},
+    net::IpAddr,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    pin::pin,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use clap::{command, Parser, Subcommand};
-use sqlx::{
-    postgres::{PgConnectOptions, PgPoolOptions, PgRow, PgSslMode},
-    Row,
+use ::time::format_description::well_known::Rfc3339;
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use futures::StreamExt;
+use rand::Rng;
+use sqlx::{postgres::PgConnectOptions, Connection, PgConnection};
+use telemetry::Telemetry;
+use timeline_check::{
+    at_first_answering, check_hosts_stream, check_thresholds, checks, clock,
+    error::{self, ErrorFormat},
+    expected_replicas, expected_timeline, find_clusters,
+    hosts::{self, HostPattern, HostSource, Listing},
+    hosts_query, jump, logical, now,
+    output::{self, Format},
+    parse_expect_replicas, percentile, probe, recovery, replicas, replication, route,
+    server_version, set_role, slots, timeline, tls, tunnel, version, CheckError, CheckOptions,
+    CheckOptionsBuilder, Failure, Health, HealthArgs, Host, HostProbe, Pools, Reason, Summary,
+    Target, Verdict, WalSource,
 };
-use tokio::join;
+use tokio::{net::lookup_host, time};
 
 #[derive(Parser, Debug)]
 #[command(about, long_about = None)]
 struct Cli {
-    /// User
+    /// User, needed by every subcommand but completions and login
     #[arg(short, long)]
-    user: String,
+    user: Option<String>,
 
     /// Password
     #[arg(short, long)]
-    password: String,
+    password: Option<String>,
+
+    /// Read the password from this file instead, e.g. a mounted Docker or Kubernetes secret
+    #[arg(long, value_name = "FILE", conflicts_with = "password")]
+    password_file: Option<PathBuf>,
+
+    /// Read --password-file even when every user may read it
+    #[arg(long, requires = "password_file")]
+    insecure_permissions: bool,
+
+    /// Take the password from the OS keyring, where `login` stored it under this name
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["password", "password_file"])]
+    profile: Option<String>,
+
+    /// File with hosts to connect to, needed by every subcommand but discover unless the hosts
+    /// come from --inventory, --ssh-config or --hosts-query. Can be repeated and be a glob, e.g.
+    /// 'inventories/*.hosts', each host then tagged hosts_file with the name of its file
+    #[arg(long, global = true, value_name = "FILE")]
+    hosts: Vec<String>,
+
+    /// Ansible inventory (INI, or YAML when named .yml/.yaml) to take the hosts from instead
+    #[arg(long, conflicts_with = "hosts")]
+    inventory: Option<PathBuf>,
+
+    /// Inventory group whose hosts, child groups included, are checked
+    #[arg(long, requires = "inventory", default_value = "all")]
+    group: String,
+
+    /// OpenSSH client config to take the hosts from instead, with HostName as their address
+    #[arg(long, conflicts_with_all = ["hosts", "inventory"])]
+    ssh_config: Option<PathBuf>,
+
+    /// Inventory database to take the hosts from instead, as 'URL # QUERY', e.g.
+    /// 'postgres://cmdb/inventory # SELECT hostname FROM db_servers'. A second column is the
+    /// address to connect to
+    #[arg(
+        long,
+        value_name = "URL # QUERY",
+        value_parser = hosts_query::HostsQuery::parse,
+        conflicts_with_all = ["hosts", "inventory", "ssh_config"]
+    )]
+    hosts_query: Option<hosts_query::HostsQuery>,
+
+    /// Only check hosts matching this glob, or regex when wrapped in slashes. Can be repeated
+    #[arg(long = "match", value_name = "PATTERN", value_parser = HostPattern::parse)]
+    matches: Vec<HostPattern>,
+
+    /// Connect to this address instead of looking the host up, as host=ip. Can be repeated
+    #[arg(long, value_name = "HOST=IP", value_parser = hosts::parse_resolve)]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Maximum number of hosts checked at the same time
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+
+    /// Open at most this many new connections a second across all hosts, for firewalls and
+    /// poolers that throttle connection bursts
+    #[arg(long, value_name = "N", value_parser = parse_rate)]
+    max_connects_per_second: Option<f64>,
+
+    /// Give up connecting to a host after this many seconds, unless its hosts file line sets
+    /// connect_timeout
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    connect_timeout: Option<Duration>,
+
+    /// application_name every connection logs in with, for telling this tool's sessions apart
+    /// in pg_stat_activity and PgBouncer's logs. Sink and --hosts-query URLs may set their own
+    #[arg(long, value_name = "NAME", default_value = "timeline_check", value_parser = parse_application_name)]
+    application_name: String,
+
+    /// SET ROLE to this role after logging in, for logins that hold their monitoring
+    /// privileges through a NOLOGIN role. Replication connections stay the login role
+    #[arg(long, value_name = "ROLE")]
+    set_role: Option<String>,
+
+    /// Vary the `daemon` and `tui` intervals by up to this many percent either way, so
+    /// instances started together drift apart instead of sweeping the fleet in step
+    #[arg(long, value_name = "PERCENT", default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    jitter: u8,
+
+    /// Give up on hosts that haven't been checked this many seconds into a sweep, reporting
+    /// them as timed out
+    #[arg(long, value_name = "SECS")]
+    deadline: Option<f64>,
+
+    /// When a host turns out to be PgBouncer, check the server it forwards to (found through its
+    /// admin console) instead of reporting it as failed
+    #[arg(long)]
+    follow_poolers: bool,
+
+    /// Also check logical replication: each primary's subscriptions, and each host's
+    /// publications and logical slots
+    #[arg(long)]
+    logical: bool,
+
+    /// How to check hosts, `replication` works for roles that may only replicate but leaves out
+    /// what needs SQL: attached replicas, streaming status, lag and timeline age
+    #[arg(long, value_enum, default_value_t)]
+    via: Via,
+
+    /// Export check spans and fleet metrics to this OTLP/HTTP collector, e.g.
+    /// http://localhost:4318 (needs the otel feature)
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Where to record the hosts that couldn't be checked, for `--retry-failed`. Defaults to
+    /// $XDG_STATE_HOME/timeline_check/failed_hosts
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Where to keep every host's role and timeline, to warn when they changed by the next run.
+    /// Defaults to $XDG_STATE_HOME/timeline_check/last_run.json
+    #[arg(long)]
+    last_run_file: Option<PathBuf>,
+
+    /// Write the hosts that couldn't be checked to this file, one per line
+    #[arg(long)]
+    failed_hosts_out: Option<PathBuf>,
+
+    /// How to print hosts that couldn't be checked on stderr, `json` for log shippers
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t)]
+    errors: ErrorFormat,
+
+    /// YAML file of extra queries to run on every host, each adding a column to the output
+    #[arg(long, value_name = "FILE")]
+    checks: Option<PathBuf>,
+
+    /// Built-in checks to skip, comma separated; their columns are left empty
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "CHECKS")]
+    checks_disable: Vec<checks::Builtin>,
+
+    /// Only run these built-in checks, comma separated
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "CHECKS",
+        conflicts_with = "checks_disable"
+    )]
+    checks_enable: Option<Vec<checks::Builtin>>,
+
+    /// Push per-host gauges to this StatsD server (host:port) after each sweep
+    #[arg(long)]
+    statsd: Option<String>,
+
+    /// Insert every sweep's per-host rows, with a run id, into this table, e.g.
+    /// postgres://writer@metrics/ops/timeline_check_results, created if missing. Or upload each
+    /// sweep's JSON report under an s3://bucket/prefix/. Can be repeated
+    #[arg(long, value_name = "URL", value_parser = sink::Sink::parse)]
+    sink: Vec<sink::Sink>,
 
-    /// File with hosts to connect to
+    /// Only check the hosts that couldn't be checked in the last run
     #[arg(long)]
-    hosts: PathBuf,
+    retry_failed: bool,
+
+    /// Reach the hosts through an SSH tunnel from this bastion, e.g. ops@bastion.example.com
+    #[arg(long, value_name = "USER@HOST[:PORT]", value_parser = jump::parse)]
+    ssh_jump: Option<jump::JumpHost>,
+
+    /// Private key to log in to the bastion with, instead of ssh-agent and ~/.ssh/id_*
+    #[arg(long, value_name = "FILE", requires = "ssh_jump")]
+    ssh_key: Option<PathBuf>,
+
+    /// Tunnel every connection through this SOCKS5 proxy, as socks5://[user:password@]host:port
+    #[arg(long, value_parser = tunnel::parse_proxy, conflicts_with = "ssh_jump")]
+    proxy: Option<tunnel::Proxy>,
+
+    /// Validate the configuration and print what would be checked, without connecting anywhere
+    #[arg(long)]
+    dry_run: bool,
+
+    /// 🔐️ Root CA certificate file path, for hosts that require cert authentication
+    #[arg(short, long, requires_all = ["client_cert", "client_key"])]
+    root_cert: Option<PathBuf>,
+
+    /// Client certificate file path
+    #[arg(long, requires_all = ["root_cert", "client_key"])]
+    client_cert: Option<PathBuf>,
+
+    /// Client certificate key file path
+    #[arg(long, requires_all = ["root_cert", "client_cert"])]
+    client_key: Option<PathBuf>,
+
+    /// Without a --root-cert, verify servers against the certificates the operating system
+    /// trusts, requiring TLS unless a host's sslmode in the hosts file says otherwise
+    #[arg(long, conflicts_with = "root_cert")]
+    system_roots: bool,
 
     #[command(subcommand)]
     command: Commands,
@@ -32,122 +264,2435 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// 🔐️ For hosts that require cert authentication
-    Cert {
-        #[arg(short, long)]
-        /// Root CA certificate file path
-        root_cert: PathBuf,
-
-        #[arg(long)]
-        /// Client certificate file path
-        client_cert: PathBuf,
-
-        #[arg(long)]
-        /// Client certificate key file path
-        client_key: PathBuf,
+    /// Judge the fleet: print only what's wrong and exit non-zero when unhealthy
+    Check(CheckArgs),
+    /// Print every host's state for people or scripts, the exit code doesn't judge it
+    Report(ReportArgs),
+    /// Interactive prompt for checking clusters again and again over connections kept open,
+    /// with `check`, `lag`, `topology` and `expect primary` commands
+    Shell(WarningArgs),
+    /// Live-updating dashboard of the fleet
+    Tui {
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
     },
-    NoCert,
+    /// Keep checking in the background, recording and exposing every sweep
+    Daemon(daemon::DaemonArgs),
+    /// Check that every standby's upstream accepts replication connections from here
+    CanReplicate,
+    /// Rank each cluster's standbys as promotion targets by the WAL they received and
+    /// replayed, synchronous state, lag and timeline, with how much WAL promoting each loses
+    AdvisePromote,
+    /// Run the checks against each host several times and print its latency percentiles
+    Bench(bench::BenchArgs),
+    /// Only connect and log in to each host, to test credentials, pg_hba.conf and firewalls
+    Ping,
+    /// Walk the replication topology from a seed host and write the servers found as a hosts file
+    Discover(discover::DiscoverArgs),
+    /// Write a commented example hosts file and checks file to start from
+    Init(init::InitArgs),
+    /// Check the --hosts file for mistakes without connecting, exiting with 1 on any, for CI
+    Lint,
+    /// Print a bash, zsh, fish, elvish or PowerShell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print a man page in roff, for packaging
+    #[command(hide = true)]
+    Mangen,
+    /// Store the password for --profile in the OS keyring, prompting for it
+    Login,
+}
+
+/// How far off certificates, subscriptions and clocks may be before `check` warns about them
+#[derive(Args, Debug, Clone, Copy)]
+struct WarningArgs {
+    /// Warn about server certificates expiring within this many days, 0 turns the TLS
+    /// handshake this takes off
+    #[arg(long, value_name = "DAYS", default_value_t = 30)]
+    cert_expiry_warning: u64,
+
+    /// With --logical, warn about subscriptions that haven't reported their position to the
+    /// publisher for this many seconds
+    #[arg(long, value_name = "SECS", default_value_t = 300.0)]
+    subscription_lag_warning: f64,
+
+    /// Warn about hosts whose clock is this many seconds off from this machine's, and standbys
+    /// whose clock is off from their primary's
+    #[arg(long, value_name = "SECS", default_value_t = 1.0)]
+    clock_skew_warning: f64,
+}
+
+#[derive(Args, Debug)]
+struct CheckArgs {
+    /// When to print the report, `anomaly` stays silent while the exit code would be 0
+    #[arg(long, value_enum, default_value_t)]
+    report_on: ReportOn,
+
+    #[command(flatten)]
+    warnings: WarningArgs,
+
+    /// Exit with the fleet's health instead: 0 when OK, 4 when DEGRADED and 1 when CRITICAL
+    #[arg(long)]
+    exit_by_health: bool,
+
+    /// Warn when the pgBackRest archive or latest backup of a cluster is on an older timeline
+    /// than its primary, from `pgbackrest info` or COMMAND given instead, e.g.
+    /// 'sudo -u postgres pgbackrest'
+    #[arg(long, value_name = "COMMAND", num_args = 0..=1, default_missing_value = "pgbackrest")]
+    pgbackrest: Option<String>,
+
+    /// Resume WAL replay with pg_wal_replay_resume() on standbys found with it paused, asking
+    /// first for each one
+    #[arg(long)]
+    resume_replay: bool,
+
+    /// Resume replay without asking
+    #[arg(long, requires = "resume_replay")]
+    yes: bool,
+
+    #[command(flatten)]
+    health: HealthArgs,
+}
+
+#[derive(Args, Debug)]
+struct ReportArgs {
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    format: Format,
+
+    /// File to write the results to, required by and only used with --format parquet
+    #[arg(long, value_name = "PATH", required_if_eq("format", "parquet"))]
+    output: Option<PathBuf>,
+
+    /// Add each server's certificate expiry and each standby's primary_conninfo (passwords
+    /// redacted), primary_slot_name and restore_command to the output
+    #[arg(long)]
+    extended: bool,
+
+    /// Add whether each host's connection is encrypted, and with which TLS version and cipher
+    #[arg(long)]
+    tls: bool,
+
+    /// Add the milliseconds each host took to resolve, connect, negotiate TLS, log in and run
+    /// the checks
+    #[arg(long)]
+    timings: bool,
+
+    /// Mark each row's status with a word instead of a glyph, for terminals without emoji
+    #[arg(long)]
+    no_emoji: bool,
+
+    /// Print every built-in column instead of just host, role, timeline and lag
+    #[arg(long, conflicts_with_all = ["columns", "template"])]
+    wide: bool,
+
+    /// Print only these columns, in this order, e.g. host,role,timeline,lag. Custom checks are
+    /// columns named after them
+    #[arg(long, value_delimiter = ',', value_parser = output::Column::parse)]
+    columns: Option<Vec<output::Column>>,
+
+    /// Print a line of this shape per host instead, e.g. '{host} tl={timeline_id}', with the
+    /// --columns names as placeholders
+    #[arg(long, conflicts_with = "columns", value_parser = output::Template::parse)]
+    template: Option<output::Template>,
+
+    /// Separate CSV fields with this character instead of a comma, `tab` and `semicolon` are
+    /// accepted by name. Fields are only quoted when they contain the delimiter or a quote
+    #[arg(long, conflicts_with = "template", value_parser = output::parse_delimiter)]
+    delimiter: Option<char>,
+
+    /// Group rows by cluster, each under a line with its verdict
+    #[arg(long, conflicts_with = "stream")]
+    by_cluster: bool,
+
+    /// Print each host as soon as its check completes instead of in hosts file order.
+    /// Byte lag needs the primary's position, so it is left out of streamed rows
+    #[arg(long)]
+    stream: bool,
+
+    /// Print only the N standbys furthest behind, worst first, with their upstream and cluster
+    #[arg(long, value_name = "N", conflicts_with_all = ["stream", "by_cluster", "wide"])]
+    top: Option<usize>,
+
+    /// Print the rows and summary as CSV to stderr, with a count of hosts checked while the run
+    /// goes on, and nothing but the --format rows or document to stdout
+    #[arg(long)]
+    human: bool,
+}
+
+impl ReportArgs {
+    /// Whether `flag` is given or one of the `--columns` needs what it collects
+    fn collects(&self, flag: &str) -> bool {
+        let given = match flag {
+            "extended" => self.extended,
+            "tls" => self.tls,
+            "timings" => self.timings,
+            _ => false,
+        };
+        given
+            || self
+                .columns()
+                .filter_map(|c| c.collected_by())
+                .any(|f| f == flag)
+    }
+
+    /// The columns picked with `--columns` or used in `--template`
+    fn columns(&self) -> impl Iterator<Item = &output::Column> {
+        let template = self.template.iter().flat_map(|t| t.columns());
+        self.columns.iter().flatten().chain(template)
+    }
+
+    fn output_options(&self) -> output::Options {
+        output::Options {
+            status: io::stdout().is_terminal(),
+            emoji: !self.no_emoji,
+            wide: self.wide,
+            extended: self.extended,
+            by_cluster: self.by_cluster,
+            tls: self.tls,
+            timings: self.timings,
+            columns: self.columns.clone().or_else(|| {
+                self.top.map(|_| {
+                    vec![
+                        output::Column::Host,
+                        output::Column::Lag,
+                        output::Column::LagSeconds,
+                        output::Column::Upstream,
+                        output::Column::SystemIdentifier,
+                    ]
+                })
+            }),
+            template: self.template.clone(),
+            delimiter: self.delimiter.unwrap_or(','),
+        }
+    }
+}
+
+impl Cli {
+    /// Root certificate, client certificate and key, all given together
+    fn tls(&self) -> Option<(&PathBuf, &PathBuf, &PathBuf)> {
+        match (&self.root_cert, &self.client_cert, &self.client_key) {
+            (Some(root), Some(cert), Some(key)) => Some((root, cert, key)),
+            _ => None,
+        }
+    }
+
+    /// The built-in checks to run
+    fn builtins(&self) -> Vec<checks::Builtin> {
+        match &self.checks_enable {
+            Some(enabled) => enabled.clone(),
+            None => checks::Builtin::value_variants()
+                .iter()
+                .filter(|b| !self.checks_disable.contains(b))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Where to read the hosts from, `None` when no host source was given
+    fn host_source(&self) -> Option<HostSource> {
+        let listing = match (&self.inventory, &self.ssh_config, &self.hosts_query) {
+            (Some(path), _, _) => Listing::Inventory {
+                path: path.clone(),
+                group: self.group.clone(),
+            },
+            (None, Some(path), _) => Listing::SshConfig(path.clone()),
+            (None, None, Some(query)) => Listing::Query(Box::new(query.clone())),
+            (None, None, None) if !self.hosts.is_empty() => Listing::Files(self.hosts.clone()),
+            (None, None, None) => return None,
+        };
+        Some(HostSource {
+            listing,
+            patterns: self.matches.clone(),
+            overrides: self.resolve.iter().cloned().collect(),
+        })
+    }
+}
+
+/// A rate above zero, for `--max-connects-per-second`
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}: {}", s, e))?;
+    if rate > 0.0 && rate.is_finite() {
+        Ok(rate)
+    } else {
+        Err("must be a positive number".to_string())
+    }
+}
+
+/// Printable ASCII of at most 63 bytes, as the server keeps it. It would replace anything else
+/// with `?` and cut longer names short, so they'd never match what DBAs look for
+fn parse_application_name(s: &str) -> Result<String, String> {
+    if s.len() > 63 {
+        Err("at most 63 characters".to_string())
+    } else if !s.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        Err("only printable ASCII characters".to_string())
+    } else {
+        Ok(s.to_string())
+    }
 }
 
-#[derive(Debug)]
-struct Host {
-    name: String,
-    is_primary: bool,
-    timeline_id: i32,
-    replica_attached: bool,
+/// A positive number of seconds
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{}: {}", s, e))?;
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|t| !t.is_zero())
+        .ok_or_else(|| "must be a positive number of seconds".to_string())
+}
+
+/// How far behind a standby is marked as lagging in table output
+const LAGGING_SECONDS: f64 = 60.0;
+
+/// Exit code when timelines diverged, there's a split brain or a threshold was exceeded
+const EXIT_UNHEALTHY: u8 = 1;
+
+/// Exit code when everything that could be checked was healthy but some hosts couldn't be
+const EXIT_UNREACHABLE: u8 = 3;
+
+/// Exit code of `check --exit-by-health` when no cluster is CRITICAL but the fleet is DEGRADED
+const EXIT_DEGRADED: u8 = 4;
+
+/// Exit code when the run was cut short with Ctrl-C, the report only covers finished hosts
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// Back to the start of the terminal line and erase it, for `--human`'s progress count
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum Via {
+    /// Regular SQL queries
+    #[default]
+    Sql,
+    /// IDENTIFY_SYSTEM and SHOW over a physical replication connection
+    Replication,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum ReportOn {
+    /// Always print the report
+    #[default]
+    Always,
+    /// Print nothing unless the run is unhealthy or hosts are unreachable
+    Anomaly,
 }
 
 #[tokio::main]
-async fn main() {
-    let cli = Cli::parse();
+async fn main() -> ExitCode {
+    let mut cli = Cli::parse();
+    let started = Instant::now();
+    if let Some(query) = &mut cli.hosts_query {
+        query.application_name(&cli.application_name);
+    }
+    for sink in &mut cli.sink {
+        sink.application_name(&cli.application_name);
+    }
 
-    let hosts = match read_lines(cli.hosts) {
-        Ok(lines) => lines.map(|l| l.unwrap()),
-        Err(e) => panic!("Error reading file: {}", e),
+    if let Commands::Completions { shell } = cli.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        return ExitCode::SUCCESS;
+    }
+    if let Commands::Init(args) = &cli.command {
+        return init::run(args);
+    }
+    if let Commands::Lint = cli.command {
+        if cli.hosts.is_empty() {
+            Cli::command()
+                .error(ErrorKind::MissingRequiredArgument, "lint needs --hosts")
+                .exit();
+        }
+        return match hosts::expand_files(&cli.hosts) {
+            Ok(paths) => lint::run(&paths).await,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", cli.hosts.join(", "), e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let Commands::Mangen = cli.command {
+        let man = clap_mangen::Man::new(Cli::command());
+        if let Err(e) = man.render(&mut io::stdout()) {
+            eprintln!("Error writing man page: {}", e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+    if let Commands::Login = cli.command {
+        let Some(name) = &cli.profile else {
+            Cli::command()
+                .error(ErrorKind::MissingRequiredArgument, "login needs --profile")
+                .exit();
+        };
+        if let Err(e) = profile::login(name).await {
+            eprintln!("Error storing the password: {}", e);
+            return ExitCode::FAILURE;
+        }
+        println!("Stored the password for profile {}", name);
+        return ExitCode::SUCCESS;
+    }
+    let password = match (&cli.password_file, &cli.profile) {
+        (None, Some(name)) => match profile::password(name).await {
+            Ok(password) => Some(password),
+            Err(e) => {
+                eprintln!("Error reading the password from the keyring: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        (Some(path), _) => match read_password_file(path, cli.insecure_permissions) {
+            Ok(password) => Some(password),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                Cli::command()
+                    .error(ErrorKind::InvalidValue, format!("--password-file: {}", e))
+                    .exit();
+            }
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        (None, None) => cli.password.clone(),
+    };
+    let (Some(user), Some(password)) = (&cli.user, &password) else {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--user and one of --password, --password-file or --profile are required",
+            )
+            .exit();
     };
 
-    let conn = PgConnectOptions::new()
-        // TODO: config these two
-        .port(5432)
-        .database("postgres")
-        .username(&cli.user)
-        .password(&cli.password);
-
-    let conn = match &cli.command {
-        Commands::Cert {
-            root_cert,
-            client_cert,
-            client_key,
-        } => conn
-            .ssl_mode(PgSslMode::Require)
-            .ssl_root_cert(root_cert)
-            .ssl_client_cert(client_cert)
-            .ssl_client_key(client_key),
-        Commands::NoCert => conn.ssl_mode(PgSslMode::Prefer),
+    let mut options = CheckOptionsBuilder::new(user, password)
+        .application_name(&cli.application_name)
+        .concurrency(cli.concurrency);
+    if let Some(role) = &cli.set_role {
+        options = options.set_role(role);
+    }
+    if let Some((root_cert, client_cert, client_key)) = cli.tls() {
+        options = options.tls(root_cert, client_cert, client_key);
+    }
+    if cli.system_roots {
+        match tls::system_roots() {
+            Ok((roots, pem)) => options = options.system_roots(roots, pem),
+            Err(e) => {
+                eprintln!("Error loading the operating system's certificates: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if (cli.ssh_jump.is_some() || cli.proxy.is_some())
+        && matches!(cli.command, Commands::Discover(_) | Commands::CanReplicate)
+    {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--ssh-jump and --proxy can't be used with discover or can-replicate",
+            )
+            .exit();
+    }
+    if let Commands::Discover(args) = &cli.command {
+        if cli.dry_run {
+            println!("Would walk the topology from {}", args.seed.join(", "));
+            return ExitCode::SUCCESS;
+        }
+        let conn = options
+            .build()
+            .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+        if let Err(e) = discover::run(&conn.pg, cli.set_role.as_deref(), args).await {
+            eprintln!("Error writing hosts: {}", e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let Some(source) = cli.host_source() else {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "one of --hosts, --inventory, --ssh-config or --hosts-query is required",
+            )
+            .exit();
+    };
+    let mut hosts = match source.load().await {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", source, e);
+            return ExitCode::FAILURE;
+        }
     };
+    if hosts.is_empty() && !cli.matches.is_empty() {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "--match: no hosts match")
+            .exit();
+    }
 
-    let mut res = Vec::new();
+    let state_file = cli.state_file.clone().or_else(state::default_failed_path);
+    if cli.retry_failed {
+        let Some(path) = &state_file else {
+            Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--retry-failed needs --state-file",
+                )
+                .exit();
+        };
+        let failed = match state::read_failed(path) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("Error reading failed hosts from {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        };
+        hosts.retain(|t| failed.contains_key(&t.name));
+        if hosts.is_empty() {
+            eprintln!("No hosts failed in the last run");
+            return ExitCode::SUCCESS;
+        }
+    }
+    let host_count = hosts.len();
 
-    // TODO: This should be done through spawned tasks, it takes like 7 seconds/host atm
-    for host in hosts {
-        let conn = conn.clone().host(&host);
-        let pool = PgPoolOptions::new()
-            .max_connections(4)
-            .connect_with(conn)
-            .await
-            .map_err(|e| {
-                println!("Error connecting to host: {}", host);
-                println!("{}", e);
-                e
+    let custom_checks = match &cli.checks {
+        Some(path) => match checks::load(path) {
+            Ok(c) => c,
+            Err(e) => {
+                Cli::command()
+                    .error(ErrorKind::InvalidValue, format!("--checks: {}", e))
+                    .exit();
+            }
+        },
+        None => Vec::new(),
+    };
+    let report = match &cli.command {
+        Commands::Report(args) => Some(args),
+        _ => None,
+    };
+    for column in report.into_iter().flat_map(|r| r.columns()) {
+        if let output::Column::Check(name) = column {
+            if !custom_checks.iter().any(|c| c.name == *name) {
+                Cli::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!("no built-in column or custom check named {}", name),
+                    )
+                    .exit();
+            }
+        }
+    }
+    if report.is_some_and(|r| {
+        (r.columns.is_some() || r.template.is_some() || r.delimiter.is_some())
+            && !matches!(r.format, Format::Csv)
+    }) {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--columns, --template and --delimiter only apply to --format csv",
+            )
+            .exit();
+    }
+
+    options = options
+        .follow_poolers(cli.follow_poolers)
+        .logical(cli.logical)
+        .via_replication(cli.via == Via::Replication)
+        .builtins(cli.builtins())
+        .checks(custom_checks)
+        // The primaries' view of their standbys tells synchronous ones apart
+        .extended(
+            report.is_some_and(|r| r.collects("extended"))
+                || matches!(cli.command, Commands::AdvisePromote),
+        )
+        .certificates(match &cli.command {
+            Commands::Check(CheckArgs { warnings, .. }) | Commands::Shell(warnings) => {
+                warnings.cert_expiry_warning > 0
+            }
+            _ => report.is_some_and(|r| r.collects("extended")),
+        })
+        .encryption(report.is_some_and(|r| r.collects("tls")))
+        .timings(report.is_some_and(|r| r.collects("timings")));
+    if let Some(timeout) = cli.connect_timeout {
+        options = options.connect_timeout(timeout);
+    }
+    if let Some(n) = cli.max_connects_per_second {
+        options = options.max_connects_per_second(n);
+    }
+    let mut conn = options
+        .build()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+
+    if cli.deadline.is_some_and(|d| !(d > 0.0 && d.is_finite())) {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--deadline must be a positive number of seconds",
+            )
+            .exit();
+    }
+
+    if let Some(r) =
+        report.filter(|r| r.stream && matches!(r.format, Format::Json | Format::Parquet))
+    {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--stream can't be used with --format {}",
+                    r.format.to_possible_value().unwrap().get_name()
+                ),
+            )
+            .exit();
+    }
+    if report.is_some_and(|r| r.output.is_some() && !matches!(r.format, Format::Parquet)) {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--output only applies to --format parquet",
+            )
+            .exit();
+    }
+    if report.is_some_and(|r| matches!(r.format, Format::Parquet)) && !cfg!(feature = "parquet") {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--format parquet: built without the parquet feature",
+            )
+            .exit();
+    }
+
+    if cli.dry_run {
+        return dry_run(&cli, &conn, &hosts);
+    }
+    if let Some(bastion) = &cli.ssh_jump {
+        match jump::Jump::connect(bastion, cli.ssh_key.as_deref()).await {
+            Ok(jump) => {
+                conn.tunnel = Some(Arc::new(tunnel::Tunnel::new(tunnel::Route::Ssh(jump))));
+            }
+            Err(e) => {
+                eprintln!("Error connecting to the jump host: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if let Some(proxy) = &cli.proxy {
+        conn.tunnel = Some(Arc::new(tunnel::Tunnel::new(tunnel::Route::Socks5(
+            proxy.clone(),
+        ))));
+    }
+
+    let telemetry = match Telemetry::new(cli.otlp_endpoint.as_deref()) {
+        Ok(t) => t,
+        Err(e) => {
+            Cli::command()
+                .error(ErrorKind::InvalidValue, format!("--otlp-endpoint: {}", e))
+                .exit();
+        }
+    };
+
+    match &cli.command {
+        Commands::Tui { interval } => {
+            let interval = Duration::from_secs((*interval).max(1));
+            return tui::run(conn, source, hosts, interval, cli.jitter).await;
+        }
+        Commands::Shell(warnings) => {
+            return shell::run(conn, source, hosts, cli.errors, *warnings).await
+        }
+        Commands::Daemon(args) => {
+            return daemon::run(&cli, &telemetry, conn, source, hosts, args).await;
+        }
+        Commands::Bench(args) => {
+            return bench::run(&conn, &hosts, args, cli.errors).await;
+        }
+        Commands::Ping => return ping::run(&conn, &hosts).await,
+        Commands::Check(_)
+        | Commands::Report(_)
+        | Commands::CanReplicate
+        | Commands::AdvisePromote => {}
+        Commands::Discover(_)
+        | Commands::Init(_)
+        | Commands::Lint
+        | Commands::Completions { .. }
+        | Commands::Mangen
+        | Commands::Login => {
+            unreachable!("handled above")
+        }
+    }
+
+    let interrupted = Cell::new(false);
+    let streaming = report.is_some_and(|r| r.stream);
+    // Overwritten in place, so only on a terminal
+    let progress = report.is_some_and(|r| r.human) && io::stderr().is_terminal();
+    let mut checked = 0;
+    // Unless rows are streamed, errors wait for the sweep too, to come out in hosts file order
+    let mut errors: Vec<(String, String)> = Vec::new();
+    let mut reasons = HashMap::new();
+    // Kept for --resume-replay to act on the hosts through
+    let pools = Pools::default();
+    let res = sweep(
+        &probe::Live::new(conn.clone(), pools.clone()),
+        &hosts,
+        conn.concurrency,
+        async {
+            tokio::select! {
+                reason = deadline(cli.deadline.map(Duration::from_secs_f64)) => reason,
+                _ = tokio::signal::ctrl_c() => {
+                    interrupted.set(true);
+                    io::Error::new(io::ErrorKind::Interrupted, "run interrupted")
+                }
+            }
+        },
+        |name, res, elapsed| {
+            telemetry.record(name, res, elapsed);
+            if progress {
+                checked += 1;
+                eprint!("{}Checked {} of {} hosts", CLEAR_LINE, checked, hosts.len());
+            }
+            match res {
+                Ok(host) => {
+                    if let Some(args) = report.filter(|r| r.stream) {
+                        if progress {
+                            eprint!("{}", CLEAR_LINE);
+                        }
+                        output::print_host(args.format, &args.output_options(), host);
+                    }
+                }
+                Err(e) => {
+                    reasons.insert(name.to_string(), e.reason());
+                    match streaming {
+                        true if progress => {
+                            eprintln!("{}{}", CLEAR_LINE, describe_error(cli.errors, e))
+                        }
+                        true => eprintln!("{}", describe_error(cli.errors, e)),
+                        false => errors.push((name.to_string(), describe_error(cli.errors, e))),
+                    }
+                }
+            }
+        },
+    )
+    .await;
+    if progress {
+        eprint!("{}", CLEAR_LINE);
+    }
+    for target in &hosts {
+        for (_, e) in errors.iter().filter(|(name, _)| *name == target.name) {
+            eprintln!("{}", e);
+        }
+    }
+    telemetry.record_sweep(&res);
+    telemetry.shutdown();
+
+    if let Some(addr) = &cli.statsd {
+        if let Err(e) = statsd::push(addr, &hosts, &res).await {
+            eprintln!("Error pushing to StatsD at {}: {}", addr, e);
+        }
+    }
+
+    let previous: HashMap<String, (Reason, u32)> = state_file
+        .as_deref()
+        .and_then(|path| state::read_failed(path).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, last)| Some((name, last?)))
+        .collect();
+    let failed = failures(&hosts, &res, &reasons, &previous);
+    // An interrupted run didn't get to everyone, keep the previous list for retrying
+    if let (Some(path), false) = (&state_file, interrupted.get()) {
+        let records = failed
+            .iter()
+            .map(|f| (f.name.as_str(), f.reason, f.failed_runs));
+        if let Err(e) = state::write_failed(path, records) {
+            eprintln!("Error recording failed hosts in {}: {}", path.display(), e);
+        }
+    }
+    if let Some(path) = &cli.failed_hosts_out {
+        let lines: String = failed.iter().map(|f| format!("{}\n", f.name)).collect();
+        if let Err(e) = fs::write(path, lines) {
+            eprintln!("Error writing failed hosts to {}: {}", path.display(), e);
+        }
+    }
+
+    let last_run_file = cli
+        .last_run_file
+        .clone()
+        .or_else(state::default_last_run_path);
+    let mut since_last_run = Vec::new();
+    if let Some(path) = &last_run_file {
+        match state::read_last_run(path) {
+            Ok(mut last) => {
+                since_last_run = find_changes_since(&last, &res);
+                remember(&mut last, &res);
+                if let Err(e) = state::write_last_run(path, &last) {
+                    eprintln!("Error recording this run in {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => eprintln!("Error reading the last run from {}: {}", path.display(), e),
+        }
+    }
+    // `check` reports them with its other warnings
+    if !matches!(cli.command, Commands::Check(_)) {
+        for w in &since_last_run {
+            eprintln!("Warning: {}", w);
+        }
+    }
+
+    let unreachable = host_count - res.len();
+    let mut res = res;
+    mark_status(&mut res);
+    let mut summary = Summary::new(&res, host_count, started.elapsed());
+    summary.failed = failed;
+    summary.interrupted = interrupted.get();
+    if let Commands::Check(args) = &cli.command {
+        summary.judge(&args.health, &res);
+    }
+    for e in write_sinks(&cli.sink, &hosts, &res, &summary).await {
+        eprintln!("{}", e);
+    }
+    match &cli.command {
+        Commands::Check(args) => {
+            let mut warnings = all_warnings(&args.warnings, &hosts, &res).await;
+            if let Some(command) = &args.pgbackrest {
+                match pgbackrest::info(command).await {
+                    Ok(stanzas) => warnings.extend(pgbackrest::cross_check(&stanzas, &res)),
+                    Err(e) => eprintln!("Error running {} info: {}", command, e),
+                }
+            }
+            warnings.extend(since_last_run);
+            let problems = find_problems(&args.health, &conn.checks, &res, host_count);
+            let code = match args.exit_by_health {
+                true => health_exit_code(summary.health, interrupted.get()),
+                false => exit_code(&problems, unreachable, interrupted.get()),
+            };
+            if args.report_on == ReportOn::Always || code != 0 {
+                output::print_verdict(&warnings, &problems, &summary);
+            }
+            if args.resume_replay {
+                resume::run(&pools, &res, args.yes).await;
+            }
+            ExitCode::from(code)
+        }
+        Commands::Report(args) => {
+            if let Some(n) = args.top {
+                keep_laggiest(&mut res, n);
+            }
+            // Streamed rows have already been printed, only the summary is left
+            let rows = if args.stream { &[][..] } else { &res[..] };
+            if let Some(path) = &args.output {
+                if let Err(e) = parquet::write(path, rows, &summary) {
+                    eprintln!("Error writing {}: {}", path.display(), e);
+                    return ExitCode::FAILURE;
+                }
+            }
+            if args.human {
+                output::print_rows(args.format, &args.output_options(), rows, &summary);
+                let mut human = args.output_options();
+                human.status = io::stderr().is_terminal();
+                human.template = None;
+                output::eprint_report(&human, &res, &summary);
+            } else {
+                output::print_report(args.format, &args.output_options(), rows, &summary);
+            }
+            ExitCode::from(exit_code(&[], unreachable, interrupted.get()))
+        }
+        Commands::CanReplicate => {
+            let code = can_replicate::run(&conn, &hosts, &res).await;
+            ExitCode::from(code.max(exit_code(&[], unreachable, interrupted.get())))
+        }
+        Commands::AdvisePromote => {
+            let code = advise::run(&hosts, &res).await;
+            ExitCode::from(code.max(exit_code(&[], unreachable, interrupted.get())))
+        }
+        Commands::Tui { .. }
+        | Commands::Shell(_)
+        | Commands::Daemon(_)
+        | Commands::Bench(_)
+        | Commands::Ping
+        | Commands::Discover(_)
+        | Commands::Init(_)
+        | Commands::Lint
+        | Commands::Completions { .. }
+        | Commands::Mangen
+        | Commands::Login => {
+            unreachable!("handled above")
+        }
+    }
+}
+
+/// Writes a sweep to every `--sink`, under a run id of its own, and describes the writes that
+/// failed
+async fn write_sinks(
+    sinks: &[sink::Sink],
+    hosts: &[Target],
+    res: &[Host],
+    summary: &Summary,
+) -> Vec<String> {
+    let run_id = format!("{:032x}", rand::random::<u128>());
+    let mut errors = Vec::new();
+    for s in sinks {
+        if let Err(e) = s.write(&run_id, hosts, res, summary).await {
+            errors.push(format!("Error writing results to {}: {}", s, e));
+        }
+    }
+    errors
+}
+
+/// Checks that the certificate files can be read and prints the hosts and checks a run would
+/// cover
+fn dry_run(cli: &Cli, conn: &CheckOptions, hosts: &[Target]) -> ExitCode {
+    let mut read = HashMap::new();
+    let mut failed = false;
+    for t in hosts {
+        for (files, res) in tls_files(cli, t, &mut read) {
+            if let Err(e) = res {
+                eprintln!("Error reading {} for {}: {}", files, t.name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return ExitCode::FAILURE;
+    }
+
+    let via = match cli.via {
+        Via::Sql => "SQL",
+        Via::Replication => "replication",
+    };
+    let through = match (&cli.ssh_jump, &cli.proxy) {
+        (Some(bastion), _) => format!(" through {}", bastion),
+        (None, Some(proxy)) => format!(" through {}", tunnel::Route::Socks5(proxy.clone())),
+        (None, None) => String::new(),
+    };
+    println!(
+        "Would check {} hosts over {} connections{}:",
+        hosts.len(),
+        via,
+        through
+    );
+    for t in hosts {
+        let mut line = format!("  {}", t.name);
+        if t.addr.is_some() {
+            line.push_str(&format!(" at {}", t.addresses().join(", then ")));
+        }
+        if !t.aliases.is_empty() {
+            line.push_str(&format!(" (also {})", t.aliases.join(", ")));
+        }
+        if !t.options.is_empty() {
+            line.push_str(&format!(" with {}", t.options));
+        }
+        println!("{}", line);
+    }
+
+    let names: Vec<String> = conn
+        .builtins
+        .iter()
+        .filter_map(|b| b.to_possible_value())
+        .map(|v| v.get_name().to_string())
+        .collect();
+    println!("Built-in checks: {}", names.join(", "));
+    if !conn.checks.is_empty() {
+        let custom: Vec<&str> = conn.checks.iter().map(|c| c.name.as_str()).collect();
+        println!("Custom checks: {}", custom.join(", "));
+    }
+    ExitCode::SUCCESS
+}
+
+/// Reads the root certificate, and the client certificate and key, `target` connects with: its
+/// hosts file line's or else the command line's. `read` keeps what each file came to, so files
+/// shared by many hosts are read once
+fn tls_files(
+    cli: &Cli,
+    target: &Target,
+    read: &mut HashMap<String, Result<(), String>>,
+) -> Vec<(String, Result<(), String>)> {
+    let options = &target.options;
+    let root = options.sslrootcert.as_ref().or(cli.root_cert.as_ref());
+    let client = match (&options.client_cert, &cli.client_cert, &cli.client_key) {
+        (Some(pair), _, _) => Some(pair.clone()),
+        (None, Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+        _ => None,
+    };
+
+    let mut files = Vec::new();
+    if let Some(root) = root {
+        let name = root.display().to_string();
+        let res = read
+            .entry(name.clone())
+            .or_insert_with(|| match tls::read_roots(root) {
+                Ok(roots) if roots.is_empty() => Err("no certificates in it".to_string()),
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.to_string()),
             });
+        files.push((name, res.clone()));
+    }
+    if let Some(pair) = client {
+        let name = format!("{} and {}", pair.0.display(), pair.1.display());
+        let res = read.entry(name.clone()).or_insert_with(|| {
+            tls::client_config(Some(&pair), None)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+        files.push((name, res.clone()));
+    }
+    files
+}
 
-        if pool.is_err() {
+/// The password in `path`, without surrounding whitespace. Files every user may read are
+/// refused unless `insecure` is set.
+fn read_password_file(path: &Path, insecure: bool) -> io::Result<String> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o004 != 0 && !insecure {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is world-readable, run chmod o-r on it or pass --insecure-permissions",
+                path.display()
+            ),
+        ));
+    }
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+/// `interval` made up to `percent` percent shorter or longer at random
+fn jittered(interval: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return interval;
+    }
+    let spread = f64::from(percent) / 100.0;
+    interval.mul_f64(1.0 + rand::thread_rng().gen_range(-spread..=spread))
+}
+
+/// A failed check as printed after the run, the host and what failed on one line and the
+/// error on the next, or all of it as a line of JSON
+fn describe_error(format: ErrorFormat, e: &CheckError) -> String {
+    match format {
+        ErrorFormat::Text => format!("Error checking host: {} ({})\n{}", e.host(), e.kind(), e),
+        ErrorFormat::Json => e.to_json_line(),
+    }
+}
+
+/// Resolves once `after` has passed, never without one
+async fn deadline(after: Option<Duration>) -> io::Error {
+    match after {
+        Some(d) => {
+            time::sleep(d).await;
+            io::Error::new(io::ErrorKind::TimedOut, "deadline exceeded")
+        }
+        None => future::pending().await,
+    }
+}
+
+fn health_exit_code(health: Health, interrupted: bool) -> u8 {
+    match health {
+        _ if interrupted => EXIT_INTERRUPTED,
+        Health::Ok => 0,
+        Health::Degraded => EXIT_DEGRADED,
+        Health::Critical => EXIT_UNHEALTHY,
+    }
+}
+
+/// `check`'s exit code. What the hosts that answered show wins over hosts that didn't answer,
+/// including those the deadline gave up on, so a split brain found in time still exits 1
+fn exit_code(problems: &[String], unreachable: usize, interrupted: bool) -> u8 {
+    if interrupted {
+        EXIT_INTERRUPTED
+    } else if !problems.is_empty() {
+        EXIT_UNHEALTHY
+    } else if unreachable > 0 {
+        EXIT_UNREACHABLE
+    } else {
+        0
+    }
+}
+
+/// Checks every host like `check_hosts_stream`, handing each result to `on_result` as it
+/// completes. Returns the reachable hosts in hosts file order with byte lag filled in
+async fn sweep(
+    probe: &impl HostProbe,
+    hosts: &[Target],
+    concurrency: usize,
+    cutoff: impl Future<Output = io::Error>,
+    mut on_result: impl FnMut(&str, Result<&Host, &CheckError>, Duration),
+) -> Vec<Host> {
+    // Results arrive in completion order, slot them back into hosts file order
+    let mut slots: Vec<Option<Host>> = (0..hosts.len()).map(|_| None).collect();
+    let mut reports = pin!(check_hosts_stream(probe, hosts, concurrency, cutoff));
+    while let Some(report) = reports.next().await {
+        on_result(&report.name, report.result.as_ref(), report.elapsed);
+        if let Ok(host) = report.result {
+            let i = hosts.iter().position(|t| t.name == report.name);
+            slots[i.expect("reports are for listed hosts")] = Some(host);
+        }
+    }
+
+    let mut res: Vec<Host> = slots.into_iter().flatten().collect();
+    compute_lag_bytes(&mut res);
+    res
+}
+
+/// The hosts missing from `res`, in hosts file order, with the reason each failed for and how
+/// many runs in a row it has, counting on from `previous` while the reason stays the same
+fn failures(
+    hosts: &[Target],
+    res: &[Host],
+    reasons: &HashMap<String, Reason>,
+    previous: &HashMap<String, (Reason, u32)>,
+) -> Vec<Failure> {
+    hosts
+        .iter()
+        .filter(|t| !res.iter().any(|r| r.name == t.name))
+        .filter_map(|t| {
+            let reason = *reasons.get(&t.name)?;
+            let failed_runs = match previous.get(&t.name) {
+                Some((last, runs)) if *last == reason => runs + 1,
+                _ => 1,
+            };
+            Some(Failure {
+                name: t.name.clone(),
+                reason,
+                failed_runs,
+            })
+        })
+        .collect()
+}
+
+/// Leaves the `n` standbys furthest behind, by bytes and then seconds, worst first
+fn keep_laggiest(res: &mut Vec<Host>, n: usize) {
+    res.retain(|r| !r.is_primary);
+    res.sort_by(|a, b| {
+        b.lag_bytes.cmp(&a.lag_bytes).then_with(|| {
+            b.lag_seconds
+                .partial_cmp(&a.lag_seconds)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+    });
+    res.truncate(n);
+}
+
+/// Describes hosts whose role or timeline changed since the last run that reached them
+fn find_changes_since(last: &BTreeMap<String, state::LastSeen>, res: &[Host]) -> Vec<String> {
+    res.iter()
+        .filter_map(|r| {
+            let seen = last.get(&r.name)?;
+            let mut changes = Vec::new();
+            if let (Some(before), Some(after)) = (seen.timeline_id, r.timeline_id) {
+                if before != after {
+                    changes.push(format!("from timeline {} to {}", before, after));
+                }
+            }
+            if seen.role != r.role.name() {
+                changes.push(format!("from {} to {}", seen.role, r.role.name()));
+            }
+            (!changes.is_empty()).then(|| {
+                let at = seen.checked_at.format(&Rfc3339).unwrap_or_default();
+                format!(
+                    "{} went {} since the last run at {}",
+                    r.name,
+                    changes.join(" and "),
+                    at
+                )
+            })
+        })
+        .collect()
+}
+
+/// Records the hosts this run reached, keeping the others as they were last seen
+fn remember(last: &mut BTreeMap<String, state::LastSeen>, res: &[Host]) {
+    for r in res {
+        let timeline_id = r
+            .timeline_id
+            .or_else(|| last.get(&r.name).and_then(|s| s.timeline_id));
+        let seen = state::LastSeen {
+            role: r.role.name().to_string(),
+            timeline_id,
+            checked_at: r.checked_at.unwrap_or_else(now),
+        };
+        last.insert(r.name.clone(), seen);
+    }
+}
+
+/// Everything `check` warns about in a sweep of `hosts`, by the thresholds in `args`
+async fn all_warnings(args: &WarningArgs, hosts: &[Target], res: &[Host]) -> Vec<String> {
+    let mut warnings = find_warnings(res, args.cert_expiry_warning);
+    warnings.extend(find_unexpected_upstreams(hosts, res).await);
+    warnings.extend(find_orphaned_slots(hosts, res));
+    warnings.extend(logical::find_warnings(res, args.subscription_lag_warning));
+    warnings.extend(clock::find_warnings(res, args.clock_skew_warning));
+    warnings
+}
+
+/// Describes inconsistencies between hosts, and certificates expiring within `cert_days`, that
+/// don't affect the exit code
+fn find_warnings(res: &[Host], cert_days: u64) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    // Corruption found on a standby matters most when picking one to promote
+    for r in res {
+        let Some(failures) = r.checksum_failures.filter(|n| *n > 0) else {
+            continue;
+        };
+        let last = match r
+            .checksum_last_failure
+            .and_then(|t| t.format(&Rfc3339).ok())
+        {
+            Some(t) => format!(", the last at {}", t),
+            None => String::new(),
+        };
+        warnings.push(format!(
+            "{} has had {} checksum failure{}{}",
+            r.name,
+            failures,
+            if failures == 1 { "" } else { "s" },
+            last
+        ));
+    }
+
+    // A paused standby looks connected and streaming while it falls further behind
+    for r in res.iter().filter(|r| r.replay_paused == Some(true)) {
+        warnings.push(format!(
+            "{} has WAL replay paused, it receives WAL but doesn't apply it",
+            r.name
+        ));
+    }
+
+    // The receiver process can outlive the connection to its upstream
+    for r in res.iter().filter(|r| !r.is_primary) {
+        let Some(status) = r
+            .walreceiver_status
+            .as_deref()
+            .filter(|s| *s != "streaming")
+        else {
+            continue;
+        };
+        let last = match r
+            .last_msg_receipt_time
+            .and_then(|t| t.format(&Rfc3339).ok())
+        {
+            Some(t) => format!(", it last heard from its upstream at {}", t),
+            None => String::new(),
+        };
+        warnings.push(format!("{}'s WAL receiver is {}{}", r.name, status, last));
+    }
+
+    // A standby can look connected while still on the timeline from before a promotion
+    for r in res.iter().filter(|r| !r.is_primary) {
+        let Some(received) = r.received_tli else {
+            continue;
+        };
+        let primary = res
+            .iter()
+            .find(|p| p.is_primary && p.system_identifier == r.system_identifier);
+        if let Some((primary, timeline)) = primary.and_then(|p| Some((p, p.timeline_id?))) {
+            if received != timeline {
+                warnings.push(format!(
+                    "{} receives timeline {} but its primary {} is on {}{}",
+                    r.name,
+                    received,
+                    primary.name,
+                    timeline,
+                    if received < timeline {
+                        ", it hasn't followed the promotion yet"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+        }
+    }
+
+    // A standby rebuilt without checksums while the primary has them on is easy to miss.
+    // Only its own cluster's primary counts, unrelated clusters may differ on purpose
+    for r in res
+        .iter()
+        .filter(|r| !r.is_primary && r.data_checksums == Some(false))
+    {
+        let Some(primary) = res.iter().find(|p| {
+            p.is_primary
+                && p.data_checksums == Some(true)
+                && p.system_identifier.is_some()
+                && p.system_identifier == r.system_identifier
+        }) else {
+            continue;
+        };
+        warnings.push(format!(
+            "{} has data checksums disabled but its primary {} has them enabled",
+            r.name, primary.name
+        ));
+    }
+
+    let now = ::time::OffsetDateTime::now_utc();
+    for r in res {
+        let Some(expires) = r.tls.as_ref().and_then(|t| t.cert_expires) else {
+            continue;
+        };
+        let days = (expires - now).whole_days();
+        if days < 0 {
+            warnings.push(format!(
+                "{}'s certificate expired {} days ago",
+                r.name, -days
+            ));
+        } else if days < cert_days as i64 {
+            warnings.push(format!(
+                "{}'s certificate expires in {} days ({})",
+                r.name,
+                days,
+                expires.date()
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Standbys streaming from neither their cluster's primary nor another of its hosts, like one
+/// still following the old primary after a failover. Names are compared by what they resolve
+/// to, since upstreams are usually configured by address.
+async fn find_unexpected_upstreams(hosts: &[Target], res: &[Host]) -> Vec<String> {
+    let mut resolved = HashMap::new();
+    let mut warnings = Vec::new();
+    for r in res.iter().filter(|r| !r.is_primary) {
+        let (Some(upstream), Some(id)) = (&r.upstream, r.system_identifier) else {
+            continue;
+        };
+        let cluster: Vec<&Host> = res
+            .iter()
+            .filter(|h| h.system_identifier == Some(id) && h.name != r.name)
+            .collect();
+        let Some(primary) = cluster.iter().find(|h| h.is_primary) else {
             continue;
+        };
+        let upstream_addrs = resolve_cached(&mut resolved, upstream).await;
+        let mut names = Vec::new();
+        for h in &cluster {
+            names.push(h.name.as_str());
+            names.extend(h.aliases.iter().map(String::as_str));
+            let target = hosts.iter().find(|t| t.name == h.name);
+            names.extend(target.iter().flat_map(|t| t.addresses()));
+        }
+        let mut expected = false;
+        for name in names {
+            expected = name.eq_ignore_ascii_case(upstream)
+                || resolve_cached(&mut resolved, name)
+                    .await
+                    .iter()
+                    .any(|a| upstream_addrs.contains(a));
+            if expected {
+                break;
+            }
+        }
+        if !expected {
+            warnings.push(format!(
+                "{} streams from {}, which is neither its cluster's primary {} nor another host \
+                 checked in it",
+                r.name, upstream, primary.name
+            ));
         }
+    }
+    warnings
+}
 
-        let pool = pool.unwrap();
+/// Inactive physical slots that no standby in the hosts file streams through, which keep WAL
+/// from being recycled until they're dropped. Standbys that couldn't be checked or didn't say
+/// which slot they use are taken to use the one named after them, as Patroni names them
+fn find_orphaned_slots(hosts: &[Target], res: &[Host]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for r in res {
+        for slot in r.slots.iter().filter(|s| !s.active) {
+            let same_cluster = |h: &Host| match (h.system_identifier, r.system_identifier) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            };
+            let used = res.iter().any(|h| {
+                h.slot_name.as_deref() == Some(slot.slot_name.as_str()) && same_cluster(h)
+            });
+            let named_after = hosts
+                .iter()
+                .filter(|t| {
+                    !res.iter()
+                        .any(|h| h.name == t.name && h.slot_name.is_some())
+                })
+                .flat_map(|t| std::iter::once(&t.name).chain(&t.aliases))
+                .flat_map(|n| [n.as_str(), n.split('.').next().unwrap_or(n)])
+                .any(|n| slots::slot_name_for(n) == slot.slot_name);
+            if used || named_after {
+                continue;
+            }
+            let retained = match slot.retained_bytes {
+                Some(bytes) => format!(", retaining {} bytes of WAL", bytes),
+                None => String::new(),
+            };
+            warnings.push(format!(
+                "{} has inactive replication slot {} that no standby in the hosts file streams \
+                 through{}",
+                r.name, slot.slot_name, retained
+            ));
+        }
+    }
+    warnings
+}
 
-        let is_primary = sqlx::query("SELECT pg_is_in_recovery();")
-            .map(|r: PgRow| {
-                let b: bool = r.get("pg_is_in_recovery");
-                !b
+/// The addresses `name` resolves to, none when it doesn't, looked up once per run
+async fn resolve_cached(cache: &mut HashMap<String, Vec<IpAddr>>, name: &str) -> Vec<IpAddr> {
+    if let Some(addrs) = cache.get(name) {
+        return addrs.clone();
+    }
+    let addrs: Vec<IpAddr> = match lookup_host((name, 0)).await {
+        Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+        Err(_) => Vec::new(),
+    };
+    cache.insert(name.to_string(), addrs.clone());
+    addrs
+}
+
+/// Everything that makes the run unhealthy
+fn find_problems(
+    health: &HealthArgs,
+    custom: &[checks::CustomCheck],
+    res: &[Host],
+    host_count: usize,
+) -> Vec<String> {
+    let mut problems = find_divergence(res);
+    problems.extend(find_strangers(res));
+    problems.extend(check_thresholds(health, res, host_count));
+    problems.extend(check_expectations(health, res));
+    for r in res {
+        problems.extend(checks::failures(custom, &r.name, &r.custom));
+    }
+    // The primary holds every commit until enough of the standbys it waits for confirm it
+    for r in res {
+        if let Some(missing) = r.sync.as_ref().and_then(|s| s.missing()) {
+            problems.push(format!("{} {}", r.name, missing));
+        }
+    }
+    problems
+}
+
+/// Describes hosts whose system identifier isn't their cluster's, restored from a backup of
+/// another cluster. A hosts file given with others is one cluster, whose primary has the right
+/// system identifier, or most of its hosts when it has no single primary. A flat hosts file may
+/// hold many clusters, so there a standby is only a stranger when no primary shares its system
+/// identifier
+fn find_strangers(res: &[Host]) -> Vec<String> {
+    let mut files: BTreeMap<Option<&String>, Vec<(&Host, i64)>> = BTreeMap::new();
+    for r in res {
+        if let Some(id) = r.system_identifier {
+            let file = r.tags.get(hosts::HOSTS_FILE_TAG);
+            files.entry(file).or_default().push((r, id));
+        }
+    }
+
+    let mut problems = Vec::new();
+    for (file, hosts) in &files {
+        let primaries: Vec<(&Host, i64)> = hosts
+            .iter()
+            .copied()
+            .filter(|(r, _)| r.is_primary)
+            .collect();
+        if file.is_none() && !primaries.is_empty() {
+            for (r, id) in hosts.iter().filter(|(r, _)| !r.is_primary) {
+                if !primaries.iter().any(|(_, p)| p == id) {
+                    problems.push(format!(
+                        "{} has system identifier {} but no primary in the hosts file has",
+                        r.name, id
+                    ));
+                }
+            }
+            continue;
+        }
+        match primaries.as_slice() {
+            [(primary, expected)] => {
+                for (r, id) in hosts.iter().filter(|(_, id)| id != expected) {
+                    problems.push(format!(
+                        "{} has system identifier {} but its primary {} has {}",
+                        r.name, id, primary.name, expected
+                    ));
+                }
+            }
+            _ => {
+                let Some(expected) = most_common(hosts.iter().map(|(_, id)| *id)) else {
+                    continue;
+                };
+                for (r, id) in hosts.iter().filter(|(_, id)| *id != expected) {
+                    problems.push(format!(
+                        "{} has system identifier {} but most hosts have {}",
+                        r.name, id, expected
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Describes split brains and diverged timelines among hosts sharing a system identifier
+fn find_divergence(res: &[Host]) -> Vec<String> {
+    let mut clusters: BTreeMap<i64, Vec<&Host>> = BTreeMap::new();
+    for r in res {
+        if let Some(id) = r.system_identifier {
+            clusters.entry(id).or_default().push(r);
+        }
+    }
+
+    let mut problems = Vec::new();
+    for (id, hosts) in &clusters {
+        let primaries: Vec<&str> = hosts
+            .iter()
+            .filter(|r| r.is_primary)
+            .map(|r| r.name.as_str())
+            .collect();
+        if primaries.len() > 1 {
+            problems.push(format!(
+                "split brain: {} are all primaries",
+                primaries.join(", ")
+            ));
+        }
+
+        let Some(expected) = expected_timeline(hosts) else {
+            continue;
+        };
+        let outliers: Vec<String> = hosts
+            .iter()
+            .filter_map(|r| match r.timeline_id {
+                Some(t) if t != expected => Some(format!("{} ({})", r.name, t)),
+                _ => None,
             })
-            .fetch_one(&pool);
+            .collect();
+        if !outliers.is_empty() {
+            problems.push(format!(
+                "timelines diverged in cluster {}: expected timeline {}; outliers: {}",
+                id,
+                expected,
+                outliers.join(", ")
+            ));
+            // Read from the primary when it's on the expected timeline
+            let reference = hosts
+                .iter()
+                .filter(|r| r.timeline_id == Some(expected) && r.timeline_history.is_some())
+                .max_by_key(|r| r.is_primary);
+            for r in hosts {
+                if let Some(fork) = reference.and_then(|reference| describe_fork(r, reference)) {
+                    problems.push(fork);
+                }
+            }
+        }
+    }
+    problems
+}
+
+fn most_common<T: Eq + std::hash::Hash + Copy>(values: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts = HashMap::new();
+    for v in values {
+        *counts.entry(v).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|&(_, c)| c).map(|(v, _)| v)
+}
 
-        let timeline_id = sqlx::query("SELECT timeline_id from pg_control_checkpoint();")
-            .map(|r: PgRow| {
-                let b: i32 = r.get("timeline_id");
-                b
+/// Where `r`'s timeline parted from `reference`'s, from their history files. `None` when
+/// they're on the same timeline or either history is unknown
+fn describe_fork(r: &Host, reference: &Host) -> Option<String> {
+    let (timeline, expected) = (r.timeline_id?, reference.timeline_id?);
+    if timeline == expected {
+        return None;
+    }
+    let Some(fork) = timeline::fork(
+        timeline,
+        r.timeline_history.as_ref()?,
+        expected,
+        reference.timeline_history.as_ref()?,
+    ) else {
+        return Some(format!(
+            "{} is on timeline {}, which shares no ancestor with timeline {}",
+            r.name, timeline, expected
+        ));
+    };
+    let lsn = timeline::format_lsn;
+    Some(match (fork.left, fork.other_left) {
+        (None, Some((at, next))) => {
+            let left = format!(
+                "{} is still on timeline {}, which the cluster left for timeline {} at {}",
+                r.name,
+                timeline,
+                next,
+                lsn(at)
+            );
+            match r.wal_lsn {
+                Some(pos) if pos > at => format!(
+                    "{}, and has WAL of its own past that up to {}; it needs pg_rewind to follow",
+                    left,
+                    lsn(pos)
+                ),
+                Some(_) => format!("{}, and hasn't gone past that; it can follow", left),
+                None => left,
+            }
+        }
+        (Some((at, next)), None) => format!(
+            "{} forked off timeline {} at {} onto timeline {}, which the cluster never followed",
+            r.name,
+            fork.ancestor,
+            lsn(at),
+            next
+        ),
+        (Some((at, next)), Some((cluster_at, cluster_next))) => format!(
+            "{} forked off timeline {} at {} onto timeline {}; the cluster left it at {} for timeline {}",
+            r.name,
+            fork.ancestor,
+            lsn(at),
+            next,
+            lsn(cluster_at),
+            cluster_next
+        ),
+        (None, None) => return None,
+    })
+}
+
+/// Sets each host's status for table output: diverged when it's on another timeline than its
+/// cluster or one of several primaries, lagging when a standby isn't streaming or is more than
+/// `LAGGING_SECONDS` behind
+fn mark_status(res: &mut [Host]) {
+    let mut statuses = Vec::new();
+    for r in res.iter() {
+        let cluster: Vec<&Host> = res
+            .iter()
+            .filter(|h| r.system_identifier.is_some() && h.system_identifier == r.system_identifier)
+            .collect();
+        let primaries = cluster.iter().filter(|h| h.is_primary).count();
+        let diverged = (r.is_primary && primaries > 1)
+            || expected_timeline(&cluster).is_some_and(|t| r.timeline_id.is_some_and(|h| h != t));
+        let lagging = !r.is_primary
+            && ((r.streaming == Some(false) && r.wal_source != Some(WalSource::Archive))
+                || r.lag_seconds.is_some_and(|s| s > LAGGING_SECONDS));
+        statuses.push(if diverged {
+            output::Status::Diverged
+        } else if lagging {
+            output::Status::Lagging
+        } else {
+            output::Status::Healthy
+        });
+    }
+    for (r, status) in res.iter_mut().zip(statuses) {
+        r.status = Some(status);
+    }
+}
+
+/// Describes where the results differ from the roles asserted on the command line
+fn check_expectations(health: &HealthArgs, res: &[Host]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let is = |r: &Host, name: &str| r.name == name || r.aliases.iter().any(|a| a == name);
+
+    for name in &health.expect_primary {
+        match res.iter().find(|r| is(r, name)) {
+            Some(r) if !r.is_primary => problems.push(format!(
+                "{} is in recovery but expected to be primary",
+                name
+            )),
+            Some(_) => {}
+            None => problems.push(format!(
+                "{} is expected to be primary but couldn't be checked",
+                name
+            )),
+        }
+    }
+    // Each expected primary speaks for the cluster it's in, so other clusters' primaries are
+    // left alone. Without the clusters of all of them, every other primary is suspect
+    let clusters: Option<Vec<i64>> = health
+        .expect_primary
+        .iter()
+        .map(|name| res.iter().find(|r| is(r, name))?.system_identifier)
+        .collect();
+    if !health.expect_primary.is_empty() {
+        for r in res.iter().filter(|r| r.is_primary) {
+            let expected: Vec<&str> = match (&clusters, r.system_identifier) {
+                (Some(ids), Some(id)) => health
+                    .expect_primary
+                    .iter()
+                    .zip(ids)
+                    .filter(|(_, c)| **c == id)
+                    .map(|(name, _)| name.as_str())
+                    .collect(),
+                _ => health.expect_primary.iter().map(String::as_str).collect(),
+            };
+            if !expected.is_empty() && !expected.iter().any(|name| is(r, name)) {
+                problems.push(format!(
+                    "{} is primary but {} expected",
+                    r.name,
+                    expected.join(", ")
+                ));
+            }
+        }
+    }
+
+    let (expected_replicas, unchecked) = expected_replicas(health, res);
+    problems.extend(unchecked);
+    for (id, expected) in expected_replicas {
+        let streaming = res
+            .iter()
+            .filter(|r| {
+                r.system_identifier == Some(id) && !r.is_primary && r.streaming == Some(true)
             })
-            .fetch_one(&pool);
+            .count();
+        if streaming != expected {
+            problems.push(format!(
+                "cluster {} has {} streaming standbys, expected {}",
+                id, streaming, expected
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Fills in each standby's byte lag against the furthest-ahead primary of the same cluster
+fn compute_lag_bytes(res: &mut [Host]) {
+    let mut primary_lsn: HashMap<i64, i64> = HashMap::new();
+    for r in res.iter().filter(|r| r.is_primary) {
+        if let (Some(id), Some(lsn)) = (r.system_identifier, r.wal_lsn) {
+            let e = primary_lsn.entry(id).or_insert(lsn);
+            *e = (*e).max(lsn);
+        }
+    }
+
+    for r in res.iter_mut().filter(|r| !r.is_primary) {
+        let primary = r.system_identifier.and_then(|id| primary_lsn.get(&id));
+        if let (Some(primary), Some(lsn)) = (primary, r.wal_lsn) {
+            r.lag_bytes = Some((primary - lsn).max(0));
+        }
+    }
+}
+
+/// Connects to `addr` with `pg` for a one-off query, assuming `role` if there is one
+async fn connect(
+    pg: &PgConnectOptions,
+    addr: &str,
+    role: Option<&str>,
+) -> Result<PgConnection, sqlx::Error> {
+    let mut db = PgConnection::connect_with(&pg.clone().host(addr)).await?;
+    if let Some(role) = role {
+        set_role(&mut db, role).await?;
+    }
+    Ok(db)
+}
 
-        let replica_attached = sqlx::query("SELECT EXISTS (select 1 from pg_stat_replication);")
-            .map(|r: PgRow| {
-                let b: bool = r.get("exists");
-                b
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake::{Fake, Server};
+    use hosts::HostOptions;
+    use sqlx::postgres::PgSslMode;
+    use std::sync::Mutex;
+    use timeline_check::{phases, Error, HostReport};
+    use transitions::Snapshot;
+
+    fn targets(names: &[&str]) -> Vec<Target> {
+        names
+            .iter()
+            .map(|name| Target {
+                name: name.to_string(),
+                aliases: Vec::new(),
+                addr: None,
+                fallbacks: Vec::new(),
+                options: HostOptions::default(),
             })
-            .fetch_one(&pool);
+            .collect()
+    }
+
+    /// A sweep the way `check` runs it, with the table statuses marked
+    async fn sweep_fake(fake: &Fake, hosts: &[Target]) -> Vec<Host> {
+        let mut res = sweep(fake, hosts, 4, future::pending(), |_, _, _| {}).await;
+        mark_status(&mut res);
+        res
+    }
+
+    fn verdicts(res: &[Host], host_count: usize) -> Vec<Verdict> {
+        let summary = Summary::new(res, host_count, Duration::ZERO);
+        summary.clusters.iter().map(|c| c.verdict).collect()
+    }
+
+    fn fleet() -> Fake {
+        Fake::default()
+            .with("db1", Server::primary(1))
+            .with("db2", Server::standby(1))
+            .with("db3", Server::standby(1))
+    }
+
+    #[tokio::test]
+    async fn a_caught_up_cluster_is_healthy() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let res = sweep_fake(&fleet(), &hosts).await;
+
+        let problems = find_problems(&HealthArgs::default(), &[], &res, hosts.len());
+        assert_eq!(problems, Vec::<String>::new());
+        assert_eq!(exit_code(&problems, 0, false), 0);
+        assert_eq!(verdicts(&res, hosts.len()), vec![Verdict::Healthy]);
+        assert!(res
+            .iter()
+            .all(|r| r.status == Some(output::Status::Healthy)));
+        assert_eq!(res[1].lag_bytes, Some(0));
+    }
+
+    #[tokio::test]
+    async fn each_cluster_gets_the_health_its_worst_finding_calls_for() {
+        let hosts = targets(&["db1", "db2", "db3", "db4", "db5"]);
+        let fake = fleet()
+            .with("db3", Server::standby(1).behind(4096))
+            .with("db4", Server::primary(1).in_cluster(2))
+            .with("db5", Server::primary(1).in_cluster(2));
+        let res = sweep_fake(&fake, &hosts).await;
+        let health = HealthArgs {
+            max_lag_bytes: Some(1024),
+            ..HealthArgs::default()
+        };
+
+        let mut summary = Summary::new(&res, hosts.len(), Duration::ZERO);
+        let health_of = |s: &Summary| s.clusters.iter().map(|c| c.health).collect::<Vec<_>>();
+        assert_eq!(health_of(&summary), vec![Health::Ok, Health::Critical]);
+        summary.judge(&health, &res);
+        assert_eq!(
+            health_of(&summary),
+            vec![Health::Degraded, Health::Critical]
+        );
+        assert_eq!(health_exit_code(summary.health, false), EXIT_UNHEALTHY);
+
+        // Without the split brain, the lagging standby and an unreachable host leave it degraded
+        fake.stop("db4");
+        let res = sweep_fake(&fake, &hosts).await;
+        let mut summary = Summary::new(&res, hosts.len(), Duration::ZERO);
+        summary.judge(&health, &res);
+        assert_eq!(summary.health, Health::Degraded);
+        assert_eq!(health_exit_code(summary.health, false), EXIT_DEGRADED);
+    }
+
+    #[tokio::test]
+    async fn clusters_in_one_hosts_file_are_judged_apart() {
+        let hosts = targets(&["db1", "db2", "db3", "db4", "db5"]);
+        let fake = fleet()
+            .with("db3", Server::primary(7).in_cluster(2))
+            .with("db4", Server::standby(7).in_cluster(2));
+        let health = HealthArgs {
+            expect_primary: vec!["db1".to_string()],
+            ..HealthArgs::default()
+        };
+
+        let res = sweep_fake(&fake, &hosts[..4]).await;
+        assert_eq!(find_problems(&health, &[], &res, 4), Vec::<String>::new());
+        assert_eq!(find_warnings(&res, 0), Vec::<String>::new());
+        assert_eq!(verdicts(&res, 4), vec![Verdict::Healthy, Verdict::Healthy]);
+
+        fake.set("db5", Server::standby(1).in_cluster(3));
+        let res = sweep_fake(&fake, &hosts).await;
+        assert_eq!(
+            find_problems(&health, &[], &res, 5),
+            vec!["db5 has system identifier 3 but no primary in the hosts file has"]
+        );
+    }
+
+    #[test]
+    fn hosts_restored_from_another_clusters_backup_are_problems() {
+        let host = |name: &str, is_primary, file: Option<&str>, id| {
+            let mut host = Host::default();
+            host.name = name.to_string();
+            host.is_primary = is_primary;
+            host.system_identifier = Some(id);
+            if let Some(file) = file {
+                host.tags.insert(hosts::HOSTS_FILE_TAG.into(), file.into());
+            }
+            host
+        };
+        let cases: [(&str, Vec<Host>, Vec<&str>); 5] = [
+            (
+                "two standbys from the same wrong backup",
+                vec![
+                    host("db1", true, None, 1),
+                    host("db2", false, None, 1),
+                    host("db3", false, None, 2),
+                    host("db4", false, None, 2),
+                ],
+                vec![
+                    "db3 has system identifier 2 but no primary in the hosts file has",
+                    "db4 has system identifier 2 but no primary in the hosts file has",
+                ],
+            ),
+            (
+                "standbys of a file compared with its primary",
+                vec![
+                    host("db1", true, Some("fra"), 1),
+                    host("db2", false, Some("fra"), 2),
+                    host("db3", false, Some("fra"), 2),
+                    host("db4", true, Some("ams"), 2),
+                ],
+                vec![
+                    "db2 has system identifier 2 but its primary db1 has 1",
+                    "db3 has system identifier 2 but its primary db1 has 1",
+                ],
+            ),
+            (
+                "a primary with the wrong system identifier",
+                vec![
+                    host("db1", true, Some("fra"), 1),
+                    host("db2", true, Some("fra"), 9),
+                    host("db3", false, Some("fra"), 1),
+                ],
+                vec!["db2 has system identifier 9 but most hosts have 1"],
+            ),
+            (
+                "most of the hosts without a primary",
+                vec![
+                    host("db1", false, None, 1),
+                    host("db2", false, None, 1),
+                    host("db3", false, None, 2),
+                ],
+                vec!["db3 has system identifier 2 but most hosts have 1"],
+            ),
+            (
+                "clusters of a flat hosts file",
+                vec![
+                    host("db1", true, None, 1),
+                    host("db2", false, None, 1),
+                    host("db3", true, None, 2),
+                    host("db4", false, None, 2),
+                ],
+                vec![],
+            ),
+        ];
+        for (case, res, expected) in cases {
+            assert_eq!(find_strangers(&res), expected, "{}", case);
+        }
+    }
+
+    #[test]
+    fn checksums_are_only_compared_with_the_standbys_own_primary() {
+        let host = |name: &str, is_primary, cluster, checksums| {
+            let mut host = Host::default();
+            host.name = name.to_string();
+            host.is_primary = is_primary;
+            host.system_identifier = Some(cluster);
+            host.data_checksums = Some(checksums);
+            host
+        };
+        let res = [
+            host("db1", true, 1, true),
+            host("db2", false, 1, false),
+            host("db3", true, 2, false),
+            host("db4", false, 2, false),
+        ];
+        assert_eq!(
+            find_warnings(&res, 0),
+            vec!["db2 has data checksums disabled but its primary db1 has them enabled"]
+        );
+    }
+
+    #[tokio::test]
+    async fn problems_found_before_the_deadline_outrank_the_hosts_it_gave_up_on() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let fake = fleet();
+        fake.hang("db3");
+        for (db2, code) in [
+            (Server::standby(1), EXIT_UNREACHABLE),
+            (Server::primary(2), EXIT_UNHEALTHY),
+        ] {
+            fake.set("db2", db2);
+            let cutoff = deadline(Some(Duration::from_millis(50)));
+            let res = sweep(&fake, &hosts, 4, cutoff, |_, _, _| {}).await;
+            let problems = find_problems(&HealthArgs::default(), &[], &res, hosts.len());
+            assert_eq!(res.len(), 2);
+            assert_eq!(exit_code(&problems, hosts.len() - res.len(), false), code);
+        }
+    }
+
+    #[tokio::test]
+    async fn promoting_a_standby_beside_its_primary_is_a_split_brain() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let fake = fleet();
+        let before = Snapshot::of(&hosts, &sweep_fake(&fake, &hosts).await);
+
+        fake.set("db2", Server::primary(2));
+        let res = sweep_fake(&fake, &hosts).await;
+
+        let problems = find_problems(&HealthArgs::default(), &[], &res, hosts.len());
+        assert_eq!(problems[0], "split brain: db1, db2 are all primaries");
+        assert_eq!(exit_code(&problems, 0, false), EXIT_UNHEALTHY);
+        assert_eq!(verdicts(&res, hosts.len()), vec![Verdict::SplitBrain]);
+        assert_eq!(res[1].status, Some(output::Status::Diverged));
+
+        let changes: Vec<String> = Snapshot::of(&hosts, &res)
+            .since(&before, now())
+            .into_iter()
+            .map(|t| format!("{}: {}", t.host, t.changes.join(", ")))
+            .collect();
+        assert_eq!(changes, vec!["db2: standby→primary, timeline 1→2"]);
+    }
+
+    #[tokio::test]
+    async fn a_standby_left_behind_by_a_failover_diverges() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let fake = fleet();
+        fake.stop("db1");
+        fake.set("db2", Server::primary(2));
+        let res = sweep_fake(&fake, &hosts).await;
+
+        let problems = find_problems(&HealthArgs::default(), &[], &res, hosts.len());
+        assert_eq!(
+            problems,
+            vec!["timelines diverged in cluster 1: expected timeline 2; outliers: db3 (1)"]
+        );
+        // Divergence outranks the unreachable primary
+        assert_eq!(exit_code(&problems, 1, false), EXIT_UNHEALTHY);
+        assert_eq!(verdicts(&res, hosts.len()), vec![Verdict::Diverged]);
+        assert_eq!(res[1].status, Some(output::Status::Diverged));
+    }
+
+    #[tokio::test]
+    async fn histories_tell_where_a_diverged_host_forked() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let fake = fleet();
+        fake.set("db1", Server::primary(2));
+        fake.set("db2", Server::standby(2));
+        let mut res = sweep_fake(&fake, &hosts).await;
+        let promoted = timeline::Switch {
+            timeline: 1,
+            switchpoint: 0x3000060,
+        };
+        res[0].timeline_history = Some(vec![promoted]);
+        res[2].timeline_history = Some(Vec::new());
+
+        // The old primary kept writing on timeline 1 after the promotion
+        res[2].wal_lsn = Some(0x3001000);
+        let problems = find_divergence(&res);
+        assert_eq!(
+            problems[1],
+            "db3 is still on timeline 1, which the cluster left for timeline 2 at 0/3000060, \
+            and has WAL of its own past that up to 0/3001000; it needs pg_rewind to follow"
+        );
 
-        let (is_primary, timeline_id, replica_attached) =
-            join!(is_primary, timeline_id, replica_attached);
+        res[2].timeline_id = Some(3);
+        res[2].timeline_history = Some(vec![timeline::Switch {
+            switchpoint: 0x2000000,
+            ..promoted
+        }]);
+        assert_eq!(
+            find_divergence(&res)[1],
+            "db3 forked off timeline 1 at 0/2000000 onto timeline 3; the cluster left it at \
+            0/3000060 for timeline 2"
+        );
+    }
 
-        res.push(Host {
-            name: host,
-            is_primary: is_primary.unwrap(),
-            timeline_id: timeline_id.unwrap(),
-            replica_attached: replica_attached.unwrap(),
+    #[tokio::test]
+    async fn unreachable_hosts_without_problems_exit_with_their_own_code() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let fake = fleet();
+        fake.stop("db3");
+        let mut failed = Vec::new();
+        let res = sweep(&fake, &hosts, 4, future::pending(), |_, res, _| {
+            if let Err(e) = res {
+                failed.push((e.host().to_string(), e.kind()));
+            }
         })
+        .await;
+
+        let problems = find_problems(&HealthArgs::default(), &[], &res, hosts.len());
+        assert_eq!(failed, vec![("db3".to_string(), "connect failed")]);
+        assert_eq!(
+            res.iter().map(|r| &r.name[..]).collect::<Vec<_>>(),
+            ["db1", "db2"]
+        );
+        assert_eq!(
+            Summary::new(&res, hosts.len(), Duration::ZERO).unreachable,
+            1
+        );
+        assert_eq!(exit_code(&problems, 1, false), EXIT_UNREACHABLE);
     }
 
-    for r in res {
-        println!(
-            "{}, {}, {}, {}",
-            r.name, r.is_primary, r.timeline_id, r.replica_attached
+    #[tokio::test]
+    async fn hosts_still_running_at_the_cutoff_come_last_with_its_reason() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let fake = fleet();
+        fake.hang("db1");
+        let cutoff = async {
+            time::sleep(Duration::from_millis(50)).await;
+            io::Error::new(io::ErrorKind::TimedOut, "deadline exceeded")
+        };
+        let reports: Vec<HostReport> = check_hosts_stream(&fake, &hosts, 4, cutoff).collect().await;
+
+        let names: Vec<&str> = reports.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[2], "db1");
+        assert!(reports[..2].iter().all(|r| r.result.is_ok()));
+        let e = reports[2].result.as_ref().unwrap_err();
+        assert_eq!(
+            (e.kind(), e.to_string()),
+            ("timed out", "deadline exceeded".into())
         );
     }
-}
 
-fn read_lines<P: AsRef<Path>>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>> {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+    #[test]
+    fn rates_of_connects_that_arent_positive_are_rejected() {
+        let options = CheckOptionsBuilder::new("monitor", "pw");
+        assert!(options.clone().max_connects_per_second(2.5).build().is_ok());
+        for n in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(
+                options
+                    .clone()
+                    .max_connects_per_second(n)
+                    .build()
+                    .unwrap_err(),
+                format!(
+                    "max_connects_per_second must be a positive number, not {}",
+                    n
+                )
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_hosts_connect_timeout_wins_over_the_default() {
+        let mut hosts = targets(&["db1", "db2"]);
+        hosts[1].options.connect_timeout = Some(Duration::from_secs(2));
+        let options = CheckOptionsBuilder::new("monitor", "pw")
+            .connect_timeout(Duration::from_secs(5))
+            .via_replication(true);
+
+        let timeouts = |mut conn: CheckOptions, target: Target| async move {
+            route(&mut conn, &target, &target.name).await.unwrap();
+            (
+                conn.connect_timeout,
+                conn.replication.unwrap().connect_timeout,
+            )
+        };
+        let five = Some(Duration::from_secs(5));
+        let two = Some(Duration::from_secs(2));
+        assert_eq!(
+            timeouts(options.build().unwrap(), hosts[0].clone()).await,
+            (five, five)
+        );
+        assert_eq!(
+            timeouts(options.build().unwrap(), hosts[1].clone()).await,
+            (two, two)
+        );
+    }
+
+    #[tokio::test]
+    async fn tls_probes_of_servers_that_never_answer_time_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accepts the connection and then says nothing
+        let silent = tokio::spawn(async move { listener.accept().await });
+
+        let probe = tls::probe(
+            "127.0.0.1",
+            port,
+            "db1",
+            None,
+            Some(Duration::from_millis(100)),
+        );
+        let e = time::timeout(Duration::from_secs(5), probe)
+            .await
+            .expect("the probe didn't time out")
+            .unwrap_err();
+        assert!(matches!(e, sqlx::Error::Io(e) if e.kind() == io::ErrorKind::TimedOut));
+        silent.abort();
+    }
+
+    #[test]
+    fn dry_runs_read_each_hosts_own_certificates() {
+        let dir =
+            std::env::temp_dir().join(format!("timeline_check_dry_run_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (root, cert, key) = (
+            dir.join("root.crt"),
+            dir.join("client.crt"),
+            dir.join("client.key"),
+        );
+        fs::write(&root, "not a certificate").unwrap();
+        fs::write(&cert, "").unwrap();
+
+        let cli =
+            Cli::try_parse_from(["timeline_check", "-u", "monitor", "-p", "pw", "report"]).unwrap();
+        let mut hosts = targets(&["db1", "db2", "db3"]);
+        hosts[1].options.sslrootcert = Some(root.clone());
+        hosts[2].options.sslrootcert = Some(root.clone());
+        hosts[2].options.client_cert = Some((cert.clone(), key.clone()));
+
+        let conn = CheckOptionsBuilder::new("monitor", "pw").build().unwrap();
+        let mut read = HashMap::new();
+        assert!(tls_files(&cli, &hosts[0], &mut read).is_empty());
+        let files = tls_files(&cli, &hosts[2], &mut read);
+        assert_eq!(
+            files[0],
+            (
+                root.display().to_string(),
+                Err("no certificates in it".to_string())
+            )
+        );
+        assert_eq!(
+            files[1].0,
+            format!("{} and {}", cert.display(), key.display())
+        );
+        assert!(files[1].1.is_err());
+        assert_eq!(read.len(), 2);
+        assert_eq!(dry_run(&cli, &conn, &hosts), ExitCode::FAILURE);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_hosts_client_certificate_wins_over_the_global_one() {
+        let mut hosts = targets(&["db1", "db2"]);
+        // Runs check the files are there before connecting
+        let dir = std::env::temp_dir().join(format!("timeline_check_certs_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let own = (dir.join("payments.crt"), dir.join("payments.key"));
+        for file in [&own.0, &own.1] {
+            fs::write(file, "").unwrap();
+        }
+        hosts[1].options.client_cert = Some(own.clone());
+        let options = CheckOptionsBuilder::new("monitor", "pw")
+            .tls(
+                Path::new("root.crt"),
+                Path::new("client.crt"),
+                Path::new("client.key"),
+            )
+            .via_replication(true);
+
+        let certs = |mut conn: CheckOptions, target: Target| async move {
+            route(&mut conn, &target, &target.name).await.unwrap();
+            let login = conn.replication.unwrap();
+            (conn.client_cert, login.client_cert, login.require_tls)
+        };
+        let global = (PathBuf::from("client.crt"), PathBuf::from("client.key"));
+        assert_eq!(
+            certs(options.build().unwrap(), hosts[0].clone()).await,
+            (Some(global.clone()), Some(global), true)
+        );
+        assert_eq!(
+            certs(options.build().unwrap(), hosts[1].clone()).await,
+            (Some(own.clone()), Some(own), true)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_full_checks_the_certificate_against_the_name_not_the_resolved_address() {
+        let mut hosts = targets(&["db1.example.com", "db2.example.com"]);
+        for target in &mut hosts {
+            target.options.sslmode = Some(PgSslMode::VerifyFull);
+        }
+        // As with --resolve db1.example.com=10.0.0.7
+        hosts[0].addr = Some("10.0.0.7".to_string());
+        let options = CheckOptionsBuilder::new("monitor", "pw").via_replication(true);
+
+        let verified = |mut conn: CheckOptions, target: Target| async move {
+            let addr = target.addresses()[0].to_string();
+            let (addr, _) = route(&mut conn, &target, &addr).await.unwrap();
+            let login = conn.replication.unwrap();
+            let sql_mode = format!("{:?}", conn.pg).contains("ssl_mode: VerifyFull");
+            (addr, conn.verify_name, login.server_name, sql_mode)
+        };
+        let name = |n: &str| Some(n.to_string());
+        assert_eq!(
+            verified(options.build().unwrap(), hosts[0].clone()).await,
+            (
+                "10.0.0.7".to_string(),
+                name("db1.example.com"),
+                name("db1.example.com"),
+                false
+            )
+        );
+        assert_eq!(
+            verified(options.build().unwrap(), hosts[1].clone()).await,
+            (
+                "db2.example.com".to_string(),
+                None,
+                name("db2.example.com"),
+                true
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn a_host_is_checked_at_the_first_address_that_answers() {
+        let mut target = targets(&["cluster-a"]).remove(0);
+        target.addr = Some("db1".to_string());
+        target.fallbacks = vec!["db2".to_string(), "db3".to_string()];
+        let refused = || Error::Io(io::ErrorKind::ConnectionRefused.into());
+
+        let answered = at_first_answering(&target, |addr| async move {
+            match addr {
+                "db1" => Err(refused()),
+                _ => Ok(addr),
+            }
+        })
+        .await;
+        assert_eq!(answered.unwrap(), "db2");
+
+        // Once a server answered, its failed checks are the outcome and the rest aren't tried
+        let tried = Mutex::new(Vec::new());
+        let failed: Result<(), _> = at_first_answering(&target, |addr| {
+            tried.lock().unwrap().push(addr);
+            async move {
+                match addr {
+                    "db1" => Err(refused()),
+                    _ => Err(Error::Check {
+                        name: "wal_senders".to_string(),
+                        source: sqlx::Error::RowNotFound,
+                    }),
+                }
+            }
+        })
+        .await;
+        assert!(matches!(failed, Err(Error::Check { .. })));
+        assert_eq!(*tried.lock().unwrap(), ["db1", "db2"]);
+
+        // So is a certificate file that can't be read, it's missing for every address alike
+        tried.lock().unwrap().clear();
+        let unreadable: Result<(), _> = at_first_answering(&target, |addr| {
+            tried.lock().unwrap().push(addr);
+            async move { Err(Error::Io(io::ErrorKind::NotFound.into())) }
+        })
+        .await;
+        assert!(matches!(unreadable, Err(Error::Io(_))));
+        assert_eq!(*tried.lock().unwrap(), ["db1"]);
+    }
+
+    #[tokio::test]
+    async fn failed_runs_count_on_while_the_reason_stays_the_same() {
+        let hosts = targets(&["db1", "db2", "db3"]);
+        let fake = fleet();
+        fake.stop("db2");
+        fake.stop("db3");
+        let mut reasons = HashMap::new();
+        let res = sweep(&fake, &hosts, 4, future::pending(), |name, res, _| {
+            if let Err(e) = res {
+                reasons.insert(name.to_string(), e.reason());
+            }
+        })
+        .await;
+
+        let previous = HashMap::from([
+            ("db2".to_string(), (Reason::TcpRefused, 2)),
+            ("db3".to_string(), (Reason::Auth, 5)),
+        ]);
+        let failed = failures(&hosts, &res, &reasons, &previous);
+        let runs: Vec<(&str, Reason, u32)> = failed
+            .iter()
+            .map(|f| (f.name.as_str(), f.reason, f.failed_runs))
+            .collect();
+        assert_eq!(
+            runs,
+            [
+                ("db2", Reason::TcpRefused, 3),
+                ("db3", Reason::TcpRefused, 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn slots_no_standby_in_the_hosts_file_uses_are_orphaned() {
+        let hosts = targets(&["db1", "db2", "db3.example.com"]);
+        let mut res = sweep_fake(&fleet(), &hosts[..2]).await;
+        let slot = |name: &str, active| slots::Slot {
+            slot_name: name.to_string(),
+            active,
+            retained_bytes: Some(1024),
+        };
+        res[0].slots = vec![
+            slot("db2_slot", false),
+            slot("db3", false),
+            slot("barman", true),
+            slot("old_db4", false),
+        ];
+        res[1].slot_name = Some("db2_slot".to_string());
+
+        // db3 couldn't be checked, the slot named after it is taken to be its
+        assert_eq!(
+            find_orphaned_slots(&hosts, &res),
+            [
+                "db1 has inactive replication slot old_db4 that no standby in the hosts file \
+              streams through, retaining 1024 bytes of WAL"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn results_read_back_from_json_serialize_the_same() {
+        let hosts = targets(&["db1", "db2"]);
+        let mut res = sweep_fake(&fleet(), &hosts).await;
+        res[1].custom = vec![("wal_senders".into(), Some("0".into())), ("a".into(), None)];
+        res[1].tags.insert("dc".into(), "fra".into());
+        res[1].phases = Some(phases::Phases {
+            tls: Some(Duration::from_millis(3)),
+            ..Default::default()
+        });
+        let summary = Summary::new(&res, hosts.len(), Duration::from_millis(250));
+
+        for host in &res {
+            let json = serde_json::to_value(host).unwrap();
+            let back: Host = serde_json::from_value(json.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&back).unwrap(), json);
+        }
+        let json = serde_json::to_value(&summary).unwrap();
+        let back: Summary = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&back).unwrap(), json);
+    }
+
+    #[tokio::test]
+    async fn reports_carry_either_the_host_or_the_error() {
+        let hosts = targets(&["db1", "db2"]);
+        let fake = fleet();
+        fake.stop("db2");
+        let reports: Vec<HostReport> = check_hosts_stream(&fake, &hosts, 4, future::pending())
+            .collect()
+            .await;
+        let json: BTreeMap<String, serde_json::Value> = reports
+            .iter()
+            .map(|r| (r.name.clone(), serde_json::to_value(r).unwrap()))
+            .collect();
+
+        assert_eq!(json["db1"]["host"]["role"], "primary");
+        assert!(json["db1"].get("error").is_none());
+        assert_eq!(json["db2"]["error"]["kind"], "connect failed");
+        assert!(json["db2"]["elapsed_seconds"].is_f64());
+    }
+
+    #[tokio::test]
+    async fn byte_lag_is_measured_against_the_clusters_primary() {
+        let hosts = targets(&["db1", "db2", "other"]);
+        let fake = fleet()
+            .with("db2", Server::standby(1).behind(4096))
+            .with("other", Server::primary(1).in_cluster(2));
+        let res = sweep_fake(&fake, &hosts).await;
+
+        let health = HealthArgs {
+            max_lag_bytes: Some(1024),
+            ..HealthArgs::default()
+        };
+        let problems = find_problems(&health, &[], &res, hosts.len());
+        assert_eq!(problems, vec!["db2 is 4096 bytes behind (max 1024)"]);
+        // A primary of another cluster is neither a split brain nor what db2 is behind
+        assert_eq!(
+            verdicts(&res, hosts.len()),
+            vec![Verdict::Healthy, Verdict::Healthy]
+        );
+    }
+
+    #[test]
+    fn a_sink_url_names_where_results_go() {
+        let sink = |url| sink::Sink::parse(url).map(|s| s.to_string());
+        assert_eq!(
+            sink("postgres://writer@metrics:5433/ops/health.results?sslmode=require"),
+            Ok("\"health\".\"results\" on metrics/ops".to_string())
+        );
+        assert_eq!(
+            sink("postgresql://metrics/ops"),
+            Ok("\"timeline_check_results\" on metrics/ops".to_string())
+        );
+        assert!(sink("postgres://metrics/ops/a/b").is_err());
+        assert_eq!(
+            sink("s3://audit/timeline_check/"),
+            Ok("s3://audit/timeline_check/".to_string())
+        );
+        assert!(sink("ftp://audit/results").is_err());
+
+        let table =
+            sink::Sink::parse("postgres://metrics/ops?options=-c%20work_mem%3D4MB").unwrap();
+        let debug = format!("{:?}", table);
+        assert!(debug.contains("statement_timeout=30s"), "{}", debug);
+        assert!(debug.contains("work_mem"), "{}", debug);
+    }
 }
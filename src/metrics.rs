@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    daemon::{unix_seconds, LastSweep},
+    error::Reason,
+};
+
+/// Renders the latest sweep in the Prometheus text exposition format
+pub fn render(last: Option<&LastSweep>) -> String {
+    let mut out = String::new();
+    let Some(last) = last else {
+        return out;
+    };
+
+    gauge(
+        &mut out,
+        "timeline_check_up",
+        "Whether the host could be checked",
+        last.hosts.iter().map(|t| {
+            let up = last.res.iter().any(|r| r.name == t.name);
+            (t.name.as_str(), &t.options.tags, Some(up as i64 as f64))
+        }),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP timeline_check_unreachable_hosts Hosts that couldn't be checked, by reason"
+    );
+    let _ = writeln!(out, "# TYPE timeline_check_unreachable_hosts gauge");
+    for reason in Reason::ALL {
+        let _ = writeln!(
+            out,
+            "timeline_check_unreachable_hosts{{reason=\"{}\"}} {}",
+            reason,
+            last.failed.iter().filter(|f| f.reason == reason).count()
+        );
+    }
+    gauge(
+        &mut out,
+        "timeline_check_is_primary",
+        "Whether the host is out of recovery",
+        last.res
+            .iter()
+            .map(|r| (r.name.as_str(), &r.tags, Some(r.is_primary as i64 as f64))),
+    );
+    gauge(
+        &mut out,
+        "timeline_check_timeline_id",
+        "Timeline of the latest checkpoint",
+        last.res
+            .iter()
+            .map(|r| (r.name.as_str(), &r.tags, r.timeline_id.map(f64::from))),
+    );
+    gauge(
+        &mut out,
+        "timeline_check_replica_attached",
+        "Whether any standby is streaming from the host",
+        last.res.iter().map(|r| {
+            (
+                r.name.as_str(),
+                &r.tags,
+                r.replica_attached.map(|b| b as i64 as f64),
+            )
+        }),
+    );
+    gauge(
+        &mut out,
+        "timeline_check_lag_bytes",
+        "Bytes of WAL the standby has yet to replay",
+        last.res
+            .iter()
+            .map(|r| (r.name.as_str(), &r.tags, r.lag_bytes.map(|b| b as f64))),
+    );
+    gauge(
+        &mut out,
+        "timeline_check_lag_seconds",
+        "Seconds since the standby's last replayed transaction",
+        last.res
+            .iter()
+            .map(|r| (r.name.as_str(), &r.tags, r.lag_seconds)),
+    );
+    gauge(
+        &mut out,
+        "timeline_check_clock_skew_seconds",
+        "Seconds the server's clock is ahead of the checking machine's, negative when behind",
+        last.res
+            .iter()
+            .map(|r| (r.name.as_str(), &r.tags, r.clock_skew_seconds)),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP timeline_check_healthy Whether the last sweep stayed within every threshold"
+    );
+    let _ = writeln!(out, "# TYPE timeline_check_healthy gauge");
+    let _ = writeln!(
+        out,
+        "timeline_check_healthy {}",
+        last.problems.is_empty() as i64
+    );
+    let _ = writeln!(
+        out,
+        "# HELP timeline_check_sweep_duration_seconds How long the last sweep took"
+    );
+    let _ = writeln!(out, "# TYPE timeline_check_sweep_duration_seconds gauge");
+    let _ = writeln!(
+        out,
+        "timeline_check_sweep_duration_seconds {}",
+        last.duration.as_secs_f64()
+    );
+    let _ = writeln!(
+        out,
+        "# HELP timeline_check_last_sweep_timestamp_seconds When the last sweep finished"
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE timeline_check_last_sweep_timestamp_seconds gauge"
+    );
+    let _ = writeln!(
+        out,
+        "timeline_check_last_sweep_timestamp_seconds {}",
+        unix_seconds(last.finished_at)
+    );
+
+    out
+}
+
+/// Writes one per-host gauge labelled with the host's tags, skipping hosts without a value
+fn gauge<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, &'a BTreeMap<String, String>, Option<f64>)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (host, tags, value) in values {
+        if let Some(v) = value {
+            let mut labels = format!("host=\"{}\"", escape(host));
+            for (k, v) in tags {
+                let _ = write!(labels, ",{}=\"{}\"", k, escape(v));
+            }
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, v);
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,897 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+
+use crate::{error::Reason, lag::LagStats, phases, Failure, Host, Summary};
+
+/// Bumped whenever a field in the JSON output is renamed, removed or changes meaning
+pub const SCHEMA_VERSION: u32 = 3;
+
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Format {
+    /// One comma separated line per host
+    #[default]
+    Csv,
+    /// A single versioned JSON document
+    Json,
+    /// One versioned JSON object per host and line
+    Ndjson,
+    /// InfluxDB line protocol, one point per host
+    Influx,
+    /// A Parquet file with a row per host, written to --output
+    Parquet,
+}
+
+/// How rows are laid out in table output
+pub struct Options {
+    /// Lead the default columns with each host's status, and list unreachable hosts too
+    pub status: bool,
+    /// Status as a glyph rather than a word
+    pub emoji: bool,
+    /// Every built-in column instead of the core four
+    pub wide: bool,
+    /// Add the recovery settings and replica lag columns
+    pub extended: bool,
+    /// Group rows by cluster under a verdict line
+    pub by_cluster: bool,
+    /// Add the encryption columns
+    pub tls: bool,
+    /// Add the phase timing columns
+    pub timings: bool,
+    /// Exactly these columns in this order instead, from `--columns`
+    pub columns: Option<Vec<Column>>,
+    /// A line of this shape per host instead of CSV, from `--template`
+    pub template: Option<Template>,
+    /// Between CSV fields, from `--delimiter`
+    pub delimiter: char,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    schema_version: u32,
+    hosts: &'a [Host],
+    summary: &'a Summary,
+}
+
+#[derive(Serialize)]
+struct NdjsonHost<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    host: &'a Host,
+}
+
+/// Prints a single host on its own, for formats that have one line per host
+pub fn print_host(format: Format, options: &Options, host: &Host) {
+    let _ = write_host(&mut io::stdout().lock(), format, options, host);
+}
+
+fn write_host(
+    out: &mut dyn Write,
+    format: Format,
+    options: &Options,
+    host: &Host,
+) -> io::Result<()> {
+    match format {
+        Format::Csv => match &options.template {
+            Some(template) => writeln!(out, "{}", template.render(host, options)),
+            None => writeln!(out, "{}", csv_row(host, options)),
+        },
+        Format::Ndjson => {
+            let line = NdjsonHost {
+                schema_version: SCHEMA_VERSION,
+                host,
+            };
+            writeln!(out, "{}", serde_json::to_string(&line).unwrap())
+        }
+        Format::Influx => writeln!(out, "{}", influx_line(host, unix_nanos())),
+        Format::Json => unreachable!("JSON output is a single document"),
+        Format::Parquet => unreachable!("Parquet output is a single file"),
+    }
+}
+
+pub fn print_report(format: Format, options: &Options, hosts: &[Host], summary: &Summary) {
+    let _ = write_report(&mut io::stdout().lock(), format, options, hosts, summary);
+}
+
+/// The rows and summary as `--format csv` prints them, on stderr for `--human`
+pub fn eprint_report(options: &Options, hosts: &[Host], summary: &Summary) {
+    let _ = write_report(
+        &mut io::stderr().lock(),
+        Format::Csv,
+        options,
+        hosts,
+        summary,
+    );
+}
+
+/// Only what `format` is parsed as on stdout, for `--human`: no cluster lines, unreachable rows
+/// or summary, which go to stderr
+pub fn print_rows(format: Format, options: &Options, hosts: &[Host], summary: &Summary) {
+    let out = &mut io::stdout().lock();
+    let _ = match format {
+        Format::Json => writeln!(out, "{}", json_report(hosts, summary)),
+        Format::Parquet => Ok(()),
+        _ => hosts
+            .iter()
+            .try_for_each(|r| write_host(out, format, options, r)),
+    };
+}
+
+fn write_report(
+    out: &mut dyn Write,
+    format: Format,
+    options: &Options,
+    hosts: &[Host],
+    summary: &Summary,
+) -> io::Result<()> {
+    match format {
+        Format::Csv if options.by_cluster => {
+            for (i, c) in summary.clusters.iter().enumerate() {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+                writeln!(out, "Cluster {}: {}, {}", c.label(), c.verdict, c.health)?;
+                for r in hosts
+                    .iter()
+                    .filter(|r| r.system_identifier == Some(c.system_identifier))
+                {
+                    write_host(out, format, options, r)?;
+                }
+            }
+            write_unreachable(out, options, summary)?;
+            writeln!(out)?;
+            write_summary(out, summary)
+        }
+        Format::Csv => {
+            for r in hosts {
+                write_host(out, format, options, r)?;
+            }
+            write_unreachable(out, options, summary)?;
+            writeln!(out)?;
+            write_summary(out, summary)
+        }
+        Format::Json => writeln!(out, "{}", json_report(hosts, summary)),
+        Format::Ndjson | Format::Influx => {
+            for r in hosts {
+                write_host(out, format, options, r)?;
+            }
+            // Keep stdout to host lines only so every line parses the same way
+            eprintln!("{}", serde_json::to_string(summary).unwrap());
+            Ok(())
+        }
+        // The rows went to the file already
+        Format::Parquet => write_summary(out, summary),
+    }
+}
+
+/// The `--format json` document
+pub fn json_report(hosts: &[Host], summary: &Summary) -> String {
+    let report = JsonReport {
+        schema_version: SCHEMA_VERSION,
+        hosts,
+        summary,
+    };
+    serde_json::to_string_pretty(&report).unwrap()
+}
+
+/// What `check` prints: everything that's wrong, then a one-line verdict
+pub fn print_verdict(warnings: &[String], problems: &[String], summary: &Summary) {
+    for w in warnings {
+        println!("Warning: {}", w);
+    }
+    for p in problems {
+        println!("Unhealthy: {}", p);
+    }
+    let verdict = if !problems.is_empty() {
+        "UNHEALTHY"
+    } else if summary.unreachable > 0 {
+        "INCOMPLETE"
+    } else {
+        "HEALTHY"
+    };
+    println!(
+        "{}: {} hosts, {} unreachable{}, {} problems, {}{}",
+        verdict,
+        summary.total_hosts,
+        summary.unreachable,
+        by_reason(&summary.failed),
+        problems.len(),
+        summary.health,
+        if summary.interrupted {
+            ", interrupted"
+        } else {
+            ""
+        }
+    );
+}
+
+/// ` (2 tls, 1 dns)`, the most common reason first, or nothing when every host was checked
+fn by_reason(failed: &[Failure]) -> String {
+    let mut counts: BTreeMap<Reason, usize> = BTreeMap::new();
+    for f in failed {
+        *counts.entry(f.reason).or_default() += 1;
+    }
+    if counts.is_empty() {
+        return String::new();
+    }
+    let mut counts: Vec<(Reason, usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+    let parts: Vec<String> = counts
+        .iter()
+        .map(|(reason, n)| format!("{} {}", n, reason))
+        .collect();
+    format!(" ({})", parts.join(", "))
+}
+
+fn write_summary(out: &mut dyn Write, s: &Summary) -> io::Result<()> {
+    let timelines: Vec<String> = s.timelines.iter().map(|t| t.to_string()).collect();
+    writeln!(out, "Total hosts:  {}", s.total_hosts)?;
+    writeln!(out, "Primaries:    {}", s.primaries)?;
+    writeln!(out, "Replicas:     {}", s.replicas)?;
+    writeln!(out, "Timelines:    {}", timelines.join(", "))?;
+    writeln!(
+        out,
+        "Unreachable:  {}{}",
+        s.unreachable,
+        by_reason(&s.failed)
+    )?;
+    writeln!(out, "Health:       {}", s.health)?;
+    if s.clusters.len() > 1 {
+        for c in &s.clusters {
+            writeln!(out, "  cluster {}: {}", c.label(), c.health)?;
+        }
+    }
+    if let Some(lag) = &s.lag {
+        writeln!(out, "Lag:          {}", lag_stats(lag))?;
+    }
+    // With a single cluster its lag is the fleet's
+    if s.clusters.len() > 1 {
+        for c in &s.clusters {
+            if let Some(lag) = &c.lag {
+                writeln!(out, "  cluster {}: {}", c.label(), lag_stats(lag))?;
+            }
+        }
+    }
+    writeln!(out, "Runtime:      {:.2}s", s.runtime_seconds)?;
+    if s.interrupted {
+        writeln!(
+            out,
+            "Run interrupted, hosts still being checked were left out"
+        )?;
+    }
+    Ok(())
+}
+
+/// `min 0, median 16, p95 2048, max 4096 bytes; min 0.0, ... seconds over 3 standbys`
+fn lag_stats(l: &LagStats) -> String {
+    let mut parts = Vec::new();
+    if let Some(b) = &l.bytes {
+        parts.push(format!(
+            "min {}, median {}, p95 {}, max {} bytes",
+            b.min, b.median, b.p95, b.max
+        ));
+    }
+    if let Some(s) = &l.seconds {
+        parts.push(format!(
+            "min {:.1}, median {:.1}, p95 {:.1}, max {:.1} seconds",
+            s.min, s.median, s.p95, s.max
+        ));
+    }
+    format!(
+        "{} over {} standby{}",
+        parts.join("; "),
+        l.standbys,
+        if l.standbys == 1 { "" } else { "s" }
+    )
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Tagged by host and cluster, the cluster being the system identifier hosts share
+fn influx_line(r: &Host, timestamp: u128) -> String {
+    let mut fields = vec![format!("is_primary={}", r.is_primary)];
+    if let Some(t) = r.timeline_id {
+        fields.push(format!("timeline_id={}i", t));
+    }
+    if let Some(a) = r.replica_attached {
+        fields.push(format!("replica_attached={}", a));
+    }
+    if let Some(s) = r.streaming {
+        fields.push(format!("streaming={}", s));
+    }
+    if let Some(b) = r.lag_bytes {
+        fields.push(format!("lag_bytes={}i", b));
+    }
+    if let Some(s) = r.lag_seconds {
+        fields.push(format!("lag_seconds={}", s));
+    }
+    if let Some(s) = r.clock_skew_seconds {
+        fields.push(format!("clock_skew_seconds={}", s));
+    }
+
+    let mut tags = r
+        .system_identifier
+        .map_or(String::new(), |id| format!(",cluster={}", id));
+    // InfluxDB rejects empty tag values
+    for (k, v) in r.tags.iter().filter(|(_, v)| !v.is_empty()) {
+        tags.push_str(&format!(",{}={}", k, escape_tag(v)));
+    }
+    format!(
+        "timeline_check,host={}{} {} {}",
+        escape_tag(&r.name),
+        tags,
+        fields.join(","),
+        timestamp
+    )
+}
+
+fn escape_tag(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn csv_row(r: &Host, options: &Options) -> String {
+    let fields: Vec<String> = match &options.columns {
+        Some(columns) => columns.iter().map(|c| c.value(r, options)).collect(),
+        None => Column::defaults(options, r)
+            .iter()
+            .map(|c| c.value(r, options))
+            .collect(),
+    };
+    // Commas keep the space after them that the output always had
+    match options.delimiter {
+        ',' => fields.join(", "),
+        d => fields.join(&d.to_string()),
+    }
+}
+
+/// A `--delimiter`: a single character, or `tab`, `semicolon` or `comma`
+pub fn parse_delimiter(s: &str) -> Result<char, String> {
+    let d = match s {
+        "tab" | "\\t" => '\t',
+        "semicolon" => ';',
+        "comma" => ',',
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err("expected a single character, tab or semicolon".to_string()),
+            }
+        }
+    };
+    if d == '"' || d == '\n' || d == '\r' {
+        return Err("quotes and line breaks can't separate fields".to_string());
+    }
+    Ok(d)
+}
+
+/// Rows for the hosts that couldn't be checked, when there's a status or reason column to mark
+/// them
+fn write_unreachable(out: &mut dyn Write, options: &Options, summary: &Summary) -> io::Result<()> {
+    let marked = |c: &Column| matches!(c, Column::Status | Column::Reason);
+    let status_shown = match (&options.columns, &options.template) {
+        (Some(columns), _) => columns.iter().any(marked),
+        (None, Some(template)) => template.columns().any(marked),
+        (None, None) => options.status,
+    };
+    if !status_shown {
+        return Ok(());
+    }
+    for failure in &summary.failed {
+        let host = Host {
+            name: failure.name.clone(),
+            status: Some(Status::Unreachable),
+            reason: Some(failure.reason),
+            ..Host::default()
+        };
+        write_host(out, Format::Csv, options, &host)?;
+    }
+    Ok(())
+}
+
+/// A host's state at a glance, from its row and the rest of its cluster
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Healthy,
+    /// A standby that isn't streaming or is far behind
+    Lagging,
+    /// On another timeline than its cluster, or one of several primaries
+    Diverged,
+    Unreachable,
+}
+
+impl Status {
+    fn label(self, emoji: bool) -> &'static str {
+        match (self, emoji) {
+            (Status::Healthy, true) => "✅",
+            (Status::Lagging, true) => "⚠",
+            (Status::Diverged | Status::Unreachable, true) => "❌",
+            (Status::Healthy, false) => "ok",
+            (Status::Lagging, false) => "lagging",
+            (Status::Diverged, false) => "diverged",
+            (Status::Unreachable, false) => "unreachable",
+        }
+    }
+}
+
+/// A column of table output, as named in `--columns`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    Status,
+    Host,
+    /// `primary` or `standby`
+    Role,
+    Primary,
+    Timeline,
+    ReplicaAttached,
+    Checksums,
+    ChecksumFailures,
+    SystemIdentifier,
+    TimelineAge,
+    /// Bytes behind the primary
+    Lag,
+    LagSeconds,
+    /// Bytes received but not replayed yet
+    ReplayGap,
+    WalSource,
+    WalreceiverStatus,
+    LastMsgReceiptTime,
+    Upstream,
+    ReceivedTli,
+    /// Seconds the server's clock is ahead of this machine's, negative when behind
+    ClockSkew,
+    Aliases,
+    Pooler,
+    /// Why an unreachable host couldn't be checked, e.g. `tls`
+    Reason,
+    /// Every tag as `key=value`, `;` separated
+    Tags,
+    CheckedAt,
+    CertExpires,
+    PrimaryConninfo,
+    PrimarySlotName,
+    RestoreCommand,
+    /// `NAME=write/flush/replay` lag in seconds for each standby streaming from the host, as it
+    /// sees them
+    ReplicaLag,
+    Encrypted,
+    TlsVersion,
+    Cipher,
+    DnsMs,
+    ConnectMs,
+    TlsMs,
+    AuthMs,
+    QueryMs,
+    /// The value of one tag, from `tag.NAME`
+    Tag(String),
+    /// Result of the `--checks` query with this name
+    Check(String),
+}
+
+impl Column {
+    /// Names other than the built-in ones, or their JSON field names, are taken for custom
+    /// checks, `main` makes sure they exist
+    pub fn parse(s: &str) -> Result<Column, String> {
+        let column = match s.trim() {
+            "status" => Column::Status,
+            "host" | "name" => Column::Host,
+            "role" => Column::Role,
+            "primary" | "is_primary" => Column::Primary,
+            "timeline" | "timeline_id" => Column::Timeline,
+            "replica_attached" => Column::ReplicaAttached,
+            "checksums" | "data_checksums" => Column::Checksums,
+            "checksum_failures" => Column::ChecksumFailures,
+            "system_identifier" => Column::SystemIdentifier,
+            "timeline_age" => Column::TimelineAge,
+            "lag" | "lag_bytes" => Column::Lag,
+            "lag_seconds" => Column::LagSeconds,
+            "replay_gap" | "replay_gap_bytes" => Column::ReplayGap,
+            "wal_source" => Column::WalSource,
+            "walreceiver_status" => Column::WalreceiverStatus,
+            "last_msg_receipt_time" => Column::LastMsgReceiptTime,
+            "upstream" => Column::Upstream,
+            "received_tli" => Column::ReceivedTli,
+            "clock_skew" | "clock_skew_seconds" => Column::ClockSkew,
+            "aliases" => Column::Aliases,
+            "pooler" => Column::Pooler,
+            "reason" => Column::Reason,
+            "tags" => Column::Tags,
+            "checked_at" => Column::CheckedAt,
+            "cert_expires" => Column::CertExpires,
+            "primary_conninfo" => Column::PrimaryConninfo,
+            "primary_slot_name" => Column::PrimarySlotName,
+            "restore_command" => Column::RestoreCommand,
+            "replica_lag" => Column::ReplicaLag,
+            "encrypted" => Column::Encrypted,
+            "tls_version" => Column::TlsVersion,
+            "cipher" => Column::Cipher,
+            "dns_ms" => Column::DnsMs,
+            "connect_ms" => Column::ConnectMs,
+            "tls_ms" => Column::TlsMs,
+            "auth_ms" => Column::AuthMs,
+            "query_ms" => Column::QueryMs,
+            "" => return Err("empty column name".to_string()),
+            name if name.starts_with("tag.") => Column::Tag(name["tag.".len()..].to_string()),
+            name => Column::Check(name.to_string()),
+        };
+        Ok(column)
+    }
+
+    /// The `report` flag that collects this column's data, if it's only collected on request
+    pub fn collected_by(&self) -> Option<&'static str> {
+        match self {
+            Column::CertExpires
+            | Column::PrimaryConninfo
+            | Column::PrimarySlotName
+            | Column::RestoreCommand
+            | Column::ReplicaLag => Some("extended"),
+            Column::Encrypted | Column::TlsVersion | Column::Cipher => Some("tls"),
+            Column::DnsMs
+            | Column::ConnectMs
+            | Column::TlsMs
+            | Column::AuthMs
+            | Column::QueryMs => Some("timings"),
+            _ => None,
+        }
+    }
+
+    /// The columns printed without `--columns`: the core four, or every built-in one with
+    /// `--wide`, then those the flags that collect more add and the custom checks
+    fn defaults(options: &Options, r: &Host) -> Vec<Column> {
+        let mut columns = match options.wide {
+            true => vec![
+                Column::Host,
+                Column::Primary,
+                Column::Timeline,
+                Column::ReplicaAttached,
+                Column::Checksums,
+                Column::ChecksumFailures,
+                Column::SystemIdentifier,
+                Column::TimelineAge,
+                Column::Lag,
+                Column::LagSeconds,
+                Column::ReplayGap,
+                Column::WalSource,
+                Column::WalreceiverStatus,
+                Column::LastMsgReceiptTime,
+                Column::Upstream,
+                Column::ReceivedTli,
+                Column::ClockSkew,
+                Column::Aliases,
+                Column::Pooler,
+                Column::Tags,
+                Column::CheckedAt,
+            ],
+            false => vec![Column::Host, Column::Role, Column::Timeline, Column::Lag],
+        };
+        if options.status {
+            columns.insert(0, Column::Status);
+        }
+        if options.extended {
+            columns.extend([
+                Column::CertExpires,
+                Column::PrimaryConninfo,
+                Column::PrimarySlotName,
+                Column::RestoreCommand,
+                Column::ReplicaLag,
+            ]);
+        }
+        if options.tls {
+            columns.extend([Column::Encrypted, Column::TlsVersion, Column::Cipher]);
+        }
+        if options.timings {
+            columns.extend([
+                Column::DnsMs,
+                Column::ConnectMs,
+                Column::TlsMs,
+                Column::AuthMs,
+                Column::QueryMs,
+            ]);
+        }
+        columns.extend(r.custom.iter().map(|(name, _)| Column::Check(name.clone())));
+        columns
+    }
+
+    /// The column's CSV field, `-` when the host has no value for it
+    fn value(&self, r: &Host, options: &Options) -> String {
+        // Connection strings, commands and query results can contain the delimiter, quote those
+        match self.text(r, options) {
+            Some(v) if v.contains([options.delimiter, '"']) => {
+                format!("\"{}\"", v.replace('"', "\"\""))
+            }
+            Some(v) => v,
+            None => "-".to_string(),
+        }
+    }
+
+    fn text(&self, r: &Host, options: &Options) -> Option<String> {
+        let recovery = r.recovery.as_ref();
+        let encryption = r.encryption.as_ref();
+        let phase = |f: fn(&phases::Phases) -> Option<Duration>| {
+            r.phases.as_ref().and_then(f).map(phases::format_millis)
+        };
+        match self {
+            Column::Status => r.status.map(|s| s.label(options.emoji).to_string()),
+            Column::Host => Some(r.name.clone()),
+            Column::Reason => r.reason.map(|r| r.name().to_string()),
+            // Nothing is known about an unreachable host but its name
+            _ if r.status == Some(Status::Unreachable) => None,
+            Column::Role => Some(r.role.name().to_string()),
+            Column::Primary => Some(r.is_primary.to_string()),
+            Column::Timeline => r.timeline_id.map(|t| t.to_string()),
+            Column::ReplicaAttached => r.replica_attached.map(|b| b.to_string()),
+            Column::Checksums => r.data_checksums.map(|b| b.to_string()),
+            Column::ChecksumFailures => r.checksum_failures.map(|n| n.to_string()),
+            Column::SystemIdentifier => r.system_identifier.map(|id| id.to_string()),
+            Column::TimelineAge => r.timeline_age.map(format_age),
+            Column::Lag => r.lag_bytes.map(|b| b.to_string()),
+            Column::LagSeconds => r.lag_seconds.map(|s| format!("{:.1}", s)),
+            Column::ReplayGap => r.replay_gap_bytes.map(|g| g.to_string()),
+            Column::WalSource => r.wal_source.map(|s| s.name().to_string()),
+            Column::WalreceiverStatus => r.walreceiver_status.clone(),
+            Column::LastMsgReceiptTime => r
+                .last_msg_receipt_time
+                .and_then(|t| t.format(&Rfc3339).ok()),
+            Column::Upstream => r.upstream.clone(),
+            Column::ReceivedTli => r.received_tli.map(|t| t.to_string()),
+            Column::ClockSkew => r.clock_skew_seconds.map(|s| format!("{:.3}", s)),
+            Column::Aliases if r.aliases.is_empty() => None,
+            Column::Aliases => Some(r.aliases.join(";")),
+            Column::Pooler => r.pooler.clone(),
+            Column::Tags if r.tags.is_empty() => None,
+            Column::Tags => Some(
+                r.tags
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(";"),
+            ),
+            Column::Tag(name) => r.tags.get(name).cloned(),
+            Column::CheckedAt => r.checked_at.and_then(|t| t.format(&Rfc3339).ok()),
+            Column::CertExpires => r
+                .tls
+                .as_ref()
+                .and_then(|t| t.cert_expires)
+                .map(|t| t.date().to_string()),
+            Column::PrimaryConninfo => recovery.and_then(|c| c.primary_conninfo.clone()),
+            Column::PrimarySlotName => recovery.and_then(|c| c.primary_slot_name.clone()),
+            Column::RestoreCommand => recovery.and_then(|c| c.restore_command.clone()),
+            Column::ReplicaLag if r.replicas.is_empty() => None,
+            Column::ReplicaLag => Some(
+                r.replicas
+                    .iter()
+                    .map(|replica| {
+                        let lags: Vec<String> = [
+                            replica.write_lag_seconds,
+                            replica.flush_lag_seconds,
+                            replica.replay_lag_seconds,
+                        ]
+                        .iter()
+                        .map(|lag| lag.map_or("-".to_string(), |s| format!("{:.3}", s)))
+                        .collect();
+                        format!("{}={}", replica.label(), lags.join("/"))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(";"),
+            ),
+            Column::Encrypted => encryption.map(|e| e.encrypted.to_string()),
+            Column::TlsVersion => encryption.and_then(|e| e.version.clone()),
+            Column::Cipher => encryption.and_then(|e| e.cipher.clone()),
+            Column::DnsMs => phase(|p| Some(p.dns)),
+            Column::ConnectMs => phase(|p| Some(p.connect)),
+            Column::TlsMs => phase(|p| p.tls),
+            Column::AuthMs => phase(|p| Some(p.auth)),
+            Column::QueryMs => phase(|p| Some(p.query)),
+            Column::Check(name) => r
+                .custom
+                .iter()
+                .find(|(n, _)| n == name)
+                .and_then(|(_, v)| v.clone()),
+        }
+    }
+}
+
+/// A line per host with `{column}` placeholders filled in from `--template`, `{{` and `}}` for
+/// literal braces
+#[derive(Clone, Debug)]
+pub struct Template(Vec<Piece>);
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Text(String),
+    Field(Column),
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, String> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed {{{}", name)),
+                        }
+                    }
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    pieces.push(Piece::Field(Column::parse(&name)?));
+                }
+                '}' => return Err("unmatched }, write }} for a literal one".to_string()),
+                c => text.push(c),
+            }
+        }
+        pieces.push(Piece::Text(text));
+        Ok(Template(pieces))
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = &Column> {
+        self.0.iter().filter_map(|p| match p {
+            Piece::Field(c) => Some(c),
+            Piece::Text(_) => None,
+        })
+    }
+
+    /// Fills in the placeholders unquoted, `-` for values the host lacks
+    fn render(&self, r: &Host, options: &Options) -> String {
+        self.0
+            .iter()
+            .map(|p| match p {
+                Piece::Text(t) => t.clone(),
+                Piece::Field(c) => c.text(r, options).unwrap_or_else(|| "-".to_string()),
+            })
+            .collect()
+    }
+}
+
+pub fn format_age(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    let (d, h, m, s) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (d, h, m) {
+        (0, 0, 0) => format!("{}s", s),
+        (0, 0, _) => format!("{}m{}s", m, s),
+        (0, _, _) => format!("{}h{}m", h, m),
+        _ => format!("{}d{}h", d, h),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(columns: &str, delimiter: char) -> Options {
+        Options {
+            extended: false,
+            by_cluster: false,
+            tls: false,
+            timings: false,
+            columns: Some(
+                columns
+                    .split(',')
+                    .map(|c| Column::parse(c).unwrap())
+                    .collect(),
+            ),
+            template: None,
+            wide: false,
+            status: false,
+            emoji: false,
+            delimiter,
+        }
+    }
+
+    fn host(value: &str) -> Host {
+        Host {
+            name: "db1".to_string(),
+            timeline_id: Some(3),
+            custom: vec![("note".to_string(), Some(value.to_string()))],
+            ..Host::default()
+        }
+    }
+
+    #[test]
+    fn fields_are_quoted_only_when_they_hold_the_delimiter_or_a_quote() {
+        for (value, delimiter, row) in [
+            ("plain", ',', "db1, 3, plain"),
+            ("a, b", ',', "db1, 3, \"a, b\""),
+            ("say \"hi\"", ',', "db1, 3, \"say \"\"hi\"\"\""),
+            ("a=b c", ',', "db1, 3, a=b c"),
+            ("a, b", ';', "db1;3;a, b"),
+            ("a;b", ';', "db1;3;\"a;b\""),
+            ("a\tb", '\t', "db1\t3\t\"a\tb\""),
+        ] {
+            let options = options("host,timeline,note", delimiter);
+            assert_eq!(csv_row(&host(value), &options), row, "{:?}", value);
+        }
+        // Columns without a value are a dash
+        assert_eq!(csv_row(&host(""), &options("host,lag", ',')), "db1, -");
+        assert_eq!(Column::parse(" ").unwrap_err(), "empty column name");
+    }
+
+    #[test]
+    fn delimiters_are_single_characters_or_named() {
+        assert_eq!(parse_delimiter("tab"), Ok('\t'));
+        assert_eq!(parse_delimiter("\\t"), Ok('\t'));
+        assert_eq!(parse_delimiter("semicolon"), Ok(';'));
+        assert_eq!(parse_delimiter("|"), Ok('|'));
+        assert!(parse_delimiter("||").is_err());
+        assert!(parse_delimiter("").is_err());
+        assert!(parse_delimiter("\"").is_err());
+    }
+
+    #[test]
+    fn templates_fill_in_columns_and_keep_literal_braces() {
+        let template = Template::parse("{host} tl={timeline_id} lag={lag} {{{note}}}").unwrap();
+        // Values go in as they are, templates don't quote
+        assert_eq!(
+            template.render(&host("a, \"b\""), &options("host", ',')),
+            "db1 tl=3 lag=- {a, \"b\"}"
+        );
+        assert_eq!(
+            Template::parse("{host").unwrap_err(),
+            "unclosed {host".to_string()
+        );
+        assert_eq!(
+            Template::parse("host}").unwrap_err(),
+            "unmatched }, write }} for a literal one".to_string()
+        );
+        assert_eq!(
+            Template::parse("{}").unwrap_err(),
+            "empty column name".to_string()
+        );
+    }
+
+    #[test]
+    fn influx_tags_escape_spaces_commas_and_equals_signs() {
+        let mut r = Host {
+            name: "db 1,a=b".to_string(),
+            is_primary: false,
+            timeline_id: Some(2),
+            system_identifier: Some(7),
+            lag_bytes: Some(16),
+            lag_seconds: Some(0.5),
+            ..Host::default()
+        };
+        r.tags.insert("dc".to_string(), "fra 1".to_string());
+        r.tags.insert("rack".to_string(), "a,b=c".to_string());
+        r.tags.insert("empty".to_string(), String::new());
+        assert_eq!(
+            influx_line(&r, 1_700_000_000_000_000_000),
+            "timeline_check,host=db\\ 1\\,a\\=b,cluster=7,dc=fra\\ 1,rack=a\\,b\\=c \
+             is_primary=false,timeline_id=2i,lag_bytes=16i,lag_seconds=0.5 1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn ages_are_shown_in_their_two_largest_units() {
+        assert_eq!(format_age(-5.0), "0s");
+        assert_eq!(format_age(59.9), "59s");
+        assert_eq!(format_age(61.0), "1m1s");
+        assert_eq!(format_age(3.0 * 3600.0 + 125.0), "3h2m");
+        assert_eq!(format_age(90_000.0), "1d1h");
+    }
+}
@@ -0,0 +1,31 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::{self, Instant};
+
+/// Spaces out new connections across all hosts, for `--max-connects-per-second`
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    /// When the next connection may be opened
+    next: Mutex<Instant>,
+}
+
+impl Pacer {
+    pub fn new(per_second: f64) -> Self {
+        Pacer {
+            interval: Duration::from_secs_f64(1.0 / per_second),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for this connection's turn, callers are let through in the order they asked
+    pub async fn wait(&self) {
+        let turn = {
+            let mut next = self.next.lock().unwrap();
+            let turn = (*next).max(Instant::now());
+            *next = turn + self.interval;
+            turn
+        };
+        time::sleep_until(turn).await;
+    }
+}
@@ -0,0 +1,260 @@
+use std::{io, path::Path};
+
+use crate::{Host, Summary};
+
+/// Writes one row per host to `path` for `--format parquet`, unreachable hosts last with only
+/// their name. Builds without the `parquet` feature refuse the format before any host is checked
+#[allow(unused_variables)]
+pub fn write(path: &Path, hosts: &[Host], summary: &Summary) -> io::Result<()> {
+    #[cfg(feature = "parquet")]
+    {
+        columns::write(path, hosts, summary)
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    Err(io::Error::other("built without the parquet feature"))
+}
+
+#[cfg(feature = "parquet")]
+mod columns {
+    use std::{fs::File, io, path::Path, sync::Arc};
+
+    use arrow_array::{
+        builder::{MapBuilder, StringBuilder},
+        ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+        TimestampMicrosecondArray, UInt32Array,
+    };
+    use arrow_schema::ArrowError;
+    use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+
+    use crate::{Host, Summary};
+
+    /// A host's name, and what was found out about it if it could be checked
+    type Row<'a> = (&'a str, Option<&'a Host>);
+
+    pub fn write(path: &Path, hosts: &[Host], summary: &Summary) -> io::Result<()> {
+        let batch = batch(hosts, summary).map_err(io::Error::other)?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(File::create(path)?, batch.schema(), Some(properties))
+                .map_err(io::Error::other)?;
+        writer.write(&batch).map_err(io::Error::other)?;
+        writer.close().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// The columns are named as in `--format json`. `swept_at`, when the run started, is the
+    /// same on every row so files from many runs can be told apart once loaded together
+    fn batch(hosts: &[Host], summary: &Summary) -> Result<RecordBatch, ArrowError> {
+        let rows: Vec<Row> = hosts
+            .iter()
+            .map(|h| (h.name.as_str(), Some(h)))
+            .chain(summary.failed.iter().map(|f| (f.name.as_str(), None)))
+            .collect();
+        let swept_at = micros(summary.checked_at);
+
+        RecordBatch::try_from_iter([
+            (
+                "swept_at",
+                timestamps(rows.iter().map(|_| Some(swept_at)).collect()),
+            ),
+            (
+                "name",
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|(n, _)| *n))) as ArrayRef,
+            ),
+            (
+                "reachable",
+                Arc::new(BooleanArray::from_iter(
+                    rows.iter().map(|(_, h)| Some(h.is_some())),
+                )),
+            ),
+            (
+                "reason",
+                strings(
+                    hosts
+                        .iter()
+                        .map(|_| None)
+                        .chain(summary.failed.iter().map(|f| Some(f.reason.name())))
+                        .collect(),
+                ),
+            ),
+            ("role", strings(each(&rows, |h| Some(h.role.name())))),
+            ("is_primary", booleans(each(&rows, |h| Some(h.is_primary)))),
+            ("timeline_id", u32s(each(&rows, |h| h.timeline_id))),
+            (
+                "system_identifier",
+                i64s(each(&rows, |h| h.system_identifier)),
+            ),
+            (
+                "timeline_age_seconds",
+                f64s(each(&rows, |h| h.timeline_age)),
+            ),
+            ("wal_lsn", i64s(each(&rows, |h| h.wal_lsn))),
+            ("lag_bytes", i64s(each(&rows, |h| h.lag_bytes))),
+            (
+                "replay_gap_bytes",
+                i64s(each(&rows, |h| h.replay_gap_bytes)),
+            ),
+            ("lag_seconds", f64s(each(&rows, |h| h.lag_seconds))),
+            (
+                "clock_skew_seconds",
+                f64s(each(&rows, |h| h.clock_skew_seconds)),
+            ),
+            ("streaming", booleans(each(&rows, |h| h.streaming))),
+            (
+                "replica_attached",
+                booleans(each(&rows, |h| h.replica_attached)),
+            ),
+            (
+                "wal_source",
+                strings(each(&rows, |h| h.wal_source.map(|s| s.name()))),
+            ),
+            ("upstream", strings(each(&rows, |h| h.upstream.as_deref()))),
+            ("received_tli", u32s(each(&rows, |h| h.received_tli))),
+            (
+                "walreceiver_status",
+                strings(each(&rows, |h| h.walreceiver_status.as_deref())),
+            ),
+            (
+                "data_checksums",
+                booleans(each(&rows, |h| h.data_checksums)),
+            ),
+            (
+                "checksum_failures",
+                i64s(each(&rows, |h| h.checksum_failures)),
+            ),
+            (
+                "server_version_num",
+                u32s(each(&rows, |h| h.server_version_num.map(|v| v.0))),
+            ),
+            ("pooler", strings(each(&rows, |h| h.pooler.as_deref()))),
+            (
+                "checked_at",
+                timestamps(each(&rows, |h| h.checked_at.map(micros))),
+            ),
+            (
+                "tags",
+                map(&rows, |h| {
+                    h.tags
+                        .iter()
+                        .map(|(k, v)| (k.as_str(), Some(v.as_str())))
+                        .collect()
+                })?,
+            ),
+            (
+                "checks",
+                map(&rows, |h| {
+                    h.custom
+                        .iter()
+                        .map(|(k, v)| (k.as_str(), v.as_deref()))
+                        .collect()
+                })?,
+            ),
+        ])
+    }
+
+    /// `f` of every host, NULL for the unreachable ones
+    fn each<'a, T>(rows: &[Row<'a>], f: impl Fn(&'a Host) -> Option<T>) -> Vec<Option<T>> {
+        rows.iter().map(|(_, h)| h.and_then(&f)).collect()
+    }
+
+    fn strings(values: Vec<Option<&str>>) -> ArrayRef {
+        Arc::new(StringArray::from(values))
+    }
+
+    fn booleans(values: Vec<Option<bool>>) -> ArrayRef {
+        Arc::new(BooleanArray::from(values))
+    }
+
+    fn u32s(values: Vec<Option<u32>>) -> ArrayRef {
+        Arc::new(UInt32Array::from(values))
+    }
+
+    fn i64s(values: Vec<Option<i64>>) -> ArrayRef {
+        Arc::new(Int64Array::from(values))
+    }
+
+    fn f64s(values: Vec<Option<f64>>) -> ArrayRef {
+        Arc::new(Float64Array::from(values))
+    }
+
+    fn timestamps(values: Vec<Option<i64>>) -> ArrayRef {
+        Arc::new(TimestampMicrosecondArray::from(values).with_timezone("UTC"))
+    }
+
+    fn micros(t: ::time::OffsetDateTime) -> i64 {
+        (t.unix_timestamp_nanos() / 1000) as i64
+    }
+
+    /// Name to value pairs from `f`, an empty map for hosts without any and NULL for the
+    /// unreachable ones
+    fn map<'a>(
+        rows: &[Row<'a>],
+        f: impl Fn(&'a Host) -> Vec<(&'a str, Option<&'a str>)>,
+    ) -> Result<ArrayRef, ArrowError> {
+        let mut builder = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
+        for (_, host) in rows {
+            for (k, v) in host.map(&f).unwrap_or_default() {
+                builder.keys().append_value(k);
+                builder.values().append_option(v);
+            }
+            builder.append(host.is_some())?;
+        }
+        Ok(Arc::new(builder.finish()))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{fs::File, time::Duration};
+
+        use arrow_array::{cast::AsArray, types::UInt32Type};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        use crate::{error::Reason, Failure, Host, Summary};
+
+        #[test]
+        fn unreachable_hosts_are_rows_of_nulls() {
+            let hosts = vec![Host {
+                name: "db1".to_string(),
+                is_primary: true,
+                timeline_id: Some(3),
+                ..Host::default()
+            }];
+            let mut summary = Summary::new(&hosts, 2, Duration::ZERO);
+            summary.failed = vec![Failure {
+                name: "db2".to_string(),
+                reason: Reason::Tls,
+                failed_runs: 1,
+            }];
+
+            let path =
+                std::env::temp_dir().join(format!("timeline_check-{}.parquet", std::process::id()));
+            super::write(&path, &hosts, &summary).unwrap();
+            let batch = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+                .unwrap()
+                .build()
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let names = batch.column_by_name("name").unwrap().as_string::<i32>();
+            assert_eq!(names.iter().collect::<Vec<_>>(), [Some("db1"), Some("db2")]);
+            let timelines = batch
+                .column_by_name("timeline_id")
+                .unwrap()
+                .as_primitive::<UInt32Type>();
+            assert_eq!(timelines.iter().collect::<Vec<_>>(), [Some(3), None]);
+            let reachable = batch.column_by_name("reachable").unwrap().as_boolean();
+            assert_eq!(
+                reachable.iter().collect::<Vec<_>>(),
+                [Some(true), Some(false)]
+            );
+            let reasons = batch.column_by_name("reason").unwrap().as_string::<i32>();
+            assert_eq!(reasons.iter().collect::<Vec<_>>(), [None, Some("tls")]);
+        }
+    }
+}
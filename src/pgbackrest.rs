@@ -0,0 +1,201 @@
+use std::io;
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{timeline, Host};
+
+/// A stanza as `pgbackrest info --output=json` describes it, with what the cross-check needs
+#[derive(Debug, Deserialize)]
+pub struct Stanza {
+    name: String,
+    /// Every cluster the stanza has backed up, more than one after a `stanza-upgrade`
+    #[serde(default)]
+    db: Vec<Database>,
+    #[serde(default)]
+    archive: Vec<Archive>,
+    #[serde(default)]
+    backup: Vec<Backup>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Database {
+    id: u32,
+    #[serde(rename = "system-id")]
+    system_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct DatabaseRef {
+    id: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Archive {
+    database: DatabaseRef,
+    /// The newest WAL file in the archive
+    max: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Backup {
+    label: String,
+    database: DatabaseRef,
+    archive: BackupArchive,
+    timestamp: Timestamps,
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupArchive {
+    /// The WAL file the backup started in, named after the timeline it was taken on
+    start: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Timestamps {
+    /// When the backup finished, in seconds since the epoch
+    stop: i64,
+}
+
+/// Runs `COMMAND info --output=json`, COMMAND split on whitespace, e.g.
+/// `sudo -u postgres pgbackrest --config=/etc/pgbackrest/prod.conf`
+pub async fn info(command: &str) -> io::Result<Vec<Stanza>> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command given"))?;
+    let output = Command::new(program)
+        .args(words)
+        .args(["info", "--output=json"])
+        .output()
+        .await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "{}: {}",
+            output.status,
+            stderr.trim()
+        )));
+    }
+    serde_json::from_slice(&output.stdout).map_err(io::Error::other)
+}
+
+/// Compares the latest timeline of every stanza's archive and backups with the timeline of the
+/// primary sharing its system identifier. A backup on an older timeline that finished after the
+/// primary's timeline began came from a host that didn't follow the failover
+pub fn cross_check(stanzas: &[Stanza], res: &[Host]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for primary in res.iter().filter(|r| r.is_primary) {
+        let (Some(id), Some(current)) = (primary.system_identifier, primary.timeline_id) else {
+            continue;
+        };
+        let began = primary
+            .checked_at
+            .zip(primary.timeline_age)
+            .map(|(at, age)| at.unix_timestamp() as f64 - age);
+        for stanza in stanzas {
+            // Stored unsigned, but SQL hands it out as a bigint
+            let Some(db) = stanza.db.iter().find(|d| d.system_id as i64 == id) else {
+                continue;
+            };
+
+            let archived = stanza
+                .archive
+                .iter()
+                .filter(|a| a.database.id == db.id)
+                .filter_map(|a| a.max.as_deref())
+                .max();
+            if let Some(max) = archived {
+                if let Some(archived_on) = timeline::from_hex(max).filter(|t| *t < current) {
+                    warnings.push(format!(
+                        "pgBackRest stanza {}'s archive ends at {} on timeline {}, but {} is on timeline {}",
+                        stanza.name, max, archived_on, primary.name, current
+                    ));
+                }
+            }
+
+            let latest = stanza
+                .backup
+                .iter()
+                .filter(|b| b.database.id == db.id)
+                .max_by_key(|b| b.timestamp.stop);
+            let Some(backup) = latest else {
+                continue;
+            };
+            let Some(taken_on) = backup.archive.start.as_deref().and_then(timeline::from_hex)
+            else {
+                continue;
+            };
+            if taken_on >= current {
+                continue;
+            }
+            if began.is_some_and(|began| backup.timestamp.stop as f64 > began) {
+                warnings.push(format!(
+                    "pgBackRest stanza {}'s latest backup {} was taken on timeline {} after {} moved to timeline {}, from a host that didn't follow the failover",
+                    stanza.name, backup.label, taken_on, primary.name, current
+                ));
+            } else {
+                warnings.push(format!(
+                    "pgBackRest stanza {} has no backup from {}'s timeline {} yet, the latest, {}, is from timeline {}",
+                    stanza.name, primary.name, current, backup.label, taken_on
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::now;
+
+    const INFO: &str = r#"[{
+        "name": "main",
+        "db": [{"id": 1, "repo-key": 1, "system-id": 7696488181148087071, "version": "15"}],
+        "archive": [{"database": {"id": 1, "repo-key": 1}, "id": "15-1",
+            "min": "000000010000000000000001", "max": "000000010000000000000009"}],
+        "backup": [
+            {"label": "20261013-010000F", "database": {"id": 1, "repo-key": 1}, "type": "full",
+                "archive": {"start": "000000010000000000000003", "stop": "000000010000000000000003"},
+                "timestamp": {"start": 1791853200, "stop": 1791853260}},
+            {"label": "20261014-010000F", "database": {"id": 1, "repo-key": 1}, "type": "full",
+                "archive": {"start": "000000010000000000000008", "stop": "000000010000000000000008"},
+                "timestamp": {"start": 1791939600, "stop": 1791939660}}
+        ],
+        "status": {"code": 0, "message": "ok"}
+    }]"#;
+
+    #[test]
+    fn backups_from_before_the_failover_are_flagged() {
+        let stanzas: Vec<Stanza> = serde_json::from_str(INFO).unwrap();
+        let mut primary = Host::default();
+        primary.name = "db2".to_string();
+        primary.is_primary = true;
+        primary.system_identifier = Some(7696488181148087071);
+        primary.timeline_id = Some(1);
+        primary.checked_at = Some(now());
+        assert_eq!(
+            cross_check(&stanzas, std::slice::from_ref(&primary)),
+            Vec::<String>::new()
+        );
+
+        // Promoted before the latest backup finished
+        primary.timeline_id = Some(2);
+        primary.checked_at = ::time::OffsetDateTime::from_unix_timestamp(1791940000).ok();
+        primary.timeline_age = Some(3600.0);
+        assert_eq!(
+            cross_check(&stanzas, std::slice::from_ref(&primary)),
+            vec![
+                "pgBackRest stanza main's archive ends at 000000010000000000000009 on timeline 1, but db2 is on timeline 2",
+                "pgBackRest stanza main's latest backup 20261014-010000F was taken on timeline 1 after db2 moved to timeline 2, from a host that didn't follow the failover",
+            ]
+        );
+
+        primary.timeline_age = Some(60.0);
+        assert_eq!(
+            cross_check(&stanzas, &[primary])[1],
+            "pgBackRest stanza main has no backup from db2's timeline 2 yet, the latest, 20261014-010000F, is from timeline 1"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Where the time connecting to and checking a host went, from `report --timings`. The
+/// connection phases come from a login of its own, sqlx doesn't expose them.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Phases {
+    /// Looking up the host's address
+    #[serde(
+        rename = "dns_ms",
+        serialize_with = "millis",
+        deserialize_with = "from_millis"
+    )]
+    pub dns: Duration,
+    /// Until the TCP connection was established
+    #[serde(
+        rename = "connect_ms",
+        serialize_with = "millis",
+        deserialize_with = "from_millis"
+    )]
+    pub connect: Duration,
+    /// The SSLRequest and TLS handshake, `None` when the server only speaks plain text
+    #[serde(
+        rename = "tls_ms",
+        serialize_with = "millis_option",
+        deserialize_with = "from_millis_option"
+    )]
+    pub tls: Option<Duration>,
+    /// From the startup message until the server was ready for queries
+    #[serde(
+        rename = "auth_ms",
+        serialize_with = "millis",
+        deserialize_with = "from_millis"
+    )]
+    pub auth: Duration,
+    /// Running the checks over an established connection
+    #[serde(
+        rename = "query_ms",
+        serialize_with = "millis",
+        deserialize_with = "from_millis"
+    )]
+    pub query: Duration,
+}
+
+pub fn format_millis(d: Duration) -> String {
+    format!("{:.1}", d.as_secs_f64() * 1000.0)
+}
+
+fn millis<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64() * 1000.0)
+}
+
+fn millis_option<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => millis(d, s),
+        None => s.serialize_none(),
+    }
+}
+
+fn from_millis<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    Duration::try_from_secs_f64(f64::deserialize(d)? / 1000.0).map_err(de::Error::custom)
+}
+
+fn from_millis_option<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    match Option::<f64>::deserialize(d)? {
+        Some(ms) => Duration::try_from_secs_f64(ms / 1000.0)
+            .map(Some)
+            .map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
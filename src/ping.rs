@@ -0,0 +1,90 @@
+use std::{
+    collections::HashMap,
+    io,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use sqlx::Connection;
+use tokio::{sync::Semaphore, task::JoinSet, time};
+
+use crate::{
+    at_first_answering, error::Error, hosts::Target, replication, route, tls, CheckOptions,
+    EXIT_UNREACHABLE,
+};
+
+/// How long a host may take to accept the login when its hosts file line doesn't say
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects and logs in to every host, runs `SELECT 1` and hangs up, without the replication
+/// queries and the rights they need. Prints one line per host in hosts file order and exits
+/// with 3 if any host failed.
+pub async fn run(conn: &CheckOptions, hosts: &[Target]) -> ExitCode {
+    let semaphore = Arc::new(Semaphore::new(conn.concurrency));
+    let mut tasks = JoinSet::new();
+    // A task that panicked only leaves its id behind, to tell which host it was pinging
+    let mut ids = HashMap::new();
+    for (i, target) in hosts.iter().cloned().enumerate() {
+        let (conn, semaphore) = (conn.clone(), semaphore.clone());
+        let task = tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.expect("semaphore closed");
+            // The wait for a connection slot isn't part of the host's latency
+            conn.pace().await;
+            let started = Instant::now();
+            let res = ping(&conn, &target).await;
+            (i, res.map(|()| started.elapsed()))
+        });
+        ids.insert(task.id(), i);
+    }
+
+    let mut outcomes = Vec::new();
+    while let Some(joined) = tasks.join_next_with_id().await {
+        outcomes.push(match joined {
+            Ok((_, outcome)) => outcome,
+            Err(e) => (ids[&e.id()], Err(e.into())),
+        });
+    }
+    outcomes.sort_unstable_by_key(|(i, _)| *i);
+
+    let mut code = ExitCode::SUCCESS;
+    for (i, outcome) in outcomes {
+        match outcome {
+            Ok(elapsed) => println!(
+                "{}: ok in {:.1}ms",
+                hosts[i].name,
+                elapsed.as_secs_f64() * 1000.0
+            ),
+            Err(e) => {
+                println!("{}: failed: {}", hosts[i].name, e);
+                code = ExitCode::from(EXIT_UNREACHABLE);
+            }
+        }
+    }
+    code
+}
+
+async fn ping(conn: &CheckOptions, target: &Target) -> Result<(), Error> {
+    at_first_answering(target, |addr| ping_at(conn.clone(), target, addr)).await
+}
+
+async fn ping_at(mut conn: CheckOptions, target: &Target, addr: &str) -> Result<(), Error> {
+    let (addr, port) = route(&mut conn, target, addr).await?;
+    let timeout = conn.connect_timeout.unwrap_or(PING_TIMEOUT);
+    let attempt = async {
+        if let Some(login) = &conn.replication {
+            return replication::probe(login, &addr, port).await;
+        }
+        if let Some(name) = &conn.verify_name {
+            tls::verify_name(&addr, port, name, conn.client_cert.as_ref()).await?;
+        }
+        let mut db = crate::connect(&conn.pg, &addr, conn.set_role.as_deref()).await?;
+        sqlx::query("SELECT 1").execute(&mut db).await?;
+        let _ = db.close().await;
+        Ok(())
+    };
+    match time::timeout(timeout, attempt).await {
+        Ok(res) => Ok(res?),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out").into()),
+    }
+}
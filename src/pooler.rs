@@ -0,0 +1,56 @@
+use sqlx::{postgres::PgConnectOptions, Connection, Executor, PgConnection, Row};
+
+/// Opens PgBouncer's admin console on the host. It only speaks the simple query protocol and
+/// rejects startup parameters it doesn't know, hence the plain `&str` queries.
+async fn admin_console(conn: &PgConnectOptions, addr: &str) -> Option<PgConnection> {
+    let options = conn
+        .clone()
+        .host(addr)
+        .database("pgbouncer")
+        .extra_float_digits(None);
+    PgConnection::connect_with(&options).await.ok()
+}
+
+/// The PgBouncer version if the host is a PgBouncer rather than a Postgres server
+pub async fn detect(conn: &PgConnectOptions, addr: &str) -> Option<String> {
+    let mut console = admin_console(conn, addr).await?;
+    let row = console.fetch_optional("SHOW VERSION").await.ok()??;
+    let version: String = row.try_get(0).ok()?;
+    let _ = console.close().await;
+    // Postgres itself has no `version` setting, its admin console would have failed above
+    version.starts_with("PgBouncer").then_some(version)
+}
+
+/// Address and port of the server PgBouncer forwards `database` to, taken from its open server
+/// connections and falling back to the configured databases
+pub async fn backing_server(
+    conn: &PgConnectOptions,
+    addr: &str,
+    database: &str,
+) -> Option<(String, u16)> {
+    let mut console = admin_console(conn, addr).await?;
+
+    let servers = console.fetch_all("SHOW SERVERS").await.ok()?;
+    let mut found = servers
+        .iter()
+        .filter(|r| r.try_get::<String, _>("database").ok().as_deref() == Some(database))
+        .find_map(|r| Some((r.try_get::<String, _>("addr").ok()?, port(r, "port")?)));
+
+    if found.is_none() {
+        let databases = console.fetch_all("SHOW DATABASES").await.ok()?;
+        found = databases
+            .iter()
+            .filter(|r| r.try_get::<String, _>("name").ok().as_deref() == Some(database))
+            .find_map(|r| Some((r.try_get::<String, _>("host").ok()?, port(r, "port")?)));
+    }
+    let _ = console.close().await;
+    found
+}
+
+/// The admin console's column types vary between versions, so accept integers and text
+fn port(row: &sqlx::postgres::PgRow, column: &str) -> Option<u16> {
+    match row.try_get::<i32, _>(column) {
+        Ok(p) => u16::try_from(p).ok(),
+        Err(_) => row.try_get::<String, _>(column).ok()?.parse().ok(),
+    }
+}
@@ -0,0 +1,38 @@
+use async_trait::async_trait;
+
+use crate::{connect_and_check, error::Error, hosts::Target, CheckOptions, Host, Pools};
+
+/// How a sweep finds out one host's state. Everything that judges the results only sees the
+/// `Host`s a probe hands back, so tests can sweep a `Fake` fleet instead of servers
+#[async_trait]
+pub trait HostProbe: Clone + Send + Sync + 'static {
+    async fn probe(&self, target: Target) -> Result<Host, Error>;
+
+    /// Called before each sweep with the hosts it covers
+    fn sweep_started(&self, _hosts: &[Target]) {}
+}
+
+/// Connects to the servers as the command line says, keeping connections in `pools` between
+/// sweeps
+#[derive(Clone)]
+pub struct Live {
+    conn: CheckOptions,
+    pools: Pools,
+}
+
+impl Live {
+    pub fn new(conn: CheckOptions, pools: Pools) -> Self {
+        Live { conn, pools }
+    }
+}
+
+#[async_trait]
+impl HostProbe for Live {
+    async fn probe(&self, target: Target) -> Result<Host, Error> {
+        connect_and_check(self.conn.clone(), &self.pools, target).await
+    }
+
+    fn sweep_started(&self, hosts: &[Target]) {
+        self.pools.retain(hosts);
+    }
+}
@@ -0,0 +1,52 @@
+use std::io;
+
+use keyring::Entry;
+
+/// Profiles are keyring entries of this service, named after the profile
+const SERVICE: &str = "timeline_check";
+
+/// Asks for the password without echoing it and stores it under `profile` in the OS keyring
+/// (Secret Service, Keychain or Credential Manager), replacing what was stored before
+pub async fn login(profile: &str) -> io::Result<()> {
+    let password = rpassword::prompt_password(format!("Password for profile {}: ", profile))?;
+    let password = password.trim().to_string();
+    if password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the password is empty",
+        ));
+    }
+    let profile = profile.to_string();
+    keyring_call(move || Entry::new(SERVICE, &profile)?.set_password(&password)).await
+}
+
+/// The password stored under `profile` by `login`
+pub async fn password(profile: &str) -> io::Result<String> {
+    let name = profile.to_string();
+    keyring_call(move || Entry::new(SERVICE, &name)?.get_password())
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no password stored for profile {}, run `timeline_check --profile {} login`",
+                    profile, profile
+                ),
+            ),
+            _ => e,
+        })
+}
+
+/// Runs a blocking keyring call off the runtime, since the Secret Service backend waits on
+/// D-Bus
+async fn keyring_call<T: Send + 'static>(
+    call: impl FnOnce() -> keyring::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let result = tokio::task::spawn_blocking(call)
+        .await
+        .map_err(io::Error::other)?;
+    result.map_err(|e| match e {
+        keyring::Error::NoEntry => io::Error::new(io::ErrorKind::NotFound, e),
+        e => io::Error::other(e),
+    })
+}
@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+const HIDDEN: &str = "********";
+
+/// Where a standby is configured to get its WAL from
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RecoveryConfig {
+    /// With any password replaced by `********`
+    pub primary_conninfo: Option<String>,
+    pub primary_slot_name: Option<String>,
+    pub restore_command: Option<String>,
+}
+
+/// The standby's recovery settings, `None` on primaries. Settings the role can't read, such as
+/// primary_conninfo without `pg_read_all_settings`, are left out.
+pub async fn fetch(pool: &PgPool) -> Result<Option<RecoveryConfig>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT name, setting FROM pg_settings
+        WHERE pg_is_in_recovery()
+            AND name IN ('primary_conninfo', 'primary_slot_name', 'restore_command');",
+    )
+    .fetch_all(pool)
+    .await?;
+    if rows.is_empty() {
+        return Ok(None);
+    }
+
+    let mut config = RecoveryConfig::default();
+    for r in &rows {
+        let name: String = r.get("name");
+        let setting = setting(r);
+        match name.as_str() {
+            "primary_conninfo" => config.primary_conninfo = setting.as_deref().map(redact),
+            "primary_slot_name" => config.primary_slot_name = setting,
+            "restore_command" => config.restore_command = setting,
+            _ => {}
+        }
+    }
+    Ok(Some(config))
+}
+
+fn setting(row: &PgRow) -> Option<String> {
+    let s: Option<String> = row.get("setting");
+    s.filter(|s| !s.is_empty())
+}
+
+/// Replaces the password in a `key=value` connection string or URI. Key/value strings come
+/// back normalized, quoted where needed.
+pub fn redact(conninfo: &str) -> String {
+    let conninfo = conninfo.trim();
+
+    let uri = conninfo.starts_with("postgresql://") || conninfo.starts_with("postgres://");
+    let (scheme, rest) = match conninfo.split_once("://") {
+        Some(parts) if uri => parts,
+        _ => return redact_pairs(conninfo),
+    };
+
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, tail) = rest.split_at(end);
+    let authority = match authority.rsplit_once('@') {
+        Some((userinfo, hosts)) => match userinfo.split_once(':') {
+            Some((user, _)) => format!("{}:{}@{}", user, HIDDEN, hosts),
+            None => authority.to_string(),
+        },
+        None => authority.to_string(),
+    };
+    // Query parameters can carry the password too
+    let tail = match tail.split_once('?') {
+        Some((path, query)) => {
+            let params: Vec<String> = query
+                .split('&')
+                .map(|p| match p.split_once('=') {
+                    Some(("password", _)) => format!("password={}", HIDDEN),
+                    _ => p.to_string(),
+                })
+                .collect();
+            format!("{}?{}", path, params.join("&"))
+        }
+        None => tail.to_string(),
+    };
+    format!("{}://{}{}", scheme, authority, tail)
+}
+
+fn redact_pairs(conninfo: &str) -> String {
+    let pairs: Vec<String> = conninfo_pairs(conninfo)
+        .into_iter()
+        .map(|(k, v)| match k.as_str() {
+            "password" => format!("{}={}", k, HIDDEN),
+            _ if v.is_empty() || v.contains([' ', '\'', '\\']) => {
+                format!("{}='{}'", k, v.replace('\\', "\\\\").replace('\'', "\\'"))
+            }
+            _ => format!("{}={}", k, v),
+        })
+        .collect();
+    pairs.join(" ")
+}
+
+/// Hosts and ports of a libpq connection string, either `key=value` pairs or a URI. Unix socket
+/// directories can't be reached from here and are left out.
+pub fn upstreams(conninfo: &str, default_port: u16) -> Vec<(String, u16)> {
+    let conninfo = conninfo.trim();
+    let (hosts, ports) = match conninfo
+        .strip_prefix("postgresql://")
+        .or_else(|| conninfo.strip_prefix("postgres://"))
+    {
+        Some(rest) => uri_hosts(rest),
+        None => {
+            let pairs = conninfo_pairs(conninfo);
+            let get = |key: &str| pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+            // hostaddr is what libpq actually connects to when both are given
+            let hosts = get("hostaddr").or_else(|| get("host")).unwrap_or_default();
+            let hosts = hosts.split(',').map(str::to_string).collect();
+            let ports = get("port").unwrap_or_default();
+            let ports = ports.split(',').map(str::to_string).collect();
+            (hosts, ports)
+        }
+    };
+
+    let port = |i: usize| {
+        // A single port applies to every host
+        let p = if ports.len() == 1 {
+            &ports[0]
+        } else {
+            ports.get(i).map_or("", String::as_str)
+        };
+        p.parse().unwrap_or(default_port)
+    };
+    hosts
+        .into_iter()
+        .enumerate()
+        .filter(|(_, h)| !h.is_empty() && !h.starts_with('/'))
+        .map(|(i, h)| (h, port(i)))
+        .collect()
+}
+
+/// The application_name a connection string sets, as a `key=value` pair or in a URI's query
+pub fn application_name(conninfo: &str) -> Option<String> {
+    let conninfo = conninfo.trim();
+    let name = match conninfo
+        .strip_prefix("postgresql://")
+        .or_else(|| conninfo.strip_prefix("postgres://"))
+    {
+        Some(rest) => rest
+            .split_once('?')?
+            .1
+            .split('&')
+            .find_map(|p| p.strip_prefix("application_name="))
+            .map(str::to_string),
+        None => conninfo_pairs(conninfo)
+            .into_iter()
+            .find(|(k, _)| k == "application_name")
+            .map(|(_, v)| v),
+    };
+    name.filter(|n| !n.is_empty())
+}
+
+/// `key=value` pairs, values optionally single quoted, with backslash escapes
+fn conninfo_pairs(conninfo: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = conninfo.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let key: String = chars
+            .by_ref()
+            .take_while(|&c| c != '=')
+            .collect::<String>()
+            .trim()
+            .to_string();
+        if key.is_empty() {
+            return pairs;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let quoted = chars.next_if_eq(&'\'').is_some();
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => value.extend(chars.next()),
+                '\'' if quoted => break,
+                c if c.is_whitespace() && !quoted => break,
+                c => value.push(c),
+            }
+        }
+        pairs.push((key, value));
+    }
+}
+
+/// Hosts and ports of a URI's authority, `user@host1:5432,[::1]:5433/db?...` after the scheme
+fn uri_hosts(rest: &str) -> (Vec<String>, Vec<String>) {
+    let authority = rest.split(['/', '?']).next().unwrap_or_default();
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+    authority
+        .split(',')
+        .map(|h| {
+            let (host, port) = match h.strip_prefix('[') {
+                Some(v6) => match v6.split_once(']') {
+                    Some((host, port)) => (host, port.trim_start_matches(':')),
+                    None => (v6, ""),
+                },
+                None => h.split_once(':').unwrap_or((h, "")),
+            };
+            (host.to_string(), port.to_string())
+        })
+        .unzip()
+}
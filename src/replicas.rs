@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+/// A standby streaming from this host, as the host's pg_stat_replication row has it. Roles
+/// without `pg_read_all_stats` only see the name, the rest is `None` for them
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Replica {
+    pub application_name: Option<String>,
+    pub client_addr: Option<String>,
+    /// `streaming`, `catchup`, `backup` and so on
+    pub state: Option<String>,
+    /// `async`, `potential`, `sync` or `quorum`
+    pub sync_state: Option<String>,
+    /// Seconds until recent WAL was written, flushed and replayed on the standby, as the host
+    /// last measured them. The server clears them once the standby is caught up and idle
+    pub write_lag_seconds: Option<f64>,
+    pub flush_lag_seconds: Option<f64>,
+    pub replay_lag_seconds: Option<f64>,
+    /// Bytes between what the host could send and what the standby replayed
+    pub replay_lag_bytes: Option<i64>,
+}
+
+/// Every standby streaming from the host, none on hosts without any. Cascading standbys send
+/// up to what they received or replayed, whichever is further
+pub async fn fetch(pool: &PgPool) -> Result<Vec<Replica>, sqlx::Error> {
+    sqlx::query(
+        "SELECT application_name, host(client_addr) AS client_addr, state, sync_state,
+            EXTRACT(EPOCH FROM write_lag)::float8 AS write_lag,
+            EXTRACT(EPOCH FROM flush_lag)::float8 AS flush_lag,
+            EXTRACT(EPOCH FROM replay_lag)::float8 AS replay_lag,
+            pg_wal_lsn_diff(CASE WHEN pg_is_in_recovery()
+                THEN greatest(pg_last_wal_receive_lsn(), pg_last_wal_replay_lsn())
+                ELSE pg_current_wal_lsn() END, replay_lsn)::int8 AS replay_lag_bytes
+        FROM pg_stat_replication
+        ORDER BY application_name, client_addr;",
+    )
+    .map(|r: PgRow| Replica {
+        application_name: r
+            .get::<Option<String>, _>("application_name")
+            .filter(|n| !n.is_empty()),
+        client_addr: r.get("client_addr"),
+        state: r.get("state"),
+        sync_state: r.get("sync_state"),
+        write_lag_seconds: r.get("write_lag"),
+        flush_lag_seconds: r.get("flush_lag"),
+        replay_lag_seconds: r.get("replay_lag"),
+        replay_lag_bytes: r.get("replay_lag_bytes"),
+    })
+    .fetch_all(pool)
+    .await
+}
+
+impl Replica {
+    /// How the standby is told apart in a column: its application_name, or its address
+    pub fn label(&self) -> &str {
+        self.application_name
+            .as_deref()
+            .or(self.client_addr.as_deref())
+            .unwrap_or("?")
+    }
+}
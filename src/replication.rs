@@ -0,0 +1,382 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use fallible_iterator::FallibleIterator;
+use postgres_protocol::{
+    authentication::{
+        md5_hash,
+        sasl::{ChannelBinding, ScramSha256, SCRAM_SHA_256},
+    },
+    message::{
+        backend::{ErrorResponseBody, Message},
+        frontend,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{phases::Phases, timeline, tls, version::Version, Host, Role};
+
+/// Credentials and TLS settings for replication connections, which sqlx can't open
+#[derive(Clone, Debug)]
+pub struct Login {
+    pub user: String,
+    pub password: String,
+    /// Database logged in to when not replicating
+    pub database: String,
+    pub port: u16,
+    /// Fail instead of falling back to plain text when the server doesn't offer TLS
+    pub require_tls: bool,
+    /// Client certificate and key files
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    /// What servers' certificates are verified against, any certificate goes without
+    pub verify: Option<tls::Verify>,
+    /// The host name certificates are for, when the address connected to is another one
+    pub server_name: Option<String>,
+    /// How long connecting and logging in may take
+    pub connect_timeout: Option<Duration>,
+    /// Shown for the session in pg_stat_activity and pg_stat_replication
+    pub application_name: String,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A replication connection, speaking just enough of the protocol for simple queries
+struct Connection {
+    stream: Box<dyn Stream>,
+    buf: BytesMut,
+    encryption: tls::Encryption,
+}
+
+/// Checks a host over a physical replication connection, for roles that may only replicate.
+/// IDENTIFY_SYSTEM gives the system identifier, timeline and WAL position, TIMELINE_HISTORY the
+/// timeline's ancestors and SHOW the rest that a replication connection can see; what needs SQL
+/// is left empty. With `timed` the time each phase took is recorded.
+pub async fn check_host(
+    login: &Login,
+    addr: &str,
+    name: String,
+    timed: bool,
+) -> Result<Host, sqlx::Error> {
+    let mut phases = Phases::default();
+    let mut conn = Connection::open_timed(login, addr, login.port, true, &mut phases).await?;
+    let started = Instant::now();
+
+    let identify = conn.simple_query("IDENTIFY_SYSTEM").await?;
+    let row = identify
+        .first()
+        .ok_or_else(|| protocol("IDENTIFY_SYSTEM returned no row"))?;
+    let field = |i: usize| row.get(i).cloned().flatten().unwrap_or_default();
+    // Stored unsigned, but SQL hands it out as a bigint, keep them comparable
+    let system_identifier = field(0)
+        .parse::<u64>()
+        .map_err(|_| protocol("invalid system identifier"))? as i64;
+    let timeline_id = timeline::parse(&field(1)).ok_or_else(|| protocol("invalid timeline"))?;
+    let wal_lsn = timeline::parse_lsn(&field(2));
+
+    // in_hot_standby only exists from Postgres 14, standbys are read only before that too
+    let in_recovery = match conn.show("in_hot_standby").await {
+        Ok(v) => v == "on",
+        Err(_) => conn.show("transaction_read_only").await? == "on",
+    };
+    let data_checksums = conn.show("data_checksums").await? == "on";
+    let version = conn
+        .show("server_version_num")
+        .await?
+        .parse()
+        .ok()
+        .map(Version);
+    // Timeline 1 has no history file to send
+    let timeline_history = if timeline_id == 1 {
+        Some(Vec::new())
+    } else {
+        let history = conn
+            .simple_query(&format!("TIMELINE_HISTORY {}", timeline_id))
+            .await;
+        history.ok().and_then(|rows| {
+            let content = rows.into_iter().next()?.into_iter().nth(1).flatten()?;
+            timeline::parse_history(&content)
+        })
+    };
+    let encryption = std::mem::replace(&mut conn.encryption, tls::Encryption::PLAIN_TEXT);
+    phases.query = started.elapsed();
+    conn.close().await;
+
+    Ok(Host {
+        name,
+        is_primary: !in_recovery,
+        // Over a replication connection there's no pg_stat_replication to tell cascades apart
+        role: Role::of(!in_recovery, None),
+        timeline_id: Some(timeline_id),
+        replica_attached: None,
+        data_checksums: Some(data_checksums),
+        checksum_failures: None,
+        checksum_last_failure: None,
+        system_identifier: Some(system_identifier),
+        timeline_age: None,
+        timeline_history,
+        wal_lsn,
+        streaming: None,
+        lag_bytes: None,
+        replay_gap_bytes: None,
+        replay_paused: None,
+        lag_seconds: None,
+        wal_source: None,
+        clock_skew_seconds: None,
+        clock_skew_error_seconds: None,
+        upstream: None,
+        received_tli: None,
+        walreceiver_status: None,
+        last_msg_receipt_time: None,
+        aliases: Vec::new(),
+        tags: BTreeMap::new(),
+        pooler: None,
+        recovery: None,
+        replicas: Vec::new(),
+        tls: None,
+        encryption: Some(encryption),
+        sync: None,
+        slots: Vec::new(),
+        slot_name: None,
+        logical: None,
+        phases: timed.then_some(phases),
+        status: None,
+        reason: None,
+        checked_at: Some(crate::now()),
+        server_version_num: version,
+        unsupported: BTreeMap::new(),
+        custom: Vec::new(),
+    })
+}
+
+/// Opens a replication connection and closes it right away, to see whether the server accepts one
+pub async fn probe(login: &Login, addr: &str, port: u16) -> Result<(), sqlx::Error> {
+    Connection::open(login, addr, port).await?.close().await;
+    Ok(())
+}
+
+/// Logs in, as a replication connection or to `login.database`, and hangs up, timing each phase
+/// on the way. The query phase is left for the caller.
+pub async fn time_login(
+    login: &Login,
+    addr: &str,
+    port: u16,
+    replication: bool,
+) -> Result<Phases, sqlx::Error> {
+    let mut phases = Phases::default();
+    Connection::open_timed(login, addr, port, replication, &mut phases)
+        .await?
+        .close()
+        .await;
+    Ok(phases)
+}
+
+impl Connection {
+    async fn open(login: &Login, addr: &str, port: u16) -> Result<Self, sqlx::Error> {
+        Self::open_timed(login, addr, port, true, &mut Phases::default()).await
+    }
+
+    async fn open_timed(
+        login: &Login,
+        addr: &str,
+        port: u16,
+        replication: bool,
+        phases: &mut Phases,
+    ) -> Result<Self, sqlx::Error> {
+        let connect = Self::connect(login, addr, port, replication, phases);
+        let Some(timeout) = login.connect_timeout else {
+            return connect.await;
+        };
+        tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?
+    }
+
+    async fn connect(
+        login: &Login,
+        addr: &str,
+        port: u16,
+        replication: bool,
+        phases: &mut Phases,
+    ) -> Result<Self, sqlx::Error> {
+        let started = Instant::now();
+        let addrs: Vec<_> = tokio::net::lookup_host((addr, port)).await?.collect();
+        phases.dns = started.elapsed();
+        let started = Instant::now();
+        let tcp = TcpStream::connect(&addrs[..]).await?;
+        phases.connect = started.elapsed();
+        let started = Instant::now();
+        let server_name = login.server_name.as_deref().unwrap_or(addr);
+        let (stream, encryption): (Box<dyn Stream>, _) = match tls::upgrade(
+            tcp,
+            server_name,
+            login.client_cert.as_ref(),
+            login.verify.as_ref(),
+        )
+        .await?
+        {
+            Ok(stream) => {
+                let encryption = tls::Encryption::of(stream.get_ref().1);
+                (Box::new(stream), encryption)
+            }
+            Err(_) if login.require_tls => return Err(protocol("server doesn't support TLS")),
+            Err(tcp) => (Box::new(tcp), tls::Encryption::PLAIN_TEXT),
+        };
+
+        phases.tls = encryption.encrypted.then(|| started.elapsed());
+
+        let mut conn = Connection {
+            stream,
+            buf: BytesMut::new(),
+            encryption,
+        };
+        let started = Instant::now();
+        conn.authenticate(login, replication).await?;
+        phases.auth = started.elapsed();
+        Ok(conn)
+    }
+
+    async fn authenticate(&mut self, login: &Login, replication: bool) -> Result<(), sqlx::Error> {
+        let session = match replication {
+            true => ("replication", "true"),
+            false => ("database", login.database.as_str()),
+        };
+        let params = [
+            ("user", login.user.as_str()),
+            session,
+            ("application_name", login.application_name.as_str()),
+        ];
+        let mut out = BytesMut::new();
+        frontend::startup_message(params, &mut out)?;
+        self.send(&out).await?;
+
+        let password = login.password.as_bytes();
+        let mut scram = None;
+        loop {
+            let mut out = BytesMut::new();
+            match self.recv().await? {
+                Message::AuthenticationOk => {}
+                Message::AuthenticationCleartextPassword => {
+                    frontend::password_message(password, &mut out)?;
+                }
+                Message::AuthenticationMd5Password(body) => {
+                    let hash = md5_hash(login.user.as_bytes(), password, body.salt());
+                    frontend::password_message(hash.as_bytes(), &mut out)?;
+                }
+                Message::AuthenticationSasl(body) => {
+                    if !body.mechanisms().any(|m| Ok(m == SCRAM_SHA_256))? {
+                        return Err(protocol("no supported SASL mechanism offered"));
+                    }
+                    let s = ScramSha256::new(password, ChannelBinding::unsupported());
+                    frontend::sasl_initial_response(SCRAM_SHA_256, s.message(), &mut out)?;
+                    scram = Some(s);
+                }
+                Message::AuthenticationSaslContinue(body) => {
+                    let s = scram
+                        .as_mut()
+                        .ok_or_else(|| protocol("unexpected SASL message"))?;
+                    s.update(body.data())?;
+                    frontend::sasl_response(s.message(), &mut out)?;
+                }
+                Message::AuthenticationSaslFinal(body) => {
+                    let s = scram
+                        .as_mut()
+                        .ok_or_else(|| protocol("unexpected SASL message"))?;
+                    s.finish(body.data())?;
+                }
+                Message::ErrorResponse(body) => return Err(server_error(body)),
+                Message::ReadyForQuery(_) => return Ok(()),
+                Message::ParameterStatus(_)
+                | Message::BackendKeyData(_)
+                | Message::NoticeResponse(_) => {}
+                _ => return Err(protocol("unsupported authentication method")),
+            }
+            if !out.is_empty() {
+                self.send(&out).await?;
+            }
+        }
+    }
+
+    /// Runs a query with the simple query protocol, every value as text
+    async fn simple_query(&mut self, query: &str) -> Result<Vec<Vec<Option<String>>>, sqlx::Error> {
+        let mut out = BytesMut::new();
+        frontend::query(query, &mut out)?;
+        self.send(&out).await?;
+
+        let mut rows = Vec::new();
+        let mut error = None;
+        loop {
+            match self.recv().await? {
+                Message::DataRow(body) => {
+                    let data = body.buffer();
+                    let row = body
+                        .ranges()
+                        .map(|r| Ok(r.map(|r| String::from_utf8_lossy(&data[r]).into_owned())))
+                        .collect()?;
+                    rows.push(row);
+                }
+                // Wait for ReadyForQuery so the connection can be used again after errors
+                Message::ErrorResponse(body) => error = Some(server_error(body)),
+                Message::ReadyForQuery(_) => break,
+                _ => {}
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(rows),
+        }
+    }
+
+    async fn show(&mut self, setting: &str) -> Result<String, sqlx::Error> {
+        let rows = self.simple_query(&format!("SHOW {}", setting)).await?;
+        rows.into_iter()
+            .next()
+            .and_then(|r| r.into_iter().next().flatten())
+            .ok_or_else(|| protocol("SHOW returned no value"))
+    }
+
+    async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data).await?;
+        self.stream.flush().await
+    }
+
+    async fn recv(&mut self) -> io::Result<Message> {
+        loop {
+            if let Some(message) = Message::parse(&mut self.buf)? {
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    async fn close(mut self) {
+        let mut out = BytesMut::new();
+        frontend::terminate(&mut out);
+        let _ = self.send(&out).await;
+    }
+}
+
+fn server_error(body: ErrorResponseBody) -> sqlx::Error {
+    let mut fields = body.fields();
+    let mut message = "server error".to_string();
+    while let Ok(Some(f)) = fields.next() {
+        if f.type_() == b'M' {
+            message = String::from_utf8_lossy(f.value_bytes()).into_owned();
+        }
+    }
+    protocol(&message)
+}
+
+fn protocol(message: &str) -> sqlx::Error {
+    sqlx::Error::Protocol(message.to_string())
+}
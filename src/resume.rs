@@ -0,0 +1,46 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::{version::Version, Host, Pools};
+
+/// Resumes WAL replay on every standby the sweep found with it paused, through the pool that
+/// checked it. Unless `yes`, asks on the terminal for each and leaves replay paused without one
+pub async fn run(pools: &Pools, res: &[Host], yes: bool) {
+    for host in res.iter().filter(|h| h.replay_paused == Some(true)) {
+        if !yes && !confirm(&format!("Resume WAL replay on {}?", host.name)) {
+            println!("Left WAL replay paused on {}", host.name);
+            continue;
+        }
+        // Checks over replication connections have no pool to run SQL through
+        let Some((pool, _)) = pools.get(&host.name) else {
+            eprintln!(
+                "Error resuming WAL replay on {}: it wasn't checked over SQL",
+                host.name
+            );
+            continue;
+        };
+        let version = host.server_version_num.unwrap_or(Version::PG10);
+        let resume = format!("SELECT {}();", version.wal("pg_wal_replay_resume"));
+        match sqlx::query(&resume).execute(&pool).await {
+            Ok(_) => println!("Resumed WAL replay on {}", host.name),
+            Err(e) => eprintln!("Error resuming WAL replay on {}: {}", host.name, e),
+        }
+    }
+}
+
+/// Asks a yes or no question on stderr, no when stdin isn't a terminal to answer on
+fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        eprintln!(
+            "{} Not without a terminal to answer on, pass --yes",
+            question
+        );
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,151 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::daemon::unix_seconds;
+
+/// When the daemon's `--history` file is moved aside to `FILE.1`, older ones shifting up to
+/// `FILE.<keep>` and the oldest deleted
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    /// Once it has grown to this many bytes
+    pub max_bytes: Option<u64>,
+    /// Once its first sweep is this old
+    pub max_age: Option<Duration>,
+    /// Rotated files kept next to the current one
+    pub keep: usize,
+}
+
+#[derive(Deserialize)]
+struct FirstRecord {
+    swept_at: f64,
+}
+
+impl Rotation {
+    /// Rotates `path` if it's due, before the next sweep is appended
+    pub fn rotate_if_due(&self, path: &Path) -> io::Result<()> {
+        let Ok(metadata) = fs::metadata(path) else {
+            return Ok(());
+        };
+        let too_big = self.max_bytes.is_some_and(|max| metadata.len() >= max);
+        let too_old = match self.max_age {
+            Some(max) if !too_big => first_sweep_age(path)?.is_some_and(|age| age >= max),
+            _ => false,
+        };
+        if too_big || too_old {
+            self.rotate(path)?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&self, path: &Path) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(path);
+        }
+        let _ = fs::remove_file(numbered(path, self.keep));
+        for n in (1..self.keep).rev() {
+            match fs::rename(numbered(path, n), numbered(path, n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(path, numbered(path, 1))
+    }
+}
+
+/// `history.ndjson.3`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// How long ago the file's first record was swept, `None` for an empty file or one that doesn't
+/// start with a history record, or with one swept at a time no clock shows
+fn first_sweep_age(path: &Path) -> io::Result<Option<Duration>> {
+    let mut line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut line)?;
+    let Ok(first) = serde_json::from_str::<FirstRecord>(&line) else {
+        return Ok(None);
+    };
+    let now = unix_seconds(std::time::SystemTime::now());
+    Ok(Duration::try_from_secs_f64((now - first.swept_at).max(0.0)).ok())
+}
+
+/// A size in bytes, optionally with a `K`, `M` or `G` suffix (powers of 1024)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.trim().to_ascii_uppercase() {
+        t if t.ends_with('K') => (t[..t.len() - 1].to_string(), 1 << 10),
+        t if t.ends_with('M') => (t[..t.len() - 1].to_string(), 1 << 20),
+        t if t.ends_with('G') => (t[..t.len() - 1].to_string(), 1 << 30),
+        t => (t, 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("{}: expected a size like 500K, 100M or 1G", s))?;
+    match n.checked_mul(unit) {
+        Some(0) => Err("must be above zero".to_string()),
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("{}: too large", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::{numbered, Rotation};
+
+    #[test]
+    fn old_files_shift_up_and_the_oldest_is_dropped() {
+        let dir =
+            std::env::temp_dir().join(format!("timeline_check-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.ndjson");
+        let rotation = Rotation {
+            max_age: Some(Duration::from_secs(3600)),
+            keep: 2,
+            ..Rotation::default()
+        };
+
+        for swept_at in [1.0, 2.0, 3.0] {
+            fs::write(&path, format!("{{\"swept_at\":{}}}\n", swept_at)).unwrap();
+            rotation.rotate_if_due(&path).unwrap();
+        }
+        // A file started within the hour stays where it is
+        let recent = super::unix_seconds(std::time::SystemTime::now());
+        fs::write(&path, format!("{{\"swept_at\":{}}}\n", recent)).unwrap();
+        rotation.rotate_if_due(&path).unwrap();
+
+        let read = |n| fs::read_to_string(numbered(&path, n)).ok();
+        assert!(path.exists());
+        assert_eq!(read(1).as_deref(), Some("{\"swept_at\":3}\n"));
+        assert_eq!(read(2).as_deref(), Some("{\"swept_at\":2}\n"));
+        assert_eq!(read(3), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_first_record_swept_at_no_real_time_is_left_alone() {
+        let dir =
+            std::env::temp_dir().join(format!("timeline_check-rotate-bad-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.ndjson");
+        let rotation = Rotation {
+            max_age: Some(Duration::from_secs(3600)),
+            keep: 1,
+            ..Rotation::default()
+        };
+
+        fs::write(&path, "{\"swept_at\":-1e300}\n").unwrap();
+        rotation.rotate_if_due(&path).unwrap();
+        assert!(path.exists());
+        assert!(!numbered(&path, 1).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
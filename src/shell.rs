@@ -0,0 +1,461 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, IsTerminal, Write},
+    net::IpAddr,
+    process::ExitCode,
+    time::Instant,
+};
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{
+    all_warnings, describe_error, failures, find_clusters, find_problems,
+    hosts::{HostSource, Target, HOSTS_FILE_TAG},
+    output, parse_expect_replicas,
+    probe::Live,
+    resolve_cached, sweep, timeline, CheckOptions, ErrorFormat, Failure, HealthArgs, Host, Pools,
+    Summary, WarningArgs,
+};
+
+const HELP: &str = "\
+check [CLUSTER|HOST...]     judge the fleet, or these clusters and hosts, like `check`
+lag [CLUSTER|HOST...]       how far behind each standby is
+topology [CLUSTER|HOST...]  who streams from whom, cluster by cluster
+expect primary HOST         hold `check` to HOST being its cluster's only primary
+expect replicas [HOST=]N    hold `check` to N standbys streaming, in HOST's cluster
+expect none                 forget the expectations, `expect` alone lists them
+hosts                       the hosts being checked
+reload                      read the hosts again
+quit                        leave, as does Ctrl-D
+
+A CLUSTER is the name of its hosts file, its system identifier or any of its hosts.";
+
+/// What the prompt keeps between commands: the probe and its pools, so that every command
+/// after the first reuses connections, and the latest result from each host
+struct Shell {
+    conn: CheckOptions,
+    probe: Live,
+    source: HostSource,
+    hosts: Vec<Target>,
+    health: HealthArgs,
+    /// The command line's thresholds for `check`'s warnings
+    warnings: WarningArgs,
+    errors: ErrorFormat,
+    /// Each host's latest result, which clusters are picked from
+    last: Vec<Host>,
+}
+
+/// Reads commands from stdin until `quit` or end of input, checking hosts through connections
+/// kept open between commands. Ctrl-C stops the command running, not the shell
+pub async fn run(
+    conn: CheckOptions,
+    source: HostSource,
+    hosts: Vec<Target>,
+    errors: ErrorFormat,
+    warnings: WarningArgs,
+) -> ExitCode {
+    let probe = Live::new(conn.clone(), Pools::default());
+    let mut shell = Shell {
+        conn,
+        probe,
+        source,
+        hosts,
+        health: HealthArgs::default(),
+        warnings,
+        errors,
+        last: Vec::new(),
+    };
+    let interactive = io::stdin().is_terminal();
+    if interactive {
+        println!("Connecting to {} hosts...", shell.hosts.len());
+    }
+    let all = shell.hosts.clone();
+    let (res, _) = shell.sweep(&all).await;
+    if interactive {
+        println!(
+            "{} of {} hosts checked, {} clusters. Type help for the commands",
+            res.len(),
+            all.len(),
+            find_clusters(&res).len()
+        );
+    }
+    shell.remember(&all, res);
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        if interactive {
+            print!("timeline_check> ");
+            let _ = io::stdout().flush();
+        }
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = tokio::signal::ctrl_c() => {
+                println!("\n(quit or Ctrl-D to leave)");
+                continue;
+            }
+        };
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Error reading commands: {}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => {}
+            ["quit" | "exit"] => break,
+            ["help" | "?"] => println!("{}", HELP),
+            ["check", ref names @ ..] => shell.check(names).await,
+            ["lag", ref names @ ..] => shell.lag(names).await,
+            ["topology", ref names @ ..] => shell.topology(names).await,
+            ["expect"] => shell.print_expectations(),
+            ["expect", "none"] => shell.health = HealthArgs::default(),
+            ["expect", "primary", host] => shell.expect_primary(host),
+            ["expect", "replicas", n] => match parse_expect_replicas(n) {
+                Ok(expected) => shell.health.expect_replicas.push(expected),
+                Err(e) => eprintln!("Error: {}", e),
+            },
+            ["hosts"] => {
+                for t in &shell.hosts {
+                    println!("{}", t.name);
+                }
+            }
+            ["reload"] => match shell.source.load().await {
+                Ok(hosts) => {
+                    println!("{} hosts, {} before", hosts.len(), shell.hosts.len());
+                    shell
+                        .last
+                        .retain(|r| hosts.iter().any(|t| t.name == r.name));
+                    shell.hosts = hosts;
+                }
+                Err(e) => eprintln!("Error reading the hosts, keeping the ones before: {}", e),
+            },
+            [command, ..] => eprintln!("Error: unknown command {}, try help", command),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+impl Shell {
+    /// Checks `targets`, printing why those that couldn't be checked failed. Ctrl-C cuts it
+    /// short, the hosts still being checked then count as unreachable
+    async fn sweep(&mut self, targets: &[Target]) -> (Vec<Host>, Vec<Failure>) {
+        let mut reasons = HashMap::new();
+        let errors = self.errors;
+        let res = sweep(
+            &self.probe,
+            targets,
+            self.conn.concurrency,
+            async {
+                let _ = tokio::signal::ctrl_c().await;
+                io::Error::new(io::ErrorKind::Interrupted, "interrupted")
+            },
+            |name, res, _| {
+                if let Err(e) = res {
+                    reasons.insert(name.to_string(), e.reason());
+                    eprintln!("{}", describe_error(errors, e));
+                }
+            },
+        )
+        .await;
+        let failed = failures(targets, &res, &reasons, &HashMap::new());
+        (res, failed)
+    }
+
+    /// Keeps `res` as the latest from `targets`, forgetting those of them that failed
+    fn remember(&mut self, targets: &[Target], res: Vec<Host>) {
+        self.last
+            .retain(|r| !targets.iter().any(|t| t.name == r.name));
+        self.last.extend(res);
+    }
+
+    /// The hosts `names` pick, every host without any. A name is a hosts file, a cluster's
+    /// system identifier or name, or a host, which picks its whole cluster once it's been seen
+    fn select(&self, names: &[&str]) -> Option<Vec<Target>> {
+        if names.is_empty() {
+            return Some(self.hosts.clone());
+        }
+        let clusters = find_clusters(&self.last);
+        let mut picked: Vec<&str> = Vec::new();
+        for name in names {
+            let from_file: Vec<&str> = self
+                .hosts
+                .iter()
+                .filter(|t| t.options.tags.get(HOSTS_FILE_TAG).map(String::as_str) == Some(name))
+                .map(|t| t.name.as_str())
+                .collect();
+            let cluster = clusters.iter().find(|c| {
+                c.system_identifier.to_string() == *name
+                    || c.name.as_deref() == Some(name)
+                    || c.hosts.iter().any(|h| h == name)
+                    || self
+                        .last
+                        .iter()
+                        .any(|r| c.hosts.contains(&r.name) && r.aliases.iter().any(|a| a == name))
+            });
+            if !from_file.is_empty() {
+                picked.extend(from_file);
+            } else if let Some(c) = cluster {
+                picked.extend(c.hosts.iter().map(String::as_str));
+            } else if let Some(t) = self.hosts.iter().find(|t| t.name == *name) {
+                picked.push(&t.name);
+            } else {
+                eprintln!("Error: no cluster or host {}", name);
+                return None;
+            }
+        }
+        Some(
+            self.hosts
+                .iter()
+                .filter(|t| picked.contains(&t.name.as_str()))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// What `check` prints, judged by the expectations set so far
+    async fn check(&mut self, names: &[&str]) {
+        let Some(targets) = self.select(names) else {
+            return;
+        };
+        let started = Instant::now();
+        let (res, failed) = self.sweep(&targets).await;
+        let warnings = all_warnings(&self.warnings, &self.hosts, &res).await;
+        let problems = find_problems(&self.health, &self.conn.checks, &res, targets.len());
+        let mut summary = Summary::new(&res, targets.len(), started.elapsed());
+        summary.failed = failed;
+        summary.judge(&self.health, &res);
+        output::print_verdict(&warnings, &problems, &summary);
+        self.remember(&targets, res);
+    }
+
+    /// One line per standby with how far behind its primary it is
+    async fn lag(&mut self, names: &[&str]) {
+        let Some(targets) = self.select(names) else {
+            return;
+        };
+        let (res, _) = self.sweep(&targets).await;
+        let standbys: Vec<&Host> = res.iter().filter(|r| !r.is_primary).collect();
+        if standbys.is_empty() {
+            println!("No standbys checked");
+        }
+        for r in standbys {
+            let mut notes = Vec::new();
+            if let Some(bytes) = r.lag_bytes {
+                notes.push(format!("{} bytes", bytes));
+            }
+            if let Some(secs) = r.lag_seconds {
+                notes.push(format!("{:.1}s", secs));
+            }
+            if let Some(gap) = r.replay_gap_bytes.filter(|g| *g > 0) {
+                notes.push(format!("{} bytes received but not replayed", gap));
+            }
+            if r.replay_paused == Some(true) {
+                notes.push("replay paused".to_string());
+            }
+            if r.streaming == Some(false) {
+                notes.push("not streaming".to_string());
+            }
+            if notes.is_empty() {
+                notes.push("lag not checked".to_string());
+            }
+            println!("{}: {}", r.name, notes.join(", "));
+        }
+        self.remember(&targets, res);
+    }
+
+    /// Each cluster as a tree from its primary down through cascading standbys, with the
+    /// standbys whose upstream isn't one of its hosts under what they stream from
+    async fn topology(&mut self, names: &[&str]) {
+        let Some(targets) = self.select(names) else {
+            return;
+        };
+        let (res, _) = self.sweep(&targets).await;
+        let mut resolved = HashMap::new();
+        for cluster in find_clusters(&res) {
+            let members: Vec<&Host> = res
+                .iter()
+                .filter(|r| cluster.hosts.contains(&r.name))
+                .collect();
+            let mut parents = HashMap::new();
+            for r in members.iter().filter(|r| !r.is_primary) {
+                if let Some(upstream) = &r.upstream {
+                    let parent =
+                        find_upstream(&mut resolved, &targets, &members, r, upstream).await;
+                    parents.insert(r.name.as_str(), parent.ok_or(upstream.as_str()));
+                }
+            }
+            println!("Cluster {}, {}", cluster.label(), cluster.verdict);
+            let roots = members
+                .iter()
+                .filter(|r| !parents.contains_key(r.name.as_str()));
+            for r in roots {
+                print_tree(&members, &parents, r, 1);
+            }
+            let mut outside: Vec<(&str, &str)> = parents
+                .iter()
+                .filter_map(|(name, p)| p.err().map(|upstream| (upstream, *name)))
+                .collect();
+            outside.sort_unstable();
+            for (upstream, name) in outside {
+                println!("  {} (not a host checked in this cluster)", upstream);
+                if let Some(r) = members.iter().find(|r| r.name == name) {
+                    print_tree(&members, &parents, r, 2);
+                }
+            }
+            let unresolved: Vec<&&Host> = members
+                .iter()
+                .filter(|r| in_cycle(&parents, &r.name))
+                .collect();
+            if !unresolved.is_empty() {
+                println!("  unresolved (upstreams form a cycle)");
+            }
+            for r in unresolved {
+                println!("    {}", describe(r));
+            }
+        }
+        for t in targets
+            .iter()
+            .filter(|t| !res.iter().any(|r| r.name == t.name))
+        {
+            println!("{}: unreachable", t.name);
+        }
+        for r in res.iter().filter(|r| r.system_identifier.is_none()) {
+            println!("{}: system identifier not checked", r.name);
+        }
+        self.remember(&targets, res);
+    }
+
+    fn expect_primary(&mut self, host: &str) {
+        if !self.hosts.iter().any(|t| t.name == host) {
+            eprintln!("Error: {} isn't one of the hosts", host);
+            return;
+        }
+        self.health.expect_primary.push(host.to_string());
+    }
+
+    fn print_expectations(&self) {
+        if self.health.expect_primary.is_empty() && self.health.expect_replicas.is_empty() {
+            println!("No expectations, `check` judges the fleet as it finds it");
+        }
+        for host in &self.health.expect_primary {
+            println!("expect primary {}", host);
+        }
+        for (host, n) in &self.health.expect_replicas {
+            match host {
+                Some(host) => println!("expect replicas {}={}", host, n),
+                None => println!("expect replicas {}", n),
+            }
+        }
+    }
+}
+
+/// The host among `members` that `standby`'s upstream names, by name, alias or address
+async fn find_upstream<'a>(
+    resolved: &mut HashMap<String, Vec<IpAddr>>,
+    targets: &[Target],
+    members: &[&'a Host],
+    standby: &Host,
+    upstream: &str,
+) -> Option<&'a Host> {
+    let upstream_addrs = resolve_cached(resolved, upstream).await;
+    for h in members.iter().filter(|h| h.name != standby.name) {
+        let mut names: Vec<&str> = vec![&h.name];
+        names.extend(h.aliases.iter().map(String::as_str));
+        let target = targets.iter().find(|t| t.name == h.name);
+        names.extend(target.iter().flat_map(|t| t.addresses()));
+        for name in names {
+            if name.eq_ignore_ascii_case(upstream)
+                || resolve_cached(resolved, name)
+                    .await
+                    .iter()
+                    .any(|a| upstream_addrs.contains(a))
+            {
+                return Some(h);
+            }
+        }
+    }
+    None
+}
+
+/// Whether following `name`'s upstreams never reaches a primary or a host outside the
+/// cluster, so no tree `topology` prints holds it
+fn in_cycle(parents: &HashMap<&str, Result<&Host, &str>>, name: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut at = name;
+    while let Some(Ok(parent)) = parents.get(at) {
+        if !seen.insert(at) {
+            return true;
+        }
+        at = &parent.name;
+    }
+    false
+}
+
+fn print_tree(
+    members: &[&Host],
+    parents: &HashMap<&str, Result<&Host, &str>>,
+    host: &Host,
+    depth: usize,
+) {
+    println!("{}{}", "  ".repeat(depth), describe(host));
+    for child in members.iter().filter(|r| {
+        parents
+            .get(r.name.as_str())
+            .is_some_and(|p| p.is_ok_and(|p| p.name == host.name))
+    }) {
+        print_tree(members, parents, child, depth + 1);
+    }
+}
+
+/// A host's line in `topology`: its name, role and position
+fn describe(host: &Host) -> String {
+    let mut notes = vec![host.role.name().to_string()];
+    if let Some(t) = host.timeline_id {
+        notes.push(format!("timeline {}", t));
+    }
+    if let Some(lsn) = host.wal_lsn {
+        notes.push(timeline::format_lsn(lsn));
+    }
+    if let Some(bytes) = host.lag_bytes {
+        notes.push(format!("{} bytes behind", bytes));
+    }
+    if host.streaming == Some(false) && !host.is_primary {
+        notes.push("not streaming".to_string());
+    }
+    format!("{} ({})", host.name, notes.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standbys_following_each_other_around_are_in_a_cycle() {
+        let host = |name: &str| {
+            let mut host = Host::default();
+            host.name = name.to_string();
+            host
+        };
+        let (primary, a, b) = (host("primary"), host("a"), host("b"));
+        let parents = HashMap::from([
+            ("a", Ok(&b)),
+            ("b", Ok(&a)),
+            ("c", Ok(&a)),
+            ("d", Ok(&primary)),
+            ("e", Err("elsewhere")),
+        ]);
+        for (name, cycle) in [
+            ("a", true),
+            ("b", true),
+            ("c", true),
+            ("d", false),
+            ("e", false),
+            ("primary", false),
+        ] {
+            assert_eq!(in_cycle(&parents, name), cycle, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::{fmt, io, str::FromStr, sync::Arc, time::Duration};
+
+use ::time::OffsetDateTime;
+use sqlx::{postgres::PgConnectOptions, ConnectOptions, Connection, Executor};
+use tokio::{sync::OnceCell, time};
+
+use crate::{hosts::Target, output, s3, Host, Summary};
+
+/// Where `--sink` writes every sweep's results
+#[derive(Debug, Clone)]
+pub enum Sink {
+    /// A table, created on first use, that gets a row per host and sweep
+    Postgres(Box<PgTable>),
+    /// A bucket that gets each sweep's JSON report as an object of its own
+    S3(s3::Bucket),
+}
+
+#[derive(Debug, Clone)]
+pub struct PgTable {
+    database: PgConnectOptions,
+    /// Quoted, and qualified by its schema if the URL gave one
+    table: String,
+    /// The URL set application_name itself, which --application-name leaves alone
+    names_session: bool,
+    /// Set once the table has been created or brought up to date, which only needs doing once
+    /// per process. A failed attempt is tried again with the next sweep
+    ready: Arc<OnceCell<()>>,
+}
+
+/// How long a sweep waits for the database to accept a login, and for each statement, so a
+/// hung sink doesn't hold up the sweeps after it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const STATEMENT_TIMEOUT: &str = "30s";
+
+/// The results table's columns after `run_id` and `swept_at`, with their types
+const COLUMNS: &[(&str, &str)] = &[
+    ("name", "text"),
+    ("reachable", "boolean"),
+    ("reason", "text"),
+    ("role", "text"),
+    ("is_primary", "boolean"),
+    ("timeline_id", "bigint"),
+    ("system_identifier", "bigint"),
+    ("timeline_age_seconds", "double precision"),
+    ("wal_lsn", "bigint"),
+    ("lag_bytes", "bigint"),
+    ("replay_gap_bytes", "bigint"),
+    ("lag_seconds", "double precision"),
+    ("streaming", "boolean"),
+    ("replica_attached", "boolean"),
+    ("wal_source", "text"),
+    ("upstream", "text"),
+    ("received_tli", "bigint"),
+    ("walreceiver_status", "text"),
+    ("data_checksums", "boolean"),
+    ("checksum_failures", "bigint"),
+    ("server_version_num", "integer"),
+    ("pooler", "text"),
+    ("checked_at", "timestamptz"),
+    ("tags", "jsonb"),
+    ("checks", "jsonb"),
+];
+
+/// Used when the URL names only the database
+const DEFAULT_TABLE: &str = "timeline_check_results";
+
+impl Sink {
+    /// A `postgres://user@host/database/table` URL, the table optionally qualified by its
+    /// schema, or an `s3://bucket/prefix/` one
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let Some((scheme, rest)) = s.split_once("://") else {
+            return Err("expected a postgres:// or s3:// URL".to_string());
+        };
+        match scheme {
+            "postgres" | "postgresql" => {}
+            "s3" => return s3::Bucket::parse(rest).map(Sink::S3),
+            _ => {
+                return Err(format!(
+                    "unsupported sink {}://, expected postgres:// or s3://",
+                    scheme
+                ))
+            }
+        }
+        let (rest, params) = match rest.split_once('?') {
+            Some((rest, params)) => (rest, Some(params)),
+            None => (rest, None),
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (database, table) = match path.split_once('/') {
+            None => (path, DEFAULT_TABLE),
+            Some((_, table)) if table.is_empty() || table.contains('/') => {
+                return Err("expected postgres://HOST/DATABASE/TABLE".to_string())
+            }
+            Some((database, table)) => (database, table),
+        };
+
+        let mut url = format!("{}://{}/{}", scheme, authority, database);
+        if let Some(params) = params {
+            url.push('?');
+            url.push_str(params);
+        }
+        let database = PgConnectOptions::from_str(&url)
+            .map_err(|e| e.to_string())?
+            .options([("statement_timeout", STATEMENT_TIMEOUT)]);
+        let names_session = params.is_some_and(|p| p.contains("application_name="));
+        let table = table
+            .split('.')
+            .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(".");
+        Ok(Sink::Postgres(Box::new(PgTable {
+            database,
+            table,
+            names_session,
+            ready: Arc::default(),
+        })))
+    }
+
+    /// Logs in to tables with `name` unless the URL has an application_name of its own
+    pub fn application_name(&mut self, name: &str) {
+        if let Sink::Postgres(t) = self {
+            if !t.names_session {
+                t.database = t.database.clone().application_name(name);
+            }
+        }
+    }
+
+    /// Writes one sweep, tagged with `run_id`. Tables get hosts that couldn't be checked with
+    /// only their name, buckets an object named after when the sweep started
+    pub async fn write(
+        &self,
+        run_id: &str,
+        hosts: &[Target],
+        res: &[Host],
+        summary: &Summary,
+    ) -> io::Result<()> {
+        match self {
+            Sink::Postgres(t) => t.insert(run_id, summary, hosts, res).await,
+            Sink::S3(bucket) => {
+                let name = format!("{}-{}.json", s3::amz_date(summary.checked_at), run_id);
+                let report = output::json_report(res, summary);
+                bucket
+                    .put(&name, "application/json", report.as_bytes())
+                    .await
+            }
+        }
+    }
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sink::Postgres(t) => {
+                write!(f, "{} on {}", t.table, t.database.get_host())?;
+                if let Some(db) = t.database.get_database() {
+                    write!(f, "/{}", db)?;
+                }
+                Ok(())
+            }
+            Sink::S3(bucket) => write!(f, "{}", bucket),
+        }
+    }
+}
+
+impl PgTable {
+    async fn insert(
+        &self,
+        run_id: &str,
+        summary: &Summary,
+        hosts: &[Target],
+        res: &[Host],
+    ) -> io::Result<()> {
+        let rows: Vec<Option<&Host>> = hosts
+            .iter()
+            .map(|t| res.iter().find(|r| r.name == t.name))
+            .collect();
+        let each = |f: &dyn Fn(&Host) -> Option<String>| -> Vec<Option<String>> {
+            rows.iter().map(|h| h.and_then(f)).collect()
+        };
+        let timestamp = |t: OffsetDateTime| t.unix_timestamp_nanos() as f64 / 1e9;
+
+        let definitions: Vec<String> = COLUMNS
+            .iter()
+            .map(|(name, ty)| format!("{} {}", name, ty))
+            .collect();
+        let create = format!(
+            "CREATE TABLE IF NOT EXISTS {} (run_id uuid NOT NULL, swept_at timestamptz NOT NULL, {})",
+            self.table,
+            definitions.join(", ")
+        );
+        // Tables created by older releases gain the columns added since
+        let additions: Vec<String> = definitions
+            .iter()
+            .map(|d| format!("ADD COLUMN IF NOT EXISTS {}", d))
+            .collect();
+        let alter = format!("ALTER TABLE {} {}", self.table, additions.join(", "));
+        // Every column goes over as a text array, cast back on the way in, so a sweep is a
+        // single statement however many hosts it covers
+        let names: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+        let values: Vec<String> = COLUMNS
+            .iter()
+            .map(|(name, ty)| match *ty {
+                "timestamptz" => format!("to_timestamp(r.{}::double precision)", name),
+                _ => format!("r.{}::{}", name, ty),
+            })
+            .collect();
+        let params: Vec<String> = (0..COLUMNS.len())
+            .map(|i| format!("${}::text[]", i + 3))
+            .collect();
+        let insert = format!(
+            "INSERT INTO {} (run_id, swept_at, {}) SELECT $1::uuid, to_timestamp($2), {} FROM unnest({}) AS r({})",
+            self.table,
+            names.join(", "),
+            values.join(", "),
+            params.join(", "),
+            names.join(", ")
+        );
+
+        let columns: Vec<Vec<Option<String>>> = vec![
+            hosts.iter().map(|t| Some(t.name.clone())).collect(),
+            rows.iter().map(|h| Some(h.is_some().to_string())).collect(),
+            hosts
+                .iter()
+                .map(|t| {
+                    let failure = summary.failed.iter().find(|f| f.name == t.name);
+                    failure.map(|f| f.reason.name().to_string())
+                })
+                .collect(),
+            each(&|h| Some(h.role.name().to_string())),
+            each(&|h| Some(h.is_primary.to_string())),
+            each(&|h| h.timeline_id.map(|v| v.to_string())),
+            each(&|h| h.system_identifier.map(|v| v.to_string())),
+            each(&|h| h.timeline_age.map(|v| v.to_string())),
+            each(&|h| h.wal_lsn.map(|v| v.to_string())),
+            each(&|h| h.lag_bytes.map(|v| v.to_string())),
+            each(&|h| h.replay_gap_bytes.map(|v| v.to_string())),
+            each(&|h| h.lag_seconds.map(|v| v.to_string())),
+            each(&|h| h.streaming.map(|v| v.to_string())),
+            each(&|h| h.replica_attached.map(|v| v.to_string())),
+            each(&|h| h.wal_source.map(|s| s.name().to_string())),
+            each(&|h| h.upstream.clone()),
+            each(&|h| h.received_tli.map(|v| v.to_string())),
+            each(&|h| h.walreceiver_status.clone()),
+            each(&|h| h.data_checksums.map(|v| v.to_string())),
+            each(&|h| h.checksum_failures.map(|v| v.to_string())),
+            each(&|h| h.server_version_num.map(|v| v.0.to_string())),
+            each(&|h| h.pooler.clone()),
+            each(&|h| h.checked_at.map(|t| timestamp(t).to_string())),
+            each(&|h| serde_json::to_string(&h.tags).ok()),
+            each(&|h| {
+                let checks: serde_json::Map<String, serde_json::Value> = h
+                    .custom
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone().into()))
+                    .collect();
+                serde_json::to_string(&checks).ok()
+            }),
+        ];
+        debug_assert_eq!(columns.len(), COLUMNS.len());
+
+        let mut conn = match time::timeout(CONNECT_TIMEOUT, self.database.connect()).await {
+            Ok(conn) => conn.map_err(io::Error::other)?,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no login within {}s", CONNECT_TIMEOUT.as_secs()),
+                ))
+            }
+        };
+        self.ready
+            .get_or_try_init(|| async {
+                for statement in [&create, &alter] {
+                    conn.execute(statement.as_str())
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Ok::<_, io::Error>(())
+            })
+            .await?;
+        let mut query = sqlx::query(&insert)
+            .bind(run_id)
+            .bind(timestamp(summary.checked_at));
+        for column in columns {
+            query = query.bind(column);
+        }
+        conn.execute(query).await.map_err(io::Error::other)?;
+        let _ = conn.close().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+/// A physical replication slot on the host
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Slot {
+    pub slot_name: String,
+    /// Whether something is streaming through it right now
+    pub active: bool,
+    /// Bytes of WAL it keeps from being recycled, `None` for a slot that never reserved any
+    pub retained_bytes: Option<i64>,
+}
+
+/// The host's physical slots, and on standbys the slot they stream through, their
+/// primary_slot_name, which servers before 12 keep in recovery.conf out of reach
+pub async fn fetch(pool: &PgPool) -> Result<(Vec<Slot>, Option<String>), sqlx::Error> {
+    let slots = sqlx::query(
+        "SELECT slot_name, active,
+            pg_wal_lsn_diff(CASE WHEN pg_is_in_recovery()
+                THEN greatest(pg_last_wal_receive_lsn(), pg_last_wal_replay_lsn())
+                ELSE pg_current_wal_lsn() END, restart_lsn)::int8 AS retained_bytes
+        FROM pg_replication_slots
+        WHERE slot_type = 'physical'
+        ORDER BY slot_name;",
+    )
+    .map(|r: PgRow| Slot {
+        slot_name: r.get("slot_name"),
+        active: r.get("active"),
+        retained_bytes: r.get("retained_bytes"),
+    })
+    .fetch_all(pool);
+    let uses = sqlx::query_scalar(
+        "SELECT setting FROM pg_settings
+        WHERE name = 'primary_slot_name' AND pg_is_in_recovery();",
+    )
+    .fetch_optional(pool);
+    let (slots, uses) = tokio::join!(slots, uses);
+    let uses: Option<String> = uses?;
+    Ok((slots?, uses.filter(|s| !s.is_empty())))
+}
+
+/// The slot name Patroni and similar tools give a member: lower case, with everything but
+/// letters, digits and underscores turned into underscores
+pub fn slot_name_for(host: &str) -> String {
+    host.chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9' | '_') => c,
+            _ => '_',
+        })
+        .collect()
+}
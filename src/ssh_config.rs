@@ -0,0 +1,97 @@
+use std::{fs, io, path::Path};
+
+/// Reads the hosts named in an OpenSSH client config's `Host` lines, with their `HostName`
+/// as the address to connect to. Patterns like `*.prod` don't name a host and are skipped.
+pub fn read(path: &Path) -> io::Result<Vec<(String, Option<String>)>> {
+    let text = fs::read_to_string(path)?;
+    let mut hosts: Vec<(String, Option<String>)> = Vec::new();
+    // Hosts of the current block, as indices into `hosts`
+    let mut block: Vec<usize> = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+            Some((k, v)) => (
+                k,
+                v.trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+            ),
+            None => (line, ""),
+        };
+
+        match key.to_ascii_lowercase().as_str() {
+            "host" => {
+                block.clear();
+                for name in value.split_whitespace() {
+                    if name.contains(['*', '?', '!']) {
+                        continue;
+                    }
+                    match hosts.iter().position(|(n, _)| n == name) {
+                        Some(i) => block.push(i),
+                        None => {
+                            block.push(hosts.len());
+                            hosts.push((name.to_string(), None));
+                        }
+                    }
+                }
+            }
+            "match" => block.clear(),
+            // Like ssh, the first HostName given for a host wins
+            "hostname" => {
+                for &i in &block {
+                    let (name, addr) = &mut hosts[i];
+                    if addr.is_none() {
+                        *addr = Some(value.replace("%h", name));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_are_read_with_the_first_hostname_given() {
+        let dir = std::env::temp_dir().join(format!("timeline_check_ssh_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+        fs::write(
+            &path,
+            "# comment\n\
+             Host db1 db2\n\
+             \x20   HostName %h.internal\n\
+             Host db1\n\
+             \x20   HostName 10.0.0.1\n\
+             Host=db3\n\
+             \x20   HostName = 10.0.0.3\n\
+             \x20   User postgres\n\
+             Host *.prod !db4 db?\n\
+             \x20   HostName 10.0.0.9\n\
+             Host db5\n\
+             Match host db5\n\
+             \x20   HostName 10.0.0.5\n\
+             HOST db6\n\
+             \x20   hostname\t10.0.0.6\n",
+        )
+        .unwrap();
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            read(&path).unwrap(),
+            vec![
+                ("db1".to_string(), some("db1.internal")),
+                ("db2".to_string(), some("db2.internal")),
+                ("db3".to_string(), some("10.0.0.3")),
+                ("db5".to_string(), None),
+                ("db6".to_string(), some("10.0.0.6")),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::error::Reason;
+
+/// `$XDG_STATE_HOME/timeline_check`, falling back to `~/.local/state`
+fn state_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(base.join("timeline_check"))
+}
+
+/// `$XDG_STATE_HOME/timeline_check/failed_hosts`
+pub fn default_failed_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("failed_hosts"))
+}
+
+/// `$XDG_STATE_HOME/timeline_check/last_run.json`
+pub fn default_last_run_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("last_run.json"))
+}
+
+/// A host as the last run that reached it found it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LastSeen {
+    pub role: String,
+    pub timeline_id: Option<u32>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub checked_at: OffsetDateTime,
+}
+
+/// Hosts by name, empty before the first run
+pub fn read_last_run(path: &Path) -> io::Result<BTreeMap<String, LastSeen>> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the recorded hosts the same way as `write_failed`
+pub fn write_last_run(path: &Path, hosts: &BTreeMap<String, LastSeen>) -> io::Result<()> {
+    replace(path, &serde_json::to_vec_pretty(hosts)?)
+}
+
+/// Hosts that couldn't be checked in the last run, one per line with the reason and how many
+/// runs in a row it has failed for it, e.g. `db3 tls 2`. Lists written before reasons were
+/// recorded have only names
+pub fn read_failed(path: &Path) -> io::Result<HashMap<String, Option<(Reason, u32)>>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let mut words = l.split_whitespace();
+            let name = words.next().unwrap_or_default().to_string();
+            let reason = words.next().and_then(Reason::parse);
+            let runs = words.next().and_then(|n| n.parse().ok());
+            (name, reason.zip(runs))
+        })
+        .collect())
+}
+
+/// Replaces the recorded hosts, written to a temporary file first so a crash can't truncate it
+pub fn write_failed<'a>(
+    path: &Path,
+    failed: impl Iterator<Item = (&'a str, Reason, u32)>,
+) -> io::Result<()> {
+    let lines: String = failed
+        .map(|(name, reason, runs)| format!("{} {} {}\n", name, reason, runs))
+        .collect();
+    replace(path, lines.as_bytes())
+}
+
+fn replace(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
@@ -0,0 +1,76 @@
+use std::io;
+
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::{hosts::Target, Host};
+
+/// Keeps datagrams under a typical MTU, StatsD servers split packets on newlines
+const MAX_PACKET: usize = 1400;
+
+/// Pushes the sweep's per-host gauges to the StatsD server at `addr` (host:port)
+pub async fn push(addr: &str, hosts: &[Target], res: &[Host]) -> io::Result<()> {
+    let target = lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+    let bind = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(target).await?;
+
+    let mut packet = String::new();
+    for line in gauges(hosts, res) {
+        if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET {
+            socket.send(packet.as_bytes()).await?;
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn gauges(hosts: &[Target], res: &[Host]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for Target { name, .. } in hosts {
+        let prefix = format!("timeline_check.{}", metric_name(name));
+        match res.iter().find(|r| &r.name == name) {
+            Some(r) => {
+                lines.push(format!("{}.reachable:1|g", prefix));
+                lines.push(format!("{}.is_primary:{}|g", prefix, r.is_primary as u8));
+                if let Some(t) = r.timeline_id {
+                    lines.push(format!("{}.timeline_id:{}|g", prefix, t));
+                }
+                if let Some(b) = r.lag_bytes {
+                    lines.push(format!("{}.lag_bytes:{}|g", prefix, b));
+                }
+                if let Some(s) = r.lag_seconds {
+                    lines.push(format!("{}.lag_seconds:{}|g", prefix, s));
+                }
+                if let Some(s) = r.clock_skew_seconds {
+                    lines.push(format!("{}.clock_skew_seconds:{}|g", prefix, s));
+                }
+            }
+            None => lines.push(format!("{}.reachable:0|g", prefix)),
+        }
+    }
+    lines
+}
+
+/// Graphite treats dots as path separators, so a hostname becomes a single path segment
+fn metric_name(host: &str) -> String {
+    host.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+/// A primary's synchronous replication settings next to the standbys actually connected
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SyncReplication {
+    /// As set, e.g. `FIRST 1 (s1, s2)`
+    pub synchronous_standby_names: String,
+    /// application_name of every standby in pg_stat_replication
+    pub connected: Vec<String>,
+}
+
+/// What synchronous_standby_names asks for
+struct Config {
+    /// Standbys that have to confirm a commit
+    num: usize,
+    /// Candidates, matched with application_name ignoring case, `*` matches any standby
+    names: Vec<String>,
+}
+
+/// The primary's settings, `None` on standbys and on primaries replicating asynchronously
+pub async fn fetch(pool: &PgPool) -> Result<Option<SyncReplication>, sqlx::Error> {
+    let names: Option<String> = sqlx::query_scalar(
+        "SELECT setting FROM pg_settings
+        WHERE name = 'synchronous_standby_names' AND NOT pg_is_in_recovery();",
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(names) = names.filter(|n| !n.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let connected = sqlx::query("SELECT application_name FROM pg_stat_replication;")
+        .map(|r: PgRow| r.get::<Option<String>, _>("application_name"))
+        .fetch_all(pool)
+        .await?;
+    Ok(Some(SyncReplication {
+        synchronous_standby_names: names,
+        connected: connected.into_iter().flatten().collect(),
+    }))
+}
+
+impl SyncReplication {
+    /// Describes how the connected standbys fall short of the setting, `None` when enough of
+    /// them are there to confirm commits
+    pub fn missing(&self) -> Option<String> {
+        let Some(config) = parse(&self.synchronous_standby_names) else {
+            return Some(format!(
+                "synchronous_standby_names '{}' couldn't be understood",
+                self.synchronous_standby_names
+            ));
+        };
+        let matching = self
+            .connected
+            .iter()
+            .filter(|c| {
+                config
+                    .names
+                    .iter()
+                    .any(|n| n == "*" || n.eq_ignore_ascii_case(c))
+            })
+            .count();
+        if matching >= config.num {
+            return None;
+        }
+        let connected = match self.connected.is_empty() {
+            true => "none is connected".to_string(),
+            false => format!("connected are {}", self.connected.join(", ")),
+        };
+        Some(format!(
+            "needs {} synchronous standby{} of {} but {} {} connected ({}), commits wait until \
+             enough are",
+            config.num,
+            if config.num == 1 { "" } else { "s" },
+            config.names.join(", "),
+            matching,
+            if matching == 1 { "is" } else { "are" },
+            connected
+        ))
+    }
+}
+
+/// Reads `FIRST n (a, b)`, `ANY n (a, b)`, `n (a, b)` and a bare `a, b`, which waits for one
+fn parse(setting: &str) -> Option<Config> {
+    let setting = setting.trim();
+    let rest = ["FIRST", "ANY"]
+        .iter()
+        .find_map(|method| {
+            let head = setting.get(..method.len())?;
+            let rest = &setting[method.len()..];
+            (head.eq_ignore_ascii_case(method) && rest.starts_with(char::is_whitespace))
+                .then_some(rest.trim_start())
+        })
+        .unwrap_or(setting);
+
+    let (num, list) = match rest.find('(') {
+        Some(open) => {
+            let num = rest[..open].trim().parse().ok()?;
+            let list = rest[open + 1..].trim_end().strip_suffix(')')?;
+            (num, list)
+        }
+        None => (1, rest),
+    };
+    let names = list
+        .split(',')
+        .map(|n| {
+            let n = n.trim();
+            match n.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\"\"", "\""),
+                None => n.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+    if names.iter().any(|n| n.is_empty()) {
+        return None;
+    }
+    Some(Config { num, names })
+}
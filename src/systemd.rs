@@ -0,0 +1,66 @@
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::unix::{ffi::OsStrExt, net::UnixDatagram},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Tells systemd how the daemon is doing over `$NOTIFY_SOCKET`, for `Type=notify` units. Does
+/// nothing when systemd didn't start the process or the unit doesn't ask for notifications
+#[derive(Clone, Default)]
+pub struct Notifier {
+    socket: Option<Arc<UnixDatagram>>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let socket = env::var_os("NOTIFY_SOCKET").and_then(|path| match connect(&path) {
+            Ok(socket) => Some(Arc::new(socket)),
+            Err(e) => {
+                eprintln!("Error connecting to NOTIFY_SOCKET: {}", e);
+                None
+            }
+        });
+        Notifier { socket }
+    }
+
+    /// Sends `KEY=value` lines, e.g. `READY=1`
+    pub fn notify(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = socket.send(state.as_bytes()) {
+                eprintln!("Error notifying systemd: {}", e);
+            }
+        }
+    }
+}
+
+/// An `@` in front names a socket in the abstract namespace
+fn connect(path: &OsStr) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.connect_addr(&addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        None => socket.connect(path)?,
+    }
+    Ok(socket)
+}
+
+/// How often `WatchdogSec=` wants to hear from this process, `None` without a watchdog
+pub fn watchdog_interval() -> Option<Duration> {
+    // Set when the watchdog is meant for another process, e.g. the one that forked this
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use crate::{error::CheckError, Host};
+
+/// Exports a span per host check and fleet gauges over OTLP/HTTP when `--otlp-endpoint` is
+/// given. Builds without the `otel` feature keep the same calls as no-ops.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    exporter: Option<otlp::Exporter>,
+}
+
+impl Telemetry {
+    pub fn new(endpoint: Option<&str>) -> Result<Self, String> {
+        #[cfg(feature = "otel")]
+        {
+            let exporter = endpoint.map(otlp::Exporter::new).transpose()?;
+            Ok(Telemetry { exporter })
+        }
+
+        #[cfg(not(feature = "otel"))]
+        match endpoint {
+            Some(_) => Err("built without the otel feature".to_string()),
+            None => Ok(Telemetry {}),
+        }
+    }
+
+    /// Records one host's check that took `elapsed` and just finished
+    #[allow(unused_variables)]
+    pub fn record(&self, name: &str, res: Result<&Host, &CheckError>, elapsed: Duration) {
+        #[cfg(feature = "otel")]
+        if let Some(exporter) = &self.exporter {
+            let end = std::time::SystemTime::now();
+            exporter.record(name, res, end - elapsed, end);
+        }
+    }
+
+    /// Records values only known once the whole sweep is in, like byte lag
+    #[allow(unused_variables)]
+    pub fn record_sweep(&self, res: &[Host]) {
+        #[cfg(feature = "otel")]
+        if let Some(exporter) = &self.exporter {
+            exporter.record_sweep(res);
+        }
+    }
+
+    /// Flushes anything still buffered, call before exiting
+    pub fn shutdown(&self) {
+        #[cfg(feature = "otel")]
+        if let Some(exporter) = &self.exporter {
+            exporter.shutdown();
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otlp {
+    use std::time::SystemTime;
+
+    use opentelemetry::{
+        metrics::{Gauge, Histogram, MeterProvider},
+        trace::{Span, Status, Tracer, TracerProvider},
+        KeyValue,
+    };
+    use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{
+        metrics::SdkMeterProvider,
+        trace::{SdkTracer, SdkTracerProvider},
+        Resource,
+    };
+
+    use crate::{error::CheckError, Host};
+
+    pub struct Exporter {
+        tracer_provider: SdkTracerProvider,
+        meter_provider: SdkMeterProvider,
+        tracer: SdkTracer,
+        up: Gauge<u64>,
+        timeline_id: Gauge<u64>,
+        lag_bytes: Gauge<i64>,
+        lag_seconds: Gauge<f64>,
+        check_duration: Histogram<f64>,
+    }
+
+    impl Exporter {
+        /// `endpoint` is the collector's base URL, e.g. http://localhost:4318
+        pub fn new(endpoint: &str) -> Result<Self, String> {
+            let endpoint = endpoint.trim_end_matches('/');
+            let resource = Resource::builder()
+                .with_service_name("timeline_check")
+                .build();
+
+            let spans = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint))
+                .build()
+                .map_err(|e| e.to_string())?;
+            let tracer_provider = SdkTracerProvider::builder()
+                .with_batch_exporter(spans)
+                .with_resource(resource.clone())
+                .build();
+
+            let metrics = MetricExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/metrics", endpoint))
+                .build()
+                .map_err(|e| e.to_string())?;
+            let meter_provider = SdkMeterProvider::builder()
+                .with_periodic_exporter(metrics)
+                .with_resource(resource)
+                .build();
+
+            let meter = meter_provider.meter("timeline_check");
+            Ok(Exporter {
+                tracer: tracer_provider.tracer("timeline_check"),
+                up: meter
+                    .u64_gauge("timeline_check.up")
+                    .with_description("Whether the host could be checked")
+                    .build(),
+                timeline_id: meter
+                    .u64_gauge("timeline_check.timeline_id")
+                    .with_description("Timeline of the latest checkpoint")
+                    .build(),
+                lag_bytes: meter
+                    .i64_gauge("timeline_check.lag")
+                    .with_unit("By")
+                    .with_description("Bytes of WAL the standby has yet to replay")
+                    .build(),
+                lag_seconds: meter
+                    .f64_gauge("timeline_check.lag.time")
+                    .with_unit("s")
+                    .with_description("Seconds since the standby's last replayed transaction")
+                    .build(),
+                check_duration: meter
+                    .f64_histogram("timeline_check.check.duration")
+                    .with_unit("s")
+                    .with_description("How long connecting to and checking a host took")
+                    .build(),
+                tracer_provider,
+                meter_provider,
+            })
+        }
+
+        pub fn record(
+            &self,
+            name: &str,
+            res: Result<&Host, &CheckError>,
+            start: SystemTime,
+            end: SystemTime,
+        ) {
+            let host = [KeyValue::new("host", name.to_string())];
+
+            let mut attributes = host.to_vec();
+            if let Ok(r) = res {
+                attributes.push(KeyValue::new("is_primary", r.is_primary));
+                if let Some(t) = r.timeline_id {
+                    attributes.push(KeyValue::new("timeline_id", t as i64));
+                }
+            }
+            let mut span = self
+                .tracer
+                .span_builder("check_host")
+                .with_start_time(start)
+                .with_attributes(attributes)
+                .start(&self.tracer);
+            if let Err(e) = res {
+                span.set_attribute(KeyValue::new("error.type", e.kind()));
+                span.set_status(Status::error(e.to_string()));
+            }
+            span.end_with_timestamp(end);
+
+            let elapsed = end.duration_since(start).unwrap_or_default();
+            self.check_duration.record(elapsed.as_secs_f64(), &host);
+            self.up.record(res.is_ok() as u64, &host);
+            if let Ok(r) = res {
+                if let Some(t) = r.timeline_id {
+                    self.timeline_id.record(t as u64, &host);
+                }
+                if let Some(s) = r.lag_seconds {
+                    self.lag_seconds.record(s, &host);
+                }
+            }
+        }
+
+        pub fn record_sweep(&self, res: &[Host]) {
+            for r in res {
+                if let Some(b) = r.lag_bytes {
+                    self.lag_bytes
+                        .record(b, &[KeyValue::new("host", r.name.clone())]);
+                }
+            }
+        }
+
+        pub fn shutdown(&self) {
+            if let Err(e) = self.tracer_provider.shutdown() {
+                eprintln!("Error flushing spans: {}", e);
+            }
+            if let Err(e) = self.meter_provider.shutdown() {
+                eprintln!("Error flushing metrics: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+
+/// Timeline ids are unsigned 32-bit on the server, but SQL has no unsigned integers and
+/// pg_control_checkpoint() hands them out as `integer`, so ids from 2^31 on come back negative
+pub fn from_int4(raw: i32) -> u32 {
+    raw as u32
+}
+
+/// An id as the replication protocol prints it, in decimal
+pub fn parse(text: &str) -> Option<u32> {
+    text.trim().parse().ok()
+}
+
+/// The timeline part of a WAL file name, its first eight hex digits
+pub fn from_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text.get(..8)?, 16).ok()
+}
+
+/// Turns `16/B374D848` into its byte position
+pub fn parse_lsn(lsn: &str) -> Option<i64> {
+    let (hi, lo) = lsn.split_once('/')?;
+    let hi = u64::from_str_radix(hi, 16).ok()?;
+    let lo = u64::from_str_radix(lo, 16).ok()?;
+    i64::try_from(hi << 32 | lo).ok()
+}
+
+/// A byte position the way the server prints it, e.g. `16/B374D848`
+pub fn format_lsn(lsn: i64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+/// One line of a timeline history file: `timeline` ended at `switchpoint`, where its child
+/// branched off
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Switch {
+    pub timeline: u32,
+    pub switchpoint: i64,
+}
+
+/// Reads a `.history` file, a `parentTLI switchpoint reason` line per ancestor of its timeline,
+/// oldest first. `None` when a line isn't one
+pub fn parse_history(text: &str) -> Option<Vec<Switch>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let mut fields = l.split_whitespace();
+            Some(Switch {
+                timeline: parse(fields.next()?)?,
+                switchpoint: parse_lsn(fields.next()?)?,
+            })
+        })
+        .collect()
+}
+
+/// Where the histories of two timelines part
+#[derive(Debug, PartialEq)]
+pub struct Fork {
+    /// The newest timeline both came through
+    pub ancestor: u32,
+    /// Where the first one left it and for which timeline, `None` while it's still on it
+    pub left: Option<(i64, u32)>,
+    /// The same for the other one
+    pub other_left: Option<(i64, u32)>,
+}
+
+/// Compares `timeline`, with the ancestors its history file lists, to `other` and its own.
+/// `None` when they don't share even their first timeline
+pub fn fork(
+    timeline: u32,
+    history: &[Switch],
+    other: u32,
+    other_history: &[Switch],
+) -> Option<Fork> {
+    let path = |current: u32, history: &[Switch]| -> Vec<(u32, Option<i64>)> {
+        let mut path: Vec<_> = history
+            .iter()
+            .map(|s| (s.timeline, Some(s.switchpoint)))
+            .collect();
+        path.push((current, None));
+        path
+    };
+    let (a, b) = (path(timeline, history), path(other, other_history));
+    let shared = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+    // Where each of them went from the ancestor at `i`
+    let left = |path: &[(u32, Option<i64>)], i: usize| path[i].1.map(|at| (at, path[i + 1].0));
+    match (a.get(shared), b.get(shared)) {
+        (Some(x), Some(y)) if x.0 == y.0 => Some(Fork {
+            ancestor: x.0,
+            left: left(&a, shared),
+            other_left: left(&b, shared),
+        }),
+        // Both children branched off their parent at the same position
+        (Some(_), Some(_)) if shared > 0 => Some(Fork {
+            ancestor: a[shared - 1].0,
+            left: left(&a, shared - 1),
+            other_left: left(&b, shared - 1),
+        }),
+        (Some(_), Some(_)) => None,
+        _ => Some(Fork {
+            ancestor: timeline,
+            left: None,
+            other_left: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_ids_are_unchanged() {
+        assert_eq!(from_int4(1), 1);
+        assert_eq!(from_int4(i32::MAX), 2_147_483_647);
+    }
+
+    #[test]
+    fn negative_ids_wrap_past_the_signed_range() {
+        assert_eq!(from_int4(i32::MIN), 2_147_483_648);
+        assert_eq!(from_int4(-1), u32::MAX);
+    }
+
+    #[test]
+    fn ids_past_the_signed_range_order_after_smaller_ones() {
+        assert!(from_int4(i32::MIN) > from_int4(i32::MAX));
+    }
+
+    #[test]
+    fn parses_the_whole_unsigned_range() {
+        assert_eq!(parse("1"), Some(1));
+        assert_eq!(parse("2147483648"), Some(2_147_483_648));
+        assert_eq!(parse("4294967295"), Some(u32::MAX));
+    }
+
+    #[test]
+    fn reads_the_timeline_from_wal_file_names() {
+        assert_eq!(from_hex("000000010000000000000003"), Some(1));
+        assert_eq!(from_hex("0000001A"), Some(26));
+        assert_eq!(from_hex("FFFFFFFF00000000000000FF"), Some(u32::MAX));
+        assert_eq!(from_hex("0000001"), None);
+        assert_eq!(from_hex("0000000G"), None);
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_timeline() {
+        assert_eq!(parse("4294967296"), None);
+        assert_eq!(parse("-1"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn reads_history_files() {
+        let text =
+            "1\t0/3000060\tno recovery target specified\n\n2\t1/A0000F8\tat restore point \"x\"\n";
+        assert_eq!(
+            parse_history(text),
+            Some(vec![
+                Switch {
+                    timeline: 1,
+                    switchpoint: 0x3000060
+                },
+                Switch {
+                    timeline: 2,
+                    switchpoint: 0x1_0A00_00F8
+                },
+            ])
+        );
+        assert_eq!(parse_history(""), Some(vec![]));
+        assert_eq!(parse_history("1 nonsense"), None);
+        assert_eq!(format_lsn(0x1_0A00_00F8), "1/A0000F8");
+    }
+
+    #[test]
+    fn finds_where_sibling_timelines_forked() {
+        let one = |at| Switch {
+            timeline: 1,
+            switchpoint: at,
+        };
+        // Timeline 2 left 1 at 0x300, timeline 3 at 0x500, both promoted from the same primary
+        assert_eq!(
+            fork(3, &[one(0x500)], 2, &[one(0x300)]),
+            Some(Fork {
+                ancestor: 1,
+                left: Some((0x500, 3)),
+                other_left: Some((0x300, 2)),
+            })
+        );
+        // A standby still on timeline 1
+        assert_eq!(
+            fork(1, &[], 2, &[one(0x300)]),
+            Some(Fork {
+                ancestor: 1,
+                left: None,
+                other_left: Some((0x300, 2)),
+            })
+        );
+        let two = |at| Switch {
+            timeline: 2,
+            switchpoint: at,
+        };
+        assert_eq!(
+            fork(4, &[one(0x300), two(0x300)], 3, &[one(0x300)]),
+            Some(Fork {
+                ancestor: 1,
+                left: Some((0x300, 2)),
+                other_left: Some((0x300, 3)),
+            })
+        );
+        assert_eq!(fork(3, &[two(0x300)], 2, &[one(0x300)]), None);
+    }
+}
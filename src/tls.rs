@@ -0,0 +1,303 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use base64::Engine;
+use bytes::BytesMut;
+use postgres_protocol::message::frontend;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, ClientConnection, PrivateKey, ProtocolVersion, RootCertStore,
+    ServerName,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// What a host's TLS handshake shows, from a connection of its own since sqlx doesn't expose it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ServerTls {
+    /// The server certificate's notAfter
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub cert_expires: Option<OffsetDateTime>,
+}
+
+/// How the connection checks ran over is protected, to catch hosts that fell back to plain text
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Encryption {
+    pub encrypted: bool,
+    /// TLS version, e.g. `TLSv1.3`
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+}
+
+impl Encryption {
+    pub const PLAIN_TEXT: Encryption = Encryption {
+        encrypted: false,
+        version: None,
+        cipher: None,
+    };
+
+    /// What a handshake of ours negotiated, named the way pg_stat_ssl names versions. TLS 1.2
+    /// ciphers keep their IANA names, pg_stat_ssl has OpenSSL's
+    pub fn of(session: &ClientConnection) -> Self {
+        let version = session.protocol_version().map(|v| match v {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            v => format!("{:?}", v),
+        });
+        // rustls prefixes the TLS 1.3 suites, OpenSSL uses their IANA names as they are
+        let cipher = session
+            .negotiated_cipher_suite()
+            .map(|c| format!("{:?}", c.suite()).replacen("TLS13_", "TLS_", 1));
+        Encryption {
+            encrypted: true,
+            version,
+            cipher,
+        }
+    }
+}
+
+/// What a server's certificate is verified against
+#[derive(Clone, Debug)]
+pub struct Verify {
+    pub roots: Arc<RootCertStore>,
+    /// Whether the certificate has to be for the host name, `sslmode=verify-ca` only checks
+    /// who signed it
+    pub hostname: bool,
+}
+
+/// The certificates in a PEM file, e.g. a hosts file line's sslrootcert
+pub fn read_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for der in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))? {
+        roots
+            .add(&Certificate(der))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(roots)
+}
+
+/// The certificates the operating system trusts, the way rustls-native-certs finds them: the
+/// keychain on macOS, the certificate store on Windows and OpenSSL's bundle elsewhere, which
+/// `SSL_CERT_FILE` and `SSL_CERT_DIR` point somewhere else. Certificates rustls can't use are
+/// left out, it's an error only when none are left
+pub fn system_roots() -> Result<(RootCertStore, Vec<u8>), String> {
+    let found = rustls_native_certs::load_native_certs();
+    let mut roots = RootCertStore::empty();
+    let mut pem = String::new();
+    for cert in &found.certs {
+        if roots.add(&Certificate(cert.to_vec())).is_err() {
+            continue;
+        }
+        // sqlx only takes root certificates as PEM
+        let base64 = base64::engine::general_purpose::STANDARD.encode(cert);
+        pem.push_str("-----BEGIN CERTIFICATE-----\n");
+        for line in base64.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+    }
+    if roots.is_empty() {
+        return Err(match found.errors.first() {
+            Some(e) => e.to_string(),
+            None => "no certificates found".to_string(),
+        });
+    }
+    Ok((roots, pem.into_bytes()))
+}
+
+/// Like sqlx with `sslmode=require`, the server's certificate isn't verified unless `verify`
+/// says what against, as with `sslmode=verify-ca` or `verify-full`
+pub fn client_config(
+    client_cert: Option<&(PathBuf, PathBuf)>,
+    verify: Option<&Verify>,
+) -> Result<ClientConfig, sqlx::Error> {
+    let verifier: Arc<dyn ServerCertVerifier> = match verify {
+        Some(v) => {
+            let webpki = WebPkiVerifier::new(RootCertStore::clone(&v.roots), None);
+            match v.hostname {
+                true => Arc::new(webpki),
+                false => Arc::new(AnyHostname(webpki)),
+            }
+        }
+        None => Arc::new(AcceptAnyCert),
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+    let config = match client_cert {
+        Some((cert, key)) => {
+            let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+                .into_iter()
+                .find_map(|item| match item {
+                    rustls_pemfile::Item::RSAKey(k)
+                    | rustls_pemfile::Item::PKCS8Key(k)
+                    | rustls_pemfile::Item::ECKey(k) => Some(PrivateKey(k)),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    sqlx::Error::Protocol("no private key in the client key file".into())
+                })?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| sqlx::Error::Tls(Box::new(e)))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+/// Sends Postgres' SSLRequest and, when the server agrees, runs the TLS handshake. `None` when
+/// the server only speaks plain text. The certificate is verified against `server_name`, the
+/// host name, which isn't the address connected to with `--resolve`, a tunnel or a fallback
+pub async fn upgrade(
+    mut tcp: TcpStream,
+    server_name: &str,
+    client_cert: Option<&(PathBuf, PathBuf)>,
+    verify: Option<&Verify>,
+) -> Result<Result<TlsStream<TcpStream>, TcpStream>, sqlx::Error> {
+    let mut buf = BytesMut::new();
+    frontend::ssl_request(&mut buf);
+    tcp.write_all(&buf).await?;
+    if tcp.read_u8().await? != b'S' {
+        return Ok(Err(tcp));
+    }
+    let server = ServerName::try_from(server_name).map_err(|e| sqlx::Error::Tls(Box::new(e)))?;
+    let tls = TlsConnector::from(Arc::new(client_config(client_cert, verify)?));
+    Ok(Ok(tls.connect(server, tcp).await?))
+}
+
+/// Checks the certificate of the server at `addr` is for `name`, the way libpq's `hostaddr`
+/// keeps `host` for verify-full. sqlx verifies the certificate against the address it connects
+/// to, so when that's another one it only verifies the chain and this handshake the name
+pub async fn verify_name(
+    addr: &str,
+    port: u16,
+    name: &str,
+    client_cert: Option<&(PathBuf, PathBuf)>,
+) -> Result<(), sqlx::Error> {
+    let tcp = TcpStream::connect((addr, port)).await?;
+    let Ok(mut stream) = upgrade(tcp, name, client_cert, None).await? else {
+        return Err(sqlx::Error::Tls("server doesn't support TLS".into()));
+    };
+    let (_, session) = stream.get_ref();
+    let for_name = session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .is_some_and(|cert| is_for(&cert.0, name));
+    let _ = stream.shutdown().await;
+    match for_name {
+        true => Ok(()),
+        false => Err(sqlx::Error::Tls(
+            format!("the server's certificate isn't for {}", name).into(),
+        )),
+    }
+}
+
+/// Whether the certificate names `name`, by DNS name or IP address
+fn is_for(der: &[u8], name: &str) -> bool {
+    let Ok(cert) = webpki::EndEntityCert::try_from(der) else {
+        return false;
+    };
+    let Ok(subject) = webpki::SubjectNameRef::try_from_ascii_str(name) else {
+        return false;
+    };
+    cert.verify_is_valid_for_subject_name(subject).is_ok()
+}
+
+/// Runs a TLS handshake with the host and hangs up before logging in, accepting any
+/// certificate so that even an untrusted one's expiry shows. `None` for servers without TLS.
+/// Connecting and the handshake together take at most `timeout`, the host's connect_timeout
+pub async fn probe(
+    addr: &str,
+    port: u16,
+    server_name: &str,
+    client_cert: Option<&(PathBuf, PathBuf)>,
+    timeout: Option<Duration>,
+) -> Result<Option<ServerTls>, sqlx::Error> {
+    let handshake = async {
+        let tcp = TcpStream::connect((addr, port)).await?;
+        upgrade(tcp, server_name, client_cert, None).await
+    };
+    let upgraded = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?,
+        None => handshake.await,
+    };
+    let Ok(mut stream) = upgraded? else {
+        return Ok(None);
+    };
+    let (_, session) = stream.get_ref();
+    let cert_expires = session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| not_after(&cert.0));
+    let _ = stream.shutdown().await;
+    Ok(Some(ServerTls { cert_expires }))
+}
+
+fn not_after(der: &[u8]) -> Option<OffsetDateTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    Some(cert.validity().not_after.to_datetime())
+}
+
+/// Verifies the chain like `WebPkiVerifier` and takes the certificate for any host name, the
+/// way sqlx does for `sslmode=verify-ca`
+struct AnyHostname(WebPkiVerifier);
+
+impl ServerCertVerifier for AnyHostname {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.0.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            Err(rustls::Error::InvalidCertificate(rustls::CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            res => res,
+        }
+    }
+}
+
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
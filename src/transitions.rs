@@ -0,0 +1,91 @@
+use std::fmt;
+
+use time::OffsetDateTime;
+
+use crate::{hosts::Target, Host};
+
+/// What changed about one host between two consecutive sweeps, e.g.
+/// `db-1: standby→primary, timeline 12→13 at 14:02:11`
+pub struct Transition {
+    pub host: String,
+    /// `standby→primary`, `timeline 12→13`, `upstream db-1→db-2`
+    pub changes: Vec<String>,
+    /// When the sweep that noticed it finished, in UTC
+    pub at: OffsetDateTime,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} at {:02}:{:02}:{:02}",
+            self.host,
+            self.changes.join(", "),
+            self.at.hour(),
+            self.at.minute(),
+            self.at.second()
+        )
+    }
+}
+
+/// The parts of a sweep transitions are looked for in, one entry per hosts file line
+pub struct Snapshot(Vec<(String, Option<State>)>);
+
+/// A reachable host's place in its cluster
+struct State {
+    role: &'static str,
+    timeline_id: Option<u32>,
+    upstream: Option<String>,
+}
+
+impl Snapshot {
+    /// Hosts missing from `res` count as unreachable
+    pub fn of(hosts: &[Target], res: &[Host]) -> Self {
+        Snapshot(
+            hosts
+                .iter()
+                .map(|t| {
+                    let state = res.iter().find(|r| r.name == t.name).map(|r| State {
+                        role: r.role.name(),
+                        timeline_id: r.timeline_id,
+                        upstream: r.upstream.clone(),
+                    });
+                    (t.name.clone(), state)
+                })
+                .collect(),
+        )
+    }
+
+    /// Compares each host's role, timeline and upstream with `prev`. Hosts `prev` didn't
+    /// cover, because the hosts file was reloaded in between, aren't compared.
+    pub fn since(&self, prev: &Snapshot, at: OffsetDateTime) -> Vec<Transition> {
+        self.0
+            .iter()
+            .filter_map(|(name, after)| {
+                let (_, before) = prev.0.iter().find(|(n, _)| n == name)?;
+                let role = |s: &Option<State>| s.as_ref().map_or("unreachable", |s| s.role);
+                let mut changes = Vec::new();
+                if role(before) != role(after) {
+                    changes.push(format!("{}→{}", role(before), role(after)));
+                }
+                if let (Some(b), Some(a)) = (before, after) {
+                    if let (Some(tb), Some(ta)) = (b.timeline_id, a.timeline_id) {
+                        if tb != ta {
+                            changes.push(format!("timeline {}→{}", tb, ta));
+                        }
+                    }
+                    if let (Some(ub), Some(ua)) = (&b.upstream, &a.upstream) {
+                        if ub != ua {
+                            changes.push(format!("upstream {}→{}", ub, ua));
+                        }
+                    }
+                }
+                (!changes.is_empty()).then(|| Transition {
+                    host: name.clone(),
+                    changes,
+                    at,
+                })
+            })
+            .collect()
+    }
+}
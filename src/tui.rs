@@ -0,0 +1,325 @@
+use std::{
+    collections::VecDeque,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use tokio::{
+    signal::unix::{self, SignalKind},
+    sync::watch,
+    time,
+};
+
+use crate::{
+    deadline,
+    hosts::{HostSource, Target},
+    jittered, now,
+    output::format_age,
+    probe::Live,
+    sweep,
+    transitions::{Snapshot, Transition},
+    CheckOptions, Host, Pools,
+};
+
+/// Transitions kept below the table, older ones scroll off
+const SHOWN_TRANSITIONS: usize = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Host,
+    Role,
+    Timeline,
+    Lag,
+}
+
+impl SortKey {
+    fn next(self) -> Self {
+        match self {
+            SortKey::Host => SortKey::Role,
+            SortKey::Role => SortKey::Timeline,
+            SortKey::Timeline => SortKey::Lag,
+            SortKey::Lag => SortKey::Host,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SortKey::Host => "host",
+            SortKey::Role => "role",
+            SortKey::Timeline => "timeline",
+            SortKey::Lag => "lag",
+        }
+    }
+}
+
+/// One table row, unreachable hosts included
+struct Entry {
+    name: String,
+    role: &'static str,
+    timeline_id: Option<u32>,
+    lag_bytes: Option<i64>,
+    lag_seconds: Option<f64>,
+    upstream: Option<String>,
+}
+
+impl Entry {
+    fn from_sweep(hosts: &[Target], res: &[Host]) -> Vec<Entry> {
+        hosts
+            .iter()
+            .map(
+                |Target { name, .. }| match res.iter().find(|r| &r.name == name) {
+                    Some(r) => Entry {
+                        name: name.clone(),
+                        role: r.role.name(),
+                        timeline_id: r.timeline_id,
+                        lag_bytes: r.lag_bytes,
+                        lag_seconds: r.lag_seconds,
+                        upstream: r.upstream.clone(),
+                    },
+                    None => Entry {
+                        name: name.clone(),
+                        role: "unreachable",
+                        timeline_id: None,
+                        lag_bytes: None,
+                        lag_seconds: None,
+                        upstream: None,
+                    },
+                },
+            )
+            .collect()
+    }
+}
+
+struct App {
+    entries: Vec<Entry>,
+    /// The latest last
+    transitions: VecDeque<Transition>,
+    updated: Option<Instant>,
+    sort: SortKey,
+    reverse: bool,
+    filter: String,
+    editing_filter: bool,
+}
+
+impl App {
+    /// Entries matching the filter, in the selected order
+    fn visible(&self) -> Vec<&Entry> {
+        let mut rows: Vec<&Entry> = self
+            .entries
+            .iter()
+            .filter(|e| e.name.contains(&self.filter))
+            .collect();
+        rows.sort_by(|a, b| match self.sort {
+            SortKey::Host => a.name.cmp(&b.name),
+            SortKey::Role => a.role.cmp(b.role),
+            SortKey::Timeline => a.timeline_id.cmp(&b.timeline_id),
+            SortKey::Lag => a.lag_bytes.cmp(&b.lag_bytes),
+        });
+        if self.reverse {
+            rows.reverse();
+        }
+        rows
+    }
+
+    /// Returns false once the user asked to quit
+    fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        if modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
+            return false;
+        }
+
+        if self.editing_filter {
+            match code {
+                KeyCode::Enter => self.editing_filter = false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.editing_filter = false;
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => {}
+            }
+            return true;
+        }
+
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('s') => self.sort = self.sort.next(),
+            KeyCode::Char('r') => self.reverse = !self.reverse,
+            KeyCode::Char('/') => self.editing_filter = true,
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [table_area, transitions_area, status_area] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(self.transitions.len() as u16),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let header = Row::new(["Host", "Role", "Timeline", "Lag", "Lag (s)", "Upstream"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = self.visible().into_iter().map(|e| {
+            let style = match e.role {
+                "unreachable" => Style::default().fg(Color::Red),
+                "primary" => Style::default().fg(Color::Green),
+                _ => Style::default(),
+            };
+            Row::new([
+                e.name.clone(),
+                e.role.to_string(),
+                e.timeline_id.map_or("-".to_string(), |t| t.to_string()),
+                e.lag_bytes.map_or("-".to_string(), |b| b.to_string()),
+                e.lag_seconds
+                    .map_or("-".to_string(), |s| format!("{:.1}", s)),
+                e.upstream.clone().unwrap_or("-".to_string()),
+            ])
+            .style(style)
+        });
+        let widths = [
+            Constraint::Fill(2),
+            Constraint::Length(12),
+            Constraint::Length(9),
+            Constraint::Length(12),
+            Constraint::Length(8),
+            Constraint::Fill(1),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(Block::bordered().title(" timeline_check "));
+        frame.render_widget(table, table_area);
+
+        let transitions = self
+            .transitions
+            .iter()
+            .map(|t| format!(" {}", t))
+            .collect::<Vec<_>>()
+            .join("\n");
+        frame.render_widget(
+            Paragraph::new(transitions).style(Style::default().fg(Color::Yellow)),
+            transitions_area,
+        );
+
+        let updated = match self.updated {
+            Some(at) => format!("updated {} ago", format_age(at.elapsed().as_secs_f64())),
+            None => "waiting for first sweep".to_string(),
+        };
+        let filter = if self.editing_filter {
+            format!("filter: {}_", self.filter)
+        } else if self.filter.is_empty() {
+            "no filter".to_string()
+        } else {
+            format!("filter: {}", self.filter)
+        };
+        let status = format!(
+            " {} | sort: {}{} | {} | q quit, s sort, r reverse, / filter",
+            updated,
+            self.sort.name(),
+            if self.reverse { " (desc)" } else { "" },
+            filter
+        );
+        frame.render_widget(Paragraph::new(status), status_area);
+    }
+}
+
+/// Runs sweeps every `interval` in the background and shows the latest one until the user quits
+/// Sending SIGHUP re-reads the hosts file and sweeps again straight away
+pub async fn run(
+    conn: CheckOptions,
+    source: HostSource,
+    mut hosts: Vec<Target>,
+    interval: Duration,
+    jitter: u8,
+) -> ExitCode {
+    let (tx, rx) = watch::channel((hosts.clone(), Vec::new()));
+    let mut hangup = unix::signal(SignalKind::hangup()).unwrap();
+    let sweeper = tokio::spawn(async move {
+        let concurrency = conn.concurrency;
+        let probe = Live::new(conn, Pools::default());
+        loop {
+            // Connection errors would scribble over the dashboard, they show up as unreachable rows
+            let res = sweep(&probe, &hosts, concurrency, deadline(None), |_, _, _| {}).await;
+            if tx.send((hosts.clone(), res)).is_err() {
+                break;
+            }
+            tokio::select! {
+                _ = time::sleep(jittered(interval, jitter)) => {}
+                _ = hangup.recv() => {
+                    // A broken file keeps the previous list rather than emptying the dashboard
+                    if let Ok(h) = source.load().await {
+                        hosts = h;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let res = ui_loop(&mut terminal, rx);
+    ratatui::restore();
+    sweeper.abort();
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error drawing dashboard: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn ui_loop(
+    terminal: &mut DefaultTerminal,
+    mut rx: watch::Receiver<(Vec<Target>, Vec<Host>)>,
+) -> std::io::Result<()> {
+    let mut app = App {
+        entries: Entry::from_sweep(&rx.borrow().0, &[]),
+        transitions: VecDeque::new(),
+        updated: None,
+        sort: SortKey::Host,
+        reverse: false,
+        filter: String::new(),
+        editing_filter: false,
+    };
+
+    // The previous sweep, to tell what changed since
+    let mut prev: Option<Snapshot> = None;
+    loop {
+        if rx.has_changed().unwrap_or(false) {
+            let (hosts, res) = &*rx.borrow_and_update();
+            app.entries = Entry::from_sweep(hosts, res);
+            app.updated = Some(Instant::now());
+            let snapshot = Snapshot::of(hosts, res);
+            if let Some(prev) = &prev {
+                app.transitions.extend(snapshot.since(prev, now()));
+                while app.transitions.len() > SHOWN_TRANSITIONS {
+                    app.transitions.pop_front();
+                }
+            }
+            prev = Some(snapshot);
+        }
+
+        terminal.draw(|frame| app.draw(frame))?;
+
+        // Polling blocks, so let the runtime move the sweeps off this worker meanwhile
+        let ready = tokio::task::block_in_place(|| event::poll(Duration::from_millis(250)))?;
+        if ready {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !app.handle_key(key.code, key.modifiers) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
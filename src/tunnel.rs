@@ -0,0 +1,136 @@
+use std::{collections::HashMap, fmt, io, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::Mutex,
+};
+use tokio_socks::{tcp::Socks5Stream, TargetAddr};
+
+use crate::jump;
+
+/// How hosts that can't be reached directly from here are reached
+pub enum Route {
+    /// SSH through a bastion, from `--ssh-jump`
+    Ssh(jump::Jump),
+    /// A SOCKS5 proxy, from `--proxy`
+    Socks5(Proxy),
+}
+
+/// A SOCKS5 proxy, given as `socks5://[user:password@]host:port`
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    addr: String,
+    login: Option<(String, String)>,
+}
+
+pub fn parse_proxy(s: &str) -> Result<Proxy, String> {
+    let rest = s
+        .strip_prefix("socks5://")
+        .ok_or_else(|| format!("expected socks5://host:port, got {}", s))?;
+    let rest = rest.trim_end_matches('/');
+    let (login, addr) = match rest.rsplit_once('@') {
+        Some((userinfo, addr)) => {
+            let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            (Some((user.to_string(), password.to_string())), addr)
+        }
+        None => (None, rest),
+    };
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(Proxy {
+            addr: addr.to_string(),
+            login,
+        }),
+        _ => Err(format!("expected socks5://host:port, got {}", s)),
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Route::Ssh(jump) => write!(f, "{}", jump.bastion()),
+            Route::Socks5(proxy) => write!(f, "socks5://{}", proxy.addr),
+        }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Hands out local addresses that lead to hosts over a route, so sqlx and the replication
+/// client can connect as usual
+pub struct Tunnel {
+    route: Route,
+    /// Local listener for each `host:port`
+    forwards: Mutex<HashMap<(String, u16), SocketAddr>>,
+}
+
+impl fmt::Debug for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Tunnel")
+            .field(&self.route.to_string())
+            .finish()
+    }
+}
+
+impl Tunnel {
+    pub fn new(route: Route) -> Self {
+        Tunnel {
+            route,
+            forwards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A local address that reaches `host:port` as seen from the far end of the route, set up
+    /// on first use. Names are resolved there too.
+    pub async fn forward(self: &Arc<Self>, host: &str, port: u16) -> io::Result<SocketAddr> {
+        let mut forwards = self.forwards.lock().await;
+        if let Some(local) = forwards.get(&(host.to_string(), port)) {
+            return Ok(*local);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local = listener.local_addr()?;
+        let (tunnel, target) = (self.clone(), host.to_string());
+        tokio::spawn(async move {
+            while let Ok((mut socket, peer)) = listener.accept().await {
+                let (tunnel, target) = (tunnel.clone(), target.clone());
+                tokio::spawn(async move {
+                    match tunnel.open(&target, port, peer).await {
+                        Ok(mut stream) => {
+                            let _ = copy_bidirectional(&mut socket, &mut stream).await;
+                        }
+                        Err(e) => eprintln!(
+                            "Error tunneling to {}:{} through {}: {}",
+                            target, port, tunnel.route, e
+                        ),
+                    }
+                });
+            }
+        });
+        forwards.insert((host.to_string(), port), local);
+        Ok(local)
+    }
+
+    async fn open(&self, host: &str, port: u16, peer: SocketAddr) -> io::Result<Box<dyn Stream>> {
+        match &self.route {
+            Route::Ssh(jump) => Ok(Box::new(jump.open(host, port, peer).await?)),
+            Route::Socks5(proxy) => {
+                let target = TargetAddr::Domain(host.into(), port);
+                let stream = match &proxy.login {
+                    Some((user, password)) => {
+                        Socks5Stream::connect_with_password(
+                            proxy.addr.as_str(),
+                            target,
+                            user,
+                            password,
+                        )
+                        .await
+                    }
+                    None => Socks5Stream::connect(proxy.addr.as_str(), target).await,
+                };
+                Ok(Box::new(stream.map_err(io::Error::other)?))
+            }
+        }
+    }
+}
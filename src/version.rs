@@ -0,0 +1,48 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A server's `server_version_num`, which decides the functions and views the checks can use
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Version(pub u32);
+
+impl Version {
+    /// pg_stat_ssl
+    pub const PG9_5: Version = Version(90500);
+    /// pg_control_checkpoint(), pg_control_system() and pg_stat_wal_receiver
+    pub const PG9_6: Version = Version(90600);
+    /// xlog became wal and location became lsn in function and directory names,
+    /// pg_stat_replication got its lag columns, and logical replication
+    pub const PG10: Version = Version(100000);
+    /// pg_stat_wal_receiver.sender_host
+    pub const PG11: Version = Version(110000);
+    /// The recovery settings moved from recovery.conf into pg_settings
+    pub const PG12: Version = Version(120000);
+    /// pg_replication_slots.wal_status
+    pub const PG13: Version = Version(130000);
+    /// pg_stat_subscription_stats
+    pub const PG15: Version = Version(150000);
+
+    /// `name` as this server spells it, e.g. `pg_current_wal_lsn` is `pg_current_xlog_location`
+    /// before 10
+    pub fn wal(self, name: &str) -> String {
+        if self >= Version::PG10 {
+            name.to_string()
+        } else {
+            name.replace("wal", "xlog").replace("lsn", "location")
+        }
+    }
+}
+
+/// The major version, `9.6` or `15`
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Version(num) = *self;
+        if num >= 100000 {
+            write!(f, "{}", num / 10000)
+        } else {
+            write!(f, "{}.{}", num / 10000, num / 100 % 100)
+        }
+    }
+}
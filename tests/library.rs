@@ -0,0 +1,108 @@
+//! Uses the library the way an application embedding it would, with a probe answering for a
+//! few made-up hosts instead of servers
+
+use std::{future, io, time::Duration};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use timeline_check::{
+    check_hosts_stream, CheckError, Cluster, Encryption, Error, Failure, Health, Host, HostProbe,
+    HostReport, LagStats, Logical, Phases, Reason, RecoveryConfig, Replica, Role, ServerTls, Slot,
+    Status, Summary, Switch, SyncReplication, Target, Version, WalSource,
+};
+
+/// db1 is a primary and db2 its standby, db3 refuses the connection and db4 never answers
+#[derive(Clone)]
+struct Fleet;
+
+#[async_trait]
+impl HostProbe for Fleet {
+    async fn probe(&self, target: Target) -> Result<Host, Error> {
+        match target.name.as_str() {
+            "db3" => Err(io::Error::from(io::ErrorKind::ConnectionRefused).into()),
+            "db4" => future::pending().await,
+            name => {
+                let mut host = Host::default();
+                host.name = name.to_string();
+                host.is_primary = name == "db1";
+                host.timeline_id = Some(1);
+                Ok(host)
+            }
+        }
+    }
+}
+
+/// Checks `names` until they're done or `cutoff` passes, sorted by name
+async fn check(names: &[&str], cutoff: Duration) -> Vec<HostReport> {
+    let hosts: Vec<Target> = names.iter().map(|name| Target::new(name)).collect();
+    let cutoff = async move {
+        tokio::time::sleep(cutoff).await;
+        io::Error::new(io::ErrorKind::TimedOut, "ran out of time")
+    };
+    let mut reports: Vec<HostReport> = check_hosts_stream(&Fleet, &hosts, 2, cutoff)
+        .collect()
+        .await;
+    reports.sort_by(|a, b| a.name.cmp(&b.name));
+    reports
+}
+
+#[tokio::test]
+async fn each_host_is_yielded_with_its_state_or_what_went_wrong() {
+    let reports = check(&["db1", "db2", "db3"], Duration::from_secs(60)).await;
+    let outcomes: Vec<(&str, Result<bool, &str>)> = reports
+        .iter()
+        .map(|r| {
+            let result = r.result.as_ref().map(|h| h.is_primary);
+            (r.name.as_str(), result.map_err(|e| e.kind()))
+        })
+        .collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            ("db1", Ok(true)),
+            ("db2", Ok(false)),
+            ("db3", Err("connect failed")),
+        ]
+    );
+    assert!(matches!(
+        &reports[2].result,
+        Err(CheckError::ConnectFailed { host, .. }) if host == "db3"
+    ));
+}
+
+#[tokio::test]
+async fn hosts_still_being_checked_at_the_cutoff_are_yielded_with_it() {
+    let reports = check(&["db1", "db4"], Duration::from_millis(50)).await;
+    assert_eq!(reports.len(), 2);
+    assert!(reports[0].result.is_ok());
+    match &reports[1].result {
+        Err(e @ CheckError::Timeout { .. }) => assert_eq!(e.to_string(), "ran out of time"),
+        other => panic!("db4 wasn't cut off: {:?}", other),
+    }
+}
+
+/// Every type a host's or summary's fields are written with can be named from outside the crate,
+/// to keep them around or hand them on
+#[test]
+fn the_types_of_result_fields_are_exported() {
+    let host = Host::default();
+    let _: &Role = &host.role;
+    let _: &Option<Vec<Switch>> = &host.timeline_history;
+    let _: &Option<WalSource> = &host.wal_source;
+    let _: &Option<RecoveryConfig> = &host.recovery;
+    let _: &Vec<Replica> = &host.replicas;
+    let _: &Option<ServerTls> = &host.tls;
+    let _: &Option<Encryption> = &host.encryption;
+    let _: &Vec<Slot> = &host.slots;
+    let _: &Option<SyncReplication> = &host.sync;
+    let _: &Option<Logical> = &host.logical;
+    let _: &Option<Phases> = &host.phases;
+    let _: &Option<Status> = &host.status;
+    let _: &Option<Reason> = &host.reason;
+    let _: &Option<Version> = &host.server_version_num;
+    let summary = Summary::new(&[host], 1, Duration::ZERO);
+    let _: &Vec<Cluster> = &summary.clusters;
+    let _: &Option<LagStats> = &summary.lag;
+    let _: &Health = &summary.health;
+    let _: &Vec<Failure> = &summary.failed;
+}
@@ -0,0 +1,233 @@
+//! Runs timeline_check against a real primary and streaming standby in Docker and promotes the
+//! standby. Needs a Docker daemon, so the tests are ignored by default:
+//!
+//!     cargo test --test promotion -- --ignored
+
+use std::{
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    process::{self, Output},
+};
+
+use serde_json::Value;
+use sqlx::{postgres::PgConnectOptions, ConnectOptions};
+use testcontainers::{
+    core::{IntoContainerPort, WaitFor},
+    runners::AsyncRunner,
+    ContainerAsync, GenericImage, ImageExt,
+};
+use tokio::{net::lookup_host, process::Command};
+
+const IMAGE: (&str, &str) = ("postgres", "16");
+const PASSWORD: &str = "pw";
+
+/// Lets pg_basebackup in from the standby's container
+const ALLOW_REPLICATION: &str =
+    "echo 'host replication all all scram-sha-256' >> \"$PGDATA/pg_hba.conf\"\n";
+
+/// A primary and a standby streaming from it, on a Docker network of their own
+struct Pair {
+    primary: ContainerAsync<GenericImage>,
+    standby: ContainerAsync<GenericImage>,
+    address: IpAddr,
+    dir: PathBuf,
+}
+
+impl Pair {
+    /// `name` keeps the containers, network and files of tests running at once apart
+    async fn start(name: &str) -> Pair {
+        let suffix = format!("{}-{}", name, process::id());
+        let network = format!("timeline_check-{}", suffix);
+        let primary_name = format!("primary-{}", suffix);
+
+        let primary = GenericImage::new(IMAGE.0, IMAGE.1)
+            .with_exposed_port(5432.tcp())
+            .with_wait_for(WaitFor::message_on_stdout(
+                "PostgreSQL init process complete; ready for start up.",
+            ))
+            .with_env_var("POSTGRES_PASSWORD", PASSWORD)
+            .with_network(&network)
+            .with_container_name(&primary_name)
+            .with_copy_to(
+                "/docker-entrypoint-initdb.d/replication.sh",
+                ALLOW_REPLICATION.as_bytes().to_vec(),
+            )
+            .start()
+            .await
+            .expect("starting the primary");
+
+        // The primary may still be restarting after initdb, so the base backup is retried
+        let script = format!(
+            "export PGPASSWORD={password}
+             mkdir -p /tmp/standby && chown postgres /tmp/standby && chmod 700 /tmp/standby
+             until gosu postgres pg_basebackup -h {primary} -U postgres -D /tmp/standby -R -X stream
+             do rm -rf /tmp/standby/*; sleep 1
+             done
+             exec gosu postgres postgres -D /tmp/standby",
+            password = PASSWORD,
+            primary = primary_name
+        );
+        let standby = GenericImage::new(IMAGE.0, IMAGE.1)
+            .with_entrypoint("bash")
+            .with_exposed_port(5432.tcp())
+            .with_wait_for(WaitFor::message_on_stderr(
+                "database system is ready to accept read-only connections",
+            ))
+            .with_cmd(["-c", script.as_str()])
+            .with_network(&network)
+            .with_container_name(format!("standby-{}", suffix))
+            .start()
+            .await
+            .expect("starting the standby");
+
+        let host = primary.get_host().await.expect("Docker host").to_string();
+        let address = lookup_host((host.as_str(), 0))
+            .await
+            .expect("resolving the Docker host")
+            .next()
+            .expect("the Docker host has no address")
+            .ip();
+
+        let dir = std::env::temp_dir().join(format!("timeline_check-{}", suffix));
+        fs::create_dir_all(&dir).unwrap();
+        let pair = Pair {
+            primary,
+            standby,
+            address,
+            dir,
+        };
+        let hosts = format!(
+            "primary port={}\nstandby port={}\n",
+            pair.port(&pair.primary).await,
+            pair.port(&pair.standby).await
+        );
+        fs::write(pair.hosts_file(), hosts).unwrap();
+        pair
+    }
+
+    async fn port(&self, container: &ContainerAsync<GenericImage>) -> u16 {
+        container
+            .get_host_port_ipv4(5432.tcp())
+            .await
+            .expect("mapped port")
+    }
+
+    fn hosts_file(&self) -> PathBuf {
+        self.dir.join("hosts")
+    }
+
+    /// Runs timeline_check with `args` after the subcommand, remembering roles and timelines
+    /// between runs in this pair's directory
+    async fn run(&self, args: &[&str]) -> Output {
+        let resolve = |name| format!("{}={}", name, self.address);
+        Command::new(env!("CARGO_BIN_EXE_timeline_check"))
+            .args(["--user", "postgres", "--password", PASSWORD, "--hosts"])
+            .arg(self.hosts_file())
+            .arg("--last-run-file")
+            .arg(self.dir.join("last_run.json"))
+            .args(["--resolve", &resolve("primary")])
+            .args(["--resolve", &resolve("standby")])
+            .args(args)
+            .output()
+            .await
+            .expect("running timeline_check")
+    }
+
+    /// Each host's role and timeline from `report --format json`
+    async fn report(&self) -> Vec<(String, String, u64)> {
+        let out = self.run(&["report", "--format", "json"]).await;
+        assert!(out.status.success(), "report failed: {}", stderr(&out));
+        let report: Value = serde_json::from_slice(&out.stdout).expect("JSON report");
+        let mut hosts: Vec<(String, String, u64)> = report["hosts"]
+            .as_array()
+            .expect("hosts in the report")
+            .iter()
+            .map(|h| {
+                (
+                    h["name"].as_str().unwrap().to_string(),
+                    h["role"].as_str().unwrap().to_string(),
+                    h["timeline_id"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        hosts.sort();
+        hosts
+    }
+
+    /// Promotes the standby and waits until its control file shows the new timeline
+    async fn promote(&self) {
+        let mut conn = PgConnectOptions::new()
+            .host(&self.address.to_string())
+            .port(self.port(&self.standby).await)
+            .username("postgres")
+            .password(PASSWORD)
+            .connect()
+            .await
+            .expect("connecting to the standby");
+        let promoted: bool = sqlx::query_scalar("SELECT pg_promote(true, 60)")
+            .fetch_one(&mut conn)
+            .await
+            .expect("pg_promote");
+        assert!(promoted, "the standby wasn't promoted within 60s");
+        // The timeline is read from the last checkpoint, which the end-of-recovery one may
+        // not have reached yet
+        sqlx::query("CHECKPOINT")
+            .execute(&mut conn)
+            .await
+            .expect("CHECKPOINT");
+    }
+}
+
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+fn host(name: &str, role: &str, timeline: u64) -> (String, String, u64) {
+    (name.to_string(), role.to_string(), timeline)
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn promotion_is_detected() {
+    let pair = Pair::start("promotion").await;
+
+    assert_eq!(
+        pair.report().await,
+        vec![host("primary", "primary", 1), host("standby", "standby", 1)]
+    );
+    let healthy = pair.run(&["check"]).await;
+    assert_eq!(healthy.status.code(), Some(0), "{}", stdout(&healthy));
+
+    pair.promote().await;
+
+    // The old primary is still up, so the cluster now has two
+    let split = pair.run(&["check"]).await;
+    assert_eq!(split.status.code(), Some(1), "{}", stdout(&split));
+    let out = stdout(&split);
+    assert!(
+        out.contains("split brain: primary, standby are all primaries"),
+        "{}",
+        out
+    );
+    assert!(
+        out.contains("standby went from timeline 1 to 2 and from standby to primary"),
+        "{}",
+        out
+    );
+
+    assert_eq!(
+        pair.report().await,
+        vec![host("primary", "primary", 1), host("standby", "primary", 2)]
+    );
+}